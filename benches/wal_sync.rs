@@ -0,0 +1,52 @@
+//! Put throughput under each WAL sync policy:
+//!
+//!     cargo bench --bench wal_sync
+//!
+//! Writers put to one collection concurrently. With `always`, each put
+//! waits for its entry to be synced, so one writer is held to the disk's
+//! sync rate; many writers share syncs.
+
+use my_vector_db::collection::{Catalog, CollectionConfig};
+use my_vector_db::payload::Payload;
+use my_vector_db::wal::{SyncPolicy, WalConfig};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const PUTS: usize = 2000;
+const DIMENSION: usize = 32;
+
+#[tokio::main]
+async fn main() {
+    let dir = std::env::temp_dir().join(format!("vdb_bench_wal_sync_{}", std::process::id()));
+    let runs = [
+        ("always, 1 writer", SyncPolicy::Always, 1),
+        ("always, 32 writers", SyncPolicy::Always, 32),
+        ("interval 100ms, 32 writers", SyncPolicy::Interval(Duration::from_millis(100)), 32),
+        ("os, 32 writers", SyncPolicy::Os, 32),
+    ];
+
+    for (name, sync, writers) in runs {
+        let _ = std::fs::remove_dir_all(&dir);
+        let catalog = Catalog::open(&dir, WalConfig { sync, ..Default::default() }).unwrap();
+        let collection = catalog.create("bench", CollectionConfig::default()).unwrap();
+
+        let start = Instant::now();
+        let tasks: Vec<_> = (0..writers)
+            .map(|writer| {
+                let collection = Arc::clone(&collection);
+                tokio::spawn(async move {
+                    for id in (writer..PUTS).step_by(writers) {
+                        let vector = (0..DIMENSION).map(|i| ((id * 31 + i) % 97) as f32).collect();
+                        collection.put(id as u32, vector, Payload::new(), 0).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let elapsed = start.elapsed();
+        println!("{:<28} {:>10.0} puts/s", name, PUTS as f64 / elapsed.as_secs_f64());
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -1,5 +1,7 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
     tonic_build::compile_protos("proto/vector_db.proto")?;
+    tonic_build::compile_protos("proto/gossip.proto")?;
+    tonic_build::compile_protos("proto/raft.proto")?;
     Ok(())
 }
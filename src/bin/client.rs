@@ -1,12 +1,52 @@
-use tonic::transport::Channel;
 use clap::{Parser, Subcommand};
+use my_vector_db::index::distance::Metric;
 
 pub mod vector_db {
     tonic::include_proto!("vector_db");
 }
 
+use vector_db::router_admin_client::RouterAdminClient;
 use vector_db::vector_db_client::VectorDbClient;
-use vector_db::{PutRequest, SearchRequest};
+use vector_db::value::Kind;
+use vector_db::{
+    filter, CreateCollectionRequest, DeleteRequest, DescribeCollectionRequest, DropCollectionRequest, Filter,
+    FilterList, GetMembershipRequest, GetRebalanceStatusRequest, GetRequest, ListCollectionsRequest, Match, PutRequest,
+    ReloadMembershipRequest, RepairRequest, SearchRequest, StringList, Value,
+};
+
+/// Parses `key=value`. The value is read as a bool, int or float if it looks
+/// like one, as a string list if written `[a,b,c]`, and as a string otherwise.
+fn parse_payload_entry(s: &str) -> Result<(String, Value), String> {
+    let (key, raw) = s.split_once('=').ok_or_else(|| format!("expected key=value, got '{}'", s))?;
+    let kind = if let Some(list) = raw.strip_prefix('[').and_then(|r| r.strip_suffix(']')) {
+        let values = list.split(',').filter(|v| !v.is_empty()).map(str::to_string).collect();
+        Kind::StringListValue(StringList { values })
+    } else if let Ok(v) = raw.parse::<bool>() {
+        Kind::BoolValue(v)
+    } else if let Ok(v) = raw.parse::<i64>() {
+        Kind::IntValue(v)
+    } else if let Ok(v) = raw.parse::<f64>() {
+        Kind::FloatValue(v)
+    } else {
+        Kind::StringValue(raw.to_string())
+    };
+    Ok((key.to_string(), Value { kind: Some(kind) }))
+}
+
+/// ANDs together `key=value` equality conditions.
+fn equality_filter(conditions: &[(String, Value)]) -> Option<Filter> {
+    let mut filters: Vec<Filter> = conditions
+        .iter()
+        .map(|(key, value)| Filter {
+            kind: Some(filter::Kind::Eq(Match { key: key.clone(), value: Some(value.clone()) })),
+        })
+        .collect();
+    match filters.len() {
+        0 => None,
+        1 => filters.pop(),
+        _ => Some(Filter { kind: Some(filter::Kind::And(FilterList { filters })) }),
+    }
+}
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -14,6 +54,10 @@ struct Cli {
     #[arg(long, default_value = "http://[::1]:50050")]
     url: String,
 
+    /// Collection to operate on (defaults to the "default" collection)
+    #[arg(long, default_value = "")]
+    collection: String,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -25,12 +69,75 @@ enum Commands {
         id: u32,
         #[arg(long, value_delimiter = ',')]
         vector: Vec<f32>,
+        /// Metadata as key=value, repeatable
+        #[arg(long, value_parser = parse_payload_entry)]
+        payload: Vec<(String, Value)>,
     },
     Search {
         #[arg(long, value_delimiter = ',')]
         vector: Vec<f32>,
         #[arg(long, default_value_t = 5)]
         k: u32,
+        /// Search beam width (defaults to the server's ef_search)
+        #[arg(long)]
+        ef: Option<u32>,
+        /// Return each result's payload
+        #[arg(long)]
+        with_payload: bool,
+        /// Only match vectors whose payload has key=value, repeatable (all must hold)
+        #[arg(long = "where", value_parser = parse_payload_entry)]
+        conditions: Vec<(String, Value)>,
+        /// Through the router: fail rather than return results missing part of the data
+        #[arg(long)]
+        fail_on_partial: bool,
+        /// Through the router: how long each node gets to answer
+        #[arg(long)]
+        shard_timeout_ms: Option<u32>,
+    },
+    Get {
+        #[arg(long)]
+        id: u32,
+    },
+    Delete {
+        #[arg(long)]
+        id: u32,
+    },
+    /// Describe the collection given by --collection
+    Describe,
+    CreateCollection {
+        #[arg(long)]
+        name: String,
+        #[arg(long, default_value_t = Metric::Euclidean)]
+        metric: Metric,
+        #[arg(long, default_value_t = 16)]
+        m: u32,
+        #[arg(long, default_value_t = 100)]
+        ef_construction: u32,
+        #[arg(long)]
+        ef_search: Option<u32>,
+        /// Declared vector dimension
+        #[arg(long)]
+        dimension: Option<u32>,
+    },
+    DropCollection {
+        #[arg(long)]
+        name: String,
+    },
+    ListCollections,
+    /// Show the router's backends
+    Membership,
+    /// Replace the router's backends, or re-read its config file if none are given
+    ReloadMembership {
+        #[arg(long = "backend")]
+        backends: Vec<String>,
+    },
+    /// Show the progress of the router's last rebalance
+    RebalanceStatus,
+    /// Compare replicas through the router and fix the keys they disagree on
+    Repair {
+        /// Only count divergent keys
+        #[arg(long)]
+        dry_run: bool,
     },
 }
 
@@ -38,27 +145,97 @@ enum Commands {
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
-    let mut client = VectorDbClient::connect(cli.url).await?;
+    let channel = tonic::transport::Endpoint::from_shared(cli.url)?.connect().await?;
+    let mut client = VectorDbClient::new(channel.clone());
+    let mut admin = RouterAdminClient::new(channel);
 
     match &cli.command {
-        Commands::Put { id, vector } => {
+        Commands::Put { id, vector, payload } => {
             let request = tonic::Request::new(PutRequest {
                 id: *id,
                 vector: vector.clone(),
+                payload: payload.iter().cloned().collect(),
+                collection: cli.collection.clone(),
+                ..Default::default()
             });
 
             let response = client.put(request).await?;
             println!("Put response: {:?}", response.into_inner());
         }
-        Commands::Search { vector, k } => {
+        Commands::Search { vector, k, ef, with_payload, conditions, fail_on_partial, shard_timeout_ms } => {
             let request = tonic::Request::new(SearchRequest {
                 vector: vector.clone(),
                 k: *k,
+                ef: *ef,
+                with_payload: *with_payload,
+                filter: equality_filter(conditions),
+                collection: cli.collection.clone(),
+                fail_on_partial: *fail_on_partial,
+                shard_timeout_ms: *shard_timeout_ms,
+                ..Default::default()
             });
 
             let response = client.search(request).await?;
             println!("Search response: {:?}", response.into_inner());
         }
+        Commands::Get { id } => {
+            let request = GetRequest { id: *id, collection: cli.collection.clone() };
+            let response = client.get(tonic::Request::new(request)).await?;
+            println!("Get response: {:?}", response.into_inner());
+        }
+        Commands::Delete { id } => {
+            let request = DeleteRequest { id: *id, collection: cli.collection.clone(), ..Default::default() };
+            let response = client.delete(tonic::Request::new(request)).await?;
+            println!("Delete response: {:?}", response.into_inner());
+        }
+        Commands::Describe => {
+            let request = DescribeCollectionRequest { name: cli.collection.clone() };
+            let response = client.describe_collection(tonic::Request::new(request)).await?;
+            println!("Describe response: {:?}", response.into_inner());
+        }
+        Commands::CreateCollection { name, metric, m, ef_construction, ef_search, dimension } => {
+            let metric = match metric {
+                Metric::Euclidean => vector_db::Metric::Euclidean,
+                Metric::Cosine => vector_db::Metric::Cosine,
+                Metric::DotProduct => vector_db::Metric::DotProduct,
+            };
+            let request = tonic::Request::new(CreateCollectionRequest {
+                name: name.clone(),
+                metric: metric as i32,
+                m: *m,
+                ef_construction: *ef_construction,
+                ef_search: *ef_search,
+                dimension: dimension.unwrap_or(0),
+            });
+            let response = client.create_collection(request).await?;
+            println!("Create collection response: {:?}", response.into_inner());
+        }
+        Commands::DropCollection { name } => {
+            let request = DropCollectionRequest { name: name.clone() };
+            let response = client.drop_collection(tonic::Request::new(request)).await?;
+            println!("Drop collection response: {:?}", response.into_inner());
+        }
+        Commands::ListCollections => {
+            let response = client.list_collections(tonic::Request::new(ListCollectionsRequest {})).await?;
+            println!("List collections response: {:?}", response.into_inner());
+        }
+        Commands::Membership => {
+            let response = admin.get_membership(tonic::Request::new(GetMembershipRequest {})).await?;
+            println!("Membership: {:?}", response.into_inner());
+        }
+        Commands::ReloadMembership { backends } => {
+            let request = ReloadMembershipRequest { backends: backends.clone() };
+            let response = admin.reload_membership(tonic::Request::new(request)).await?;
+            println!("Membership: {:?}", response.into_inner());
+        }
+        Commands::RebalanceStatus => {
+            let response = admin.get_rebalance_status(tonic::Request::new(GetRebalanceStatusRequest {})).await?;
+            println!("Rebalance status: {:?}", response.into_inner());
+        }
+        Commands::Repair { dry_run } => {
+            let response = admin.repair(tonic::Request::new(RepairRequest { dry_run: *dry_run })).await?;
+            println!("Repair report: {:?}", response.into_inner());
+        }
     }
 
     Ok(())
@@ -1,9 +1,30 @@
-use tonic::{transport::Channel, transport::Server, Request, Response, Status};
-use std::collections::{BTreeMap, HashSet};
-use std::hash::{Hash, Hasher};
-use std::collections::hash_map::DefaultHasher;
+// tonic::Status is large, but it is the natural error type for request handlers
+#![allow(clippy::result_large_err)]
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use tonic::{transport::{Channel, Endpoint, Server}, Request, Response, Status};
+use futures::future::join_all;
+use prost::Message;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::RwLock;
+use my_vector_db::collection::DEFAULT_COLLECTION;
+use my_vector_db::hints::{now_ms, Hint, HintStore};
+use my_vector_db::merkle::{Checksum, MerkleTree};
+use my_vector_db::raft::{group_id, leader_hint};
+use my_vector_db::wal::{OpType, VersionClock};
+use my_vector_db::network::gossip::{GossipConfig, GossipNode, MemberState, Role};
+use my_vector_db::network::quorum::{is_unreachable, newest, recency, WriteTally};
+use my_vector_db::network::ring::{
+    coalesce, covering_plan, ownership_changes, rebalance_plan, ConsistentHashRing, HashRange, RebalancePlan, DEFAULT_VIRTUAL_NODES,
+};
+use rand::seq::SliceRandom;
 
 pub mod vector_db {
     tonic::include_proto!("vector_db");
@@ -11,137 +32,1271 @@ pub mod vector_db {
 
 use vector_db::vector_db_server::{VectorDb, VectorDbServer};
 use vector_db::vector_db_client::VectorDbClient;
-use vector_db::{PutRequest, PutResponse, SearchRequest, SearchResponse, SearchResult};
+use vector_db::router_admin_server::{RouterAdmin, RouterAdminServer};
+use vector_db::{
+    CollectionInfo, CreateCollectionRequest, CreateCollectionResponse, DeleteRequest, DeleteResponse,
+    DescribeCollectionRequest, DigestRequest, DigestResponse, DropCollectionRequest, DropCollectionResponse,
+    GetMembershipRequest, GetRebalanceStatusRequest, GetRequest, GetResponse, ListCollectionsRequest,
+    ListCollectionsResponse, LoadRequest, LoadResponse, Membership, PutRequest, PutResponse, RebalanceStatus,
+    ReloadMembershipRequest, ReplicateRequest, RepairReport, RepairRequest, ScanEntry, ScanRequest, SearchRequest,
+    SearchResponse, ShardStatus, SnapshotRequest, SnapshotResponse, WalRecord,
+};
+use vector_db::rebalance_status::State as RebalanceState;
+use vector_db::shard_status::State as ShardState;
+
+/// How writes reach a key's replicas.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Consistency {
+    /// Sent to every replica at once; acknowledged by `write_quorum` of them
+    #[default]
+    Eventual,
+    /// Committed through the Raft group of the key's replicas, in one order
+    /// on all of them; acknowledged by its leader once a majority has it
+    Strong,
+}
 
-#[derive(Clone)]
-pub struct ConsistentHashRing {
-    nodes: BTreeMap<u64, String>, // Hash -> Node Address
+/// Router settings, read from a TOML file and overridden by command-line flags.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouterConfig {
+    pub listen: SocketAddr,
+    // Backend URLs, e.g. "http://[::1]:50051"
+    pub backends: Vec<String>,
+    // Relative share of keys per backend; unlisted backends have weight 1.0
+    pub weights: HashMap<String, f64>,
+    // Ring points per backend of weight 1.0
+    pub virtual_nodes: usize,
+    pub replication_factor: usize,
+    // Replicas that must answer a Get
+    pub read_quorum: usize,
+    // Replicas that must acknowledge a Put or Delete, in eventual consistency
+    pub write_quorum: usize,
+    pub consistency: Consistency,
+    // A backend that can't be reached within this long counts as failed
+    pub connect_timeout_ms: u64,
+    // Deadline for each call to a backend
+    pub request_timeout_ms: u64,
+    // Deadline for each node's part of a search, unless the request sets one
+    pub search_shard_timeout_ms: u64,
+    // Gossip members to join through. When set, the backends follow the
+    // gossiped membership and `backends` is ignored.
+    pub seeds: Vec<String>,
+    // URL gossip members reach the router at; defaults to http://<listen>
+    pub advertise: Option<String>,
+    pub gossip_period_ms: u64,
+    // How long a suspect backend has to refute before it's declared dead
+    pub suspicion_timeout_ms: u64,
+    // Writes a backend missed are kept here until it comes back
+    pub hints_dir: PathBuf,
+    // Hints kept per backend; 0 disables hinted handoff
+    pub max_hint_bytes: u64,
+    // Hints older than this are dropped instead of replayed
+    pub max_hint_age_secs: u64,
+    // How often to try replaying hints
+    pub hint_replay_interval_ms: u64,
+    // How often to compare replicas and repair differences; 0 disables
+    pub repair_interval_secs: u64,
 }
 
-impl ConsistentHashRing {
-    pub fn new() -> Self {
-        ConsistentHashRing {
-            nodes: BTreeMap::new(),
+impl Default for RouterConfig {
+    fn default() -> Self {
+        RouterConfig {
+            listen: "[::1]:50050".parse().unwrap(),
+            backends: vec![
+                "http://[::1]:50051".to_string(),
+                "http://[::1]:50052".to_string(),
+                "http://[::1]:50053".to_string(),
+            ],
+            weights: HashMap::new(),
+            virtual_nodes: DEFAULT_VIRTUAL_NODES,
+            // RF 2 with quorums of 1 favours speed and availability
+            replication_factor: 2,
+            read_quorum: 1,
+            write_quorum: 1,
+            consistency: Consistency::Eventual,
+            connect_timeout_ms: 1000,
+            request_timeout_ms: 5000,
+            search_shard_timeout_ms: 2000,
+            seeds: Vec::new(),
+            advertise: None,
+            gossip_period_ms: 1000,
+            suspicion_timeout_ms: 5000,
+            hints_dir: PathBuf::from("router_hints"),
+            max_hint_bytes: 64 << 20,
+            max_hint_age_secs: 3 * 60 * 60,
+            hint_replay_interval_ms: 10_000,
+            repair_interval_secs: 60 * 60,
         }
     }
+}
+
+impl RouterConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+    }
 
-    pub fn add_node(&mut self, node_addr: &str) {
-        let mut hasher = DefaultHasher::new();
-        node_addr.hash(&mut hasher);
-        let hash = hasher.finish();
-        self.nodes.insert(hash, node_addr.to_string());
-        println!("Added node {} at hash {}", node_addr, hash);
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.replication_factor == 0 {
+            bail!("replication_factor must be at least 1");
+        }
+        for (name, quorum) in [("read_quorum", self.read_quorum), ("write_quorum", self.write_quorum)] {
+            if quorum == 0 || quorum > self.replication_factor {
+                bail!("{} must be between 1 and replication_factor ({})", name, self.replication_factor);
+            }
+        }
+        if self.virtual_nodes == 0 {
+            bail!("virtual_nodes must be at least 1");
+        }
+        if let Some((addr, w)) = self.weights.iter().find(|(_, w)| !(w.is_finite() && **w > 0.0)) {
+            bail!("weight of {} must be a positive number, got {}", addr, w);
+        }
+        if self.gossip_period_ms == 0 || self.suspicion_timeout_ms == 0 || self.hint_replay_interval_ms == 0 {
+            bail!("gossip_period_ms, suspicion_timeout_ms and hint_replay_interval_ms must be positive");
+        }
+        if self.consistency == Consistency::Strong && !self.seeds.is_empty() {
+            bail!("strong consistency needs a fixed set of backends; it can't follow gossip");
+        }
+        if self.seeds.is_empty() {
+            validate_backends(&self.backends)
+        } else {
+            validate_backends(&self.seeds).context("in seeds")
+        }
     }
 
-    // Get the primary node and its successors for replication
-    pub fn get_preference_list(&self, key: u32, n: usize) -> Vec<String> {
-        if self.nodes.is_empty() {
-            return vec![];
+    /// Gossip settings, if the router follows gossiped membership.
+    pub fn gossip(&self) -> Option<GossipConfig> {
+        if self.seeds.is_empty() {
+            return None;
         }
+        let advertise = self.advertise.clone().unwrap_or_else(|| format!("http://{}", self.listen));
+        let mut config = GossipConfig::new(&advertise, Role::Router);
+        config.seeds = self.seeds.clone();
+        config.protocol_period = Duration::from_millis(self.gossip_period_ms);
+        config.ping_timeout = config.protocol_period * 3 / 10;
+        config.suspicion_timeout = Duration::from_millis(self.suspicion_timeout_ms);
+        Some(config)
+    }
+}
 
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash = hasher.finish();
+fn validate_backends(backends: &[String]) -> anyhow::Result<()> {
+    if backends.is_empty() {
+        bail!("at least one backend is required");
+    }
+    for backend in backends {
+        Endpoint::from_shared(backend.clone()).with_context(|| format!("invalid backend address {}", backend))?;
+    }
+    Ok(())
+}
 
-        let mut nodes = Vec::new();
-        let mut unique_nodes = HashSet::new();
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// TOML config file; flags below override its values. Re-read on SIGHUP to reload the backends.
+    #[arg(long)]
+    config: Option<PathBuf>,
 
-        // Find the first node with a hash >= key hash
-        let mut iter = self.nodes.range(hash..).chain(self.nodes.iter());
+    #[arg(long)]
+    listen: Option<SocketAddr>,
 
-        while nodes.len() < n && unique_nodes.len() < self.nodes.len() {
-            if let Some((_, addr)) = iter.next() {
-                if unique_nodes.insert(addr.clone()) {
-                    nodes.push(addr.clone());
-                }
-            } else {
-                // Restart iterator from beginning if we ran out (should be handled by chain, but just in case of logic gaps)
-                // actually chain(self.nodes.iter()) handles the wrap around once. 
-                // If we need to wrap around multiple times (e.g. n > total nodes), we stop at total nodes.
-                break;
-            }
+    /// Backend URL, repeatable. Replaces the config file's list.
+    #[arg(long = "backend")]
+    backends: Vec<String>,
+
+    #[arg(long)]
+    replication_factor: Option<usize>,
+
+    #[arg(long)]
+    virtual_nodes: Option<usize>,
+
+    #[arg(long)]
+    read_quorum: Option<usize>,
+
+    #[arg(long)]
+    write_quorum: Option<usize>,
+
+    #[arg(long)]
+    consistency: Option<Consistency>,
+
+    #[arg(long)]
+    connect_timeout_ms: Option<u64>,
+
+    #[arg(long)]
+    request_timeout_ms: Option<u64>,
+
+    #[arg(long)]
+    search_shard_timeout_ms: Option<u64>,
+
+    /// Gossip seed URL, repeatable. Replaces the config file's list.
+    #[arg(long = "seed")]
+    seeds: Vec<String>,
+
+    #[arg(long)]
+    advertise: Option<String>,
+
+    #[arg(long)]
+    gossip_period_ms: Option<u64>,
+
+    #[arg(long)]
+    suspicion_timeout_ms: Option<u64>,
+
+    #[arg(long)]
+    hints_dir: Option<PathBuf>,
+
+    #[arg(long)]
+    max_hint_bytes: Option<u64>,
+
+    #[arg(long)]
+    max_hint_age_secs: Option<u64>,
+
+    #[arg(long)]
+    hint_replay_interval_ms: Option<u64>,
+
+    #[arg(long)]
+    repair_interval_secs: Option<u64>,
+}
+
+impl Args {
+    fn resolve(&self) -> anyhow::Result<RouterConfig> {
+        let mut config = match &self.config {
+            Some(path) => RouterConfig::load(path)?,
+            None => RouterConfig::default(),
+        };
+        if let Some(listen) = self.listen {
+            config.listen = listen;
         }
-        
-        nodes
+        if !self.backends.is_empty() {
+            config.backends = self.backends.clone();
+        }
+        if let Some(rf) = self.replication_factor {
+            config.replication_factor = rf;
+        }
+        if let Some(virtual_nodes) = self.virtual_nodes {
+            config.virtual_nodes = virtual_nodes;
+        }
+        if let Some(quorum) = self.read_quorum {
+            config.read_quorum = quorum;
+        }
+        if let Some(quorum) = self.write_quorum {
+            config.write_quorum = quorum;
+        }
+        if let Some(consistency) = self.consistency {
+            config.consistency = consistency;
+        }
+        if let Some(ms) = self.connect_timeout_ms {
+            config.connect_timeout_ms = ms;
+        }
+        if let Some(ms) = self.request_timeout_ms {
+            config.request_timeout_ms = ms;
+        }
+        if let Some(ms) = self.search_shard_timeout_ms {
+            config.search_shard_timeout_ms = ms;
+        }
+        if !self.seeds.is_empty() {
+            config.seeds = self.seeds.clone();
+        }
+        if let Some(advertise) = &self.advertise {
+            config.advertise = Some(advertise.clone());
+        }
+        if let Some(ms) = self.gossip_period_ms {
+            config.gossip_period_ms = ms;
+        }
+        if let Some(ms) = self.suspicion_timeout_ms {
+            config.suspicion_timeout_ms = ms;
+        }
+        if let Some(dir) = &self.hints_dir {
+            config.hints_dir = dir.clone();
+        }
+        if let Some(bytes) = self.max_hint_bytes {
+            config.max_hint_bytes = bytes;
+        }
+        if let Some(secs) = self.max_hint_age_secs {
+            config.max_hint_age_secs = secs;
+        }
+        if let Some(ms) = self.hint_replay_interval_ms {
+            config.hint_replay_interval_ms = ms;
+        }
+        if let Some(secs) = self.repair_interval_secs {
+            config.repair_interval_secs = secs;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn build_ring(virtual_nodes: usize, backends: &[String], weights: &HashMap<String, f64>) -> ConsistentHashRing {
+    let mut ring = ConsistentHashRing::new(virtual_nodes);
+    for backend in backends {
+        ring.add_weighted_node(backend, weights.get(backend).copied().unwrap_or(1.0));
+    }
+    ring
+}
+
+/// Long-lived channels to the backends, one per address. A `Channel`
+/// multiplexes concurrent requests over one HTTP/2 connection and reconnects
+/// by itself, so it is created once and cloned for every request.
+pub struct ConnectionPool {
+    channels: Mutex<HashMap<String, Channel>>,
+    connect_timeout: Duration,
+    request_timeout: Duration,
+}
+
+impl ConnectionPool {
+    pub fn new(connect_timeout: Duration, request_timeout: Duration) -> Self {
+        ConnectionPool {
+            channels: Mutex::new(HashMap::new()),
+            connect_timeout,
+            request_timeout,
+        }
+    }
+
+    pub fn client(&self, addr: &str) -> Result<VectorDbClient<Channel>, Status> {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(channel) = channels.get(addr) {
+            return Ok(VectorDbClient::new(channel.clone()));
+        }
+
+        let channel = Endpoint::from_shared(addr.to_string())
+            .map_err(|e| Status::internal(format!("Invalid backend address {}: {}", addr, e)))?
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.request_timeout)
+            .connect_lazy();
+        channels.insert(addr.to_string(), channel.clone());
+        Ok(VectorDbClient::new(channel))
+    }
+
+    /// Closes the channels to every backend not in `addrs`.
+    pub fn retain(&self, addrs: &[String]) {
+        self.channels.lock().unwrap().retain(|addr, _| addrs.contains(addr));
+    }
+}
+
+/// The ring serving requests, and the ring being moved to, if any.
+struct Topology {
+    ring: ConsistentHashRing,
+    rebalance: Option<Arc<Rebalance>>,
+}
+
+// A rebalance copies entries in batches of about this many encoded bytes,
+// well under gRPC's default 4 MiB message limit
+const COPY_BATCH_BYTES: usize = 1 << 20;
+
+// A collection name and vector id
+type Key = (String, u32);
+
+// A write in strong consistency mode tries this many times to find its
+// group's leader, pausing between members that fail
+const CONSENSUS_ATTEMPTS: usize = 20;
+const CONSENSUS_RETRY_PAUSE: Duration = Duration::from_millis(200);
+
+// Repair compares trees of 1024 leaves, so a leaf of a replica set holding a
+// million keys lists about a thousand
+const REPAIR_DEPTH: u32 = 10;
+
+/// A move to a new ring, from the moment writes start going to both rings
+/// until the cut-over.
+struct Rebalance {
+    ring: ConsistentHashRing,
+    // The first write that failed to reach a new owner
+    error: Mutex<Option<String>>,
+}
+
+impl Rebalance {
+    /// New owners of a key that aren't among `current`, its replicas on the
+    /// serving ring.
+    fn extra_owners(&self, collection: &str, id: u32, current: &[String], n: usize) -> Vec<String> {
+        self.ring
+            .get_preference_list(collection, id, n)
+            .into_iter()
+            .filter(|addr| !current.contains(addr))
+            .collect()
     }
 
-    pub fn get_all_nodes(&self) -> Vec<String> {
-        self.nodes.values().cloned().collect()
+    fn fail(&self, message: String) {
+        self.error.lock().unwrap().get_or_insert(message);
     }
 }
 
+fn collection_name(collection: &str) -> String {
+    if collection.is_empty() { DEFAULT_COLLECTION.to_string() } else { collection.to_string() }
+}
+
+fn range_to_proto(range: &HashRange) -> vector_db::HashRange {
+    vector_db::HashRange { start: range.start, end: range.end }
+}
+
+// Runs file I/O, such as a hint's fsync, on a blocking thread rather than
+// the runtime's
+async fn run_blocking<T: Send + 'static>(f: impl FnOnce() -> std::io::Result<T> + Send + 'static) -> std::io::Result<T> {
+    tokio::task::spawn_blocking(f).await.unwrap_or_else(|e| Err(std::io::Error::other(e)))
+}
+
 pub struct Router {
-    ring: Arc<RwLock<ConsistentHashRing>>,
+    // Writes hold the read lock until every replica has answered, so taking
+    // the write lock waits for writes routed by the previous topology
+    topology: RwLock<Topology>,
+    status: Mutex<RebalanceStatus>,
+    pool: ConnectionPool,
+    // Set when membership follows gossip
+    gossip: Option<Arc<GossipNode>>,
+    // Writes held for replicas that couldn't be reached, replayed once they
+    // can. None when hinted handoff is disabled.
+    handoff: Option<Arc<HintStore>>,
+    // Held for the whole of a repair, so only one runs at a time
+    repair: tokio::sync::Mutex<()>,
+    // Versions writes that don't carry one
+    clock: VersionClock,
+    // Keys written since the running repair began, which it leaves alone.
+    // Held while a key is repaired, so a write can't slip in between.
+    repair_touched: tokio::sync::Mutex<Option<HashSet<Key>>>,
+    virtual_nodes: usize,
     replication_factor: usize,
+    read_quorum: usize,
     write_quorum: usize,
+    consistency: Consistency,
+    // Last known leader of each Raft group, by group id
+    leaders: Mutex<HashMap<String, String>>,
+    search_shard_timeout: Duration,
 }
 
 impl Router {
-    pub fn new(ring: ConsistentHashRing, replication_factor: usize, write_quorum: usize) -> Self {
-        Router {
-            ring: Arc::new(RwLock::new(ring)),
-            replication_factor,
-            write_quorum,
+    /// With `gossip`, the ring starts empty and fills from the gossiped view.
+    pub fn new(config: &RouterConfig, gossip: Option<Arc<GossipNode>>) -> std::io::Result<Self> {
+        let backends = if gossip.is_some() { &[][..] } else { &config.backends[..] };
+        let ring = build_ring(config.virtual_nodes, backends, &config.weights);
+        let handoff = if config.max_hint_bytes > 0 {
+            let max_age = Duration::from_secs(config.max_hint_age_secs);
+            Some(Arc::new(HintStore::open(&config.hints_dir, config.max_hint_bytes, max_age)?))
+        } else {
+            None
+        };
+        Ok(Router {
+            gossip,
+            handoff,
+            repair: tokio::sync::Mutex::new(()),
+            clock: VersionClock::default(),
+            repair_touched: tokio::sync::Mutex::new(None),
+            topology: RwLock::new(Topology { ring, rebalance: None }),
+            status: Mutex::new(RebalanceStatus::default()),
+            pool: ConnectionPool::new(
+                Duration::from_millis(config.connect_timeout_ms),
+                Duration::from_millis(config.request_timeout_ms),
+            ),
+            virtual_nodes: config.virtual_nodes,
+            replication_factor: config.replication_factor,
+            read_quorum: config.read_quorum,
+            write_quorum: config.write_quorum,
+            consistency: config.consistency,
+            leaders: Mutex::new(HashMap::new()),
+            search_shard_timeout: Duration::from_millis(config.search_shard_timeout_ms),
+        })
+    }
+
+    /// Nodes serving traffic.
+    pub async fn backends(&self) -> Vec<String> {
+        self.topology.read().await.ring.get_all_nodes()
+    }
+
+    pub async fn weights(&self) -> HashMap<String, f64> {
+        self.topology.read().await.ring.weights()
+    }
+
+    /// Serving nodes and, during a rebalance, the nodes being moved to.
+    async fn all_backends(&self) -> Vec<String> {
+        let topology = self.topology.read().await;
+        let mut nodes = topology.ring.get_all_nodes();
+        if let Some(rebalance) = &topology.rebalance {
+            for node in rebalance.ring.get_all_nodes() {
+                if !nodes.contains(&node) {
+                    nodes.push(node);
+                }
+            }
         }
+        nodes
     }
-}
 
-#[tonic::async_trait]
-impl VectorDb for Router {
-    async fn put(
-        &self,
-        request: Request<PutRequest>,
-    ) -> Result<Response<PutResponse>, Status> {
-        let req = request.into_inner();
-        let id = req.id;
-        
-        let targets = {
-            let ring = self.ring.read().await;
-            ring.get_preference_list(id, self.replication_factor)
+    pub fn rebalance_status(&self) -> RebalanceStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub async fn membership(&self) -> Membership {
+        let status = self.rebalance_status();
+        Membership {
+            backends: self.backends().await,
+            pending_backends: if status.state() == RebalanceState::Running { status.target_backends } else { vec![] },
+            member_states: self.member_states(),
+            pending_hints: self.handoff.as_ref().map(|h| h.pending()).unwrap_or_default(),
+        }
+    }
+
+    fn member_states(&self) -> HashMap<String, String> {
+        let Some(gossip) = &self.gossip else { return HashMap::new() };
+        gossip.members()
+            .into_iter()
+            .map(|m| (m.addr.clone(), m.state().as_str_name().to_lowercase()))
+            .collect()
+    }
+
+    /// `nodes` without those gossip has declared dead, so requests don't
+    /// wait on them. Each death is logged once, by gossip.
+    fn routable(&self, mut nodes: Vec<String>) -> Vec<String> {
+        if let Some(gossip) = &self.gossip {
+            nodes.retain(|node| gossip.state_of(node) != Some(MemberState::Dead));
+        }
+        nodes
+    }
+
+    /// Starts moving to a ring of `backends` in the background, and returns
+    /// the membership with `backends` pending. Fails if a rebalance is
+    /// already running.
+    pub async fn start_rebalance(
+        self: &Arc<Self>,
+        backends: &[String],
+        weights: &HashMap<String, f64>,
+    ) -> Result<Membership, Status> {
+        if self.consistency == Consistency::Strong {
+            // Changing owners would change the Raft groups under the data
+            return Err(Status::failed_precondition("Membership is fixed in strong consistency mode"));
+        }
+        let target = build_ring(self.virtual_nodes, backends, weights);
+        {
+            let mut status = self.status.lock().unwrap();
+            if status.state() == RebalanceState::Running {
+                return Err(Status::failed_precondition("A rebalance is already running"));
+            }
+            *status = RebalanceStatus {
+                state: RebalanceState::Running as i32,
+                target_backends: target.get_all_nodes(),
+                ..Default::default()
+            };
+        }
+
+        let membership = Membership { pending_backends: target.get_all_nodes(), ..self.membership().await };
+        let router = self.clone();
+        tokio::spawn(async move {
+            let result = router.rebalance(target).await;
+            let mut status = router.status.lock().unwrap();
+            match result {
+                Ok(()) => status.set_state(RebalanceState::Done),
+                Err(e) => {
+                    println!("Rebalance failed: {}", e.message());
+                    status.set_state(RebalanceState::Failed);
+                    status.error = e.message().to_string();
+                }
+            }
+        });
+        Ok(membership)
+    }
+
+    fn update_status(&self, update: impl FnOnce(&mut RebalanceStatus)) {
+        update(&mut self.status.lock().unwrap());
+    }
+
+    /// Moves every key whose replicas differ between the serving ring and
+    /// `target`, then switches traffic to `target`:
+    ///
+    /// 1. Every collection is created on every node of `target`.
+    /// 2. Stale copies on the new owners of each changed range, left from an
+    ///    earlier membership, are deleted.
+    /// 3. Writes start going to the new owners as well as the current ones.
+    /// 4. Each range is streamed from a current owner to each new one.
+    /// 5. `target` starts serving, and nodes that stay in the cluster drop
+    ///    the ranges they no longer own.
+    ///
+    /// If anything before step 5 fails, the serving ring is left unchanged.
+    async fn rebalance(&self, target: ConsistentHashRing) -> Result<(), Status> {
+        let n = self.replication_factor;
+        let (current, changes) = {
+            let topology = self.topology.read().await;
+            (topology.ring.clone(), ownership_changes(&topology.ring, &target, n))
         };
+        let target_nodes = target.get_all_nodes();
+        println!("Rebalancing to {:?}: {} ranges change owners", target_nodes, changes.len());
 
-        if targets.is_empty() {
-            return Err(Status::unavailable("No nodes available"));
+        let RebalancePlan { transfers, losses } = rebalance_plan(&changes, &target_nodes);
+
+        let collections = self.copy_collections(&current.get_all_nodes(), &target_nodes).await?;
+        self.update_status(|status| {
+            status.changed_ranges = changes.len() as u32;
+            status.transfers_total = (transfers.len() * collections.len()) as u32;
+        });
+
+        for ((dest, _), ranges) in &transfers {
+            for collection in &collections {
+                self.delete_ranges(dest, collection, ranges).await?;
+            }
         }
 
-        let mut successes = 0;
-        let mut errors = Vec::new();
+        let rebalance = Arc::new(Rebalance {
+            ring: target.clone(),
+            error: Mutex::new(None),
+        });
+        self.topology.write().await.rebalance = Some(rebalance.clone());
+
+        let mut result = Ok(());
+        'copy: for ((dest, sources), ranges) in &transfers {
+            for collection in &collections {
+                if let Err(e) = self.copy_ranges(collection, ranges, sources, dest).await {
+                    result = Err(e);
+                    break 'copy;
+                }
+                self.update_status(|status| status.transfers_done += 1);
+            }
+        }
+
+        // Waits for in-flight writes, so none is lost between the check and the swap
+        let mut topology = self.topology.write().await;
+        topology.rebalance = None;
+        result?;
+        if let Some(e) = rebalance.error.lock().unwrap().take() {
+            return Err(Status::aborted(format!("A write to a new owner failed: {}", e)));
+        }
+        topology.ring = target;
+        drop(topology);
+        println!("Membership is now {:?}", target_nodes);
+
+        // Removed nodes' data was copied from the other replicas, so their
+        // hints would only replay stale writes if they ever came back
+        if let Some(handoff) = &self.handoff {
+            for node in current.get_all_nodes().iter().filter(|node| !target_nodes.contains(node)) {
+                if let Err(e) = handoff.discard(node) {
+                    println!("Failed to discard hints for {}: {}", node, e);
+                }
+            }
+        }
+
+        // The cut-over is done; leftover copies only waste space, so failures are logged
+        for (node, ranges) in &losses {
+            for collection in &collections {
+                if let Err(e) = self.delete_ranges(node, collection, ranges).await {
+                    println!("Failed to remove moved vectors of {} from {}: {}", collection, node, e);
+                }
+            }
+        }
+        self.pool.retain(&target_nodes);
+        Ok(())
+    }
+
+    /// Creates the collections found on `sources` on every node of `targets`,
+    /// and returns their names.
+    async fn copy_collections(&self, sources: &[String], targets: &[String]) -> Result<Vec<String>, Status> {
+        let collections = self.list_collections(sources).await;
+        for info in collections.values() {
+            let req = CreateCollectionRequest {
+                name: info.name.clone(),
+                metric: info.metric,
+                m: info.m,
+                ef_construction: info.ef_construction,
+                ef_search: Some(info.ef_search),
+                dimension: info.dimension,
+            };
+            let results = self.fan_out(targets, |mut client| {
+                let req = req.clone();
+                async move { client.create_collection(req).await }
+            }).await;
+            for (target, result) in targets.iter().zip(results) {
+                match result {
+                    Err(e) if e.code() != tonic::Code::AlreadyExists => {
+                        return Err(Status::unavailable(format!(
+                            "Failed to create collection {} on {}: {}",
+                            info.name, target, e
+                        )));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(collections.into_keys().collect())
+    }
+
+    // Every collection on any of `nodes`, by name
+    async fn list_collections(&self, nodes: &[String]) -> BTreeMap<String, CollectionInfo> {
+        let results = self.fan_out(nodes, |mut client| async move {
+            client.list_collections(ListCollectionsRequest {}).await
+        }).await;
+
+        let mut collections: BTreeMap<String, CollectionInfo> = BTreeMap::new();
+        for (node, result) in nodes.iter().zip(results) {
+            match result {
+                Ok(resp) => {
+                    for info in resp.collections {
+                        collections.entry(info.name.clone()).or_insert(info);
+                    }
+                }
+                Err(e) => println!("Failed to list collections on {}: {}", node, e),
+            }
+        }
+        collections
+    }
+
+    /// Deletes every key of `collection` in `ranges` from `node`, forgetting
+    /// its deletes there too, and returns how many vectors there were.
+    async fn delete_ranges(&self, node: &str, collection: &str, ranges: &[HashRange]) -> Result<u64, Status> {
+        let mut client = self.pool.client(node)?;
+        let ids = scan(&mut client, collection, ranges, true).await?;
+        for entry in &ids {
+            // Whatever version the node holds, it no longer owns the key
+            let req = DeleteRequest {
+                id: entry.id,
+                collection: collection.to_string(),
+                evict: true,
+                ..Default::default()
+            };
+            client.delete(req).await?;
+        }
+        let removed = ids.iter().filter(|entry| !entry.deleted).count() as u64;
+        if removed > 0 {
+            println!("Removed {} vectors of {} from {}", removed, collection, node);
+            self.update_status(|status| status.vectors_removed += removed);
+        }
+        Ok(removed)
+    }
 
-        // Simple scatter-gather for Put
-        // In a real system, we might want to do this in parallel
-        for target in targets {
-            // Connect to node
-            // TODO: Connection pooling
-            // For now, we connect on every request (inefficient but simple)
-            let mut client = match VectorDbClient::connect(target.clone()).await {
-                Ok(c) => c,
+    /// Copies every key of `collection` in `ranges` to `dest`, from the first
+    /// of `sources` that can be scanned.
+    async fn copy_ranges(
+        &self,
+        collection: &str,
+        ranges: &[HashRange],
+        sources: &[String],
+        dest: &str,
+    ) -> Result<(), Status> {
+        let mut last_error = None;
+        for source in sources {
+            let entries = match self.pool.client(source) {
+                Ok(mut client) => scan(&mut client, collection, ranges, false).await,
+                Err(e) => Err(e),
+            };
+            let entries = match entries {
+                Ok(entries) => entries,
                 Err(e) => {
-                    println!("Failed to connect to {}: {}", target, e);
-                    errors.push(e.to_string());
+                    println!("Failed to scan {} on {}: {}", collection, source, e);
+                    last_error = Some(e);
                     continue;
                 }
             };
 
-            let req_clone = tonic::Request::new(PutRequest {
+            // Deletes are copied at their versions too, so a stale replica
+            // can't bring a deleted key back. A write that reached `dest`
+            // since the scan carries a newer version than the copy, so the
+            // copy loses to it there
+            let mut client = self.pool.client(dest)?;
+            let mut entries = entries.into_iter().peekable();
+            while entries.peek().is_some() {
+                let mut batch = Vec::new();
+                let mut bytes = 0;
+                while let Some(entry) = entries.next_if(|entry| batch.is_empty() || bytes + entry.encoded_len() <= COPY_BATCH_BYTES) {
+                    bytes += entry.encoded_len();
+                    batch.push(entry);
+                }
+                let copied = batch.len() as u64;
+                let req = LoadRequest { collection: collection.to_string(), entries: batch };
+                client.load(req).await.map_err(|e| {
+                    Status::unavailable(format!("Failed to copy {} to {}: {}", collection, dest, e))
+                })?;
+                self.update_status(|status| status.vectors_copied += copied);
+            }
+            return Ok(());
+        }
+        Err(last_error.unwrap_or_else(|| Status::unavailable("No source to copy from")))
+    }
+
+    // Keeps a write for each replica in `missed`
+    async fn store_hints(&self, missed: &[String], collection: &str, id: u32, op: OpType, request: Vec<u8>) {
+        let Some(handoff) = &self.handoff else { return };
+        for target in missed {
+            let hint = Hint {
+                seq: 0,
+                created_ms: now_ms(),
+                collection: collection_name(collection),
                 id,
-                vector: req.vector.clone(),
+                op: op.clone(),
+                request: request.clone(),
+            };
+            let store = handoff.clone();
+            let target_owned = target.clone();
+            match run_blocking(move || store.add(&target_owned, hint)).await {
+                Ok(Some(_)) => println!("Stored a hint for {} ({} {})", target, collection_name(collection), id),
+                Ok(None) => {}
+                Err(e) => println!("Failed to store a hint for {}: {}", target, e),
+            }
+        }
+    }
+
+    /// Replays every hint held for a backend that is a member and not known
+    /// to be dead, stopping at the first failure.
+    pub async fn replay_hints(&self) {
+        let Some(handoff) = &self.handoff else { return };
+        let members = self.all_backends().await;
+        for (target, _) in handoff.pending() {
+            // Not routed to yet, e.g. while gossip fills in the ring
+            if !members.contains(&target) || self.routable(vec![target.clone()]).is_empty() {
+                continue;
+            }
+            if let Err(e) = self.replay_hints_to(handoff, &target).await {
+                println!("Failed to replay hints to {}: {}", target, e);
+            }
+        }
+    }
+
+    // Hints carry the write's version, so one replayed after a newer write
+    // reached the target directly loses to it there
+    async fn replay_hints_to(&self, handoff: &Arc<HintStore>, target: &str) -> Result<(), Status> {
+        let store = handoff.clone();
+        let target_owned = target.to_string();
+        let hints = run_blocking(move || store.load(&target_owned)).await.map_err(|e| Status::internal(e.to_string()))?;
+        let mut client = self.pool.client(target)?;
+        let (mut replayed, mut skipped) = (0, 0);
+        let mut acked = 0;
+        let mut result = Ok(());
+        for hint in hints {
+            if handoff.is_expired(&hint) {
+                skipped += 1;
+            } else {
+                let sent = match hint.op {
+                    OpType::Insert => match PutRequest::decode(&hint.request[..]) {
+                        Ok(req) => client.put(req).await.map(drop),
+                        Err(e) => Err(Status::internal(e.to_string())),
+                    },
+                    OpType::Delete | OpType::Evict => match DeleteRequest::decode(&hint.request[..]) {
+                        Ok(req) => client.delete(req).await.map(drop),
+                        Err(e) => Err(Status::internal(e.to_string())),
+                    },
+                };
+                // Unreachable again: try later. Refused: replaying won't help.
+                match sent {
+                    Err(e) if is_unreachable(&e) => {
+                        result = Err(e);
+                        break;
+                    }
+                    Err(e) => {
+                        println!("{} refused hinted {} {}: {}", target, hint.collection, hint.id, e);
+                        skipped += 1;
+                    }
+                    Ok(()) => replayed += 1,
+                }
+            }
+            acked = hint.seq;
+        }
+
+        if acked > 0 {
+            let store = handoff.clone();
+            let target_owned = target.to_string();
+            run_blocking(move || store.ack(&target_owned, acked)).await.map_err(|e| Status::internal(e.to_string()))?;
+            println!("Replayed {} hints to {} ({} expired or refused)", replayed, target, skipped);
+        }
+        result
+    }
+
+    /// Compares the replicas of every range of the serving ring, collection by
+    /// collection, and unless `dry_run`, copies the newest version of each
+    /// divergent key to the replicas that lack it. Replicas keep the version
+    /// of each delete, so a delete newer than a replica's write is copied
+    /// like a write, and removes it. Fails while a rebalance is running.
+    pub async fn repair(&self, dry_run: bool) -> Result<RepairReport, Status> {
+        let _running = self.repair.lock().await;
+        let ring = {
+            let topology = self.topology.read().await;
+            if topology.rebalance.is_some() {
+                return Err(Status::failed_precondition("A rebalance is running"));
+            }
+            topology.ring.clone()
+        };
+        *self.repair_touched.lock().await = Some(HashSet::new());
+        // A write already under way may have reached only some replicas, and
+        // isn't in the touched set; writes hold the topology lock, so wait
+        // them out
+        drop(self.topology.write().await);
+        let report = self.repair_ring(&ring, dry_run).await;
+        *self.repair_touched.lock().await = None;
+        report
+    }
+
+    async fn repair_ring(&self, ring: &ConsistentHashRing, dry_run: bool) -> Result<RepairReport, Status> {
+        let nodes = self.routable(ring.get_all_nodes());
+        // A replica missing a whole collection gets it created, except on a dry run
+        let collections = if dry_run {
+            self.list_collections(&nodes).await.into_keys().collect()
+        } else {
+            self.copy_collections(&nodes, &nodes).await?
+        };
+
+        // Arcs with the same replicas are compared in one go
+        let mut replica_sets: BTreeMap<Vec<String>, Vec<HashRange>> = BTreeMap::new();
+        for (range, replicas) in ring.replica_ranges(self.replication_factor) {
+            let replicas = self.routable(replicas);
+            if replicas.len() > 1 {
+                replica_sets.entry(replicas).or_default().push(range);
+            }
+        }
+
+        let mut report = RepairReport::default();
+        for collection in &collections {
+            for (replicas, ranges) in &replica_sets {
+                report.ranges_checked += ranges.len() as u32;
+                match self.repair_ranges(collection, replicas, ranges, dry_run).await {
+                    Ok((divergent, repaired)) => {
+                        report.divergent_keys += divergent;
+                        report.keys_repaired += repaired;
+                    }
+                    Err(e) => report.errors.push(format!("{} on {:?}: {}", collection, replicas, e.message())),
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    // Returns the number of divergent keys and how many of them were repaired
+    async fn repair_ranges(
+        &self,
+        collection: &str,
+        replicas: &[String],
+        ranges: &[HashRange],
+        dry_run: bool,
+    ) -> Result<(u64, u64), Status> {
+        let mut req = DigestRequest {
+            collection: collection.to_string(),
+            ranges: ranges.iter().map(range_to_proto).collect(),
+            depth: REPAIR_DEPTH,
+            leaves: vec![],
+        };
+        let trees = self.digests(replicas, &req).await?
+            .into_iter()
+            .zip(replicas)
+            .map(|(resp, replica)| {
+                MerkleTree::from_nodes(resp.nodes)
+                    .filter(|tree| tree.depth() == REPAIR_DEPTH)
+                    .ok_or_else(|| Status::internal(format!("Malformed digest from {}", replica)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let leaves: BTreeSet<u32> = trees[1..].iter().flat_map(|tree| trees[0].diff(tree)).collect();
+        if leaves.is_empty() {
+            return Ok((0, 0));
+        }
+
+        // Only the keys under differing leaves are listed
+        req.leaves = leaves.into_iter().collect();
+        let listed: Vec<HashMap<u32, Checksum>> = self.digests(replicas, &req).await?
+            .into_iter()
+            .map(|resp| {
+                resp.entries
+                    .into_iter()
+                    .map(|e| (e.id, Checksum { version: e.version, deleted: e.deleted, hash: e.hash }))
+                    .collect()
+            })
+            .collect();
+        let ids: BTreeSet<u32> = listed.iter().flat_map(|entries| entries.keys().copied()).collect();
+
+        let (mut divergent, mut repaired) = (0, 0);
+        for id in ids {
+            let checksums: Vec<Option<Checksum>> = listed.iter().map(|entries| entries.get(&id).copied()).collect();
+            if checksums.iter().all(|checksum| *checksum == checksums[0]) {
+                continue;
+            }
+            divergent += 1;
+            if !dry_run && self.repair_key(collection, id, replicas, &checksums).await? {
+                repaired += 1;
+            }
+        }
+        if divergent > 0 {
+            println!("{} keys of {} differ between {:?}", divergent, collection, replicas);
+        }
+        Ok((divergent, repaired))
+    }
+
+    // Asks every replica for a digest; a replica without the collection
+    // holds nothing
+    async fn digests(&self, replicas: &[String], req: &DigestRequest) -> Result<Vec<DigestResponse>, Status> {
+        let results = self.fan_out(replicas, |mut client| {
+            let req = req.clone();
+            async move { client.digest(req).await }
+        }).await;
+        replicas
+            .iter()
+            .zip(results)
+            .map(|(replica, result)| match result {
+                Ok(resp) => Ok(resp),
+                Err(e) if e.code() == tonic::Code::NotFound => Ok(DigestResponse {
+                    nodes: MerkleTree::empty(req.depth).into_nodes(),
+                    entries: vec![],
+                }),
+                Err(e) => Err(Status::new(e.code(), format!("{}: {}", replica, e.message()))),
+            })
+            .collect()
+    }
+
+    // Copies the newest version of a key, a write or a delete, to the
+    // replicas that lack it. Returns false if a write got there first.
+    async fn repair_key(
+        &self,
+        collection: &str,
+        id: u32,
+        replicas: &[String],
+        checksums: &[Option<Checksum>],
+    ) -> Result<bool, Status> {
+        let touched = self.repair_touched.lock().await;
+        if touched.as_ref().is_some_and(|keys| keys.contains(&(collection.to_string(), id))) {
+            return Ok(false);
+        }
+        let newest = checksums.iter().max().copied().flatten();
+        let Some(source) = checksums.iter().position(|checksum| checksum.is_some() && *checksum == newest) else {
+            return Ok(false);
+        };
+        let stale: Vec<String> = replicas
+            .iter()
+            .zip(checksums)
+            .filter(|(_, checksum)| **checksum != newest)
+            .map(|(replica, _)| replica.clone())
+            .collect();
+
+        if let Some(Checksum { deleted: true, version, .. }) = newest {
+            let req = DeleteRequest { id, collection: collection.to_string(), version, ..Default::default() };
+            let results = self.fan_out(&stale, |mut client| {
+                let req = req.clone();
+                async move { client.delete(req).await }
+            }).await;
+            for result in results {
+                result?;
+            }
+            return Ok(true);
+        }
+
+        let mut client = self.pool.client(&replicas[source])?;
+        let req = GetRequest { id, collection: collection.to_string() };
+        let entry = client.get(req).await?.into_inner();
+        if !entry.found {
+            return Ok(false);
+        }
+        let req = PutRequest {
+            id,
+            vector: entry.vector,
+            payload: entry.payload,
+            collection: collection.to_string(),
+            version: entry.version,
+            ..Default::default()
+        };
+        let results = self.fan_out(&stale, |mut client| {
+            let req = req.clone();
+            async move { client.put(req).await }
+        }).await;
+        for result in results {
+            result?;
+        }
+        Ok(true)
+    }
+
+    // Writes `newest`, or deletes the key at its version if it's a delete,
+    // on replicas that answered a Get with something older or nothing, without
+    // holding up the answer
+    fn read_repair(&self, req: &GetRequest, newest: &GetResponse, stale: &[&String]) {
+        if stale.is_empty() {
+            return;
+        }
+        let put = PutRequest {
+            id: req.id,
+            vector: newest.vector.clone(),
+            payload: newest.payload.clone(),
+            collection: req.collection.clone(),
+            version: newest.version,
+            ..Default::default()
+        };
+        let delete = DeleteRequest {
+            id: req.id,
+            collection: req.collection.clone(),
+            version: newest.version,
+            ..Default::default()
+        };
+        let key = format!("{} {}", collection_name(&req.collection), req.id);
+        for &target in stale {
+            let client = self.pool.client(target);
+            let (target, put, delete, key) = (target.clone(), put.clone(), delete.clone(), key.clone());
+            let found = newest.found;
+            tokio::spawn(async move {
+                let result = match client {
+                    Ok(mut client) if found => client.put(put).await.map(drop),
+                    Ok(mut client) => client.delete(delete).await.map(drop),
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(()) => println!("Read repair brought {} up to date on {}", key, target),
+                    Err(e) => println!("Read repair of {} on {} failed: {}", key, target, e),
+                }
             });
+        }
+    }
 
-            match client.put(req_clone).await {
-                Ok(_) => successes += 1,
-                Err(e) => {
-                    println!("Failed to write to {}: {}", target, e);
-                    errors.push(e.to_string());
+    /// New owners a write must also reach while a rebalance is running.
+    async fn extra_targets(&self, topology: &Topology, collection: &str, id: u32, targets: &[String]) -> Vec<String> {
+        match &topology.rebalance {
+            Some(rebalance) => rebalance.extra_owners(collection, id, targets, self.replication_factor),
+            None => vec![],
+        }
+    }
+
+    /// Sends a request to every target concurrently and waits for all of them,
+    /// so a fan-out takes as long as its slowest target. Results are returned
+    /// in target order.
+    async fn fan_out<T, F, Fut>(&self, targets: &[String], call: F) -> Vec<Result<T, Status>>
+    where
+        F: Fn(VectorDbClient<Channel>) -> Fut,
+        Fut: Future<Output = Result<Response<T>, Status>>,
+    {
+        let calls = targets.iter().map(|target| {
+            let client = self.pool.client(target);
+            let call = &call;
+            async move { call(client?).await.map(Response::into_inner) }
+        });
+        join_all(calls).await
+    }
+
+    /// Sends a write to the leader of the Raft group of the key's replicas,
+    /// following redirects from members that aren't the leader, and moving
+    /// on to another member while one can't be reached or the group has no
+    /// leader. `call` gets the group's members to put in the request.
+    async fn consensus_write<T, F, Fut>(&self, collection: &str, id: u32, call: F) -> Result<T, Status>
+    where
+        F: Fn(VectorDbClient<Channel>, Vec<String>) -> Fut,
+        Fut: Future<Output = Result<Response<T>, Status>>,
+    {
+        let members = {
+            let topology = self.topology.read().await;
+            topology.ring.get_preference_list(collection, id, self.replication_factor)
+        };
+        if members.is_empty() {
+            return Err(Status::unavailable("No nodes available"));
+        }
+        let group = group_id(&members);
+        let cached = self.leaders.lock().unwrap().get(&group).cloned();
+        let mut target = cached.unwrap_or_else(|| members[0].clone());
+
+        let mut errors = Vec::new();
+        for _ in 0..CONSENSUS_ATTEMPTS {
+            let result = match self.pool.client(&target) {
+                Ok(client) => call(client, members.clone()).await,
+                Err(e) => Err(e),
+            };
+            let e = match result {
+                Ok(resp) => {
+                    self.leaders.lock().unwrap().insert(group, target);
+                    return Ok(resp.into_inner());
                 }
+                Err(e) => e,
+            };
+            target = match leader_hint(&e) {
+                Some(leader) if members.contains(&leader) => leader,
+                _ if is_unreachable(&e) => {
+                    // Give the group time to elect a leader
+                    tokio::time::sleep(CONSENSUS_RETRY_PAUSE).await;
+                    let next = members.iter().position(|m| *m == target).map_or(0, |i| i + 1);
+                    members[next % members.len()].clone()
+                }
+                _ => return Err(e),
+            };
+            errors.push(e.message().to_string());
+        }
+        Err(Status::unavailable(format!("No leader of {:?} took the write: {:?}", members, errors)))
+    }
+}
+
+// Writes to new owners don't count towards the quorum, but a missed one
+// would leave the new owner stale, so it fails the rebalance
+fn check_extra_writes<T>(topology: &Topology, extra: &[String], results: Vec<Result<T, Status>>) {
+    if let Some(rebalance) = &topology.rebalance {
+        for (target, result) in extra.iter().zip(results) {
+            if let Err(e) = result {
+                println!("Failed to write to new owner {}: {}", target, e);
+                rebalance.fail(format!("{}: {}", target, e));
             }
         }
+    }
+}
+
+/// Every entry of `collection` on a node whose key hash lies in `ranges`,
+/// including the deletes it keeps the versions of. A node without the
+/// collection has none.
+async fn scan(
+    client: &mut VectorDbClient<Channel>,
+    collection: &str,
+    ranges: &[HashRange],
+    keys_only: bool,
+) -> Result<Vec<ScanEntry>, Status> {
+    let req = ScanRequest {
+        collection: collection.to_string(),
+        ranges: ranges.iter().map(range_to_proto).collect(),
+        keys_only,
+        deletes: true,
+    };
+    let mut stream = match client.scan(req).await {
+        Ok(resp) => resp.into_inner(),
+        Err(e) if e.code() == tonic::Code::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut entries = Vec::new();
+    while let Some(entry) = stream.message().await? {
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[tonic::async_trait]
+impl VectorDb for Router {
+    type ScanStream = futures::stream::Empty<Result<ScanEntry, Status>>;
+    type ReplicateStream = futures::stream::Empty<Result<WalRecord, Status>>;
+
+    async fn put(
+        &self,
+        request: Request<PutRequest>,
+    ) -> Result<Response<PutResponse>, Status> {
+        let mut req = request.into_inner();
+        if self.consistency == Consistency::Strong {
+            // The group's leader versions the write
+            return self.consensus_write(&req.collection, req.id, |mut client, raft_group| {
+                let req = PutRequest { raft_group, ..req.clone() };
+                async move { client.put(req).await }
+            }).await.map(Response::new);
+        }
+        // Every replica, and any hint, gets the same version
+        if req.version == 0 {
+            req.version = self.clock.next();
+        }
+
+        let topology = self.topology.read().await;
+        let replicas = topology.ring.get_preference_list(&req.collection, req.id, self.replication_factor);
+        let targets = self.routable(replicas.clone());
 
-        if successes >= self.write_quorum {
+        if targets.is_empty() {
+            return Err(Status::unavailable("No nodes available"));
+        }
+        let extra = self.extra_targets(&topology, &req.collection, req.id, &targets).await;
+        if let Some(touched) = self.repair_touched.lock().await.as_mut() {
+            touched.insert((collection_name(&req.collection), req.id));
+        }
+
+        // Write to every replica at once
+        let all: Vec<String> = targets.iter().chain(&extra).cloned().collect();
+        let mut results = self.fan_out(&all, |mut client| {
+            let req = req.clone();
+            async move { client.put(req).await }
+        }).await;
+        check_extra_writes(&topology, &extra, results.split_off(targets.len()));
+
+        let tally = WriteTally::new(&replicas, &targets, results);
+        for (target, e) in &tally.failures {
+            println!("Failed to write to {}: {}", target, e);
+        }
+        // A write no replica took simply failed; one some took must reach the rest
+        if !tally.acked.is_empty() {
+            self.store_hints(&tally.missed, &req.collection, req.id, OpType::Insert, req.encode_to_vec()).await;
+        }
+
+        if tally.met(self.write_quorum) {
             Ok(Response::new(PutResponse { success: true }))
         } else {
             Err(Status::internal(format!(
                 "Write quorum not met. Successes: {}, Errors: {:?}",
-                successes, errors
+                tally.acked.len(), tally.errors()
             )))
         }
     }
@@ -151,93 +1306,566 @@ impl VectorDb for Router {
         request: Request<SearchRequest>,
     ) -> Result<Response<SearchResponse>, Status> {
         let req = request.into_inner();
-        
-        // Broadcast to all unique nodes
-        // Optimization: Only query one replica set that covers the whole ring?
-        // For simplicity: Query ALL nodes in the ring.
-        let targets = {
-            let ring = self.ring.read().await;
-            let mut unique = HashSet::new();
-            for node in ring.get_all_nodes() {
-                unique.insert(node);
-            }
-            unique.into_iter().collect::<Vec<_>>()
+
+        // Each arc of the ring is searched on one of its replicas only: plan
+        // as few nodes as cover every arc, ask each for just its arcs, and
+        // hand the arcs of a node that fails to the others holding them
+        let (arcs, mut candidates) = {
+            let topology = self.topology.read().await;
+            let arcs = topology.ring.replica_ranges(self.replication_factor);
+            (arcs, self.routable(topology.ring.get_all_nodes()))
         };
+        if candidates.is_empty() {
+            return Err(Status::unavailable("No nodes available"));
+        }
+        // Spread searches over the replicas that could serve them equally
+        candidates.shuffle(&mut rand::thread_rng());
+
+        let deadline = req.shard_timeout_ms
+            .map(|ms| Duration::from_millis(ms as u64))
+            .unwrap_or(self.search_shard_timeout);
+
+        let mut all_results = Vec::new();
+        let mut shards = Vec::new();
+        let mut open = arcs;
+        while !open.is_empty() {
+            let plan = covering_plan(&open, &candidates);
+            if plan.assigned.is_empty() {
+                break;
+            }
+            let calls = plan.assigned.iter().map(|(node, assigned)| {
+                let client = self.pool.client(node);
+                let mut request = Request::new(SearchRequest {
+                    ranges: coalesce(assigned.iter().map(|&i| open[i].0)).iter().map(range_to_proto).collect(),
+                    ..req.clone()
+                });
+                // Sent to the node too, so it can give up as well
+                request.set_timeout(deadline);
+                async move { client?.search(request).await.map(Response::into_inner) }
+            });
+            let results = join_all(calls).await;
+
+            let mut failed: Vec<_> = plan.uncovered.iter().map(|&i| open[i].clone()).collect();
+            for ((node, assigned), result) in plan.assigned.iter().zip(results) {
+                let mut shard = ShardStatus { node: node.clone(), arcs: assigned.len() as u32, ..Default::default() };
+                match result {
+                    Ok(resp) => all_results.extend(resp.results),
+                    Err(e) => {
+                        println!("Failed to search on {}: {}", node, e);
+                        // The channel reports its own deadline passing as cancelled
+                        let timed_out = matches!(e.code(), tonic::Code::DeadlineExceeded | tonic::Code::Cancelled);
+                        shard.set_state(if timed_out { ShardState::Timeout } else { ShardState::Error });
+                        shard.error = e.message().to_string();
+                        candidates.retain(|c| c != node);
+                        failed.extend(assigned.iter().map(|&i| open[i].clone()));
+                    }
+                }
+                shards.push(shard);
+            }
+            open = failed;
+        }
+
+        let partial = !open.is_empty();
+        if partial {
+            let errors: Vec<String> = shards
+                .iter()
+                .filter(|s| s.state() != ShardState::Ok)
+                .map(|s| format!("{}: {}", s.node, s.error))
+                .collect();
+            println!("No replica could search {} of the ring's arcs; results are partial", open.len());
+            if req.fail_on_partial {
+                return Err(Status::unavailable(format!(
+                    "No replica could search {} of the ring's arcs ({})",
+                    open.len(),
+                    errors.join("; "),
+                )));
+            }
+        }
+
+        // Merge and Sort
+        // Sort by distance ascending. Arcs don't overlap, so no id comes back twice.
+        all_results.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        all_results.truncate(req.k as usize);
+
+        Ok(Response::new(SearchResponse {
+            results: all_results,
+            partial,
+            shards,
+            missing: coalesce(open.iter().map(|(range, _)| *range)).iter().map(range_to_proto).collect(),
+        }))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let mut req = request.into_inner();
+        if self.consistency == Consistency::Strong {
+            return self.consensus_write(&req.collection, req.id, |mut client, raft_group| {
+                let req = DeleteRequest { raft_group, ..req.clone() };
+                async move { client.delete(req).await }
+            }).await.map(Response::new);
+        }
+        if req.version == 0 {
+            req.version = self.clock.next();
+        }
+
+        // Every replica holding the id must drop it
+        let topology = self.topology.read().await;
+        let replicas = topology.ring.get_preference_list(&req.collection, req.id, self.replication_factor);
+        let targets = self.routable(replicas.clone());
 
         if targets.is_empty() {
             return Err(Status::unavailable("No nodes available"));
         }
+        let extra = self.extra_targets(&topology, &req.collection, req.id, &targets).await;
+        if let Some(touched) = self.repair_touched.lock().await.as_mut() {
+            touched.insert((collection_name(&req.collection), req.id));
+        }
 
-        let mut all_results = Vec::new();
+        let all: Vec<String> = targets.iter().chain(&extra).cloned().collect();
+        let mut results = self.fan_out(&all, |mut client| {
+            let req = req.clone();
+            async move { client.delete(req).await }
+        }).await;
+        check_extra_writes(&topology, &extra, results.split_off(targets.len()));
 
-        for target in targets {
-             // TODO: Parallelize this!
-            let mut client = match VectorDbClient::connect(target.clone()).await {
-                Ok(c) => c,
-                Err(e) => {
-                    println!("Failed to connect to {}: {}", target, e);
-                    continue;
+        let tally = WriteTally::new(&replicas, &targets, results);
+        for (target, e) in &tally.failures {
+            println!("Failed to delete on {}: {}", target, e);
+        }
+        // An evict leaves no version to order it before later writes, so it
+        // can't be replayed safely
+        if !tally.acked.is_empty() && !req.evict {
+            self.store_hints(&tally.missed, &req.collection, req.id, OpType::Delete, req.encode_to_vec()).await;
+        }
+
+        if tally.met(self.write_quorum) {
+            let found = tally.acked.iter().any(|resp| resp.found);
+            Ok(Response::new(DeleteResponse { success: true, found }))
+        } else {
+            Err(Status::internal(format!(
+                "Write quorum not met. Successes: {}, Errors: {:?}",
+                tally.acked.len(), tally.errors()
+            )))
+        }
+    }
+
+    async fn get(
+        &self,
+        request: Request<GetRequest>,
+    ) -> Result<Response<GetResponse>, Status> {
+        let req = request.into_inner();
+        let id = req.id;
+
+        let targets = {
+            let topology = self.topology.read().await;
+            self.routable(topology.ring.get_preference_list(&req.collection, id, self.replication_factor))
+        };
+
+        if targets.is_empty() {
+            return Err(Status::unavailable("No nodes available"));
+        }
+
+        // Ask the first R replicas in preference order, and for each one
+        // that fails, the next one not yet asked
+        let mut answers: Vec<(String, GetResponse)> = Vec::new();
+        let mut errors = Vec::new();
+        let mut asked = 0;
+        while answers.len() < self.read_quorum && asked < targets.len() {
+            let batch = &targets[asked..(asked + self.read_quorum - answers.len()).min(targets.len())];
+            asked += batch.len();
+            let results = self.fan_out(batch, |mut client| {
+                let req = req.clone();
+                async move { client.get(req).await }
+            }).await;
+            for (target, result) in batch.iter().zip(results) {
+                match result {
+                    Ok(resp) => answers.push((target.clone(), resp)),
+                    Err(e) => {
+                        println!("Failed to get from {}: {}", target, e);
+                        errors.push(e.to_string());
+                    }
                 }
-            };
+            }
+        }
 
-            let req_clone = tonic::Request::new(SearchRequest {
-                vector: req.vector.clone(),
-                k: req.k,
-            });
+        if answers.len() < self.read_quorum {
+            return Err(Status::unavailable(format!(
+                "Read quorum not met for {}. Answered: {}, Errors: {:?}",
+                id, answers.len(), errors
+            )));
+        }
 
-            match client.search(req_clone).await {
+        // The newest version wins, whether a write or a delete; replicas
+        // behind it get it in the background
+        let recencies: Vec<_> = answers.iter().map(|(_, resp)| recency(resp.found, resp.version)).collect();
+        let Some((newest, stale)) = newest(&recencies) else {
+            return Ok(Response::new(GetResponse::default()));
+        };
+        let stale: Vec<&String> = stale.into_iter().map(|i| &answers[i].0).collect();
+        self.read_repair(&req, &answers[newest].1, &stale);
+        Ok(Response::new(answers[newest].1.clone()))
+    }
+
+    async fn snapshot(
+        &self,
+        _request: Request<SnapshotRequest>,
+    ) -> Result<Response<SnapshotResponse>, Status> {
+        let targets = self.routable(self.all_backends().await);
+
+        let results = self.fan_out(&targets, |mut client| async move {
+            client.snapshot(SnapshotRequest {}).await
+        }).await;
+
+        let mut errors = Vec::new();
+        for (target, result) in targets.iter().zip(results) {
+            if let Err(e) = result {
+                println!("Failed to snapshot {}: {}", target, e);
+                errors.push(e.to_string());
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(Response::new(SnapshotResponse { success: true }))
+        } else {
+            Err(Status::internal(format!("Snapshot failed on some nodes: {:?}", errors)))
+        }
+    }
+
+    async fn create_collection(
+        &self,
+        request: Request<CreateCollectionRequest>,
+    ) -> Result<Response<CreateCollectionResponse>, Status> {
+        let req = request.into_inner();
+
+        // Every node may own keys of every collection, so all of them need it
+        let targets = self.routable(self.all_backends().await);
+
+        let results = self.fan_out(&targets, |mut client| {
+            let req = req.clone();
+            async move { client.create_collection(req).await }
+        }).await;
+
+        let mut errors = Vec::new();
+        for (target, result) in targets.iter().zip(results) {
+            if let Err(e) = result {
+                println!("Failed to create collection on {}: {}", target, e);
+                errors.push(format!("{}: {}", target, e));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(Response::new(CreateCollectionResponse { success: true }))
+        } else {
+            Err(Status::internal(format!("Create collection failed on some nodes: {:?}", errors)))
+        }
+    }
+
+    async fn drop_collection(
+        &self,
+        request: Request<DropCollectionRequest>,
+    ) -> Result<Response<DropCollectionResponse>, Status> {
+        let req = request.into_inner();
+
+        let targets = self.routable(self.all_backends().await);
+
+        let results = self.fan_out(&targets, |mut client| {
+            let req = req.clone();
+            async move { client.drop_collection(req).await }
+        }).await;
+
+        let mut errors = Vec::new();
+        for (target, result) in targets.iter().zip(results) {
+            if let Err(e) = result {
+                println!("Failed to drop collection on {}: {}", target, e);
+                errors.push(format!("{}: {}", target, e));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(Response::new(DropCollectionResponse { success: true }))
+        } else {
+            Err(Status::internal(format!("Drop collection failed on some nodes: {:?}", errors)))
+        }
+    }
+
+    async fn list_collections(
+        &self,
+        _request: Request<ListCollectionsRequest>,
+    ) -> Result<Response<ListCollectionsResponse>, Status> {
+        let targets = self.routable(self.backends().await);
+
+        let results = self.fan_out(&targets, |mut client| async move {
+            client.list_collections(ListCollectionsRequest {}).await
+        }).await;
+
+        let mut combined: BTreeMap<String, CollectionInfo> = BTreeMap::new();
+        let mut reached = 0;
+        for (target, result) in targets.iter().zip(results) {
+            match result {
                 Ok(resp) => {
-                    all_results.extend(resp.into_inner().results);
+                    reached += 1;
+                    for info in resp.collections {
+                        match combined.get_mut(&info.name) {
+                            Some(acc) => acc.vector_count += info.vector_count,
+                            None => {
+                                combined.insert(info.name.clone(), CollectionInfo { last_lsn: 0, ..info });
+                            }
+                        }
+                    }
                 }
+                Err(e) => println!("Failed to list collections on {}: {}", target, e),
+            }
+        }
+
+        if reached == 0 {
+            return Err(Status::unavailable("No nodes available"));
+        }
+
+        Ok(Response::new(ListCollectionsResponse {
+            collections: combined.into_values().collect(),
+        }))
+    }
+
+    async fn describe_collection(
+        &self,
+        request: Request<DescribeCollectionRequest>,
+    ) -> Result<Response<CollectionInfo>, Status> {
+        let req = request.into_inner();
+
+        let targets = self.routable(self.backends().await);
+
+        let results = self.fan_out(&targets, |mut client| {
+            let req = req.clone();
+            async move { client.describe_collection(req).await }
+        }).await;
+
+        let mut combined: Option<CollectionInfo> = None;
+        let mut last_error = None;
+        for (target, result) in targets.iter().zip(results) {
+            let resp = match result {
+                Ok(resp) => resp,
                 Err(e) => {
-                    println!("Failed to search on {}: {}", target, e);
+                    println!("Failed to describe {} on {}: {}", req.name, target, e);
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+
+            match combined.as_mut() {
+                None => combined = Some(CollectionInfo { last_lsn: 0, ..resp }),
+                Some(acc) => {
+                    // Distances from different metrics can't be merged into one ranking
+                    if acc.metric != resp.metric {
+                        return Err(Status::failed_precondition(format!(
+                            "Node {} uses a different metric for {} than the rest of the cluster",
+                            target, req.name
+                        )));
+                    }
+                    acc.vector_count += resp.vector_count;
                 }
             }
         }
 
-        // Merge and Sort
-        // Sort by distance ascending
-        all_results.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(std::cmp::Ordering::Equal));
-        
-        // Deduplicate by ID?
-        // If we have replication, we might get the same ID from multiple nodes.
-        // We should take the one with the same ID (distance should be same).
-        let mut unique_results = Vec::new();
-        let mut seen_ids = HashSet::new();
-        for res in all_results {
-            if seen_ids.insert(res.id) {
-                unique_results.push(res);
-            }
-            if unique_results.len() >= req.k as usize {
-                break;
-            }
+        match (combined, last_error) {
+            (Some(info), _) => Ok(Response::new(info)),
+            (None, Some(e)) => Err(e),
+            (None, None) => Err(Status::unavailable("No nodes available")),
         }
+    }
 
-        Ok(Response::new(SearchResponse {
-            results: unique_results,
-        }))
+    async fn scan(
+        &self,
+        _request: Request<ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        Err(Status::unimplemented("Scan is served by storage nodes, not the router"))
+    }
+
+    async fn load(&self, _request: Request<LoadRequest>) -> Result<Response<LoadResponse>, Status> {
+        Err(Status::unimplemented("Load is served by storage nodes, not the router"))
+    }
+
+    async fn digest(&self, _request: Request<DigestRequest>) -> Result<Response<DigestResponse>, Status> {
+        Err(Status::unimplemented("Digest is served by storage nodes, not the router"))
+    }
+
+    async fn replicate(
+        &self,
+        _request: Request<ReplicateRequest>,
+    ) -> Result<Response<Self::ReplicateStream>, Status> {
+        Err(Status::unimplemented("Replicate is served by storage nodes, not the router"))
     }
 }
 
+/// Keeps the ring in step with the gossiped view: every storage member that
+/// isn't dead. Each change goes through a rebalance; one that arrives while
+/// another runs, or whose rebalance fails, is retried every `retry`.
+async fn follow_gossip(router: Arc<Router>, gossip: Arc<GossipNode>, weights: HashMap<String, f64>, retry: Duration) {
+    let mut view = gossip.subscribe();
+    let mut retries = tokio::time::interval(retry);
+    loop {
+        tokio::select! {
+            changed = view.changed() => if changed.is_err() { return },
+            _ = retries.tick() => {}
+        }
+        let backends: Vec<String> = view
+            .borrow()
+            .iter()
+            .filter(|m| m.role() == Role::Storage && m.state() != MemberState::Dead)
+            .map(|m| m.addr.clone())
+            .collect();
+        // With every backend gone there is nothing to move data to; wait for one to return
+        if backends.is_empty() || backends == router.backends().await {
+            continue;
+        }
+        if router.rebalance_status().state() == RebalanceState::Running {
+            continue;
+        }
+        if let Err(e) = router.start_rebalance(&backends, &weights).await {
+            println!("Failed to follow gossiped membership: {}", e.message());
+        }
+    }
+}
+
+/// Membership administration. An empty reload request re-reads the config file.
+pub struct Admin {
+    router: Arc<Router>,
+    config_path: Option<PathBuf>,
+}
+
+impl Admin {
+    fn check_static(&self) -> Result<(), Status> {
+        match self.router.gossip {
+            Some(_) => Err(Status::failed_precondition("Membership follows gossip; start or stop backends instead")),
+            None => Ok(()),
+        }
+    }
+
+    async fn reload_from_file(&self) -> Result<Membership, Status> {
+        self.check_static()?;
+        let Some(path) = &self.config_path else {
+            return Err(Status::failed_precondition("the router was started without --config"));
+        };
+        let config = RouterConfig::load(path)
+            .and_then(|config| config.validate().map(|_| config))
+            .map_err(|e| Status::failed_precondition(format!("{:#}", e)))?;
+        self.router.start_rebalance(&config.backends, &config.weights).await
+    }
+}
+
+#[tonic::async_trait]
+impl RouterAdmin for Admin {
+    async fn reload_membership(
+        &self,
+        request: Request<ReloadMembershipRequest>,
+    ) -> Result<Response<Membership>, Status> {
+        let backends = request.into_inner().backends;
+        let membership = if backends.is_empty() {
+            self.reload_from_file().await?
+        } else {
+            self.check_static()?;
+            validate_backends(&backends).map_err(|e| Status::invalid_argument(format!("{:#}", e)))?;
+            // Backends keep their current weights
+            let weights = self.router.weights().await;
+            self.router.start_rebalance(&backends, &weights).await?
+        };
+        Ok(Response::new(membership))
+    }
+
+    async fn get_membership(
+        &self,
+        _request: Request<GetMembershipRequest>,
+    ) -> Result<Response<Membership>, Status> {
+        Ok(Response::new(self.router.membership().await))
+    }
+
+    async fn get_rebalance_status(
+        &self,
+        _request: Request<GetRebalanceStatusRequest>,
+    ) -> Result<Response<RebalanceStatus>, Status> {
+        Ok(Response::new(self.router.rebalance_status()))
+    }
+
+    async fn repair(&self, request: Request<RepairRequest>) -> Result<Response<RepairReport>, Status> {
+        let report = self.router.repair(request.into_inner().dry_run).await?;
+        Ok(Response::new(report))
+    }
+}
+
+// Re-reads the backends from the config file on every SIGHUP
+#[cfg(unix)]
+fn reload_on_sighup(admin: Arc<Admin>) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            if let Err(e) = admin.reload_from_file().await {
+                println!("Failed to reload membership: {}", e.message());
+            }
+        }
+    });
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "[::1]:50050".parse()?;
-    
-    let mut ring = ConsistentHashRing::new();
-    // Hardcoded backend nodes for MVP
-    // Ensure these are running!
-    ring.add_node("http://[::1]:50051");
-    ring.add_node("http://[::1]:50052");
-    ring.add_node("http://[::1]:50053");
+    let args = Args::parse();
+    let config = args.resolve()?;
+
+    let gossip = config.gossip().map(GossipNode::new);
+    let router = Arc::new(Router::new(&config, gossip.clone())?);
+    let admin = Arc::new(Admin { router: router.clone(), config_path: args.config.clone() });
+    #[cfg(unix)]
+    reload_on_sighup(admin.clone())?;
+
+    let replay_router = router.clone();
+    let replay_interval = Duration::from_millis(config.hint_replay_interval_ms);
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(replay_interval);
+        loop {
+            ticks.tick().await;
+            replay_router.replay_hints().await;
+        }
+    });
+
+    if config.repair_interval_secs > 0 {
+        let repair_router = router.clone();
+        let period = Duration::from_secs(config.repair_interval_secs);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                ticks.tick().await;
+                match repair_router.repair(false).await {
+                    Ok(report) if report.divergent_keys > 0 || !report.errors.is_empty() => {
+                        println!("Repair: {:?}", report);
+                    }
+                    Ok(_) => {}
+                    Err(e) => println!("Repair skipped: {}", e.message()),
+                }
+            }
+        });
+    }
 
-    // Replication Factor = 2, Write Quorum = 1 (for speed/availability in this demo)
-    let router = Router::new(ring, 2, 1);
+    let gossip_service = gossip.as_ref().map(|gossip| {
+        gossip.start();
+        let retry = Duration::from_millis(config.suspicion_timeout_ms);
+        tokio::spawn(follow_gossip(router.clone(), gossip.clone(), config.weights.clone(), retry));
+        println!("Following gossiped membership through {:?}", config.seeds);
+        gossip.service()
+    });
 
-    println!("Router listening on {}", addr);
+    println!(
+        "Router listening on {} (replication factor {}, read quorum {}, write quorum {}, {:?} consistency)",
+        config.listen, config.replication_factor, config.read_quorum, config.write_quorum, config.consistency
+    );
 
     Server::builder()
-        .add_service(VectorDbServer::new(router))
-        .serve(addr)
+        .add_service(VectorDbServer::from_arc(router))
+        .add_service(RouterAdminServer::from_arc(admin))
+        .add_optional_service(gossip_service)
+        .serve(config.listen)
         .await?;
 
     Ok(())
@@ -1,65 +1,297 @@
+// tonic::Status is large, but it is the natural error type for request handlers
+#![allow(clippy::result_large_err)]
+
 use tonic::{transport::Server, Request, Response, Status};
-use my_vector_db::index::hnsw::Hnsw;
-use my_vector_db::wal::{Wal, WalEntry, OpType};
+use my_vector_db::collection::{Catalog, Collection, CollectionConfig, DEFAULT_COLLECTION};
+use my_vector_db::filter::Filter;
+use my_vector_db::index::distance::Metric;
+use my_vector_db::index::hnsw::SearchScope;
+use my_vector_db::merkle::MAX_DEPTH;
+use my_vector_db::network::gossip::{GossipConfig, GossipNode, Role};
+use my_vector_db::network::ring::{key_hash, HashRange};
+use my_vector_db::payload::{Payload, PayloadValue};
+use my_vector_db::raft::{GrpcTransport, LogEntry, RaftConfig, RaftGroups, StateMachine};
+use my_vector_db::wal::{is_corruption, OpType, SyncPolicy, WalConfig, WalEntry};
+use futures::future::join_all;
+use futures::{Stream, StreamExt};
 use ndarray::Array1;
-use std::sync::{Arc, Mutex};
-use tokio::sync::RwLock;
+use prost::Message;
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
 
 pub mod vector_db {
     tonic::include_proto!("vector_db");
 }
 
 use vector_db::vector_db_server::{VectorDb, VectorDbServer};
-use vector_db::{PutRequest, PutResponse, SearchRequest, SearchResponse, SearchResult, SnapshotRequest, SnapshotResponse};
+use vector_db::vector_db_client::VectorDbClient;
+use vector_db::{
+    CollectionInfo, CreateCollectionRequest, CreateCollectionResponse, DeleteRequest, DeleteResponse,
+    DescribeCollectionRequest, DigestRequest, DigestResponse, EntryDigest, DropCollectionRequest, DropCollectionResponse, GetRequest, GetResponse,
+    ListCollectionsRequest, ListCollectionsResponse, LoadRequest, LoadResponse, PutRequest, PutResponse, RaftWrite, ReplicateRequest, ScanEntry, ScanRequest,
+    SearchRequest, SearchResponse, SearchResult, SnapshotRequest, SnapshotResponse, WalRecord,
+};
+use vector_db::raft_write::Kind as RaftWriteKind;
+
+// Raft groups live here, under the data directory. Not a valid collection
+// name, so it can't clash with one.
+const RAFT_DIR: &str = ".raft";
+
+// How often a follower re-reads its primary's collections, restarting the
+// streams that ended
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(1);
+// Vectors a follower applies at once while copying a collection
+const COPY_BATCH: usize = 1024;
+
+fn value_from_proto(key: &str, value: vector_db::Value) -> Result<PayloadValue, String> {
+    use vector_db::value::Kind;
+
+    match value.kind {
+        Some(Kind::StringValue(v)) => Ok(PayloadValue::String(v)),
+        Some(Kind::IntValue(v)) => Ok(PayloadValue::Int(v)),
+        Some(Kind::FloatValue(v)) => Ok(PayloadValue::Float(v)),
+        Some(Kind::BoolValue(v)) => Ok(PayloadValue::Bool(v)),
+        Some(Kind::StringListValue(v)) => Ok(PayloadValue::StringList(v.values)),
+        None => Err(format!("Payload key '{}' has no value", key)),
+    }
+}
+
+fn payload_from_proto(payload: HashMap<String, vector_db::Value>) -> Result<Payload, String> {
+    payload
+        .into_iter()
+        .map(|(key, value)| value_from_proto(&key, value).map(|v| (key, v)))
+        .collect()
+}
+
+fn filter_from_proto(filter: vector_db::Filter) -> Result<Filter, String> {
+    use vector_db::filter::Kind;
+
+    let list = |filters: vector_db::FilterList| -> Result<Vec<Filter>, String> {
+        filters.filters.into_iter().map(filter_from_proto).collect()
+    };
+
+    match filter.kind {
+        Some(Kind::Eq(m)) => {
+            let value = m.value.ok_or_else(|| format!("Filter on '{}' has no value", m.key))?;
+            let value = value_from_proto(&m.key, value)?;
+            Ok(Filter::Eq(m.key, value))
+        }
+        Some(Kind::AnyOf(a)) => {
+            let values = a.values
+                .into_iter()
+                .map(|v| value_from_proto(&a.key, v))
+                .collect::<Result<_, _>>()?;
+            Ok(Filter::AnyOf(a.key, values))
+        }
+        Some(Kind::Range(r)) => Ok(Filter::Range { key: r.key, gt: r.gt, gte: r.gte, lt: r.lt, lte: r.lte }),
+        Some(Kind::And(l)) => Ok(Filter::And(list(l)?)),
+        Some(Kind::Or(l)) => Ok(Filter::Or(list(l)?)),
+        Some(Kind::Not(f)) => Ok(Filter::Not(Box::new(filter_from_proto(*f)?))),
+        None => Err("Filter has no condition".to_string()),
+    }
+}
+
+fn payload_to_proto(payload: Payload) -> HashMap<String, vector_db::Value> {
+    use vector_db::value::Kind;
+
+    payload
+        .into_iter()
+        .map(|(key, value)| {
+            let kind = match value {
+                PayloadValue::String(v) => Kind::StringValue(v),
+                PayloadValue::Int(v) => Kind::IntValue(v),
+                PayloadValue::Float(v) => Kind::FloatValue(v),
+                PayloadValue::Bool(v) => Kind::BoolValue(v),
+                PayloadValue::StringList(v) => Kind::StringListValue(vector_db::StringList { values: v }),
+            };
+            (key, vector_db::Value { kind: Some(kind) })
+        })
+        .collect()
+}
+
+fn metric_to_proto(metric: Metric) -> vector_db::Metric {
+    match metric {
+        Metric::Euclidean => vector_db::Metric::Euclidean,
+        Metric::Cosine => vector_db::Metric::Cosine,
+        Metric::DotProduct => vector_db::Metric::DotProduct,
+    }
+}
+
+fn metric_from_proto(metric: vector_db::Metric) -> Metric {
+    match metric {
+        vector_db::Metric::Euclidean => Metric::Euclidean,
+        vector_db::Metric::Cosine => Metric::Cosine,
+        vector_db::Metric::DotProduct => Metric::DotProduct,
+    }
+}
+
+fn status_from_io(e: io::Error) -> Status {
+    match e.kind() {
+        io::ErrorKind::NotFound => Status::not_found(e.to_string()),
+        io::ErrorKind::AlreadyExists => Status::already_exists(e.to_string()),
+        io::ErrorKind::InvalidInput => Status::invalid_argument(e.to_string()),
+        _ => Status::internal(e.to_string()),
+    }
+}
+
+fn record_from_entry(entry: WalEntry) -> WalRecord {
+    WalRecord {
+        lsn: entry.lsn,
+        delete: matches!(entry.op, OpType::Delete | OpType::Evict),
+        evict: matches!(entry.op, OpType::Evict),
+        id: entry.vector_id,
+        version: entry.version,
+        vector: entry.vector,
+        payload: payload_to_proto(entry.payload),
+    }
+}
+
+fn entry_from_record(record: WalRecord) -> Result<WalEntry, String> {
+    Ok(WalEntry {
+        lsn: record.lsn,
+        op: match (record.delete, record.evict) {
+            (_, true) => OpType::Evict,
+            (true, false) => OpType::Delete,
+            (false, false) => OpType::Insert,
+        },
+        vector_id: record.id,
+        version: record.version,
+        vector: record.vector,
+        payload: payload_from_proto(record.payload)?,
+    })
+}
+
+fn collection(catalog: &Catalog, name: &str) -> Result<Arc<Collection>, Status> {
+    catalog
+        .get(name)
+        .ok_or_else(|| Status::not_found(format!("Collection '{}' does not exist", name)))
+}
+
+fn status_from_write(e: io::Error) -> Status {
+    match e.kind() {
+        io::ErrorKind::InvalidInput => Status::invalid_argument(e.to_string()),
+        _ => Status::internal(format!("Failed to write to WAL: {}", e)),
+    }
+}
+
+/// Applies the writes Raft groups commit to the catalog, at the version the
+/// leader stamped on each, so every replica ends up with the same versions.
+/// Writes no replica could apply fail with INVALID_ARGUMENT; anything else,
+/// such as a collection this server is missing, holds up the group here
+/// until it applies.
+struct CatalogMachine {
+    catalog: Arc<Catalog>,
+}
+
+#[tonic::async_trait]
+impl StateMachine for CatalogMachine {
+    async fn apply(&self, entry: &LogEntry) -> Result<bool, Status> {
+        let write = RaftWrite::decode(&entry.data[..])
+            .map_err(|e| Status::invalid_argument(format!("Undecodable Raft entry: {}", e)))?;
+        // Reapplying an entry is a no-op: its version is already there
+        match write.kind {
+            Some(RaftWriteKind::Put(req)) => {
+                let collection = collection(&self.catalog, &req.collection)?;
+                let payload = payload_from_proto(req.payload).map_err(Status::invalid_argument)?;
+                collection.put(req.id, req.vector, payload, entry.version).await.map_err(status_from_write)?;
+                Ok(true)
+            }
+            Some(RaftWriteKind::Delete(req)) => {
+                let collection = collection(&self.catalog, &req.collection)?;
+                let deleted = if req.evict {
+                    collection.evict(req.id).await
+                } else {
+                    collection.delete(req.id, entry.version).await
+                };
+                deleted.map_err(status_from_write)
+            }
+            None => Err(Status::invalid_argument("Raft entry holds no write")),
+        }
+    }
+}
 
 pub struct MyVectorDb {
-    index: Arc<RwLock<Hnsw>>,
-    wal: Arc<Mutex<Wal>>,
-    snapshot_path: String,
+    catalog: Arc<Catalog>,
+    raft: Arc<RaftGroups>,
+    // Set on a follower, which refuses writes
+    primary: Option<String>,
 }
 
 impl MyVectorDb {
-    pub fn new(index: Arc<RwLock<Hnsw>>, wal: Arc<Mutex<Wal>>, snapshot_path: String) -> Self {
-        MyVectorDb { index, wal, snapshot_path }
+    pub fn new(catalog: Arc<Catalog>, raft: Arc<RaftGroups>, primary: Option<String>) -> Self {
+        MyVectorDb { catalog, raft, primary }
+    }
+
+    fn collection(&self, name: &str) -> Result<Arc<Collection>, Status> {
+        collection(&self.catalog, name)
+    }
+
+    fn check_writable(&self) -> Result<(), Status> {
+        match &self.primary {
+            Some(primary) => Err(Status::failed_precondition(format!(
+                "Read-only: this server follows {}",
+                primary
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    // Logs a write in its shard's Raft group, and waits until it is applied here
+    async fn propose(&self, members: &[String], kind: RaftWriteKind) -> Result<bool, Status> {
+        let group = self.raft.group(members)?;
+        group.propose(RaftWrite { kind: Some(kind) }.encode_to_vec()).await
+    }
+}
+
+async fn collection_info(collection: &Collection) -> CollectionInfo {
+    let index = collection.index.read().await;
+    CollectionInfo {
+        name: collection.name.clone(),
+        metric: metric_to_proto(index.metric) as i32,
+        m: index.m as u32,
+        ef_construction: index.ef_construction as u32,
+        ef_search: index.ef_search as u32,
+        dimension: index.dimension.unwrap_or(0) as u32,
+        vector_count: index.len() as u64,
+        last_lsn: collection.last_lsn(),
     }
 }
 
+type ScanStream = Pin<Box<dyn Stream<Item = Result<ScanEntry, Status>> + Send>>;
+type ReplicateStream = Pin<Box<dyn Stream<Item = Result<WalRecord, Status>> + Send>>;
+
 #[tonic::async_trait]
 impl VectorDb for MyVectorDb {
+    type ScanStream = ScanStream;
+    type ReplicateStream = ReplicateStream;
+
     async fn put(
         &self,
         request: Request<PutRequest>,
     ) -> Result<Response<PutResponse>, Status> {
-        let req = request.into_inner();
-        let id = req.id;
-        let vector_data = req.vector;
-
-        // Validate vector
-        if vector_data.is_empty() {
-            return Err(Status::invalid_argument("Vector cannot be empty"));
-        }
-
-        let vector = Array1::from(vector_data.clone());
-
-        // 1. Write to WAL
-        let entry = WalEntry {
-            op: OpType::Insert,
-            vector_id: id,
-            vector: vector_data,
-        };
+        self.check_writable()?;
+        let mut req = request.into_inner();
+        let collection = self.collection(&req.collection)?;
 
-        {
-            let wal = self.wal.lock().unwrap();
-            if let Err(e) = wal.append(&entry) {
-                return Err(Status::internal(format!("Failed to write to WAL: {}", e)));
-            }
+        if !req.raft_group.is_empty() {
+            // Refuse what would fail to apply before it is logged
+            payload_from_proto(req.payload.clone()).map_err(Status::invalid_argument)?;
+            collection.index.read().await
+                .validate(&req.vector)
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+            let members = std::mem::take(&mut req.raft_group);
+            self.propose(&members, RaftWriteKind::Put(req)).await?;
+            return Ok(Response::new(PutResponse { success: true }));
         }
 
-        // 2. Update Index
-        {
-            let mut index = self.index.write().await;
-            index.insert(id, vector);
-        }
+        let payload = payload_from_proto(req.payload).map_err(Status::invalid_argument)?;
+        // The vector itself is validated against the index under its write lock
+        collection.put(req.id, req.vector, payload, req.version).await.map_err(status_from_write)?;
 
         Ok(Response::new(PutResponse { success: true }))
     }
@@ -69,52 +301,526 @@ impl VectorDb for MyVectorDb {
         request: Request<SearchRequest>,
     ) -> Result<Response<SearchResponse>, Status> {
         let req = request.into_inner();
+        let collection = self.collection(&req.collection)?;
         let vector_data = req.vector;
         let k = req.k as usize;
-
-        if vector_data.is_empty() {
-            return Err(Status::invalid_argument("Vector cannot be empty"));
+        if req.ef == Some(0) {
+            return Err(Status::invalid_argument("ef must be at least 1"));
         }
 
+        let filter = req.filter
+            .map(filter_from_proto)
+            .transpose()
+            .map_err(Status::invalid_argument)?;
+
+        let index = collection.index.read().await;
+        index.validate(&vector_data).map_err(|e| Status::invalid_argument(e.to_string()))?;
         let vector = Array1::from(vector_data);
 
-        let results = {
-            let index = self.index.read().await;
-            index.search(&vector.view(), k)
+        let ranges: Vec<HashRange> = req.ranges
+            .iter()
+            .map(|r| HashRange { start: r.start, end: r.end })
+            .collect();
+        let in_ranges = |id| ranges.iter().any(|r| r.contains(key_hash(&collection.name, id)));
+        let scope = SearchScope {
+            filter: filter.as_ref(),
+            ids: if ranges.is_empty() { None } else { Some(&in_ranges) },
         };
 
+        let ef = req.ef.map(|ef| ef as usize).unwrap_or(index.ef_search);
+        let results = index.search_scoped(&vector.view(), k, ef, scope);
+
         let search_results = results
             .into_iter()
             .map(|(id, dist)| SearchResult {
                 id,
                 distance: dist,
+                payload: if req.with_payload {
+                    index.payload(id).map(payload_to_proto).unwrap_or_default()
+                } else {
+                    HashMap::new()
+                },
             })
             .collect();
 
         Ok(Response::new(SearchResponse {
             results: search_results,
+            ..Default::default()
         }))
     }
 
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        self.check_writable()?;
+        let mut req = request.into_inner();
+        let collection = self.collection(&req.collection)?;
+
+        if !req.raft_group.is_empty() {
+            let members = std::mem::take(&mut req.raft_group);
+            let found = self.propose(&members, RaftWriteKind::Delete(req)).await?;
+            return Ok(Response::new(DeleteResponse { success: true, found }));
+        }
+
+        let deleted = if req.evict {
+            collection.evict(req.id).await
+        } else {
+            collection.delete(req.id, req.version).await
+        };
+        let found = deleted.map_err(status_from_write)?;
+        Ok(Response::new(DeleteResponse { success: true, found }))
+    }
+
+    async fn get(
+        &self,
+        request: Request<GetRequest>,
+    ) -> Result<Response<GetResponse>, Status> {
+        let req = request.into_inner();
+        let collection = self.collection(&req.collection)?;
+        let index = collection.index.read().await;
+
+        let response = match (index.get(req.id), index.version(req.id)) {
+            (Some((vector, payload)), Some(version)) => GetResponse {
+                found: true,
+                vector,
+                payload: payload_to_proto(payload),
+                version,
+            },
+            _ => GetResponse {
+                version: index.delete_version(req.id).unwrap_or(0),
+                ..Default::default()
+            },
+        };
+        Ok(Response::new(response))
+    }
+
     async fn snapshot(
         &self,
         _request: Request<SnapshotRequest>,
     ) -> Result<Response<SnapshotResponse>, Status> {
-        let index = self.index.read().await;
-        if let Err(e) = index.save_snapshot(&self.snapshot_path) {
-            return Err(Status::internal(format!("Failed to save snapshot: {}", e)));
+        for collection in self.catalog.list() {
+            if let Err(e) = collection.snapshot().await {
+                return Err(Status::internal(format!("Failed to save snapshot of {}: {}", collection.name, e)));
+            }
         }
-        println!("Snapshot saved to {}", self.snapshot_path);
         Ok(Response::new(SnapshotResponse { success: true }))
     }
+
+    async fn create_collection(
+        &self,
+        request: Request<CreateCollectionRequest>,
+    ) -> Result<Response<CreateCollectionResponse>, Status> {
+        self.check_writable()?;
+        let req = request.into_inner();
+        if req.ef_search == Some(0) {
+            return Err(Status::invalid_argument("ef_search must be at least 1"));
+        }
+        let defaults = CollectionConfig::default();
+        let config = CollectionConfig {
+            m: if req.m == 0 { defaults.m } else { req.m as usize },
+            ef_construction: if req.ef_construction == 0 { defaults.ef_construction } else { req.ef_construction as usize },
+            ef_search: req.ef_search.map(|ef| ef as usize),
+            metric: metric_from_proto(req.metric()),
+            dimension: if req.dimension == 0 { None } else { Some(req.dimension as usize) },
+        };
+
+        let catalog = self.catalog.clone();
+        tokio::task::spawn_blocking(move || catalog.create(&req.name, config))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(status_from_io)?;
+        Ok(Response::new(CreateCollectionResponse { success: true }))
+    }
+
+    async fn drop_collection(
+        &self,
+        request: Request<DropCollectionRequest>,
+    ) -> Result<Response<DropCollectionResponse>, Status> {
+        self.check_writable()?;
+        let req = request.into_inner();
+        let catalog = self.catalog.clone();
+        tokio::task::spawn_blocking(move || catalog.remove(&req.name))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(status_from_io)?;
+        Ok(Response::new(DropCollectionResponse { success: true }))
+    }
+
+    async fn list_collections(
+        &self,
+        _request: Request<ListCollectionsRequest>,
+    ) -> Result<Response<ListCollectionsResponse>, Status> {
+        let mut collections = Vec::new();
+        for collection in self.catalog.list() {
+            collections.push(collection_info(&collection).await);
+        }
+        Ok(Response::new(ListCollectionsResponse { collections }))
+    }
+
+    async fn describe_collection(
+        &self,
+        request: Request<DescribeCollectionRequest>,
+    ) -> Result<Response<CollectionInfo>, Status> {
+        let collection = self.collection(&request.into_inner().name)?;
+        Ok(Response::new(collection_info(&collection).await))
+    }
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let req = request.into_inner();
+        let collection = self.collection(&req.collection)?;
+        let ranges: Vec<HashRange> = req.ranges
+            .iter()
+            .map(|r| HashRange { start: r.start, end: r.end })
+            .collect();
+
+        // Pick the ids up front, then read each one as it is sent, so the
+        // index isn't locked for the whole stream. Ids deleted, or written
+        // again, in between are skipped.
+        let ids: Vec<(u32, bool)> = {
+            let index = collection.index.read().await;
+            let in_ranges = |&id: &u32| {
+                let hash = key_hash(&collection.name, id);
+                ranges.iter().any(|r| r.contains(hash))
+            };
+            let deleted = index.delete_versions.keys().copied().filter(in_ranges).filter(|_| req.deletes);
+            index.ids().filter(in_ranges).map(|id| (id, false)).chain(deleted.map(|id| (id, true))).collect()
+        };
+
+        let keys_only = req.keys_only;
+        let stream = futures::stream::iter(ids)
+            .filter_map(move |(id, deleted)| {
+                let collection = collection.clone();
+                async move {
+                    if keys_only {
+                        return Some(Ok(ScanEntry { id, deleted, ..Default::default() }));
+                    }
+                    let index = collection.index.read().await;
+                    if deleted {
+                        let version = index.delete_version(id)?;
+                        return Some(Ok(ScanEntry { id, version, deleted, ..Default::default() }));
+                    }
+                    let version = index.version(id)?;
+                    index.get(id).map(|(vector, payload)| {
+                        Ok(ScanEntry { id, vector, payload: payload_to_proto(payload), version, deleted })
+                    })
+                }
+            });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn load(&self, request: Request<LoadRequest>) -> Result<Response<LoadResponse>, Status> {
+        self.check_writable()?;
+        let req = request.into_inner();
+        let collection = self.collection(&req.collection)?;
+        let entries = req.entries
+            .into_iter()
+            .map(|entry| Ok((entry.id, entry.vector, payload_from_proto(entry.payload)?, entry.version, entry.deleted)))
+            .collect::<Result<Vec<_>, String>>()
+            .map_err(Status::invalid_argument)?;
+
+        // Written concurrently, so the entries share WAL syncs
+        let writes = entries.into_iter().map(|(id, vector, payload, version, deleted)| {
+            let collection = &collection;
+            async move {
+                if deleted {
+                    collection.delete(id, version).await
+                } else {
+                    collection.put(id, vector, payload, version).await.map(|lsn| lsn.is_some())
+                }
+            }
+        });
+        let mut written = 0;
+        for result in join_all(writes).await {
+            if result.map_err(status_from_write)? {
+                written += 1;
+            }
+        }
+        Ok(Response::new(LoadResponse { written }))
+    }
+
+    async fn digest(&self, request: Request<DigestRequest>) -> Result<Response<DigestResponse>, Status> {
+        let req = request.into_inner();
+        if req.depth > MAX_DEPTH {
+            return Err(Status::invalid_argument(format!("Depth must be at most {}", MAX_DEPTH)));
+        }
+        if let Some(leaf) = req.leaves.iter().find(|&&leaf| leaf as u64 >= 1 << req.depth) {
+            return Err(Status::invalid_argument(format!("No leaf {} at depth {}", leaf, req.depth)));
+        }
+        let collection = self.collection(&req.collection)?;
+        let ranges: Vec<HashRange> = req.ranges
+            .iter()
+            .map(|r| HashRange { start: r.start, end: r.end })
+            .collect();
+
+        let digests = collection.digests.lock().unwrap();
+        let nodes = digests.tree(&ranges, req.depth).into_nodes();
+        let entries = digests
+            .entries(&ranges, req.depth, &req.leaves)
+            .into_iter()
+            .map(|(id, checksum)| EntryDigest {
+                id,
+                hash: checksum.hash,
+                version: checksum.version,
+                deleted: checksum.deleted,
+            })
+            .collect();
+        Ok(Response::new(DigestResponse { nodes, entries }))
+    }
+
+    async fn replicate(
+        &self,
+        request: Request<ReplicateRequest>,
+    ) -> Result<Response<Self::ReplicateStream>, Status> {
+        let req = request.into_inner();
+        let collection = self.collection(&req.collection)?;
+        let last_lsn = collection.last_lsn();
+        if req.after_lsn > last_lsn {
+            return Err(Status::out_of_range(format!(
+                "The log of {} ends at LSN {}, before {}",
+                collection.name, last_lsn, req.after_lsn
+            )));
+        }
+
+        // The stream holds only the tail, so it ends when the collection is dropped
+        let tail = collection.tail(req.after_lsn).map_err(status_from_io)?;
+        let stream = futures::stream::unfold(Some(tail), |tail| async move {
+            let mut tail = tail?;
+            match tail.next().await? {
+                Ok(entry) => Some((Ok(record_from_entry(entry)), Some(tail))),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Some((Err(Status::out_of_range(e.to_string())), None)),
+                Err(e) => Some((Err(Status::internal(format!("Failed to read WAL: {}", e))), None)),
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+/// Keeps the catalog a copy of `primary`'s: creates and drops collections to
+/// match it, and streams each one's WAL from the last LSN logged here, so a
+/// follower resumes where it left off.
+async fn follow(primary: String, catalog: Arc<Catalog>) {
+    let mut streams = HashMap::new();
+    loop {
+        if let Err(e) = sync_with_primary(&primary, &catalog, &mut streams).await {
+            println!("Following {}: {}", primary, e.message());
+        }
+        tokio::time::sleep(FOLLOW_INTERVAL).await;
+    }
+}
+
+async fn sync_with_primary(
+    primary: &str,
+    catalog: &Arc<Catalog>,
+    streams: &mut HashMap<String, JoinHandle<()>>,
+) -> Result<(), Status> {
+    let mut client = VectorDbClient::connect(primary.to_string())
+        .await
+        .map_err(|e| Status::unavailable(e.to_string()))?;
+    let infos = client.list_collections(ListCollectionsRequest {}).await?.into_inner().collections;
+
+    for local in catalog.list() {
+        if !infos.iter().any(|info| info.name == local.name) {
+            if let Some(stream) = streams.remove(&local.name) {
+                stream.abort();
+            }
+            catalog.remove(&local.name).map_err(status_from_io)?;
+        }
+    }
+
+    for info in infos {
+        let config = config_from_info(&info);
+        let collection = match catalog.get(&info.name) {
+            Some(collection) => collection,
+            None => catalog.create(&info.name, config.clone()).map_err(status_from_io)?,
+        };
+        let local = &collection.config;
+        if (local.metric, local.m, local.ef_construction) != (config.metric, config.m, config.ef_construction) {
+            println!("[{}] Not replicating: index parameters differ from {}'s", info.name, primary);
+            continue;
+        }
+        if streams.get(&info.name).is_some_and(|stream| !stream.is_finished()) {
+            continue;
+        }
+        let task = replicate_collection(client.clone(), catalog.clone(), collection);
+        streams.insert(info.name, tokio::spawn(task));
+    }
+    Ok(())
+}
+
+fn config_from_info(info: &CollectionInfo) -> CollectionConfig {
+    CollectionConfig {
+        m: info.m as usize,
+        ef_construction: info.ef_construction as usize,
+        ef_search: Some(info.ef_search as usize),
+        metric: metric_from_proto(info.metric()),
+        dimension: if info.dimension == 0 { None } else { Some(info.dimension as usize) },
+    }
+}
+
+// Applies a collection's WAL stream until it ends or fails. If the primary's
+// log no longer reaches back to the last LSN logged here, copies the
+// collection over again and streams from there.
+async fn replicate_collection(
+    mut client: VectorDbClient<tonic::transport::Channel>,
+    catalog: Arc<Catalog>,
+    mut collection: Arc<Collection>,
+) {
+    let name = collection.name.clone();
+    let result = async {
+        loop {
+            match stream_wal(&mut client, &collection).await {
+                Err(e) if e.code() == tonic::Code::OutOfRange => {
+                    println!("[{}] {}; copying the collection from the primary", name, e.message());
+                    collection = copy_collection(&mut client, &catalog, &name).await?;
+                }
+                result => return result,
+            }
+        }
+    }.await;
+    if let Err(e) = result {
+        println!("[{}] Replication stopped: {}", name, e.message());
+    }
+}
+
+async fn stream_wal(
+    client: &mut VectorDbClient<tonic::transport::Channel>,
+    collection: &Collection,
+) -> Result<(), Status> {
+    let req = ReplicateRequest { collection: collection.name.clone(), after_lsn: collection.last_lsn() };
+    let mut stream = client.replicate(req).await?.into_inner();
+    while let Some(record) = stream.message().await? {
+        let entry = entry_from_record(record).map_err(Status::internal)?;
+        collection.replicate(entry).await.map_err(status_from_io)?;
+    }
+    Ok(())
+}
+
+// Replaces the collection with a new one holding the primary's contents, as
+// of the LSN the primary had logged when the copy began
+async fn copy_collection(
+    client: &mut VectorDbClient<tonic::transport::Channel>,
+    catalog: &Catalog,
+    name: &str,
+) -> Result<Arc<Collection>, Status> {
+    let info = client.describe_collection(DescribeCollectionRequest { name: name.to_string() }).await?.into_inner();
+    match catalog.remove(name) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(status_from_io(e)),
+        _ => {}
+    }
+    let collection = catalog.create(name, config_from_info(&info)).map_err(status_from_io)?;
+
+    // The whole ring
+    let ranges = vec![vector_db::HashRange { start: 0, end: 0 }];
+    let req = ScanRequest { collection: name.to_string(), ranges, keys_only: false, deletes: true };
+    let mut stream = client.scan(req).await?.into_inner();
+    let mut batch = Vec::new();
+    let mut copied = 0;
+    while let Some(entry) = stream.message().await? {
+        batch.push(WalEntry {
+            lsn: 0,
+            op: if entry.deleted { OpType::Delete } else { OpType::Insert },
+            vector_id: entry.id,
+            version: entry.version,
+            vector: entry.vector,
+            payload: payload_from_proto(entry.payload).map_err(Status::internal)?,
+        });
+        if batch.len() == COPY_BATCH {
+            collection.restore(&batch).await.map_err(status_from_io)?;
+            copied += batch.len();
+            batch.clear();
+        }
+    }
+    collection.restore(&batch).await.map_err(status_from_io)?;
+    copied += batch.len();
+    collection.restore_through(info.last_lsn).await.map_err(status_from_io)?;
+    println!("[{}] Copied {} vectors and deletes from the primary, as of LSN {}", name, copied, info.last_lsn);
+    Ok(collection)
 }
 
 use clap::Parser;
 
+/// When WAL entries are synced to disk
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum WalSync {
+    /// Before each write is acknowledged; concurrent writes share a sync
+    Always,
+    /// Every --wal-sync-interval-ms, in the background
+    Interval,
+    /// Whenever the OS flushes its buffers
+    Os,
+}
+
+/// Parses a search beam width, which has to hold at least one candidate.
+fn parse_ef_search(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>().map_err(|e| e.to_string())? {
+        0 => Err("must be at least 1".to_string()),
+        ef => Ok(ef),
+    }
+}
+
 #[derive(Parser)]
 struct Args {
     #[arg(long, default_value_t = 50051)]
     port: u16,
+    /// Directory holding one subdirectory (WAL, snapshot) per collection.
+    /// Defaults to `vectors_<port>`.
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+    /// Distance metric of the default collection when it is first created
+    /// (euclidean, cosine, dot-product).
+    #[arg(long)]
+    metric: Option<Metric>,
+    /// Search beam width of the default collection (defaults to ef_construction)
+    #[arg(long, value_parser = parse_ef_search)]
+    ef_search: Option<usize>,
+    /// Gossip member to join the cluster through, repeatable. Without one,
+    /// the server waits for others to join it.
+    #[arg(long = "seed")]
+    seeds: Vec<String>,
+    /// URL other members reach this server at. Defaults to `http://[::1]:<port>`.
+    #[arg(long)]
+    advertise: Option<String>,
+    /// Gossip protocol period; pings time out after 30% of it
+    #[arg(long, default_value_t = 1000)]
+    gossip_period_ms: u64,
+    /// How long a suspect member has to refute before it's declared dead
+    #[arg(long, default_value_t = 5000)]
+    suspicion_timeout_ms: u64,
+    /// How often a Raft leader sends followers entries or a heartbeat
+    #[arg(long, default_value_t = 100)]
+    raft_heartbeat_ms: u64,
+    /// How long a Raft follower waits for its leader (randomized up to twice
+    /// this) before standing for election
+    #[arg(long, default_value_t = 1000)]
+    raft_election_timeout_ms: u64,
+    /// Start a new WAL segment once the current one would pass this size
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    wal_segment_bytes: u64,
+    /// ... or once it is this old when an entry is appended; 0 for no limit
+    #[arg(long, default_value_t = 0)]
+    wal_segment_secs: u64,
+    /// Keep the WAL segments a snapshot covers in each collection's
+    /// `wal/archive` directory instead of deleting them
+    #[arg(long)]
+    wal_archive: bool,
+    /// When WAL entries are synced to disk. With `interval` or `os`, an OS
+    /// crash can lose acknowledged writes.
+    #[arg(long, value_enum, default_value_t = WalSync::Always)]
+    wal_sync: WalSync,
+    #[arg(long, default_value_t = 100)]
+    wal_sync_interval_ms: u64,
+    /// Skip corrupt entries in the middle of a WAL instead of refusing to
+    /// start. The writes they held are lost.
+    #[arg(long)]
+    wal_skip_corrupt: bool,
+    /// Primary to follow: replicate its collections from its WAL, and serve
+    /// only reads
+    #[arg(long)]
+    follow: Option<String>,
 }
 
 #[tokio::main]
@@ -123,34 +829,85 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let addr = format!("[::1]:{}", args.port).parse()?;
     
     // Initialize components
-    let snapshot_path = format!("vectors_{}.snap", args.port);
-    let wal_path = format!("vectors_{}.wal", args.port);
-
-    let hnsw = if std::path::Path::new(&snapshot_path).exists() {
-        println!("Loading snapshot from {}", snapshot_path);
-        Hnsw::load_snapshot(&snapshot_path)?
-    } else {
-        println!("Creating new HNSW index");
-        // M=16, ef_construction=100
-        Hnsw::new(16, 100)
+    let data_dir = args.data_dir.unwrap_or_else(|| PathBuf::from(format!("vectors_{}", args.port)));
+    let wal_config = WalConfig {
+        segment_bytes: args.wal_segment_bytes,
+        segment_age: (args.wal_segment_secs > 0).then(|| Duration::from_secs(args.wal_segment_secs)),
+        archive: args.wal_archive,
+        sync: match args.wal_sync {
+            WalSync::Always => SyncPolicy::Always,
+            WalSync::Interval => SyncPolicy::Interval(Duration::from_millis(args.wal_sync_interval_ms.max(1))),
+            WalSync::Os => SyncPolicy::Os,
+        },
+        skip_corrupt: args.wal_skip_corrupt,
     };
-    
-    let hnsw = Arc::new(RwLock::new(hnsw));
-    let wal = Arc::new(Mutex::new(Wal::new(&wal_path)?));
+    let catalog = Catalog::open(&data_dir, wal_config).map_err(|e| {
+        if is_corruption(&e) {
+            format!("{} (start with --wal-skip-corrupt to skip it)", e)
+        } else {
+            e.to_string()
+        }
+    })?;
 
-    // TODO: Replay WAL here. 
-    // Ideally we should only replay entries AFTER the snapshot.
-    // For now, we are just loading the snapshot. 
-    // If we crash between snapshot and now, we might lose data unless we replay WAL.
-    // Implementing full WAL replay with snapshot offset is complex for this step.
-    // We will assume manual snapshotting for now.
+    match catalog.get(DEFAULT_COLLECTION) {
+        Some(default) => {
+            let mut index = default.index.write().await;
+            if let Some(metric) = args.metric {
+                if metric != index.metric {
+                    return Err(format!(
+                        "--metric {} does not match the default collection's metric {}",
+                        metric, index.metric
+                    ).into());
+                }
+            }
+            // Query-time only, so it may change across restarts
+            if let Some(ef_search) = args.ef_search {
+                index.ef_search = ef_search;
+            }
+        }
+        // A follower takes the primary's
+        None if args.follow.is_some() => {}
+        None => {
+            let config = CollectionConfig {
+                metric: args.metric.unwrap_or_default(),
+                ef_search: args.ef_search,
+                ..Default::default()
+            };
+            catalog.create(DEFAULT_COLLECTION, config)?;
+        }
+    }
+
+    let catalog = Arc::new(catalog);
+    let advertise = args.advertise.unwrap_or_else(|| format!("http://[::1]:{}", args.port));
+
+    let mut raft_config = RaftConfig::new(&advertise);
+    raft_config.heartbeat_interval = Duration::from_millis(args.raft_heartbeat_ms.max(1));
+    raft_config.election_timeout = Duration::from_millis(args.raft_election_timeout_ms.max(1));
+    // An unanswered message shouldn't hold up an election for long
+    let transport = Arc::new(GrpcTransport::new(raft_config.election_timeout / 2));
+    let machine = Arc::new(CatalogMachine { catalog: catalog.clone() });
+    let raft = RaftGroups::open(raft_config, &data_dir.join(RAFT_DIR), transport, machine)?;
+
+    if let Some(primary) = args.follow.clone() {
+        println!("Following {}", primary);
+        tokio::spawn(follow(primary, catalog.clone()));
+    }
+    let service = MyVectorDb::new(catalog, raft.clone(), args.follow);
 
-    let service = MyVectorDb::new(hnsw, wal, snapshot_path);
+    let mut gossip_config = GossipConfig::new(&advertise, Role::Storage);
+    gossip_config.seeds = args.seeds;
+    gossip_config.protocol_period = Duration::from_millis(args.gossip_period_ms.max(1));
+    gossip_config.ping_timeout = gossip_config.protocol_period * 3 / 10;
+    gossip_config.suspicion_timeout = Duration::from_millis(args.suspicion_timeout_ms);
+    let gossip = GossipNode::new(gossip_config);
+    gossip.start();
 
-    println!("Vector DB Server listening on {}", addr);
+    println!("Vector DB Server listening on {} (gossiping as {})", addr, advertise);
 
     Server::builder()
         .add_service(VectorDbServer::new(service))
+        .add_service(gossip.service())
+        .add_service(raft.service())
         .serve(addr)
         .await?;
 
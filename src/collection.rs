@@ -0,0 +1,826 @@
+use crate::index::distance::Metric;
+use crate::index::hnsw::{Hnsw, InvalidVector};
+use crate::merkle::{Checksum, Digests};
+use crate::payload::Payload;
+use crate::storage::VectorStorage;
+use crate::wal::{sync_parent, OpType, VersionClock, Wal, WalConfig, WalEntry, WalTail};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock as StdRwLock};
+use tokio::sync::RwLock;
+
+pub use crate::DEFAULT_COLLECTION;
+
+const CONFIG_FILE: &str = "collection.meta";
+const WAL_DIR: &str = "wal";
+// The log before it was split into segments
+const LEGACY_WAL_FILE: &str = "vectors.wal";
+const SNAPSHOT_FILE: &str = "vectors.snap";
+// A collection is set up in a directory with this suffix, then renamed into
+// place. Not a valid collection name, so it can't clash with one.
+const CREATING_SUFFIX: &str = ".creating";
+const VECTORS_FILE: &str = "vectors.dat";
+
+/// Index parameters fixed when a collection is created.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CollectionConfig {
+    pub m: usize,
+    pub ef_construction: usize,
+    // Defaults to ef_construction
+    pub ef_search: Option<usize>,
+    pub metric: Metric,
+    // Declared vector dimension, if any
+    pub dimension: Option<usize>,
+}
+
+impl Default for CollectionConfig {
+    fn default() -> Self {
+        CollectionConfig {
+            m: 16,
+            ef_construction: 100,
+            ef_search: None,
+            metric: Metric::Euclidean,
+            dimension: None,
+        }
+    }
+}
+
+/// A named index with its own WAL, snapshot and vector file, stored in its own directory.
+/// Taking a snapshot removes the WAL segments it covers.
+pub struct Collection {
+    pub name: String,
+    pub config: CollectionConfig,
+    pub index: RwLock<Hnsw>,
+    // Entry checksums for anti-entropy; updated under the index write lock
+    pub digests: Mutex<Digests>,
+    // Entries logged but not committed yet, in LSN order. They reach the
+    // index and digests once committed, so a write whose sync fails never
+    // shows. Changed under the index write lock.
+    pending: Mutex<VecDeque<WalEntry>>,
+    // Versions writes that don't carry one
+    clock: VersionClock,
+    wal: Wal,
+    snapshot_path: String,
+}
+
+impl Collection {
+    /// Writes the config into a scratch directory and renames it to `dir`,
+    /// so a failed create leaves nothing behind that blocks the next one.
+    fn create(dir: &Path, name: &str, config: CollectionConfig, wal_config: &WalConfig) -> io::Result<Self> {
+        let tmp_dir = creating_path(dir);
+        if tmp_dir.exists() {
+            fs::remove_dir_all(&tmp_dir)?;
+        }
+        // Left by a create that failed before there was a config to rename in
+        if dir.exists() && !dir.join(CONFIG_FILE).exists() {
+            fs::remove_dir_all(dir)?;
+        }
+        let written = Self::write_config(&tmp_dir, &config).and_then(|()| {
+            fs::rename(&tmp_dir, dir)?;
+            sync_parent(dir)
+        });
+        if let Err(e) = written {
+            let _ = fs::remove_dir_all(&tmp_dir);
+            return Err(e);
+        }
+        Self::open(dir, name, wal_config).inspect_err(|_| {
+            let _ = fs::remove_dir_all(dir);
+        })
+    }
+
+    fn write_config(dir: &Path, config: &CollectionConfig) -> io::Result<()> {
+        fs::create_dir(dir)?;
+        let mut writer = BufWriter::new(File::create(dir.join(CONFIG_FILE))?);
+        bincode::serialize_into(&mut writer, config).map_err(io::Error::other)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        sync_parent(&dir.join(CONFIG_FILE))
+    }
+
+    /// Rebuilds the index from the last snapshot plus every WAL entry written after it.
+    fn open(dir: &Path, name: &str, wal_config: &WalConfig) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(dir.join(CONFIG_FILE))?);
+        let config: CollectionConfig = bincode::deserialize_from(reader).map_err(io::Error::other)?;
+
+        let snapshot_path = dir.join(SNAPSHOT_FILE).to_string_lossy().into_owned();
+        let wal_dir = dir.join(WAL_DIR);
+        if dir.join(LEGACY_WAL_FILE).exists() {
+            Wal::import(&wal_dir, &dir.join(LEGACY_WAL_FILE))?;
+        }
+        let wal = Wal::open(&wal_dir, wal_config.clone())?;
+        let vectors_path = dir.join(VECTORS_FILE);
+
+        let mut hnsw = if Path::new(&snapshot_path).exists() {
+            println!("[{}] Loading snapshot from {}", name, snapshot_path);
+            Hnsw::load_snapshot_with_storage(&snapshot_path, &vectors_path)?
+        } else {
+            let mut hnsw = Hnsw::with_metric(config.m, config.ef_construction, config.metric);
+            if let Some(ef_search) = config.ef_search {
+                hnsw.ef_search = ef_search;
+            }
+            hnsw.dimension = config.dimension;
+            // Nothing references vectors written before a crash without a snapshot
+            hnsw.storage = VectorStorage::create(&vectors_path)?;
+            hnsw
+        };
+
+        let snapshot_lsn = hnsw.applied_lsn;
+        let mut replayed = 0;
+        for entry in wal.read_all()? {
+            let entry = entry?;
+            if entry.lsn <= snapshot_lsn {
+                continue;
+            }
+            hnsw.apply(&entry)?;
+            replayed += 1;
+        }
+        println!("[{}] Replayed {} WAL entries after LSN {}", name, replayed, snapshot_lsn);
+
+        // Never hand out an LSN the snapshot already covers
+        wal.skip_to(hnsw.applied_lsn);
+
+        let mut digests = Digests::new(name);
+        for id in hnsw.ids() {
+            if let (Some((vector, payload)), Some(version)) = (hnsw.get(id), hnsw.version(id)) {
+                digests.insert(id, Checksum::new(id, version, &vector, &payload));
+            }
+        }
+        for (&id, &version) in &hnsw.delete_versions {
+            digests.insert(id, Checksum::deleted(id, version));
+        }
+
+        Ok(Collection {
+            name: name.to_string(),
+            config,
+            index: RwLock::new(hnsw),
+            digests: Mutex::new(digests),
+            pending: Mutex::new(VecDeque::new()),
+            clock: VersionClock::default(),
+            wal,
+            snapshot_path,
+        })
+    }
+
+    /// Validates, logs and applies an insert (or replacement) at `version`,
+    /// or at the next version of the collection's clock if `version` is 0.
+    /// Last write wins: if the id holds a later version, or the same version
+    /// with a higher checksum, or was deleted at the same or a later version,
+    /// nothing changes and this returns None.
+    /// Otherwise returns the entry's LSN. An invalid vector fails with
+    /// `ErrorKind::InvalidInput`.
+    pub async fn put(&self, id: u32, vector: Vec<f32>, payload: Payload, version: u64) -> io::Result<Option<u64>> {
+        let version = if version == 0 { self.clock.next() } else { version };
+        let checksum = Checksum::new(id, version, &vector, &payload);
+        let mut entry = WalEntry {
+            lsn: 0,
+            op: OpType::Insert,
+            vector_id: id,
+            version,
+            vector,
+            payload,
+        };
+
+        // Hold the index lock across the WAL append so entries are applied in
+        // LSN order and a snapshot never records an LSN it hasn't applied.
+        // Commit after releasing it, so concurrent writes share a sync.
+        let index = self.index.write().await;
+        self.validate(&index, &entry.vector)?;
+        if self.latest(id).is_some_and(|current| current >= checksum) {
+            return Ok(None);
+        }
+        self.wal.append(&mut entry)?;
+        let lsn = entry.lsn;
+        self.pending.lock().unwrap().push_back(entry);
+        drop(index);
+        self.commit(lsn).await?;
+        Ok(Some(lsn))
+    }
+
+    /// Logs and applies a delete at `version` (0 for the collection's clock),
+    /// and keeps the version so an older write arriving later loses to it.
+    /// That holds for an id this replica hasn't seen yet too. Nothing changes
+    /// if the id holds a later version; a delete wins a tie. Returns whether
+    /// a vector was deleted.
+    pub async fn delete(&self, id: u32, version: u64) -> io::Result<bool> {
+        let version = if version == 0 { self.clock.next() } else { version };
+        let checksum = Checksum::deleted(id, version);
+        let index = self.index.write().await;
+        let current = self.latest(id);
+        if current.is_some_and(|current| current >= checksum) {
+            return Ok(false);
+        }
+
+        let mut entry = WalEntry {
+            lsn: 0,
+            op: OpType::Delete,
+            vector_id: id,
+            version,
+            vector: vec![],
+            payload: Payload::new(),
+        };
+        self.wal.append(&mut entry)?;
+        let lsn = entry.lsn;
+        self.pending.lock().unwrap().push_back(entry);
+        drop(index);
+        self.commit(lsn).await?;
+        Ok(current.is_some_and(|current| !current.deleted))
+    }
+
+    /// Logs and applies the removal of an id this node no longer owns. Unlike
+    /// a delete, it leaves no version behind, so the key can be written here
+    /// again at any version. Returns whether a vector was removed.
+    pub async fn evict(&self, id: u32) -> io::Result<bool> {
+        let index = self.index.write().await;
+        let Some(current) = self.latest(id) else {
+            return Ok(false);
+        };
+
+        let mut entry = WalEntry {
+            lsn: 0,
+            op: OpType::Evict,
+            vector_id: id,
+            version: 0,
+            vector: vec![],
+            payload: Payload::new(),
+        };
+        self.wal.append(&mut entry)?;
+        let lsn = entry.lsn;
+        self.pending.lock().unwrap().push_back(entry);
+        drop(index);
+        self.commit(lsn).await?;
+        Ok(!current.deleted)
+    }
+
+    /// Logs and applies an entry replicated from a primary's WAL, keeping
+    /// its LSN and version. Entries at or before the last LSN logged here are
+    /// skipped, so a resumed stream may overlap what was already applied.
+    /// Returns whether the entry was applied.
+    pub async fn replicate(&self, entry: WalEntry) -> io::Result<bool> {
+        let index = self.index.write().await;
+        if entry.lsn <= self.wal.last_lsn() {
+            return Ok(false);
+        }
+        self.wal.append_at(&entry)?;
+        let lsn = entry.lsn;
+        self.pending.lock().unwrap().push_back(entry);
+        drop(index);
+        self.commit(lsn).await?;
+        Ok(true)
+    }
+
+    // Waits until the entries up to `lsn` are committed, then applies those
+    // still pending, in LSN order. If the commit fails, they never are: the
+    // WAL takes no more entries, and they stay out of the index.
+    async fn commit(&self, lsn: u64) -> io::Result<()> {
+        self.wal.commit(lsn).await?;
+        let mut index = self.index.write().await;
+        // Neither mutex is held while applying, so a panic in the index
+        // can't poison them. The index lock keeps anyone from seeing the
+        // entry between the two.
+        loop {
+            let entry = {
+                let mut pending = self.pending.lock().unwrap();
+                match pending.front() {
+                    Some(entry) if entry.lsn <= lsn => pending.pop_front().unwrap(),
+                    _ => break,
+                }
+            };
+            // Left pending on failure, so the next commit retries it
+            if let Err(e) = index.apply(&entry) {
+                self.pending.lock().unwrap().push_front(entry);
+                return Err(e);
+            }
+            let mut digests = self.digests.lock().unwrap();
+            match checksum(&entry) {
+                Some(checksum) => digests.insert(entry.vector_id, checksum),
+                None => digests.remove(entry.vector_id),
+            }
+        }
+        Ok(())
+    }
+
+    // The checksum `id` will have once the pending entries are applied. Call
+    // with the index write lock held.
+    fn latest(&self, id: u32) -> Option<Checksum> {
+        match self.pending.lock().unwrap().iter().rev().find(|entry| entry.vector_id == id) {
+            Some(entry) => checksum(entry),
+            None => self.digests.lock().unwrap().get(id),
+        }
+    }
+
+    // Checks `vector` against the index, and against the first pending
+    // insert if that will fix the dimension
+    fn validate(&self, index: &Hnsw, vector: &[f32]) -> io::Result<()> {
+        let mut result = index.validate(vector);
+        if result.is_ok() && index.dimension.is_none() {
+            let pending = self.pending.lock().unwrap();
+            if let Some(first) = pending.iter().find(|entry| matches!(entry.op, OpType::Insert)) {
+                if first.vector.len() != vector.len() {
+                    result = Err(InvalidVector::DimensionMismatch { expected: first.vector.len(), actual: vector.len() });
+                }
+            }
+        }
+        result.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Applies entries a follower copied from its primary to a collection
+    /// created for the copy, without logging them. They last once
+    /// `restore_through` snapshots them.
+    pub async fn restore(&self, entries: &[WalEntry]) -> io::Result<()> {
+        let mut index = self.index.write().await;
+        let mut digests = self.digests.lock().unwrap();
+        for entry in entries {
+            index.apply(entry)?;
+            if let Some(checksum) = checksum(entry) {
+                digests.insert(entry.vector_id, checksum);
+            }
+        }
+        Ok(())
+    }
+
+    /// Snapshots what `restore` applied as of `lsn`, the primary's last LSN
+    /// when the copy began, so replication resumes after it. Replaying the
+    /// entries after `lsn` over the copy ends where the primary did.
+    pub async fn restore_through(&self, lsn: u64) -> io::Result<()> {
+        let mut index = self.index.write().await;
+        if self.wal.last_lsn() > 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Only a new collection can be restored"));
+        }
+        index.applied_lsn = lsn;
+        index.save_snapshot(&self.snapshot_path)?;
+        self.wal.skip_to(lsn);
+        Ok(())
+    }
+
+    /// LSN of the last entry logged or covered by the snapshot, 0 if none.
+    pub fn last_lsn(&self) -> u64 {
+        self.wal.last_lsn()
+    }
+
+    /// Reads the WAL entries after `after_lsn`, then each new one as it is
+    /// logged. See `WalTail`.
+    pub fn tail(&self, after_lsn: u64) -> io::Result<WalTail> {
+        self.wal.tail(after_lsn)
+    }
+
+    pub async fn snapshot(&self) -> io::Result<()> {
+        let index = self.index.read().await;
+        // Syncs the directory too, so the segments go only once the snapshot
+        // replacing them survives a crash
+        index.save_snapshot(&self.snapshot_path)?;
+        println!("[{}] Snapshot saved to {}", self.name, self.snapshot_path);
+        let removed = self.wal.remove_through(index.applied_lsn)?;
+        if removed > 0 {
+            println!("[{}] Removed {} WAL segments up to LSN {}", self.name, removed, index.applied_lsn);
+        }
+        Ok(())
+    }
+}
+
+// What an entry leaves in the digests once applied; None for an evict
+fn checksum(entry: &WalEntry) -> Option<Checksum> {
+    match entry.op {
+        OpType::Insert => Some(Checksum::new(entry.vector_id, entry.version, &entry.vector, &entry.payload)),
+        OpType::Delete => Some(Checksum::deleted(entry.vector_id, entry.version)),
+        OpType::Evict => None,
+    }
+}
+
+/// The set of collections on a server, one subdirectory each under `dir`.
+pub struct Catalog {
+    dir: PathBuf,
+    wal_config: WalConfig,
+    collections: StdRwLock<HashMap<String, Arc<Collection>>>,
+    // Names being created. Their files are set up without holding
+    // `collections`, so lookups don't wait on the fsyncs.
+    creating: Mutex<HashSet<String>>,
+}
+
+impl Catalog {
+    /// Opens every collection found under `dir`, creating `dir` if needed.
+    /// Every collection's WAL is segmented as `wal_config` says.
+    pub fn open(dir: &Path, wal_config: WalConfig) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut collections = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.to_string_lossy().ends_with(CREATING_SUFFIX) {
+                println!("Removing {}, left by an unfinished create", path.display());
+                fs::remove_dir_all(&path)?;
+                continue;
+            }
+            if !path.join(CONFIG_FILE).exists() {
+                continue;
+            }
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let collection = Collection::open(&path, &name, &wal_config)?;
+            collections.insert(name, Arc::new(collection));
+        }
+        Ok(Catalog {
+            dir: dir.to_path_buf(),
+            wal_config,
+            collections: StdRwLock::new(collections),
+            creating: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Looks up a collection. An empty name means `DEFAULT_COLLECTION`.
+    pub fn get(&self, name: &str) -> Option<Arc<Collection>> {
+        let name = if name.is_empty() { DEFAULT_COLLECTION } else { name };
+        self.collections.read().unwrap().get(name).cloned()
+    }
+
+    /// Creates a collection and its files. Blocks on fsyncs.
+    pub fn create(&self, name: &str, config: CollectionConfig) -> io::Result<Arc<Collection>> {
+        validate_name(name)?;
+        {
+            let mut creating = self.creating.lock().unwrap();
+            if creating.contains(name) || self.collections.read().unwrap().contains_key(name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("Collection '{}' already exists", name),
+                ));
+            }
+            creating.insert(name.to_string());
+        }
+
+        let created = Collection::create(&self.dir.join(name), name, config, &self.wal_config).map(Arc::new);
+        if let Ok(collection) = &created {
+            self.collections.write().unwrap().insert(name.to_string(), collection.clone());
+            println!("Created collection {}", name);
+        }
+        // Only now, so the name is never free in between
+        self.creating.lock().unwrap().remove(name);
+        created
+    }
+
+    /// Removes a collection and deletes its files. Writes still in flight on
+    /// it fail, and the name can't be created again until the files are
+    /// gone. Blocks on the removal.
+    pub fn remove(&self, name: &str) -> io::Result<()> {
+        let removed = {
+            let mut creating = self.creating.lock().unwrap();
+            let removed = if creating.contains(name) {
+                None
+            } else {
+                self.collections.write().unwrap().remove(name)
+            };
+            let Some(removed) = removed else {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Collection '{}' does not exist", name),
+                ));
+            };
+            creating.insert(name.to_string());
+            removed
+        };
+
+        removed.wal.close(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Collection '{}' was dropped", name),
+        ));
+        let result = fs::remove_dir_all(self.dir.join(name));
+        self.creating.lock().unwrap().remove(name);
+        result?;
+        println!("Dropped collection {}", name);
+        Ok(())
+    }
+
+    /// All collections, sorted by name.
+    pub fn list(&self) -> Vec<Arc<Collection>> {
+        let mut collections: Vec<_> = self.collections.read().unwrap().values().cloned().collect();
+        collections.sort_by(|a, b| a.name.cmp(&b.name));
+        collections
+    }
+}
+
+// Names become directory names, so keep them to a safe character set
+fn validate_name(name: &str) -> io::Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid collection name '{}': use 1-64 letters, digits, '_' or '-'", name),
+        ));
+    }
+    Ok(())
+}
+
+fn creating_path(dir: &Path) -> PathBuf {
+    let mut path = dir.as_os_str().to_owned();
+    path.push(CREATING_SUFFIX);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("catalog_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_catalog_persists_collections() {
+        let dir = temp_dir("persist");
+        let catalog = Catalog::open(&dir, WalConfig::default()).unwrap();
+
+        let config = CollectionConfig { metric: Metric::Cosine, dimension: Some(2), ..Default::default() };
+        let docs = catalog.create("docs", config.clone()).unwrap();
+        catalog.create("images", CollectionConfig::default()).unwrap();
+        docs.put(1, vec![1.0, 0.0], Payload::new(), 0).await.unwrap();
+        docs.put(2, vec![0.0, 1.0], Payload::new(), 0).await.unwrap();
+        assert!(docs.delete(2, 0).await.unwrap());
+        assert!(!docs.delete(2, 0).await.unwrap());
+
+        assert_eq!(catalog.create("docs", CollectionConfig::default()).err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(catalog.create("../etc", CollectionConfig::default()).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        drop(docs);
+        drop(catalog);
+
+        let catalog = Catalog::open(&dir, WalConfig::default()).unwrap();
+        let names: Vec<String> = catalog.list().iter().map(|c| c.name.clone()).collect();
+        assert_eq!(names, vec!["docs", "images"]);
+
+        let docs = catalog.get("docs").unwrap();
+        assert_eq!(docs.config, config);
+        let err = docs.put(3, vec![1.0, 0.0, 0.0], Payload::new(), 0).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let index = docs.index.read().await;
+        assert_eq!(index.metric, Metric::Cosine);
+        assert_eq!(index.len(), 1);
+        assert!(index.contains(1));
+        drop(index);
+
+        catalog.remove("images").unwrap();
+        assert!(catalog.get("images").is_none());
+        assert!(!dir.join("images").exists());
+        assert_eq!(catalog.remove("images").unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_create_recovers_from_failed_creates() {
+        let dir = temp_dir("failed_create");
+        // What an earlier create that failed part way may have left
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join("docs").join(format!("{}.tmp", CONFIG_FILE)), b"partial").unwrap();
+        fs::create_dir_all(dir.join("images.creating")).unwrap();
+        fs::write(dir.join("images.creating").join(CONFIG_FILE), b"partial").unwrap();
+
+        let catalog = Catalog::open(&dir, WalConfig::default()).unwrap();
+        assert!(catalog.list().is_empty());
+        assert!(!dir.join("images.creating").exists());
+        catalog.create("docs", CollectionConfig::default()).unwrap();
+        catalog.create("images", CollectionConfig::default()).unwrap();
+        assert_eq!(catalog.create("docs", CollectionConfig::default()).err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+        drop(catalog);
+
+        let catalog = Catalog::open(&dir, WalConfig::default()).unwrap();
+        let names: Vec<String> = catalog.list().iter().map(|c| c.name.clone()).collect();
+        assert_eq!(names, vec!["docs", "images"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dropped_collection_takes_no_writes() {
+        let dir = temp_dir("drop");
+        let catalog = Catalog::open(&dir, WalConfig::default()).unwrap();
+        let docs = catalog.create("docs", CollectionConfig::default()).unwrap();
+        docs.put(1, vec![1.0, 0.0], Payload::new(), 0).await.unwrap();
+        catalog.remove("docs").unwrap();
+
+        // Requests still holding the dropped collection can't write to it,
+        // nor to the one created under its name after it
+        assert_eq!(docs.put(2, vec![2.0, 0.0], Payload::new(), 0).await.unwrap_err().kind(), io::ErrorKind::NotFound);
+        let recreated = catalog.create("docs", CollectionConfig::default()).unwrap();
+        assert!(docs.put(3, vec![3.0, 0.0], Payload::new(), 0).await.is_err());
+        assert!(docs.delete(1, 0).await.is_err());
+        recreated.put(4, vec![4.0, 0.0], Payload::new(), 0).await.unwrap();
+        drop(docs);
+        drop(recreated);
+        drop(catalog);
+
+        // Nothing the dropped collection took comes back on restart
+        let catalog = Catalog::open(&dir, WalConfig::default()).unwrap();
+        let docs = catalog.get("docs").unwrap();
+        let index = docs.index.read().await;
+        assert_eq!(index.len(), 1);
+        assert!(index.contains(4));
+        drop(index);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_empty_name_is_default_collection() {
+        let dir = temp_dir("default");
+        let catalog = Catalog::open(&dir, WalConfig::default()).unwrap();
+        assert!(catalog.get("").is_none());
+        let default = catalog.create(DEFAULT_COLLECTION, CollectionConfig::default()).unwrap();
+        assert_eq!(catalog.get("").unwrap().name, DEFAULT_COLLECTION);
+
+        // Without a declared dimension, the first put fixes it
+        default.put(1, vec![1.0, 2.0], Payload::new(), 0).await.unwrap();
+        assert!(default.put(2, vec![1.0], Payload::new(), 0).await.is_err());
+        assert!(default.put(2, vec![1.0, f32::NAN], Payload::new(), 0).await.is_err());
+        assert_eq!(default.index.read().await.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_last_write_wins() {
+        let dir = temp_dir("versions");
+        let catalog = Catalog::open(&dir, WalConfig::default()).unwrap();
+        let docs = catalog.create("docs", CollectionConfig::default()).unwrap();
+
+        assert!(docs.put(1, vec![1.0], Payload::new(), 10).await.unwrap().is_some());
+        assert!(docs.put(1, vec![2.0], Payload::new(), 5).await.unwrap().is_none(), "an older write loses");
+        assert!(docs.put(1, vec![1.0], Payload::new(), 10).await.unwrap().is_none(), "a repeated write is a no-op");
+        assert!(docs.put(1, vec![3.0], Payload::new(), 20).await.unwrap().is_some());
+        assert!(!docs.delete(1, 15).await.unwrap(), "an older delete loses");
+        docs.put(2, vec![4.0], Payload::new(), 0).await.unwrap();
+        docs.snapshot().await.unwrap();
+        docs.put(3, vec![5.0], Payload::new(), 30).await.unwrap();
+        drop(docs);
+        drop(catalog);
+
+        // Versions survive both the snapshot and the WAL
+        let catalog = Catalog::open(&dir, WalConfig::default()).unwrap();
+        let docs = catalog.get("docs").unwrap();
+        let index = docs.index.read().await;
+        assert_eq!(index.get(1).unwrap().0, vec![3.0]);
+        assert_eq!(index.version(1), Some(20));
+        assert!(index.version(2).unwrap() > 30, "unversioned writes take the current time");
+        assert_eq!(index.version(3), Some(30));
+        drop(index);
+        assert_eq!(docs.digests.lock().unwrap().get(1).unwrap().version, 20);
+        assert!(docs.delete(1, 20).await.unwrap(), "a delete wins a tie");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_deletes_keep_their_version() {
+        let dir = temp_dir("deletes");
+        let catalog = Catalog::open(&dir, WalConfig::default()).unwrap();
+        let docs = catalog.create("docs", CollectionConfig::default()).unwrap();
+
+        // A delete that arrives before its write still wins over it
+        assert!(!docs.delete(1, 10).await.unwrap());
+        assert!(docs.put(1, vec![1.0], Payload::new(), 5).await.unwrap().is_none());
+        assert!(docs.put(1, vec![1.0], Payload::new(), 10).await.unwrap().is_none(), "a delete wins a tie");
+        assert!(docs.put(1, vec![1.0], Payload::new(), 11).await.unwrap().is_some());
+
+        // A write older than a delete loses, however late it arrives
+        docs.put(2, vec![2.0], Payload::new(), 20).await.unwrap();
+        assert!(docs.delete(2, 30).await.unwrap());
+        assert!(!docs.delete(2, 25).await.unwrap());
+        assert!(docs.put(2, vec![2.0], Payload::new(), 25).await.unwrap().is_none());
+        docs.snapshot().await.unwrap();
+        assert!(!docs.delete(3, 40).await.unwrap());
+        drop(docs);
+        drop(catalog);
+
+        // Delete versions survive both the snapshot and the WAL
+        let catalog = Catalog::open(&dir, WalConfig::default()).unwrap();
+        let docs = catalog.get("docs").unwrap();
+        assert_eq!(docs.digests.lock().unwrap().get(2), Some(Checksum::deleted(2, 30)));
+        assert_eq!(docs.index.read().await.delete_version(3), Some(40));
+        assert!(docs.put(2, vec![2.0], Payload::new(), 29).await.unwrap().is_none());
+        assert!(docs.put(3, vec![3.0], Payload::new(), 39).await.unwrap().is_none());
+        assert!(docs.put(3, vec![3.0], Payload::new(), 41).await.unwrap().is_some());
+        assert_eq!(docs.index.read().await.delete_version(3), None);
+        assert_eq!(docs.index.read().await.len(), 2);
+
+        // Evicting an id forgets its delete too
+        assert!(!docs.evict(2).await.unwrap());
+        assert!(docs.digests.lock().unwrap().get(2).is_none());
+        assert!(docs.put(2, vec![2.0], Payload::new(), 1).await.unwrap().is_some());
+        assert!(docs.evict(2).await.unwrap());
+        assert!(!docs.index.read().await.contains(2));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_removes_covered_wal_segments() {
+        let dir = temp_dir("segments");
+        let wal_config = WalConfig { segment_bytes: 200, ..Default::default() };
+        let catalog = Catalog::open(&dir, wal_config.clone()).unwrap();
+        let docs = catalog.create("docs", CollectionConfig::default()).unwrap();
+        for id in 0..10 {
+            docs.put(id, vec![id as f32, 0.0], Payload::new(), 0).await.unwrap();
+        }
+        let segments = || fs::read_dir(dir.join("docs").join(WAL_DIR)).unwrap().count();
+        assert!(segments() > 3);
+        docs.snapshot().await.unwrap();
+        assert_eq!(segments(), 1);
+        docs.put(10, vec![10.0, 0.0], Payload::new(), 0).await.unwrap();
+        assert!(docs.delete(0, 0).await.unwrap());
+        drop(docs);
+        drop(catalog);
+
+        // The snapshot and what's left of the log hold everything
+        let catalog = Catalog::open(&dir, wal_config).unwrap();
+        let docs = catalog.get("docs").unwrap();
+        let index = docs.index.read().await;
+        assert_eq!(index.len(), 10);
+        assert!(index.contains(10) && !index.contains(0));
+        assert_eq!(docs.last_lsn(), 12);
+        drop(index);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_writes_are_never_applied() {
+        let dir = temp_dir("failed");
+        let wal_config = WalConfig { segment_bytes: 200, ..Default::default() };
+        let catalog = Catalog::open(&dir, wal_config).unwrap();
+        let docs = catalog.create("docs", CollectionConfig::default()).unwrap();
+        docs.put(0, vec![0.0, 0.0], Payload::new(), 0).await.unwrap();
+        docs.put(1, vec![1.0, 0.0], Payload::new(), 0).await.unwrap();
+
+        // Writes go on into the open segment until the next one can't be created
+        fs::remove_dir_all(dir.join("docs").join(WAL_DIR)).unwrap();
+        let mut id = 2;
+        while docs.put(id, vec![id as f32, 0.0], Payload::new(), 0).await.is_ok() {
+            id += 1;
+        }
+        fs::create_dir_all(dir.join("docs").join(WAL_DIR)).unwrap();
+        assert!(docs.put(id, vec![id as f32, 0.0], Payload::new(), 0).await.is_err());
+        assert!(docs.delete(0, 0).await.is_err());
+        let index = docs.index.read().await;
+        assert_eq!(index.len(), id as usize);
+        assert!(index.contains(0) && !index.contains(id));
+        drop(index);
+        assert!(docs.digests.lock().unwrap().get(id).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_writes_apply_in_order() {
+        let dir = temp_dir("concurrent");
+        let catalog = Catalog::open(&dir, WalConfig::default()).unwrap();
+        let docs = catalog.create("docs", CollectionConfig::default()).unwrap();
+        let writes = (0..50u64).map(|i| {
+            let docs = docs.clone();
+            tokio::spawn(async move { docs.put((i % 5) as u32, vec![i as f32, 1.0], Payload::new(), i + 1).await })
+        });
+        for write in writes.collect::<Vec<_>>() {
+            write.await.unwrap().unwrap();
+        }
+
+        // Every id ends at its newest write, in the index and the digests alike
+        let index = docs.index.read().await;
+        for id in 0..5u32 {
+            assert_eq!(index.version(id), Some(46 + id as u64));
+            assert_eq!(docs.digests.lock().unwrap().get(id).unwrap().version, 46 + id as u64);
+        }
+        assert!(docs.pending.lock().unwrap().is_empty());
+        drop(index);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replica_follows_tail() {
+        let dir = temp_dir("tail");
+        let catalog = Catalog::open(&dir, WalConfig::default()).unwrap();
+        let primary = catalog.create("primary", CollectionConfig::default()).unwrap();
+        let replica = catalog.create("replica", CollectionConfig::default()).unwrap();
+
+        primary.put(1, vec![1.0], Payload::new(), 0).await.unwrap();
+        primary.put(2, vec![2.0], Payload::new(), 0).await.unwrap();
+        let mut tail = primary.tail(0).unwrap();
+        let first = tail.next().await.unwrap().unwrap();
+        assert!(replica.replicate(first.clone()).await.unwrap());
+        assert!(!replica.replicate(first).await.unwrap(), "a replayed entry is skipped");
+
+        // The tail waits for entries not yet logged
+        let writer = primary.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            writer.delete(1, 0).await.unwrap();
+        });
+        for _ in 0..2 {
+            replica.replicate(tail.next().await.unwrap().unwrap()).await.unwrap();
+        }
+        assert_eq!(replica.last_lsn(), 3);
+        assert!(!replica.index.read().await.contains(1));
+        assert_eq!(replica.index.read().await.version(2), primary.index.read().await.version(2));
+        assert_eq!(replica.digests.lock().unwrap().get(2), primary.digests.lock().unwrap().get(2));
+
+        // Resuming past the end of the log waits; a gap is an error
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(50), primary.tail(3).unwrap().next()).await.is_err());
+        let gap = catalog.create("gap", CollectionConfig::default()).unwrap();
+        gap.replicate(WalEntry { lsn: 5, ..primary.tail(0).unwrap().next().await.unwrap().unwrap() }).await.unwrap();
+        let err = gap.tail(0).unwrap().next().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
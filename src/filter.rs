@@ -0,0 +1,120 @@
+use crate::payload::{Payload, PayloadValue};
+
+/// A predicate over a vector's payload, evaluated during search.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// The key equals the value. A string also matches a string list that contains it,
+    /// and ints and floats compare numerically.
+    Eq(String, PayloadValue),
+    /// The key equals any of the values
+    AnyOf(String, Vec<PayloadValue>),
+    /// The key is numeric and within the bounds. Unset bounds are open.
+    Range {
+        key: String,
+        gt: Option<f64>,
+        gte: Option<f64>,
+        lt: Option<f64>,
+        lte: Option<f64>,
+    },
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn matches(&self, payload: &Payload) -> bool {
+        match self {
+            Filter::Eq(key, value) => payload.get(key).is_some_and(|stored| value_eq(stored, value)),
+            Filter::AnyOf(key, values) => payload
+                .get(key)
+                .is_some_and(|stored| values.iter().any(|v| value_eq(stored, v))),
+            Filter::Range { key, gt, gte, lt, lte } => {
+                let Some(x) = payload.get(key).and_then(as_f64) else {
+                    return false;
+                };
+                gt.is_none_or(|b| x > b)
+                    && gte.is_none_or(|b| x >= b)
+                    && lt.is_none_or(|b| x < b)
+                    && lte.is_none_or(|b| x <= b)
+            }
+            Filter::And(filters) => filters.iter().all(|f| f.matches(payload)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(payload)),
+            Filter::Not(filter) => !filter.matches(payload),
+        }
+    }
+}
+
+fn as_f64(value: &PayloadValue) -> Option<f64> {
+    match value {
+        PayloadValue::Int(v) => Some(*v as f64),
+        PayloadValue::Float(v) => Some(*v),
+        _ => None,
+    }
+}
+
+fn value_eq(stored: &PayloadValue, expected: &PayloadValue) -> bool {
+    match (stored, expected) {
+        (PayloadValue::StringList(list), PayloadValue::String(s)) => list.contains(s),
+        (PayloadValue::Int(_), PayloadValue::Float(_)) | (PayloadValue::Float(_), PayloadValue::Int(_)) => {
+            as_f64(stored) == as_f64(expected)
+        }
+        _ => stored == expected,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload() -> Payload {
+        Payload::from([
+            ("lang".to_string(), PayloadValue::String("en".to_string())),
+            ("year".to_string(), PayloadValue::Int(2020)),
+            ("score".to_string(), PayloadValue::Float(0.75)),
+            ("public".to_string(), PayloadValue::Bool(true)),
+            ("tags".to_string(), PayloadValue::StringList(vec!["rust".to_string(), "db".to_string()])),
+        ])
+    }
+
+    fn range(key: &str, gte: Option<f64>, lt: Option<f64>) -> Filter {
+        Filter::Range { key: key.to_string(), gt: None, gte, lt, lte: None }
+    }
+
+    #[test]
+    fn test_eq_and_any_of() {
+        let p = payload();
+        assert!(Filter::Eq("lang".into(), PayloadValue::String("en".into())).matches(&p));
+        assert!(!Filter::Eq("lang".into(), PayloadValue::String("de".into())).matches(&p));
+        assert!(Filter::Eq("tags".into(), PayloadValue::String("db".into())).matches(&p));
+        assert!(Filter::Eq("year".into(), PayloadValue::Float(2020.0)).matches(&p));
+        assert!(Filter::Eq("public".into(), PayloadValue::Bool(true)).matches(&p));
+        assert!(!Filter::Eq("missing".into(), PayloadValue::Bool(true)).matches(&p));
+        assert!(Filter::AnyOf(
+            "lang".into(),
+            vec![PayloadValue::String("de".into()), PayloadValue::String("en".into())]
+        )
+        .matches(&p));
+    }
+
+    #[test]
+    fn test_range() {
+        let p = payload();
+        assert!(range("year", Some(2020.0), Some(2021.0)).matches(&p));
+        assert!(!range("year", Some(2021.0), None).matches(&p));
+        assert!(range("score", None, Some(1.0)).matches(&p));
+        // Non-numeric values never satisfy a range
+        assert!(!range("lang", None, None).matches(&p));
+    }
+
+    #[test]
+    fn test_boolean_combinators() {
+        let p = payload();
+        let en = Filter::Eq("lang".into(), PayloadValue::String("en".into()));
+        let old = range("year", None, Some(2000.0));
+        assert!(!Filter::And(vec![en.clone(), old.clone()]).matches(&p));
+        assert!(Filter::Or(vec![en.clone(), old.clone()]).matches(&p));
+        assert!(Filter::And(vec![en, Filter::Not(Box::new(old))]).matches(&p));
+        assert!(Filter::And(vec![]).matches(&p));
+        assert!(!Filter::Or(vec![]).matches(&p));
+    }
+}
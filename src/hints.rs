@@ -0,0 +1,329 @@
+use crate::wal::OpType;
+use crc32fast::Hasher;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const HINTS_EXTENSION: &str = "hints";
+
+/// A write that didn't reach one replica, kept to be replayed to it later.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hint {
+    // Assigned by `HintStore::add`, increasing per target
+    pub seq: u64,
+    pub created_ms: u64,
+    pub collection: String,
+    pub id: u32,
+    pub op: OpType,
+    // The request to replay, encoded by whoever recorded the hint
+    pub request: Vec<u8>,
+}
+
+struct TargetLog {
+    file: File,
+    bytes: u64,
+    count: usize,
+    next_seq: u64,
+    // Whether a hint was dropped for lack of room since the log last shrank
+    full: bool,
+}
+
+/// Durable hints, one append-only file per target in `dir`.
+///
+/// Each target's hints take at most `max_bytes` on disk; past that, new
+/// hints for it are dropped, and the replica has to be repaired some other
+/// way. Hints older than `max_age` are dropped when replayed. Records use the
+/// WAL's framing (CRC32, length, bincode); a torn record at the end of a file
+/// is ignored along with everything after it.
+pub struct HintStore {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+    targets: Mutex<HashMap<String, TargetLog>>,
+}
+
+impl HintStore {
+    /// Opens `dir`, creating it if needed, with the hints already in it.
+    pub fn open(dir: &Path, max_bytes: u64, max_age: Duration) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut targets = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(HINTS_EXTENSION) {
+                continue;
+            }
+            let Some(target) = path.file_stem().and_then(|s| s.to_str()).and_then(decode_target) else {
+                continue;
+            };
+            let (hints, valid_len) = read_hints(&path)?;
+            let file = OpenOptions::new().append(true).open(&path)?;
+            // New hints go after the last intact one
+            file.set_len(valid_len)?;
+            let log = TargetLog {
+                file,
+                bytes: valid_len,
+                count: hints.len(),
+                next_seq: hints.last().map_or(1, |h| h.seq + 1),
+                full: false,
+            };
+            targets.insert(target, log);
+        }
+        Ok(HintStore { dir: dir.to_path_buf(), max_bytes, max_age, targets: Mutex::new(targets) })
+    }
+
+    /// Stamps `hint` with its target's next sequence number and appends it,
+    /// returning once it is on disk. Returns the sequence number, or `None`
+    /// if the target's hints are full. The sync happens outside the store's
+    /// lock, so adds don't queue behind each other's syncs.
+    pub fn add(&self, target: &str, mut hint: Hint) -> io::Result<Option<u64>> {
+        let mut targets = self.targets.lock().unwrap();
+        if !targets.contains_key(target) {
+            let file = OpenOptions::new().create(true).append(true).open(self.path(target))?;
+            targets.insert(target.to_string(), TargetLog { file, bytes: 0, count: 0, next_seq: 1, full: false });
+        }
+        let log = targets.get_mut(target).unwrap();
+
+        hint.seq = log.next_seq;
+        let record = encode(&hint)?;
+        if log.bytes + record.len() as u64 > self.max_bytes {
+            if !log.full {
+                println!("Hints for {} are full ({} bytes); dropping new ones", target, log.bytes);
+                log.full = true;
+            }
+            return Ok(None);
+        }
+        log.file.write_all(&record)?;
+        log.bytes += record.len() as u64;
+        log.count += 1;
+        log.next_seq += 1;
+        let file = log.file.try_clone()?;
+        drop(targets);
+
+        file.sync_data()?;
+        Ok(Some(hint.seq))
+    }
+
+    /// Number of hints waiting for each target that has any.
+    pub fn pending(&self) -> HashMap<String, u64> {
+        self.targets
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, log)| log.count > 0)
+            .map(|(target, log)| (target.clone(), log.count as u64))
+            .collect()
+    }
+
+    /// Every hint waiting for `target`, oldest first.
+    pub fn load(&self, target: &str) -> io::Result<Vec<Hint>> {
+        let _targets = self.targets.lock().unwrap();
+        let path = self.path(target);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        Ok(read_hints(&path)?.0)
+    }
+
+    pub fn is_expired(&self, hint: &Hint) -> bool {
+        now_ms().saturating_sub(hint.created_ms) > self.max_age.as_millis() as u64
+    }
+
+    /// Drops the hints for `target` up to and including `seq`.
+    pub fn ack(&self, target: &str, seq: u64) -> io::Result<()> {
+        let mut targets = self.targets.lock().unwrap();
+        let Some(log) = targets.get_mut(target) else { return Ok(()) };
+        let path = self.path(target);
+        let rest: Vec<Hint> = read_hints(&path)?.0.into_iter().filter(|h| h.seq > seq).collect();
+
+        // Rewrite what's left, then swap it in
+        let tmp_path = path.with_extension("tmp");
+        let mut bytes = Vec::new();
+        for hint in &rest {
+            bytes.extend(encode(hint)?);
+        }
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&bytes)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+
+        log.file = OpenOptions::new().append(true).open(&path)?;
+        log.bytes = bytes.len() as u64;
+        log.count = rest.len();
+        log.full = false;
+        Ok(())
+    }
+
+    /// Drops every hint for `target`.
+    pub fn discard(&self, target: &str) -> io::Result<()> {
+        if let Some(log) = self.targets.lock().unwrap().remove(target) {
+            drop(log);
+            fs::remove_file(self.path(target))?;
+        }
+        Ok(())
+    }
+
+    fn path(&self, target: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", encode_target(target), HINTS_EXTENSION))
+    }
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+// Target addresses contain characters file names can't, so files are named by their hex
+fn encode_target(target: &str) -> String {
+    target.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_target(name: &str) -> Option<String> {
+    if !name.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes: Option<Vec<u8>> = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(name.get(i..i + 2)?, 16).ok())
+        .collect();
+    String::from_utf8(bytes?).ok()
+}
+
+// [CRC32 (4 bytes)] [Length (8 bytes)] [Data], as in the WAL
+fn encode(hint: &Hint) -> io::Result<Vec<u8>> {
+    let data = bincode::serialize(hint).map_err(io::Error::other)?;
+    let mut hasher = Hasher::new();
+    hasher.update(&data);
+    let mut record = Vec::with_capacity(data.len() + 12);
+    record.extend_from_slice(&hasher.finalize().to_le_bytes());
+    record.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    record.extend_from_slice(&data);
+    Ok(record)
+}
+
+// The intact hints in a file, and the length they take up
+fn read_hints(path: &Path) -> io::Result<(Vec<Hint>, u64)> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut hints = Vec::new();
+    let mut valid_len = 0;
+    loop {
+        let mut header = [0u8; 12];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let len = u64::from_le_bytes(header[4..].try_into().unwrap());
+        if valid_len + 12 + len > file_len {
+            println!("Ignoring a torn hint at the end of {}", path.display());
+            break;
+        }
+        let mut data = vec![0u8; len as usize];
+        let torn = match reader.read_exact(&mut data) {
+            Ok(()) => {
+                let mut hasher = Hasher::new();
+                hasher.update(&data);
+                hasher.finalize() != crc
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => true,
+            Err(e) => return Err(e),
+        };
+        if torn {
+            println!("Ignoring a torn hint at the end of {}", path.display());
+            break;
+        }
+        hints.push(bincode::deserialize(&data).map_err(io::Error::other)?);
+        valid_len += 12 + len;
+    }
+    Ok((hints, valid_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET: &str = "http://[::1]:50051";
+
+    fn hint(id: u32, created_ms: u64) -> Hint {
+        Hint {
+            seq: 0,
+            created_ms,
+            collection: "default".to_string(),
+            id,
+            op: OpType::Insert,
+            request: vec![id as u8; 10],
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hints_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_hints_survive_reopen_and_ack() {
+        let dir = temp_dir("reopen");
+        let store = HintStore::open(&dir, 1 << 20, Duration::from_secs(60)).unwrap();
+        for id in 0..5 {
+            assert_eq!(store.add(TARGET, hint(id, now_ms())).unwrap(), Some(id as u64 + 1));
+        }
+        drop(store);
+
+        let store = HintStore::open(&dir, 1 << 20, Duration::from_secs(60)).unwrap();
+        assert_eq!(store.pending(), HashMap::from([(TARGET.to_string(), 5)]));
+        let hints = store.load(TARGET).unwrap();
+        assert_eq!(hints.iter().map(|h| h.id).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+
+        // Acked hints are gone; numbering carries on
+        store.ack(TARGET, 3).unwrap();
+        assert_eq!(store.load(TARGET).unwrap().iter().map(|h| h.seq).collect::<Vec<_>>(), vec![4, 5]);
+        assert_eq!(store.add(TARGET, hint(9, now_ms())).unwrap(), Some(6));
+        store.discard(TARGET).unwrap();
+        assert!(store.pending().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_hints_are_bounded_in_size_and_age() {
+        let dir = temp_dir("bounds");
+        let record = encode(&hint(0, 0)).unwrap().len() as u64;
+        let store = HintStore::open(&dir, record * 3, Duration::from_secs(60)).unwrap();
+        for id in 0..3 {
+            assert!(store.add(TARGET, hint(id, now_ms())).unwrap().is_some());
+        }
+        assert_eq!(store.add(TARGET, hint(3, now_ms())).unwrap(), None);
+        // Room frees up once hints are replayed
+        store.ack(TARGET, 1).unwrap();
+        assert!(store.add(TARGET, hint(4, now_ms())).unwrap().is_some());
+
+        assert!(store.is_expired(&hint(0, now_ms() - 61_000)));
+        assert!(!store.is_expired(&hint(0, now_ms() - 1_000)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_tail_is_ignored() {
+        let dir = temp_dir("torn");
+        let store = HintStore::open(&dir, 1 << 20, Duration::from_secs(60)).unwrap();
+        store.add(TARGET, hint(0, now_ms())).unwrap();
+        store.add(TARGET, hint(1, now_ms())).unwrap();
+        drop(store);
+
+        let path = dir.join(format!("{}.{}", encode_target(TARGET), HINTS_EXTENSION));
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+        let store = HintStore::open(&dir, 1 << 20, Duration::from_secs(60)).unwrap();
+        assert_eq!(store.load(TARGET).unwrap().len(), 1);
+        // Later hints aren't lost behind the torn one
+        assert_eq!(store.add(TARGET, hint(2, now_ms())).unwrap(), Some(2));
+        assert_eq!(store.load(TARGET).unwrap().iter().map(|h| h.id).collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(decode_target(&encode_target(TARGET)).as_deref(), Some(TARGET));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
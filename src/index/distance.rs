@@ -1,4 +1,53 @@
-use ndarray::{ArrayView1, Array1};
+use ndarray::ArrayView1;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Distance metric used by an index. Every metric is exposed as a
+/// "smaller is better" distance so search and merge logic can stay uniform.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Metric {
+    #[default]
+    Euclidean,
+    /// 1 - cosine similarity, in [0, 2]
+    Cosine,
+    /// Negated inner product
+    DotProduct,
+}
+
+impl Metric {
+    pub fn distance(&self, a: &ArrayView1<f32>, b: &ArrayView1<f32>) -> f32 {
+        match self {
+            Metric::Euclidean => euclidean_distance(a, b),
+            Metric::Cosine => cosine_distance(a, b),
+            Metric::DotProduct => dot_product_distance(a, b),
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Metric::Euclidean => "euclidean",
+            Metric::Cosine => "cosine",
+            Metric::DotProduct => "dot-product",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "euclidean" | "l2" => Ok(Metric::Euclidean),
+            "cosine" => Ok(Metric::Cosine),
+            "dot-product" | "dot_product" | "dot" | "ip" => Ok(Metric::DotProduct),
+            other => Err(format!("unknown metric '{}' (expected euclidean, cosine or dot-product)", other)),
+        }
+    }
+}
 
 pub fn euclidean_distance(a: &ArrayView1<f32>, b: &ArrayView1<f32>) -> f32 {
     let diff = a - b;
@@ -12,6 +61,19 @@ pub fn cosine_similarity(a: &ArrayView1<f32>, b: &ArrayView1<f32>) -> f32 {
     dot / (norm_a * norm_b)
 }
 
+pub fn cosine_distance(a: &ArrayView1<f32>, b: &ArrayView1<f32>) -> f32 {
+    let similarity = cosine_similarity(a, b);
+    // A zero vector has no direction; treat it as orthogonal to everything
+    if similarity.is_nan() {
+        return 1.0;
+    }
+    1.0 - similarity
+}
+
+pub fn dot_product_distance(a: &ArrayView1<f32>, b: &ArrayView1<f32>) -> f32 {
+    -a.dot(b)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -27,4 +89,34 @@ mod tests {
         let dist = euclidean_distance(&a.view(), &b.view());
         assert!((dist - 5.196152).abs() < 1e-5);
     }
+
+    #[test]
+    fn test_cosine_distance() {
+        let a = arr1(&[1.0, 0.0]);
+        let b = arr1(&[0.0, 1.0]);
+        let c = arr1(&[2.0, 0.0]);
+        let zero = arr1(&[0.0, 0.0]);
+        assert!((cosine_distance(&a.view(), &b.view()) - 1.0).abs() < 1e-6);
+        assert!(cosine_distance(&a.view(), &c.view()).abs() < 1e-6);
+        assert!((cosine_distance(&a.view(), &(-&a).view()) - 2.0).abs() < 1e-6);
+        assert_eq!(cosine_distance(&a.view(), &zero.view()), 1.0);
+    }
+
+    #[test]
+    fn test_dot_product_distance() {
+        let a = arr1(&[1.0, 2.0]);
+        let b = arr1(&[3.0, 4.0]);
+        let c = arr1(&[1.0, 1.0]);
+        // Larger inner product => smaller distance
+        assert_eq!(dot_product_distance(&a.view(), &b.view()), -11.0);
+        assert!(dot_product_distance(&a.view(), &b.view()) < dot_product_distance(&a.view(), &c.view()));
+    }
+
+    #[test]
+    fn test_metric_parse_roundtrip() {
+        for metric in [Metric::Euclidean, Metric::Cosine, Metric::DotProduct] {
+            assert_eq!(metric.to_string().parse::<Metric>().unwrap(), metric);
+        }
+        assert!("manhattan".parse::<Metric>().is_err());
+    }
 }
@@ -1,6 +1,43 @@
 
 
-use std::collections::BinaryHeap;
+use crate::filter::Filter;
+use crate::index::distance::Metric;
+use crate::payload::Payload;
+use crate::storage::{Checkpoint, VectorStorage};
+use crate::wal::{sync_parent, OpType, WalEntry};
+use ndarray::{Array1, ArrayView1};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+// Run `repair` once tombstones reach 1/REPAIR_THRESHOLD_DIVISOR of all nodes
+const REPAIR_THRESHOLD_DIVISOR: usize = 10;
+
+// Snapshots start with these bytes and a format number, bumped whenever the
+// serialized index changes. A build reads its own format and the one before.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"VDBSNAP\0";
+const SNAPSHOT_FORMAT: u32 = 6;
+
+// Filtered searches estimate selectivity from this many nodes...
+const FILTER_SAMPLE_SIZE: usize = 512;
+// ...and scan every node instead of walking the graph when fewer match than this
+const BRUTE_FORCE_SELECTIVITY: f64 = 0.02;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Node {
+    pub id: u32,
+    // Where the vector lives in `Hnsw::storage`
+    pub offset: u32,
+    pub layers: Vec<Vec<u32>>, // Neighbors at each layer
+    pub payload: Payload,
+    // Version of the write that stored this node; see `WalEntry::version`
+    pub version: u64,
+}
 
 #[derive(Debug, Clone, Copy)]
 struct Candidate {
@@ -10,7 +47,7 @@ struct Candidate {
 
 impl PartialEq for Candidate {
     fn eq(&self, other: &Self) -> bool {
-        self.distance == other.distance
+        self.cmp(other) == Ordering::Equal
     }
 }
 
@@ -19,29 +56,84 @@ impl Eq for Candidate {}
 // Max-heap behavior: larger distance is "greater"
 impl PartialOrd for Candidate {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.distance.partial_cmp(&other.distance)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for Candidate {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap_or(Ordering::Equal)
+        self.distance.total_cmp(&other.distance)
     }
 }
 
+/// Which live nodes a search may return: those whose payload matches
+/// `filter` and whose id `ids` accepts, when set.
+#[derive(Clone, Copy, Default)]
+pub struct SearchScope<'a> {
+    pub filter: Option<&'a Filter>,
+    // e.g. only the ids whose keys hash into the ranges a node is asked for
+    pub ids: Option<&'a dyn Fn(u32) -> bool>,
+}
+
+impl SearchScope<'_> {
+    fn is_everything(&self) -> bool {
+        self.filter.is_none() && self.ids.is_none()
+    }
+
+    fn matches(&self, node: &Node) -> bool {
+        self.filter.is_none_or(|f| f.matches(&node.payload)) && self.ids.is_none_or(|ids| ids(node.id))
+    }
+}
+
+/// Why a vector can't be inserted into, or used to query, an index.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum InvalidVector {
+    #[error("vector cannot be empty")]
+    Empty,
+    #[error("expected a {expected}-dimensional vector, got {actual} dimensions")]
+    DimensionMismatch { expected: usize, actual: usize },
+    #[error("vector component {0} is NaN or infinite")]
+    NonFinite(usize),
+    #[error("vector is too large: its squared norm overflows")]
+    Overflow,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Hnsw {
     pub nodes: HashMap<u32, Arc<RwLock<Node>>>,
     pub entry_point: Option<u32>,
     pub max_layers: usize,
     pub ef_construction: usize,
+    // Default beam width for queries; a request may override it
+    pub ef_search: usize,
     pub m: usize,
     pub m_max0: usize,
     pub level_mult: f64,
+    pub metric: Metric,
+    // Fixed by the first insert unless declared up front
+    pub dimension: Option<usize>,
+    // Logically deleted ids: still traversed during search, never returned
+    pub deleted: HashSet<u32>,
+    // Version of the last delete of each id not written since, whether or
+    // not the id was present
+    pub delete_versions: HashMap<u32, u64>,
+    // LSN of the last WAL entry reflected in this index
+    pub applied_lsn: u64,
+    // The nodes that list each id as a neighbor on some layer, so removing
+    // a node only revisits those. Rebuilt from the graph on load.
+    #[serde(skip)]
+    pub(crate) referrers: HashMap<u32, HashSet<u32>>,
+    // Saved alongside the graph as a `Checkpoint`
+    #[serde(skip)]
+    pub storage: VectorStorage,
 }
 
 impl Hnsw {
     pub fn new(m: usize, ef_construction: usize) -> Self {
+        Self::with_metric(m, ef_construction, Metric::Euclidean)
+    }
+
+    pub fn with_metric(m: usize, ef_construction: usize, metric: Metric) -> Self {
         let m_max0 = m * 2;
         let level_mult = 1.0 / (m as f64).ln();
         Hnsw {
@@ -49,26 +141,102 @@ impl Hnsw {
             entry_point: None,
             max_layers: 0,
             ef_construction,
+            // Matches the beam used before ef_search existed, so recall is unchanged by default
+            ef_search: ef_construction,
             m,
             m_max0,
             level_mult,
+            metric,
+            dimension: None,
+            deleted: HashSet::new(),
+            delete_versions: HashMap::new(),
+            applied_lsn: 0,
+            referrers: HashMap::new(),
+            storage: VectorStorage::in_memory(),
         }
     }
 
     pub fn save_snapshot(&self, path: &str) -> io::Result<()> {
-        let file = File::create(path)?;
-        let writer = BufWriter::new(file);
-        bincode::serialize_into(writer, self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        // Write to a temporary file and rename it into place, so a crash
+        // mid-snapshot never leaves a truncated snapshot behind.
+        let tmp_path = format!("{}.tmp", path);
+        let file = File::create(&tmp_path)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_FORMAT.to_le_bytes())?;
+        let releases = self.storage.releases();
+        bincode::serialize_into(&mut writer, self).map_err(io::Error::other)?;
+        bincode::serialize_into(&mut writer, &self.storage.checkpoint()?).map_err(io::Error::other)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        sync_parent(Path::new(path))?;
+        // Slots this snapshot doesn't reference can now be reused
+        self.storage.mark_durable(releases);
         Ok(())
     }
 
+    /// Loads a snapshot of an index with in-memory storage.
     pub fn load_snapshot(path: &str) -> io::Result<Self> {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        let hnsw: Hnsw = bincode::deserialize_from(reader).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let (mut hnsw, checkpoint) = Self::read_snapshot(path)?;
+        hnsw.storage = VectorStorage::restore_in_memory(checkpoint)?;
+        hnsw.reclaim_unreferenced();
+        Ok(hnsw)
+    }
+
+    /// Loads a snapshot whose vectors live in the storage file at `storage_path`.
+    pub fn load_snapshot_with_storage(path: &str, storage_path: &Path) -> io::Result<Self> {
+        let (mut hnsw, checkpoint) = Self::read_snapshot(path)?;
+        hnsw.storage = VectorStorage::restore(storage_path, checkpoint)?;
+        hnsw.reclaim_unreferenced();
         Ok(hnsw)
     }
 
+    // Frees the storage slots of vectors replaced or removed before the
+    // snapshot just loaded
+    fn reclaim_unreferenced(&mut self) {
+        let offsets: Vec<u32> = self.nodes.values().map(|node| node.read().unwrap().offset).collect();
+        self.storage.reclaim_unreferenced(offsets);
+    }
+
+    fn read_snapshot(path: &str) -> io::Result<(Self, Checkpoint)> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let (mut hnsw, checkpoint) = match Self::read_format(&mut reader)? {
+            SNAPSHOT_FORMAT => {
+                let hnsw: Hnsw = bincode::deserialize_from(&mut reader).map_err(io::Error::other)?;
+                let checkpoint: Checkpoint = bincode::deserialize_from(&mut reader).map_err(io::Error::other)?;
+                (hnsw, checkpoint)
+            }
+            format if format + 1 == SNAPSHOT_FORMAT => {
+                let previous: PreviousFormatHnsw = bincode::deserialize_from(&mut reader).map_err(io::Error::other)?;
+                let checkpoint: Checkpoint = bincode::deserialize_from(&mut reader).map_err(io::Error::other)?;
+                (Hnsw::from(previous), checkpoint)
+            }
+            format => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Snapshot {} is in format {}; this build reads formats {} and {}", path, format, SNAPSHOT_FORMAT - 1, SNAPSHOT_FORMAT),
+                ));
+            }
+        };
+        hnsw.link_referrers();
+        Ok((hnsw, checkpoint))
+    }
+
+    // Reads the header, leaving `reader` at the index. Snapshots written
+    // before there was a header are format 0.
+    fn read_format<R: Read + Seek>(reader: &mut R) -> io::Result<u32> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            reader.seek(SeekFrom::Start(0))?;
+            return Ok(0);
+        }
+        let mut format = [0u8; 4];
+        reader.read_exact(&mut format)?;
+        Ok(u32::from_le_bytes(format))
+    }
+
     fn random_level(&self) -> usize {
         let mut rng = rand::thread_rng();
         let r: f64 = rng.gen();
@@ -76,15 +244,62 @@ impl Hnsw {
     }
 
     fn dist(&self, v1: &ArrayView1<f32>, v2: &ArrayView1<f32>) -> f32 {
-        euclidean_distance(v1, v2)
+        self.metric.distance(v1, v2)
+    }
+
+    fn vector(&self, node: &Node) -> ArrayView1<'_, f32> {
+        ArrayView1::from(self.storage.get(node.offset))
+    }
+
+    /// Inserts a vector. An existing id (live or tombstoned) is replaced: the
+    /// old node is unlinked from every layer before the new one goes in.
+    pub fn insert(&mut self, id: u32, vector: Array1<f32>) -> io::Result<()> {
+        self.insert_with_payload(id, vector, Payload::new())
+    }
+
+    /// Checks that a vector can be inserted or searched for. Callers must
+    /// validate before `insert`, which only rejects a vector whose dimension
+    /// differs from those already stored.
+    pub fn validate(&self, vector: &[f32]) -> Result<(), InvalidVector> {
+        if vector.is_empty() {
+            return Err(InvalidVector::Empty);
+        }
+        if let Some(expected) = self.dimension {
+            if vector.len() != expected {
+                return Err(InvalidVector::DimensionMismatch { expected, actual: vector.len() });
+            }
+        }
+        if let Some(i) = vector.iter().position(|x| !x.is_finite()) {
+            return Err(InvalidVector::NonFinite(i));
+        }
+        // Keeps every distance to it finite or infinite, never NaN
+        let vector = ArrayView1::from(vector);
+        if !vector.dot(&vector).is_finite() {
+            return Err(InvalidVector::Overflow);
+        }
+        Ok(())
     }
 
-    pub fn insert(&mut self, id: u32, vector: Array1<f32>) {
+    /// Inserts a vector, replacing any node with the same id. Fails, leaving
+    /// the index as it was, if the vector can't be stored.
+    pub fn insert_with_payload(&mut self, id: u32, vector: Array1<f32>, payload: Payload) -> io::Result<()> {
+        let offset = self.storage.push(&vector.to_vec())?;
+        if self.dimension.is_none() {
+            self.dimension = Some(vector.len());
+        }
+
+        if self.nodes.contains_key(&id) {
+            self.deleted.remove(&id);
+            self.unlink(&HashSet::from([id]));
+        }
+
         let level = self.random_level();
         let new_node = Arc::new(RwLock::new(Node {
             id,
-            vector: vector.clone(),
+            offset,
             layers: vec![vec![]; level + 1],
+            payload,
+            version: 0,
         }));
         
         self.nodes.insert(id, new_node.clone());
@@ -94,12 +309,12 @@ impl Hnsw {
             None => {
                 self.entry_point = Some(id);
                 self.max_layers = level;
-                return;
+                return Ok(());
             }
         };
 
         let mut curr_ep = entry_point;
-        let mut curr_dist = self.dist(&vector.view(), &self.nodes[&curr_ep].read().unwrap().vector.view());
+        let mut curr_dist = self.dist(&vector.view(), &self.vector(&self.nodes[&curr_ep].read().unwrap()));
 
         // Phase 1: Zoom down to the insertion level
         for l in (level + 1..=self.max_layers).rev() {
@@ -112,7 +327,7 @@ impl Hnsw {
                 if let Some(neighbors) = guard.layers.get(l) {
                     for &neighbor_id in neighbors {
                         let neighbor_node = self.nodes.get(&neighbor_id).unwrap();
-                        let d = self.dist(&vector.view(), &neighbor_node.read().unwrap().vector.view());
+                        let d = self.dist(&vector.view(), &self.vector(&neighbor_node.read().unwrap()));
                         if d < curr_dist {
                             curr_dist = d;
                             curr_ep = neighbor_id;
@@ -124,48 +339,25 @@ impl Hnsw {
         }
 
         // Phase 2: Insert at each level from `level` down to 0
+        let mut pruned = Vec::new();
         for l in (0..=std::cmp::min(level, self.max_layers)).rev() {
             // Find ef_construction nearest neighbors at this layer
-            let mut candidates = self.search_layer(&vector.view(), curr_ep, self.ef_construction, l);
+            let mut candidates = self.search_layer(&vector.view(), curr_ep, self.ef_construction, l, &SearchScope::default());
             
             // Select M neighbors
             let neighbors = self.select_neighbors(&mut candidates, self.m);
 
             // Add bidirectional connections
-            {
-                let mut new_guard = new_node.write().unwrap();
-                new_guard.layers[l] = neighbors.clone();
-            }
+            self.set_neighbors(id, l, neighbors.clone());
 
             for &neighbor_id in &neighbors {
-                let neighbor_node = self.nodes.get(&neighbor_id).unwrap();
-                let mut neighbor_guard = neighbor_node.write().unwrap();
-                neighbor_guard.layers[l].push(id);
-                
-                // Prune connections if too many
-                let m_max = if l == 0 { self.m_max0 } else { self.m };
-                if neighbor_guard.layers[l].len() > m_max {
-                    // Re-evaluate neighbors for this node to prune the worst one
-                    // Optimization: Just remove the furthest one? 
-                    // Better: Run select_neighbors on the existing list + new one.
-                    // For MVP, let's just keep the closest ones.
-                    let mut neighbor_candidates = BinaryHeap::new();
-                    let neighbor_vec = neighbor_guard.vector.clone(); // Clone to avoid borrow issues
-                    
-                    for &n_id in &neighbor_guard.layers[l] {
-                        let n_node = self.nodes.get(&n_id).unwrap();
-                        let d = self.dist(&neighbor_vec.view(), &n_node.read().unwrap().vector.view());
-                        neighbor_candidates.push(Candidate { id: n_id, distance: d });
-                    }
-                    
-                    // Keep only m_max
-                    while neighbor_candidates.len() > m_max {
-                        neighbor_candidates.pop(); // Pops the largest distance
-                    }
-                    
-                    neighbor_guard.layers[l] = neighbor_candidates.into_vec().iter().map(|c| c.id).collect();
-                }
+                let mut links = self.nodes[&neighbor_id].read().unwrap().layers[l].clone();
+                links.push(id);
+                let links = self.prune_links(neighbor_id, l, links);
+                pruned.extend(self.set_neighbors(neighbor_id, l, links).into_iter().map(|n| (n, l)));
             }
+            // Every neighbor may have pruned the new node away again
+            pruned.push((id, l));
             
             // Update entry point for next layer (closest from candidates)
             if candidates.peek().is_some() {
@@ -174,9 +366,8 @@ impl Hnsw {
                  // So peek gives the *worst* of the best.
                  // We want the *best* of the best to be the entry point for the next layer.
                  // We need to iterate to find the min.
-                 if let Some(best_cand) = candidates.iter().min_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap()) {
+                 if let Some(best_cand) = candidates.iter().min_by(|a, b| a.distance.total_cmp(&b.distance)) {
                      curr_ep = best_cand.id;
-                     curr_dist = best_cand.distance;
                  }
             }
         }
@@ -185,15 +376,382 @@ impl Hnsw {
             self.max_layers = level;
             self.entry_point = Some(id);
         }
+        self.relink_orphans(pruned);
+        Ok(())
+    }
+
+    /// Applies a WAL entry to the index and records its LSN. Fails, without
+    /// applying it, if an insert's vector can't be stored.
+    pub fn apply(&mut self, entry: &WalEntry) -> io::Result<()> {
+        match entry.op {
+            OpType::Insert => match self.validate(&entry.vector) {
+                Ok(()) => {
+                    self.insert_with_payload(
+                        entry.vector_id,
+                        Array1::from(entry.vector.clone()),
+                        entry.payload.clone(),
+                    )?;
+                    self.nodes[&entry.vector_id].write().unwrap().version = entry.version;
+                    self.delete_versions.remove(&entry.vector_id);
+                }
+                // Only reachable for entries logged before validation existed
+                Err(e) => println!("Skipping WAL entry {} for id {}: {}", entry.lsn, entry.vector_id, e),
+            },
+            OpType::Delete => {
+                self.delete(entry.vector_id);
+                self.delete_versions.insert(entry.vector_id, entry.version);
+            }
+            OpType::Evict => {
+                self.delete(entry.vector_id);
+                self.delete_versions.remove(&entry.vector_id);
+            }
+        }
+        self.applied_lsn = entry.lsn;
+        Ok(())
+    }
+
+    /// Number of live (non-deleted) vectors.
+    pub fn len(&self) -> usize {
+        self.nodes.len() - self.deleted.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Ids of every live node, in no particular order.
+    pub fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.nodes.keys().copied().filter(|id| !self.deleted.contains(id))
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.nodes.contains_key(&id) && !self.deleted.contains(&id)
+    }
+
+    /// Returns the vector and payload of a live node.
+    pub fn get(&self, id: u32) -> Option<(Vec<f32>, Payload)> {
+        if self.deleted.contains(&id) {
+            return None;
+        }
+        self.nodes.get(&id).map(|node| {
+            let guard = node.read().unwrap();
+            (self.storage.get(guard.offset).to_vec(), guard.payload.clone())
+        })
+    }
+
+    /// Version of a live node.
+    pub fn version(&self, id: u32) -> Option<u64> {
+        if self.deleted.contains(&id) {
+            return None;
+        }
+        self.nodes.get(&id).map(|node| node.read().unwrap().version)
+    }
+
+    /// Version of the delete that removed an id, unless it was written since.
+    pub fn delete_version(&self, id: u32) -> Option<u64> {
+        self.delete_versions.get(&id).copied()
+    }
+
+    pub fn payload(&self, id: u32) -> Option<Payload> {
+        if self.deleted.contains(&id) {
+            return None;
+        }
+        self.nodes.get(&id).map(|node| node.read().unwrap().payload.clone())
+    }
+
+    /// Tombstones a node so search no longer returns it. The node keeps
+    /// routing traffic until `repair` drops it from the graph, which happens
+    /// automatically once tombstones make up a large enough share of the index.
+    /// Returns false if the id was not present.
+    pub fn delete(&mut self, id: u32) -> bool {
+        if !self.contains(id) {
+            return false;
+        }
+        self.deleted.insert(id);
+
+        if self.entry_point == Some(id) {
+            self.reassign_entry_point();
+        }
+
+        if self.deleted.len() * REPAIR_THRESHOLD_DIVISOR >= self.nodes.len() {
+            self.repair();
+        }
+        true
+    }
+
+    /// Physically removes every tombstoned node. Live nodes that pointed at a
+    /// removed node are reconnected to the removed node's live neighbors,
+    /// keeping the closest `m` (or `m_max0` on layer 0).
+    /// Returns the number of nodes removed.
+    pub fn repair(&mut self) -> usize {
+        if self.deleted.is_empty() {
+            return 0;
+        }
+        let ids = std::mem::take(&mut self.deleted);
+        self.unlink(&ids)
+    }
+
+    // Drops `ids` from the graph, reconnecting the nodes that pointed at them.
+    // `ids` must not be in `self.deleted`.
+    fn unlink(&mut self, ids: &HashSet<u32>) -> usize {
+        // Neighbor lists of the removed nodes, per layer
+        let removed: HashMap<u32, Vec<Vec<u32>>> = ids.iter()
+            .filter_map(|id| self.nodes.get(id).map(|n| (*id, n.read().unwrap().layers.clone())))
+            .collect();
+
+        // Only the nodes pointing at a removed one need new neighbors
+        let referrers: HashSet<u32> = removed.keys()
+            .filter_map(|id| self.referrers.get(id))
+            .flatten()
+            .copied()
+            .filter(|id| !removed.contains_key(id))
+            .collect();
+
+        // Compute replacement lists first, then write them, so no node lock is
+        // held while reading other nodes.
+        let mut updates = Vec::new();
+        for id in referrers {
+            let guard = self.nodes[&id].read().unwrap();
+            for (l, neighbors) in guard.layers.iter().enumerate() {
+                if !neighbors.iter().any(|n| removed.contains_key(n)) {
+                    continue;
+                }
+
+                let mut pool = HashSet::new();
+                for &n in neighbors {
+                    match removed.get(&n) {
+                        Some(layers) => {
+                            if let Some(orphaned) = layers.get(l) {
+                                pool.extend(orphaned.iter().copied());
+                            }
+                        }
+                        None => {
+                            pool.insert(n);
+                        }
+                    }
+                }
+                pool.remove(&id);
+
+                let mut candidates = BinaryHeap::new();
+                for n in pool {
+                    if removed.contains_key(&n) {
+                        continue;
+                    }
+                    let n_node = self.nodes[&n].read().unwrap();
+                    let d = self.dist(&self.vector(&guard), &self.vector(&n_node));
+                    candidates.push(Candidate { id: n, distance: d });
+                }
+                let m_max = if l == 0 { self.m_max0 } else { self.m };
+                updates.push((id, l, self.select_neighbors(&mut candidates, m_max)));
+            }
+        }
+
+        let mut dropped = Vec::new();
+        for (id, l, neighbors) in updates {
+            dropped.extend(self.set_neighbors(id, l, neighbors).into_iter().map(|n| (n, l)));
+        }
+
+        for (id, layers) in &removed {
+            for n in layers.iter().flatten() {
+                if let Some(referrers) = self.referrers.get_mut(n) {
+                    referrers.remove(id);
+                }
+            }
+            self.referrers.remove(id);
+            if let Some(node) = self.nodes.remove(id) {
+                self.storage.release(node.read().unwrap().offset);
+            }
+        }
+
+        if self.entry_point.is_some_and(|ep| !self.nodes.contains_key(&ep)) {
+            self.reassign_entry_point();
+        }
+        // Nodes only the removed ones linked to are orphaned too
+        let orphaned = removed.values().flat_map(|layers| {
+            layers.iter().enumerate().flat_map(|(l, layer)| layer.iter().map(move |&n| (n, l)))
+        });
+        dropped.extend(orphaned);
+        self.relink_orphans(dropped);
+        removed.len()
+    }
+
+    // Replaces the neighbors of `id` on layer `l`, keeping `referrers` in
+    // step. Returns the ids that were dropped from the layer.
+    fn set_neighbors(&mut self, id: u32, l: usize, neighbors: Vec<u32>) -> Vec<u32> {
+        let node = self.nodes[&id].clone();
+        let mut guard = node.write().unwrap();
+        let old = std::mem::replace(&mut guard.layers[l], neighbors);
+        let dropped: Vec<u32> = old.into_iter().filter(|n| !guard.layers[l].contains(n)).collect();
+        for n in &dropped {
+            if !guard.layers.iter().any(|layer| layer.contains(n)) {
+                if let Some(referrers) = self.referrers.get_mut(n) {
+                    referrers.remove(&id);
+                }
+            }
+        }
+        for &n in &guard.layers[l] {
+            self.referrers.entry(n).or_default().insert(id);
+        }
+        dropped
+    }
+
+    // Prunes `links`, the neighbors of `id` on layer `l`, to the layer's
+    // limit by keeping the closest ones
+    fn prune_links(&self, id: u32, l: usize, links: Vec<u32>) -> Vec<u32> {
+        let m_max = if l == 0 { self.m_max0 } else { self.m };
+        if links.len() <= m_max {
+            return links;
+        }
+        let vector = self.vector(&self.nodes[&id].read().unwrap());
+        let mut candidates = BinaryHeap::new();
+        for n in links {
+            let d = self.dist(&vector, &self.vector(&self.nodes[&n].read().unwrap()));
+            candidates.push(Candidate { id: n, distance: d });
+            // Keep only m_max, popping the furthest
+            if candidates.len() > m_max {
+                candidates.pop();
+            }
+        }
+        candidates.into_vec().iter().map(|c| c.id).collect()
+    }
+
+    // Pruning and unlinking can drop the last link to a node on a layer,
+    // leaving search no way to reach it there. Each such node of
+    // `candidates` (id, layer) gets linked again from the nearest of its
+    // neighbors whose pruned links keep it. If every one would prune it
+    // away, the nearest that can trades a link for it, and failing that the
+    // nearest keeps it over its limit: an unreachable node costs more than
+    // one long neighbor list.
+    fn relink_orphans(&mut self, candidates: Vec<(u32, usize)>) {
+        for (id, l) in candidates {
+            if self.entry_point == Some(id) || !self.nodes.contains_key(&id) {
+                continue;
+            }
+            let linked = self.referrers.get(&id).is_some_and(|referrers| {
+                referrers.iter().any(|r| self.nodes[r].read().unwrap().layers.get(l).is_some_and(|layer| layer.contains(&id)))
+            });
+            if linked {
+                continue;
+            }
+            let mut neighbors: Vec<Candidate> = {
+                let guard = self.nodes[&id].read().unwrap();
+                let vector = self.vector(&guard);
+                guard.layers[l]
+                    .iter()
+                    .map(|&n| Candidate { id: n, distance: self.dist(&vector, &self.vector(&self.nodes[&n].read().unwrap())) })
+                    .collect()
+            };
+            if neighbors.is_empty() {
+                // Unlinking can take every neighbor it had on the layer too;
+                // find it new ones from the entry point, as insert does
+                let Some(ep) = self.entry_point.filter(|ep| self.nodes[ep].read().unwrap().layers.len() > l) else {
+                    continue;
+                };
+                let vector = self.vector(&self.nodes[&id].read().unwrap()).to_owned();
+                let mut found = self.search_layer(&vector.view(), ep, self.ef_construction, l, &SearchScope::default());
+                let links = self.select_neighbors(&mut found, self.m);
+                self.set_neighbors(id, l, links);
+                neighbors = found.into_vec();
+            }
+            neighbors.sort();
+            let Some(nearest) = neighbors.first().map(|c| c.id) else {
+                continue;
+            };
+            // Pruning may not orphan another node in turn, or relinking
+            // could go on forever between nodes at equal distances
+            let relinked = neighbors.iter().find_map(|c| {
+                let old = self.nodes[&c.id].read().unwrap().layers[l].clone();
+                let mut links = old.clone();
+                links.push(id);
+                let links = self.prune_links(c.id, l, links);
+                let keeps_all = old.iter().all(|n| links.contains(n) || self.linked_elsewhere(*n, c.id, l));
+                (links.contains(&id) && keeps_all).then_some((c.id, links))
+            });
+            // Otherwise a neighbor gives up its furthest link that another
+            // node also holds
+            let relinked = relinked.or_else(|| {
+                neighbors.iter().find_map(|c| {
+                    let mut links = self.nodes[&c.id].read().unwrap().layers[l].clone();
+                    let vector = self.vector(&self.nodes[&c.id].read().unwrap()).to_owned();
+                    links.sort_by_cached_key(|n| Candidate { id: *n, distance: self.dist(&vector.view(), &self.vector(&self.nodes[n].read().unwrap())) });
+                    let spare = links.iter().rposition(|n| self.linked_elsewhere(*n, c.id, l))?;
+                    links[spare] = id;
+                    Some((c.id, links))
+                })
+            });
+            let (neighbor, links) = relinked.unwrap_or_else(|| {
+                let mut links = self.nodes[&nearest].read().unwrap().layers[l].clone();
+                links.push(id);
+                (nearest, links)
+            });
+            self.set_neighbors(neighbor, l, links);
+        }
+    }
+
+    // Whether a node other than `from` links to `id` on layer `l`
+    fn linked_elsewhere(&self, id: u32, from: u32, l: usize) -> bool {
+        self.referrers.get(&id).is_some_and(|referrers| {
+            referrers.iter().any(|&r| r != from && self.nodes[&r].read().unwrap().layers.get(l).is_some_and(|layer| layer.contains(&id)))
+        })
+    }
+
+    // Rebuilds `referrers` from the neighbor lists
+    fn link_referrers(&mut self) {
+        let mut referrers: HashMap<u32, HashSet<u32>> = HashMap::new();
+        for (&id, node) in &self.nodes {
+            for &n in node.read().unwrap().layers.iter().flatten() {
+                referrers.entry(n).or_default().insert(id);
+            }
+        }
+        self.referrers = referrers;
+    }
+
+    // Promote the live node with the highest level
+    fn reassign_entry_point(&mut self) {
+        let top = self.nodes.values()
+            .filter_map(|n| {
+                let guard = n.read().unwrap();
+                if self.deleted.contains(&guard.id) {
+                    None
+                } else {
+                    Some((guard.layers.len() - 1, guard.id))
+                }
+            })
+            .max();
+        self.entry_point = top.map(|(_, id)| id);
+        self.max_layers = top.map(|(level, _)| level).unwrap_or(0);
     }
 
     pub fn search(&self, query: &ArrayView1<f32>, k: usize) -> Vec<(u32, f32)> {
+        self.search_with_ef(query, k, self.ef_search)
+    }
+
+    /// Like `search`, with an explicit beam width. `ef` below `k` is raised to `k`.
+    pub fn search_with_ef(&self, query: &ArrayView1<f32>, k: usize, ef: usize) -> Vec<(u32, f32)> {
+        self.search_filtered(query, k, ef, None)
+    }
+
+    /// Searches only among nodes whose payload matches `filter`.
+    pub fn search_filtered(&self, query: &ArrayView1<f32>, k: usize, ef: usize, filter: Option<&Filter>) -> Vec<(u32, f32)> {
+        self.search_scoped(query, k, ef, SearchScope { filter, ids: None })
+    }
+
+    /// Searches only among nodes in `scope`. The scope is applied while
+    /// walking the graph, so nodes outside it still route the search but
+    /// never take a slot in the beam. Very narrow scopes fall back to an exact
+    /// scan, and a walk that comes up short of `k` results is topped up by
+    /// scanning the nodes it never reached.
+    pub fn search_scoped(&self, query: &ArrayView1<f32>, k: usize, ef: usize, scope: SearchScope) -> Vec<(u32, f32)> {
         if self.entry_point.is_none() {
             return vec![];
         }
 
+        if !scope.is_everything() && self.estimate_selectivity(&scope) < BRUTE_FORCE_SELECTIVITY {
+            return self.brute_force_search(query, k, &scope, &HashSet::new());
+        }
+
         let mut curr_ep = self.entry_point.unwrap();
-        let mut curr_dist = self.dist(query, &self.nodes[&curr_ep].read().unwrap().vector.view());
+        let mut curr_dist = self.dist(query, &self.vector(&self.nodes[&curr_ep].read().unwrap()));
 
         // 1. Zoom down to layer 1 (greedy search)
         for l in (1..=self.max_layers).rev() {
@@ -206,7 +764,7 @@ impl Hnsw {
                 if let Some(neighbors) = guard.layers.get(l) {
                     for &neighbor_id in neighbors {
                         let neighbor_node = self.nodes.get(&neighbor_id).unwrap();
-                        let d = self.dist(query, &neighbor_node.read().unwrap().vector.view());
+                        let d = self.dist(query, &self.vector(&neighbor_node.read().unwrap()));
                         if d < curr_dist {
                             curr_dist = d;
                             curr_ep = neighbor_id;
@@ -218,7 +776,7 @@ impl Hnsw {
         }
 
         // 2. Search layer 0 (Beam search / search_layer)
-        let mut candidates = self.search_layer(query, curr_ep, self.ef_construction.max(k), 0);
+        let (mut candidates, visited) = self.search_layer_visited(query, curr_ep, ef.max(k).max(1), 0, &scope);
         
         // Return top K
         let mut results = Vec::new();
@@ -229,20 +787,97 @@ impl Hnsw {
         // We want smallest distance first.
         results.reverse();
         results.truncate(k);
+
+        // A beam short of `k` never filled, so it holds every match the walk
+        // visited; only the nodes it never reached are left to scan
+        if !scope.is_everything() && results.len() < k && visited.len() < self.nodes.len() {
+            results.extend(self.brute_force_search(query, k, &scope, &visited));
+            results.sort_by(|a, b| a.1.total_cmp(&b.1));
+            results.truncate(k);
+        }
         results
     }
 
-    fn search_layer(&self, query: &ArrayView1<f32>, entry_point: u32, ef: usize, layer: usize) -> BinaryHeap<Candidate> {
+    // Fraction of live nodes in `scope`, from a sample of the index
+    fn estimate_selectivity(&self, scope: &SearchScope) -> f64 {
+        let mut sampled = 0;
+        let mut matched = 0;
+        // Spread the sample over the whole index rather than taking its first
+        // nodes, which may all share a payload
+        let stride = (self.nodes.len() / FILTER_SAMPLE_SIZE).max(1);
+        for node in self.nodes.values().step_by(stride) {
+            let guard = node.read().unwrap();
+            if self.deleted.contains(&guard.id) {
+                continue;
+            }
+            sampled += 1;
+            if scope.matches(&guard) {
+                matched += 1;
+            }
+            if sampled == FILTER_SAMPLE_SIZE {
+                break;
+            }
+        }
+        if sampled == 0 {
+            return 1.0;
+        }
+        matched as f64 / sampled as f64
+    }
+
+    // Exact top-k over every live node in `scope`, but those in `skip`
+    fn brute_force_search(&self, query: &ArrayView1<f32>, k: usize, scope: &SearchScope, skip: &HashSet<u32>) -> Vec<(u32, f32)> {
+        let mut nearest = BinaryHeap::new(); // Max-heap, furthest first
+        for (id, node) in &self.nodes {
+            if skip.contains(id) {
+                continue;
+            }
+            let guard = node.read().unwrap();
+            if self.deleted.contains(&guard.id) || !scope.matches(&guard) {
+                continue;
+            }
+            let distance = self.dist(query, &self.vector(&guard));
+            if nearest.len() < k {
+                nearest.push(Candidate { id: guard.id, distance });
+            } else if nearest.peek().is_some_and(|furthest: &Candidate| distance < furthest.distance) {
+                nearest.pop();
+                nearest.push(Candidate { id: guard.id, distance });
+            }
+        }
+        nearest.into_sorted_vec().into_iter().map(|c| (c.id, c.distance)).collect()
+    }
+
+    // Tombstoned nodes, and nodes outside `scope`, are explored but never enter the result set
+    fn accepts(&self, node: &Node, scope: &SearchScope) -> bool {
+        !self.deleted.contains(&node.id) && scope.matches(node)
+    }
+
+    fn search_layer(&self, query: &ArrayView1<f32>, entry_point: u32, ef: usize, layer: usize, scope: &SearchScope) -> BinaryHeap<Candidate> {
+        self.search_layer_visited(query, entry_point, ef, layer, scope).0
+    }
+
+    // Like `search_layer`, also returning every node the walk visited
+    fn search_layer_visited(
+        &self,
+        query: &ArrayView1<f32>,
+        entry_point: u32,
+        ef: usize,
+        layer: usize,
+        scope: &SearchScope,
+    ) -> (BinaryHeap<Candidate>, HashSet<u32>) {
         let mut visited = HashSet::new();
         let mut candidates = BinaryHeap::new(); // Min-heap of candidates to explore (closest first)
         let mut nearest_neighbors = BinaryHeap::new(); // Max-heap of found neighbors (furthest first)
 
-        let entry_dist = self.dist(query, &self.nodes[&entry_point].read().unwrap().vector.view());
+        let entry_guard = self.nodes[&entry_point].read().unwrap();
+        let entry_dist = self.dist(query, &self.vector(&entry_guard));
         let entry_cand = Candidate { id: entry_point, distance: entry_dist };
 
         visited.insert(entry_point);
         candidates.push(Reverse(entry_cand));
-        nearest_neighbors.push(entry_cand);
+        if self.accepts(&entry_guard, scope) {
+            nearest_neighbors.push(entry_cand);
+        }
+        drop(entry_guard);
 
         while let Some(Reverse(curr)) = candidates.pop() {
             if let Some(furthest_found) = nearest_neighbors.peek() {
@@ -261,12 +896,15 @@ impl Hnsw {
                     }
                     visited.insert(neighbor_id);
 
-                    let neighbor_node = self.nodes.get(&neighbor_id).unwrap();
-                    let dist = self.dist(query, &neighbor_node.read().unwrap().vector.view());
+                    let neighbor_guard = self.nodes.get(&neighbor_id).unwrap().read().unwrap();
+                    let dist = self.dist(query, &self.vector(&neighbor_guard));
                     let neighbor_cand = Candidate { id: neighbor_id, distance: dist };
 
                     if nearest_neighbors.len() < ef || dist < nearest_neighbors.peek().unwrap().distance {
                         candidates.push(Reverse(neighbor_cand));
+                        if !self.accepts(&neighbor_guard, scope) {
+                            continue;
+                        }
                         nearest_neighbors.push(neighbor_cand);
                         
                         if nearest_neighbors.len() > ef {
@@ -276,7 +914,7 @@ impl Hnsw {
                 }
             }
         }
-        nearest_neighbors
+        (nearest_neighbors, visited)
     }
 
     fn select_neighbors(&self, candidates: &mut BinaryHeap<Candidate>, m: usize) -> Vec<u32> {
@@ -296,3 +934,59 @@ impl Hnsw {
         result
     }
 }
+
+// The layout of format 5 snapshots, when nodes had no version and deletes
+// left none behind
+#[derive(Deserialize)]
+struct PreviousFormatHnsw {
+    nodes: HashMap<u32, PreviousFormatNode>,
+    entry_point: Option<u32>,
+    max_layers: usize,
+    ef_construction: usize,
+    ef_search: usize,
+    m: usize,
+    m_max0: usize,
+    level_mult: f64,
+    metric: Metric,
+    dimension: Option<usize>,
+    deleted: HashSet<u32>,
+    applied_lsn: u64,
+}
+
+#[derive(Deserialize)]
+struct PreviousFormatNode {
+    id: u32,
+    offset: u32,
+    layers: Vec<Vec<u32>>,
+    payload: Payload,
+}
+
+// Nodes take version 0, so any versioned write replaces them
+impl From<PreviousFormatHnsw> for Hnsw {
+    fn from(old: PreviousFormatHnsw) -> Self {
+        let nodes = old.nodes
+            .into_iter()
+            .map(|(id, node)| {
+                let node = Node { id: node.id, offset: node.offset, layers: node.layers, payload: node.payload, version: 0 };
+                (id, Arc::new(RwLock::new(node)))
+            })
+            .collect();
+        Hnsw {
+            nodes,
+            entry_point: old.entry_point,
+            max_layers: old.max_layers,
+            ef_construction: old.ef_construction,
+            ef_search: old.ef_search,
+            m: old.m,
+            m_max0: old.m_max0,
+            level_mult: old.level_mult,
+            metric: old.metric,
+            dimension: old.dimension,
+            deleted: old.deleted,
+            delete_versions: HashMap::new(),
+            applied_lsn: old.applied_lsn,
+            referrers: HashMap::new(),
+            storage: VectorStorage::in_memory(),
+        }
+    }
+}
@@ -1,81 +1,668 @@
-#[cfg(test)]
-mod tests {
-    use crate::index::hnsw::Hnsw;
-    use ndarray::Array1;
-    use rand::Rng;
-
-    #[test]
-    fn test_hnsw_basic_insert_search() {
-        let mut hnsw = Hnsw::new(16, 100);
-        
-        // Insert a vector
-        let v1 = Array1::from(vec![1.0, 2.0, 3.0]);
-        hnsw.insert(1, v1.clone());
-        
-        // Search for it
-        let results = hnsw.search(&v1.view(), 1);
-        assert_eq!(results.len(), 1);
-        assert_eq!(results[0].0, 1);
-        // Distance should be 0 (or very close due to float precision)
-        assert!(results[0].1 < 1e-6);
+use crate::index::hnsw::{Hnsw, SearchScope};
+use ndarray::Array1;
+use rand::Rng;
+
+#[test]
+fn test_hnsw_basic_insert_search() {
+    let mut hnsw = Hnsw::new(16, 100);
+    
+    // Insert a vector
+    let v1 = Array1::from(vec![1.0, 2.0, 3.0]);
+    hnsw.insert(1, v1.clone()).unwrap();
+    
+    // Search for it
+    let results = hnsw.search(&v1.view(), 1);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].0, 1);
+    // Distance should be 0 (or very close due to float precision)
+    assert!(results[0].1 < 1e-6);
+}
+
+#[test]
+fn test_hnsw_multiple_inserts() {
+    let mut hnsw = Hnsw::new(16, 100);
+    let mut rng = rand::thread_rng();
+    
+    let mut vectors = Vec::new();
+    for i in 0..100 {
+        let v: Array1<f32> = Array1::from((0..10).map(|_| rng.gen()).collect::<Vec<f32>>());
+        hnsw.insert(i, v.clone()).unwrap();
+        vectors.push(v);
     }
+    
+    // Search for the 50th vector
+    let query = &vectors[50];
+    let results = hnsw.search(&query.view(), 1);
+    assert_eq!(results[0].0, 50);
+    assert!(results[0].1 < 1e-6);
+}
 
-    #[test]
-    fn test_hnsw_multiple_inserts() {
-        let mut hnsw = Hnsw::new(16, 100);
-        let mut rng = rand::thread_rng();
-        
-        let mut vectors = Vec::new();
-        for i in 0..100 {
-            let v: Array1<f32> = Array1::from((0..10).map(|_| rng.gen()).collect::<Vec<f32>>());
-            hnsw.insert(i, v.clone());
-            vectors.push(v);
+#[test]
+fn test_hnsw_recall() {
+    // This test verifies that we can find the nearest neighbor in a small dataset
+    let mut hnsw = Hnsw::new(16, 100);
+    let mut rng = rand::thread_rng();
+    
+    let mut vectors = Vec::new();
+    for i in 0..200 {
+        let v: Array1<f32> = Array1::from((0..10).map(|_| rng.gen()).collect::<Vec<f32>>());
+        hnsw.insert(i, v.clone()).unwrap();
+        vectors.push(v);
+    }
+    
+    // Generate a random query
+    let query: Array1<f32> = Array1::from((0..10).map(|_| rng.gen()).collect::<Vec<f32>>());
+    
+    // Brute force search
+    let mut best_id = 0;
+    let mut best_dist = f32::MAX;
+    
+    for (i, v) in vectors.iter().enumerate() {
+        let dist = crate::index::distance::euclidean_distance(&query.view(), &v.view());
+        if dist < best_dist {
+            best_dist = dist;
+            best_id = i as u32;
         }
-        
-        // Search for the 50th vector
-        let query = &vectors[50];
-        let results = hnsw.search(&query.view(), 1);
-        assert_eq!(results[0].0, 50);
-        assert!(results[0].1 < 1e-6);
     }
+    
+    // HNSW search
+    let results = hnsw.search(&query.view(), 10); // Search for top 10 to increase chance
+    
+    // Check if the best_id is in the results
+    let found = results.iter().any(|(id, _)| *id == best_id);
+    
+    // Note: HNSW is approximate, so it might not ALWAYS find the exact nearest, 
+    // but with these parameters and small size, it should be very likely.
+    // If this flakes, we might need to relax it or increase ef_construction.
+    assert!(found, "HNSW failed to find the nearest neighbor. Best: {}, Found: {:?}", best_id, results);
+}
+
+#[test]
+fn test_hnsw_apply_tracks_lsn() {
+    use crate::wal::{OpType, WalEntry};
+
+    let mut hnsw = Hnsw::new(16, 100);
+    for i in 0..10u32 {
+        hnsw.apply(&WalEntry { lsn: i as u64 + 1, op: OpType::Insert, vector_id: i, version: 0, vector: vec![i as f32, 0.0], payload: Default::default() }).unwrap();
+    }
+    hnsw.apply(&WalEntry { lsn: 11, op: OpType::Delete, vector_id: 3, version: 0, vector: vec![], payload: Default::default() }).unwrap();
+
+    assert_eq!(hnsw.applied_lsn, 11);
+    assert_eq!(hnsw.len(), 9);
+    let results = hnsw.search(&Array1::from(vec![3.0, 0.0]).view(), 1);
+    assert_ne!(results[0].0, 3);
+}
+
+#[test]
+fn test_hnsw_delete_entry_point() {
+    let mut hnsw = Hnsw::new(16, 100);
+    for i in 0..50u32 {
+        hnsw.insert(i, Array1::from(vec![i as f32, 1.0])).unwrap();
+    }
+    let ep = hnsw.entry_point.unwrap();
+    assert!(hnsw.delete(ep));
+    assert!(!hnsw.delete(ep));
+    assert!(hnsw.entry_point.is_some());
+    assert_ne!(hnsw.entry_point, Some(ep));
+
+    let results = hnsw.search(&Array1::from(vec![10.0, 1.0]).view(), 5);
+    assert!(results.iter().all(|(id, _)| *id != ep));
+}
+
+#[test]
+fn test_hnsw_metric_changes_ranking() {
+    use crate::index::distance::Metric;
+
+    // Long vector pointing the same way as the query vs short vector close to it
+    let far_aligned = Array1::from(vec![10.0, 0.0]);
+    let near_skewed = Array1::from(vec![0.7, 0.7]);
+    let query = Array1::from(vec![1.0, 0.0]);
+
+    let expected = [(Metric::Euclidean, 2), (Metric::Cosine, 1), (Metric::DotProduct, 1)];
+    for (metric, best) in expected {
+        let mut hnsw = Hnsw::with_metric(16, 100, metric);
+        hnsw.insert(1, far_aligned.clone()).unwrap();
+        hnsw.insert(2, near_skewed.clone()).unwrap();
+        let results = hnsw.search(&query.view(), 2);
+        assert_eq!(results[0].0, best, "wrong nearest neighbour for {}", metric);
+        assert!(results[0].1 <= results[1].1, "{} results not sorted ascending", metric);
+    }
+}
+
+#[test]
+fn test_hnsw_snapshot_keeps_metric() {
+    use crate::index::distance::Metric;
+
+    let path = std::env::temp_dir().join(format!("hnsw_metric_{}.snap", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    let mut hnsw = Hnsw::with_metric(16, 100, Metric::Cosine);
+    hnsw.insert(1, Array1::from(vec![1.0, 0.0])).unwrap();
+    hnsw.save_snapshot(path).unwrap();
+
+    let loaded = Hnsw::load_snapshot(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(loaded.metric, Metric::Cosine);
+    assert_eq!(loaded.nodes.len(), 1);
+}
+
+#[test]
+fn test_hnsw_delete_tombstones_then_repairs() {
+    let mut hnsw = Hnsw::new(16, 100);
+    let mut rng = rand::thread_rng();
+
+    let mut vectors = Vec::new();
+    for i in 0..300 {
+        let v: Array1<f32> = Array1::from((0..10).map(|_| rng.gen()).collect::<Vec<f32>>());
+        hnsw.insert(i, v.clone()).unwrap();
+        vectors.push(v);
+    }
+
+    // A single delete stays a tombstone: still in the graph, never returned
+    assert!(hnsw.delete(7));
+    assert!(hnsw.nodes.contains_key(&7));
+    let results = hnsw.search(&vectors[7].view(), 10);
+    assert!(results.iter().all(|(id, _)| *id != 7));
 
-    #[test]
-    fn test_hnsw_recall() {
-        // This test verifies that we can find the nearest neighbor in a small dataset
-        let mut hnsw = Hnsw::new(16, 100);
-        let mut rng = rand::thread_rng();
-        
-        let mut vectors = Vec::new();
-        for i in 0..200 {
+    // Deleting half the index crosses the repair threshold repeatedly
+    for i in (0..300).step_by(2) {
+        hnsw.delete(i);
+    }
+    hnsw.repair();
+    assert_eq!(hnsw.nodes.len(), 149);
+    assert_eq!(hnsw.len(), 149);
+    assert!(hnsw.deleted.is_empty());
+    assert!(hnsw.nodes.contains_key(&hnsw.entry_point.unwrap()));
+
+    // No edge may point at a removed node
+    for node in hnsw.nodes.values() {
+        for layer in &node.read().unwrap().layers {
+            assert!(layer.iter().all(|n| hnsw.nodes.contains_key(n)));
+        }
+    }
+
+    // Every survivor is still reachable
+    let mut found = 0;
+    for i in (1..300).step_by(2).filter(|&i| i != 7) {
+        let results = hnsw.search(&vectors[i as usize].view(), 1);
+        if results.first().map(|r| r.0) == Some(i) {
+            found += 1;
+        }
+    }
+    assert!(found >= 145, "only {} of 149 survivors found after repair", found);
+}
+
+#[test]
+fn test_hnsw_loads_previous_snapshot_format() {
+    use crate::index::distance::Metric;
+    use crate::payload::Payload;
+    use crate::storage::Checkpoint;
+    use serde::Serialize;
+    use std::collections::{HashMap, HashSet};
+
+    // Format 5 nodes had no version
+    #[derive(Serialize)]
+    struct OldNode {
+        id: u32,
+        offset: u32,
+        layers: Vec<Vec<u32>>,
+        payload: Payload,
+    }
+    #[derive(Serialize)]
+    struct OldHnsw {
+        nodes: HashMap<u32, OldNode>,
+        entry_point: Option<u32>,
+        max_layers: usize,
+        ef_construction: usize,
+        ef_search: usize,
+        m: usize,
+        m_max0: usize,
+        level_mult: f64,
+        metric: Metric,
+        dimension: Option<usize>,
+        deleted: HashSet<u32>,
+        applied_lsn: u64,
+    }
+
+    let node = |id: u32, neighbor: u32| OldNode { id, offset: id - 1, layers: vec![vec![neighbor]], payload: Payload::new() };
+    let old = OldHnsw {
+        nodes: HashMap::from([(1, node(1, 2)), (2, node(2, 1))]),
+        entry_point: Some(1),
+        max_layers: 0,
+        ef_construction: 100,
+        ef_search: 100,
+        m: 16,
+        m_max0: 32,
+        level_mult: 1.0 / 16f64.ln(),
+        metric: Metric::Cosine,
+        dimension: Some(2),
+        deleted: HashSet::new(),
+        applied_lsn: 7,
+    };
+    let checkpoint = Checkpoint { dimension: 2, flushed: 0, buffered: vec![1.0, 0.0, 0.0, 1.0] };
+    let mut bytes = b"VDBSNAP\0".to_vec();
+    bytes.extend(5u32.to_le_bytes());
+    bytes.extend(bincode::serialize(&old).unwrap());
+    bytes.extend(bincode::serialize(&checkpoint).unwrap());
+    let path = std::env::temp_dir().join(format!("hnsw_previous_format_{}.snap", std::process::id()));
+    let path = path.to_str().unwrap();
+    std::fs::write(path, bytes).unwrap();
+
+    let loaded = Hnsw::load_snapshot(path).unwrap();
+    assert_eq!((loaded.applied_lsn, loaded.metric, loaded.len()), (7, Metric::Cosine, 2));
+    assert_eq!(loaded.get(2).unwrap().0, vec![0.0, 1.0]);
+    assert_eq!(loaded.version(2), Some(0), "any versioned write replaces an unversioned node");
+    assert_eq!(loaded.search(&Array1::from(vec![0.1, 1.0]).view(), 1)[0].0, 2);
+
+    // Saved again, it's in the current format
+    loaded.save_snapshot(path).unwrap();
+    assert!(std::fs::read(path).unwrap().starts_with(b"VDBSNAP"));
+    assert_eq!(Hnsw::load_snapshot(path).unwrap().len(), 2);
+    std::fs::remove_file(path).unwrap();
+}
+
+fn brute_force_top_k(vectors: &[Array1<f32>], query: &Array1<f32>, k: usize) -> Vec<u32> {
+    let mut dists: Vec<(u32, f32)> = vectors
+        .iter()
+        .enumerate()
+        .map(|(i, v)| (i as u32, crate::index::distance::euclidean_distance(&query.view(), &v.view())))
+        .collect();
+    dists.sort_by(|a, b| a.1.total_cmp(&b.1));
+    dists.into_iter().take(k).map(|(id, _)| id).collect()
+}
+
+fn assert_graph_consistent(hnsw: &Hnsw) {
+    let ep = hnsw.entry_point.expect("non-empty index must have an entry point");
+    assert_eq!(hnsw.nodes[&ep].read().unwrap().layers.len() - 1, hnsw.max_layers);
+    for (id, node) in &hnsw.nodes {
+        for layer in &node.read().unwrap().layers {
+            assert!(layer.iter().all(|n| hnsw.nodes.contains_key(n)), "dangling edge from {}", id);
+            assert!(!layer.contains(id), "self-loop on {}", id);
+            for n in layer {
+                assert!(hnsw.referrers.get(n).is_some_and(|r| r.contains(id)), "{} -> {} has no reverse link", id, n);
+            }
+        }
+    }
+    // Every node but the entry point is linked to on each of its layers
+    for (id, node) in &hnsw.nodes {
+        if *id == ep {
+            continue;
+        }
+        for l in 0..node.read().unwrap().layers.len() {
+            let linked = hnsw.referrers.get(id).is_some_and(|r| {
+                r.iter().any(|from| hnsw.nodes[from].read().unwrap().layers.get(l).is_some_and(|layer| layer.contains(id)))
+            });
+            assert!(linked, "{} is unreachable on layer {}", id, l);
+        }
+    }
+    for (n, referrers) in &hnsw.referrers {
+        for id in referrers {
+            let linked = hnsw.nodes.get(id).is_some_and(|node| node.read().unwrap().layers.iter().flatten().any(|x| x == n));
+            assert!(linked, "stale reverse link {} -> {}", id, n);
+        }
+    }
+}
+
+#[test]
+fn test_hnsw_reput_same_id_keeps_recall() {
+    let mut hnsw = Hnsw::new(16, 100);
+    let mut rng = rand::thread_rng();
+
+    let mut vectors = Vec::new();
+    for i in 0..200 {
+        let v: Array1<f32> = Array1::from((0..10).map(|_| rng.gen()).collect::<Vec<f32>>());
+        hnsw.insert(i, v.clone()).unwrap();
+        vectors.push(v);
+    }
+
+    // Keep moving the same 20 ids around
+    for _ in 0..50 {
+        for i in 0..20 {
             let v: Array1<f32> = Array1::from((0..10).map(|_| rng.gen()).collect::<Vec<f32>>());
-            hnsw.insert(i, v.clone());
-            vectors.push(v);
+            hnsw.insert(i, v.clone()).unwrap();
+            vectors[i as usize] = v;
         }
-        
-        // Generate a random query
+    }
+
+    assert_eq!(hnsw.nodes.len(), 200);
+    assert_graph_consistent(&hnsw);
+
+    // Each id is found at its latest position
+    for (i, v) in vectors.iter().enumerate() {
+        let results = hnsw.search(&v.view(), 1);
+        assert_eq!(results[0].0, i as u32);
+        assert!(results[0].1 < 1e-6);
+    }
+
+    // Recall@10 against brute force
+    let mut hits = 0;
+    for _ in 0..20 {
         let query: Array1<f32> = Array1::from((0..10).map(|_| rng.gen()).collect::<Vec<f32>>());
-        
-        // Brute force search
-        let mut best_id = 0;
-        let mut best_dist = f32::MAX;
-        
-        for (i, v) in vectors.iter().enumerate() {
-            let dist = crate::index::distance::euclidean_distance(&query.view(), &v.view());
-            if dist < best_dist {
-                best_dist = dist;
-                best_id = i as u32;
-            }
+        let expected = brute_force_top_k(&vectors, &query, 10);
+        let results = hnsw.search(&query.view(), 10);
+        hits += results.iter().filter(|(id, _)| expected.contains(id)).count();
+    }
+    assert!(hits >= 180, "recall@10 dropped to {}/200 after re-puts", hits);
+}
+
+#[test]
+fn test_hnsw_reput_entry_point() {
+    let mut hnsw = Hnsw::new(16, 100);
+    for i in 0..100u32 {
+        hnsw.insert(i, Array1::from(vec![i as f32, 0.0])).unwrap();
+    }
+
+    for round in 0..30 {
+        let ep = hnsw.entry_point.unwrap();
+        hnsw.insert(ep, Array1::from(vec![ep as f32, round as f32])).unwrap();
+        assert_eq!(hnsw.nodes.len(), 100);
+        assert_graph_consistent(&hnsw);
+    }
+
+    // Re-putting a deleted id brings it back exactly once
+    hnsw.delete(42);
+    hnsw.insert(42, Array1::from(vec![42.0, 0.0])).unwrap();
+    assert!(hnsw.contains(42));
+    assert_eq!(hnsw.len(), 100);
+    let results = hnsw.search(&Array1::from(vec![42.0, 0.0]).view(), 3);
+    assert_eq!(results[0].0, 42);
+    assert_eq!(results.iter().filter(|(id, _)| *id == 42).count(), 1);
+}
+
+#[test]
+fn test_hnsw_search_ef_override() {
+    let mut hnsw = Hnsw::new(16, 100);
+    let mut rng = rand::thread_rng();
+
+    let mut vectors = Vec::new();
+    for i in 0..500 {
+        let v: Array1<f32> = Array1::from((0..10).map(|_| rng.gen()).collect::<Vec<f32>>());
+        hnsw.insert(i, v.clone()).unwrap();
+        vectors.push(v);
+    }
+    assert_eq!(hnsw.ef_search, 100);
+
+    let query: Array1<f32> = Array1::from((0..10).map(|_| rng.gen()).collect::<Vec<f32>>());
+
+    // The beam never drops below k
+    assert_eq!(hnsw.search_with_ef(&query.view(), 10, 1).len(), 10);
+    // ... nor below one, even when neither is set
+    assert!(hnsw.search_with_ef(&query.view(), 0, 0).is_empty());
+    assert_eq!(hnsw.search_with_ef(&query.view(), 1, 0).len(), 1);
+
+    // A wide beam finds (almost) the exact top 10
+    let expected = brute_force_top_k(&vectors, &query, 10);
+    let results = hnsw.search_with_ef(&query.view(), 10, 400);
+    let hits = results.iter().filter(|(id, _)| expected.contains(id)).count();
+    assert!(hits >= 9, "ef=400 only found {}/10", hits);
+
+    // Lowering the stored default changes plain `search`
+    hnsw.ef_search = 10;
+    assert_eq!(hnsw.search(&query.view(), 10), hnsw.search_with_ef(&query.view(), 10, 10));
+}
+
+#[test]
+fn test_hnsw_payload_survives_snapshot_and_reput() {
+    use crate::payload::{Payload, PayloadValue};
+
+    let mut payload = Payload::new();
+    payload.insert("lang".to_string(), PayloadValue::String("en".to_string()));
+    payload.insert("tags".to_string(), PayloadValue::StringList(vec!["a".to_string()]));
+
+    let mut hnsw = Hnsw::new(16, 100);
+    hnsw.insert_with_payload(1, Array1::from(vec![1.0, 0.0]), payload.clone()).unwrap();
+    hnsw.insert(2, Array1::from(vec![0.0, 1.0])).unwrap();
+
+    let path = std::env::temp_dir().join(format!("hnsw_payload_{}.snap", std::process::id()));
+    let path = path.to_str().unwrap();
+    hnsw.save_snapshot(path).unwrap();
+    let mut loaded = Hnsw::load_snapshot(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(loaded.get(1), Some((vec![1.0, 0.0], payload)));
+    assert_eq!(loaded.payload(2), Some(Payload::new()));
+
+    // Re-putting replaces the payload too
+    loaded.insert(1, Array1::from(vec![1.0, 0.0])).unwrap();
+    assert_eq!(loaded.payload(1), Some(Payload::new()));
+    assert_graph_consistent(&loaded);
+
+    loaded.delete(2);
+    assert_eq!(loaded.get(2), None);
+}
+
+
+#[test]
+fn test_hnsw_filtered_search() {
+    use crate::filter::Filter;
+    use crate::payload::{Payload, PayloadValue};
+
+    let mut hnsw = Hnsw::new(16, 100);
+    let mut rng = rand::thread_rng();
+
+    let mut vectors = Vec::new();
+    for i in 0..500u32 {
+        let v: Array1<f32> = Array1::from((0..10).map(|_| rng.gen()).collect::<Vec<f32>>());
+        let payload = Payload::from([
+            ("bucket".to_string(), PayloadValue::Int((i % 10) as i64)),
+            ("rare".to_string(), PayloadValue::Bool(i % 100 == 0)),
+            ("uncommon".to_string(), PayloadValue::Bool(i % 20 == 0)),
+        ]);
+        hnsw.insert_with_payload(i, v.clone(), payload).unwrap();
+        vectors.push(v);
+    }
+    let query: Array1<f32> = Array1::from((0..10).map(|_| rng.gen()).collect::<Vec<f32>>());
+
+    // 10% selectivity: handled by the graph walk
+    let bucket = Filter::Eq("bucket".to_string(), PayloadValue::Int(3));
+    let results = hnsw.search_filtered(&query.view(), 10, 100, Some(&bucket));
+    assert_eq!(results.len(), 10);
+    assert!(results.iter().all(|(id, _)| id % 10 == 3));
+    let matching: Vec<Array1<f32>> = vectors.iter().skip(3).step_by(10).cloned().collect();
+    let expected: Vec<u32> = brute_force_top_k(&matching, &query, 10).iter().map(|i| i * 10 + 3).collect();
+    let hits = results.iter().filter(|(id, _)| expected.contains(id)).count();
+    assert!(hits >= 9, "filtered recall@10 was {}/10", hits);
+
+    // 5% selectivity, asking for more than match: the walk comes up short,
+    // and what it missed is scanned, so all 25 are found in order
+    let uncommon = Filter::Eq("uncommon".to_string(), PayloadValue::Bool(true));
+    let results = hnsw.search_filtered(&query.view(), 40, 100, Some(&uncommon));
+    let mut ids: Vec<u32> = results.iter().map(|(id, _)| *id).collect();
+    ids.sort();
+    assert_eq!(ids, (0..500).step_by(20).collect::<Vec<u32>>());
+    assert!(results.windows(2).all(|w| w[0].1 <= w[1].1));
+
+    // 1% selectivity: falls back to an exact scan and finds all 5 matches
+    let rare = Filter::Eq("rare".to_string(), PayloadValue::Bool(true));
+    let results = hnsw.search_filtered(&query.view(), 10, 100, Some(&rare));
+    let mut ids: Vec<u32> = results.iter().map(|(id, _)| *id).collect();
+    ids.sort();
+    assert_eq!(ids, vec![0, 100, 200, 300, 400]);
+    assert!(results.windows(2).all(|w| w[0].1 <= w[1].1));
+
+    // Tombstoned matches are never returned
+    hnsw.delete(100);
+    let results = hnsw.search_filtered(&query.view(), 10, 100, Some(&rare));
+    assert_eq!(results.len(), 4);
+    assert!(results.iter().all(|(id, _)| *id != 100));
+
+    // An id predicate narrows the search alongside the filter
+    let odd = |id: u32| id % 2 == 1;
+    let scope = SearchScope { filter: Some(&bucket), ids: Some(&odd) };
+    let results = hnsw.search_scoped(&query.view(), 10, 100, scope);
+    assert_eq!(results.len(), 10);
+    assert!(results.iter().all(|(id, _)| id % 10 == 3));
+}
+
+#[test]
+fn test_hnsw_validate_vectors() {
+    use crate::index::hnsw::InvalidVector;
+    use crate::wal::{OpType, WalEntry};
+    let mut hnsw = Hnsw::new(16, 100);
+    assert_eq!(hnsw.validate(&[]), Err(InvalidVector::Empty));
+    assert_eq!(hnsw.validate(&[1.0, f32::NAN]), Err(InvalidVector::NonFinite(1)));
+    assert_eq!(hnsw.validate(&[f32::INFINITY]), Err(InvalidVector::NonFinite(0)));
+    // Finite, but a dot product with it could come out NaN
+    assert_eq!(hnsw.validate(&[3e38, 3e38]), Err(InvalidVector::Overflow));
+
+    // The first insert fixes the dimension
+    assert_eq!(hnsw.dimension, None);
+    hnsw.insert(1, Array1::from(vec![1.0, 2.0, 3.0])).unwrap();
+    assert_eq!(hnsw.dimension, Some(3));
+    assert_eq!(hnsw.validate(&[1.0, 2.0, 3.0]), Ok(()));
+    assert_eq!(
+        hnsw.validate(&[1.0, 2.0]),
+        Err(InvalidVector::DimensionMismatch { expected: 3, actual: 2 })
+    );
+
+    // Replayed entries that fail validation are skipped, not applied
+    let entry = WalEntry { lsn: 2, op: OpType::Insert, vector_id: 2, version: 0, vector: vec![1.0], payload: Default::default() };
+    hnsw.apply(&entry).unwrap();
+    assert!(!hnsw.contains(2));
+    assert_eq!(hnsw.applied_lsn, 2);
+
+    // An insert that skipped validation fails rather than panicking, and
+    // leaves the node it would have replaced in place
+    let err = hnsw.insert(1, Array1::from(vec![1.0])).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(hnsw.get(1).unwrap().0, vec![1.0, 2.0, 3.0]);
+}
+
+#[test]
+fn test_hnsw_file_storage_snapshot() {
+    use crate::storage::VectorStorage;
+    let dir = std::env::temp_dir();
+    let snap_path = dir.join(format!("test_storage_{}.snap", std::process::id()));
+    let snap = snap_path.to_str().unwrap();
+    let vectors_path = dir.join(format!("test_storage_{}.dat", std::process::id()));
+    let mut rng = rand::thread_rng();
+
+    // 512 floats per vector: the first 512 are flushed to the file and read through the map
+    let mut hnsw = Hnsw::new(4, 16);
+    hnsw.storage = VectorStorage::create(&vectors_path).unwrap();
+    let mut vectors = Vec::new();
+    for i in 0..600u32 {
+        let v: Array1<f32> = Array1::from((0..512).map(|_| rng.gen()).collect::<Vec<f32>>());
+        hnsw.insert(i, v.clone()).unwrap();
+        vectors.push(v);
+    }
+    hnsw.save_snapshot(snap).unwrap();
+    // Written after the snapshot, so dropped when it is loaded
+    hnsw.insert(5000, vectors[0].clone()).unwrap();
+    drop(hnsw);
+
+    let loaded = Hnsw::load_snapshot_with_storage(snap, &vectors_path).unwrap();
+    assert_eq!(loaded.len(), 600);
+    assert_eq!(loaded.storage.len(), 600);
+    assert!(!loaded.contains(5000));
+    for i in [0u32, 511, 512, 599] {
+        assert_eq!(loaded.get(i).unwrap().0, vectors[i as usize].to_vec());
+    }
+    // Distances are computed from the mapped and buffered vectors alike
+    let query = &vectors[0];
+    let results = loaded.search(&query.view(), 10);
+    assert_eq!(results.len(), 10);
+    for (id, distance) in results {
+        let expected = crate::index::distance::euclidean_distance(&query.view(), &vectors[id as usize].view());
+        assert!((distance - expected).abs() < 1e-3);
+    }
+    assert!(Hnsw::load_snapshot(snap).is_err(), "file-backed snapshot needs its storage");
+
+    std::fs::remove_file(&snap_path).unwrap();
+    std::fs::remove_file(&vectors_path).unwrap();
+}
+
+#[test]
+fn test_hnsw_reuses_storage_after_snapshot() {
+    use crate::storage::VectorStorage;
+    let dir = std::env::temp_dir();
+    let snap_path = dir.join(format!("test_reuse_{}.snap", std::process::id()));
+    let snap = snap_path.to_str().unwrap();
+    let vectors_path = dir.join(format!("test_reuse_{}.dat", std::process::id()));
+    let mut rng = rand::thread_rng();
+    let mut random = || Array1::from((0..512).map(|_| rng.gen()).collect::<Vec<f32>>());
+
+    let mut hnsw = Hnsw::new(4, 16);
+    hnsw.storage = VectorStorage::create(&vectors_path).unwrap();
+    let mut vectors: Vec<Array1<f32>> = (0..600).map(|_| random()).collect();
+    for (i, v) in vectors.iter().enumerate() {
+        hnsw.insert(i as u32, v.clone()).unwrap();
+    }
+    // Replaced slots wait for a snapshot that no longer references them
+    for (i, v) in vectors.iter_mut().enumerate().take(100) {
+        *v = random();
+        hnsw.insert(i as u32, v.clone()).unwrap();
+    }
+    assert_eq!(hnsw.storage.len(), 700);
+    hnsw.save_snapshot(snap).unwrap();
+    let saved = vectors.clone();
+
+    for (i, v) in vectors.iter_mut().enumerate().take(100) {
+        *v = random();
+        hnsw.insert(i as u32, v.clone()).unwrap();
+    }
+    assert_eq!(hnsw.storage.len(), 700);
+    for (i, v) in vectors.iter().enumerate() {
+        assert_eq!(hnsw.get(i as u32).unwrap().0, v.to_vec());
+    }
+    drop(hnsw);
+
+    // Reused slots were ones the snapshot doesn't reference
+    let loaded = Hnsw::load_snapshot_with_storage(snap, &vectors_path).unwrap();
+    for (i, v) in saved.iter().enumerate() {
+        assert_eq!(loaded.get(i as u32).unwrap().0, v.to_vec());
+    }
+    assert_eq!(loaded.storage.free_slots(), 100);
+
+    std::fs::remove_file(&snap_path).unwrap();
+    std::fs::remove_file(&vectors_path).unwrap();
+}
+
+#[test]
+fn test_hnsw_relinking_keeps_neighbor_limits() {
+    let mut hnsw = Hnsw::new(4, 50);
+    let mut rng = rand::thread_rng();
+
+    for i in 0..300 {
+        let v: Array1<f32> = Array1::from((0..4).map(|_| rng.gen()).collect::<Vec<f32>>());
+        hnsw.insert(i, v).unwrap();
+    }
+    // Re-puts and repairs both relink the nodes they orphan
+    for _ in 0..10 {
+        for i in 0..50 {
+            let v: Array1<f32> = Array1::from((0..4).map(|_| rng.gen()).collect::<Vec<f32>>());
+            hnsw.insert(i, v).unwrap();
         }
-        
-        // HNSW search
-        let results = hnsw.search(&query.view(), 10); // Search for top 10 to increase chance
-        
-        // Check if the best_id is in the results
-        let found = results.iter().any(|(id, _)| *id == best_id);
-        
-        // Note: HNSW is approximate, so it might not ALWAYS find the exact nearest, 
-        // but with these parameters and small size, it should be very likely.
-        // If this flakes, we might need to relax it or increase ef_construction.
-        assert!(found, "HNSW failed to find the nearest neighbor. Best: {}, Found: {:?}", best_id, results);
     }
+    assert_graph_consistent(&hnsw);
+    for i in (0..300).step_by(3) {
+        hnsw.delete(i);
+    }
+    hnsw.repair();
+
+    assert_graph_consistent(&hnsw);
+    for (id, node) in &hnsw.nodes {
+        for (l, layer) in node.read().unwrap().layers.iter().enumerate() {
+            let m_max = if l == 0 { hnsw.m_max0 } else { hnsw.m };
+            assert!(layer.len() <= m_max, "{} has {} links on layer {}", id, layer.len(), l);
+        }
+    }
+}
+
+#[test]
+fn test_hnsw_dot_product_near_float_limits() {
+    use crate::index::distance::Metric;
+    let mut hnsw = Hnsw::with_metric(4, 20, Metric::DotProduct);
+
+    // Each vector passes validation, and opposite signs cancel out in the
+    // dot products with each other
+    for i in 0..50u32 {
+        let sign = if i % 2 == 0 { 1.0 } else { -1.0 };
+        let v = vec![9e18, sign * 9e18];
+        assert_eq!(hnsw.validate(&v), Ok(()));
+        hnsw.insert(i, Array1::from(v)).unwrap();
+    }
+    let results = hnsw.search(&Array1::from(vec![9e18, 9e18]).view(), 5);
+    assert_eq!(results.len(), 5);
+    assert!(results.iter().all(|(id, _)| id % 2 == 0));
 }
@@ -1,4 +1,13 @@
 pub mod wal;
 pub mod index;
 pub mod network;
+pub mod filter;
+pub mod collection;
+pub mod payload;
 pub mod storage;
+pub mod hints;
+pub mod merkle;
+pub mod raft;
+
+/// Collection used by requests that don't name one.
+pub const DEFAULT_COLLECTION: &str = "default";
@@ -0,0 +1,263 @@
+use crate::network::ring::{key_hash, xxh64, HashRange};
+use crate::payload::Payload;
+use std::collections::BTreeMap;
+
+/// Deepest tree a digest may ask for: 65536 leaves.
+pub const MAX_DEPTH: u32 = 16;
+
+/// Checksum of one entry: XXH64 (seed 0) of its id, version, vector and
+/// payload. Replicas holding the same entry agree on it.
+pub fn entry_hash(id: u32, version: u64, vector: &[f32], payload: &Payload) -> u64 {
+    let mut bytes = Vec::with_capacity(12 + vector.len() * 4);
+    bytes.extend_from_slice(&id.to_le_bytes());
+    bytes.extend_from_slice(&version.to_le_bytes());
+    for x in vector {
+        bytes.extend_from_slice(&x.to_le_bytes());
+    }
+    // Payloads are ordered maps, so this encoding is deterministic
+    bytes.extend(bincode::serialize(payload).unwrap_or_default());
+    xxh64(&bytes, 0)
+}
+
+/// Checksum of a delete: XXH64 (seed 1) of its id and version.
+pub fn delete_hash(id: u32, version: u64) -> u64 {
+    let mut bytes = [0u8; 12];
+    bytes[..4].copy_from_slice(&id.to_le_bytes());
+    bytes[4..].copy_from_slice(&version.to_le_bytes());
+    xxh64(&bytes, 1)
+}
+
+/// An entry's version and checksum, or a delete's. Ordered by version, then
+/// deletes after writes, then checksum, which is the last-write-wins order:
+/// a delete beats a write with the same version, and of two writes with the
+/// same version, every replica keeps the same one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Checksum {
+    pub version: u64,
+    pub deleted: bool,
+    pub hash: u64,
+}
+
+impl Checksum {
+    pub fn new(id: u32, version: u64, vector: &[f32], payload: &Payload) -> Self {
+        Checksum { version, deleted: false, hash: entry_hash(id, version, vector, payload) }
+    }
+
+    /// The checksum a delete leaves behind.
+    pub fn deleted(id: u32, version: u64) -> Self {
+        Checksum { version, deleted: true, hash: delete_hash(id, version) }
+    }
+}
+
+// Leaf holding a key hash: its top `depth` bits
+fn leaf_of(hash: u64, depth: u32) -> u32 {
+    if depth == 0 { 0 } else { (hash >> (64 - depth)) as u32 }
+}
+
+/// The checksum of every entry in a collection, and of every delete not
+/// overwritten since, kept up to date as entries change, from which Merkle
+/// trees over any set of hash ranges are built without reading vectors.
+pub struct Digests {
+    collection: String,
+    // (key hash, id) -> checksum, so each leaf is a contiguous run
+    entries: BTreeMap<(u64, u32), Checksum>,
+}
+
+impl Digests {
+    pub fn new(collection: &str) -> Self {
+        Digests { collection: collection.to_string(), entries: BTreeMap::new() }
+    }
+
+    /// Records an insert, replacement or delete.
+    pub fn insert(&mut self, id: u32, checksum: Checksum) {
+        let hash = key_hash(&self.collection, id);
+        self.entries.insert((hash, id), checksum);
+    }
+
+    pub fn get(&self, id: u32) -> Option<Checksum> {
+        let hash = key_hash(&self.collection, id);
+        self.entries.get(&(hash, id)).copied()
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        let hash = key_hash(&self.collection, id);
+        self.entries.remove(&(hash, id));
+    }
+
+    /// Tree of `2^depth` leaves over the keys in `ranges`. A key hashing to
+    /// `h` lands in leaf `h >> (64 - depth)`, so trees of the same depth line
+    /// up across replicas whatever they hold.
+    pub fn tree(&self, ranges: &[HashRange], depth: u32) -> MerkleTree {
+        let mut leaves = vec![0u64; 1 << depth];
+        for (&(hash, _), &entry) in &self.entries {
+            if ranges.iter().any(|r| r.contains(hash)) {
+                let leaf = &mut leaves[leaf_of(hash, depth) as usize];
+                *leaf = leaf.wrapping_add(entry.hash);
+            }
+        }
+        MerkleTree::from_leaves(depth, leaves)
+    }
+
+    /// Id and checksum of every key in `ranges` that falls in one of `leaves`.
+    pub fn entries(&self, ranges: &[HashRange], depth: u32, leaves: &[u32]) -> Vec<(u32, Checksum)> {
+        let mut found = Vec::new();
+        for &leaf in leaves {
+            let (low, high) = if depth == 0 {
+                (0, u64::MAX)
+            } else {
+                let shift = 64 - depth;
+                let low = (leaf as u64) << shift;
+                (low, low | (u64::MAX >> depth))
+            };
+            for (&(hash, id), &entry) in self.entries.range((low, 0)..=(high, u32::MAX)) {
+                if ranges.iter().any(|r| r.contains(hash)) {
+                    found.push((id, entry));
+                }
+            }
+        }
+        found
+    }
+}
+
+/// A complete binary hash tree stored in heap order: node `i` has children
+/// `2i + 1` and `2i + 2`, and the last `2^depth` nodes are the leaves. A leaf
+/// is the wrapping sum of its entries' hashes, so it doesn't depend on the
+/// order entries were written in; an inner node is XXH64 of its children.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MerkleTree {
+    depth: u32,
+    nodes: Vec<u64>,
+}
+
+impl MerkleTree {
+    fn from_leaves(depth: u32, leaves: Vec<u64>) -> Self {
+        let inner = leaves.len() - 1;
+        let mut nodes = vec![0u64; inner];
+        nodes.extend(leaves);
+        for i in (0..inner).rev() {
+            let mut bytes = [0u8; 16];
+            bytes[..8].copy_from_slice(&nodes[2 * i + 1].to_le_bytes());
+            bytes[8..].copy_from_slice(&nodes[2 * i + 2].to_le_bytes());
+            nodes[i] = xxh64(&bytes, 0);
+        }
+        MerkleTree { depth, nodes }
+    }
+
+    /// The tree of a replica holding nothing.
+    pub fn empty(depth: u32) -> Self {
+        Self::from_leaves(depth, vec![0; 1 << depth])
+    }
+
+    /// Rebuilds a tree sent over the wire. None unless `nodes` is a whole
+    /// tree of at most `MAX_DEPTH`.
+    pub fn from_nodes(nodes: Vec<u64>) -> Option<Self> {
+        let depth = (nodes.len() + 1).trailing_zeros().checked_sub(1)?;
+        if depth > MAX_DEPTH || nodes.len() + 1 != 2 << depth {
+            return None;
+        }
+        Some(MerkleTree { depth, nodes })
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    pub fn root(&self) -> u64 {
+        self.nodes[0]
+    }
+
+    pub fn into_nodes(self) -> Vec<u64> {
+        self.nodes
+    }
+
+    /// Leaves whose hashes differ, found by descending only into subtrees
+    /// whose hashes differ. Trees of different depths differ everywhere.
+    pub fn diff(&self, other: &MerkleTree) -> Vec<u32> {
+        if self.depth != other.depth {
+            return (0..1u32 << self.depth.max(other.depth)).collect();
+        }
+        let first_leaf = (1usize << self.depth) - 1;
+        let mut leaves = Vec::new();
+        let mut stack = vec![0usize];
+        while let Some(i) = stack.pop() {
+            if self.nodes[i] == other.nodes[i] {
+                continue;
+            }
+            if i >= first_leaf {
+                leaves.push((i - first_leaf) as u32);
+            } else {
+                stack.extend([2 * i + 2, 2 * i + 1]);
+            }
+        }
+        leaves
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::PayloadValue;
+
+    const ALL: [HashRange; 1] = [HashRange { start: 0, end: 0 }];
+
+    #[test]
+    fn test_trees_agree_regardless_of_write_order() {
+        let mut a = Digests::new("docs");
+        let mut b = Digests::new("docs");
+        for id in 0..100 {
+            a.insert(id, Checksum::new(id, 1, &[id as f32, 1.0], &Payload::new()));
+            b.insert(99 - id, Checksum::new(99 - id, 1, &[(99 - id) as f32, 1.0], &Payload::new()));
+        }
+        assert_eq!(a.tree(&ALL, 6), b.tree(&ALL, 6));
+
+        // One changed entry shows up as exactly its leaf
+        let payload = Payload::from([("tag".to_string(), PayloadValue::Bool(true))]);
+        b.insert(42, Checksum::new(42, 1, &[42.0, 1.0], &payload));
+        let leaves = a.tree(&ALL, 6).diff(&b.tree(&ALL, 6));
+        assert_eq!(leaves, vec![leaf_of(key_hash("docs", 42), 6)]);
+        let in_a = a.entries(&ALL, 6, &leaves);
+        let in_b = b.entries(&ALL, 6, &leaves);
+        assert!(in_a.iter().any(|&(id, _)| id == 42));
+        assert_eq!(in_a.len(), in_b.len());
+        assert_eq!(in_a.iter().zip(&in_b).filter(|(x, y)| x != y).count(), 1);
+
+        // Removing an entry leaves the same tree as never having it
+        b.insert(500, Checksum::new(500, 1, &[5.0, 0.0], &Payload::new()));
+        b.remove(500);
+        b.insert(42, Checksum::new(42, 1, &[42.0, 1.0], &Payload::new()));
+        assert_eq!(a.tree(&ALL, 6).root(), b.tree(&ALL, 6).root());
+
+        // So does a different version of the same value
+        b.insert(42, Checksum::new(42, 2, &[42.0, 1.0], &Payload::new()));
+        assert_ne!(a.tree(&ALL, 6).root(), b.tree(&ALL, 6).root());
+        assert!(b.get(42).unwrap() > a.get(42).unwrap());
+    }
+
+    #[test]
+    fn test_trees_only_cover_their_ranges() {
+        let mut a = Digests::new("default");
+        for id in 0..50 {
+            a.insert(id, Checksum::new(id, 1, &[id as f32], &Payload::new()));
+        }
+        let mut b = Digests::new("default");
+        b.insert(7, Checksum::new(7, 1, &[7.0], &Payload::new()));
+
+        // A range holding just key 7
+        let hash = key_hash("default", 7);
+        let range = [HashRange { start: hash - 1, end: hash }];
+        assert_eq!(a.tree(&range, 4), b.tree(&range, 4));
+        assert_eq!(a.entries(&range, 4, &[leaf_of(hash, 4)]), b.entries(&range, 4, &[leaf_of(hash, 4)]));
+        assert_ne!(a.tree(&ALL, 4), b.tree(&ALL, 4));
+        assert_eq!(Digests::new("default").tree(&range, 4), MerkleTree::empty(4));
+    }
+
+    #[test]
+    fn test_tree_round_trips() {
+        let tree = MerkleTree::empty(3);
+        assert_eq!(MerkleTree::from_nodes(tree.clone().into_nodes()), Some(tree));
+        assert_eq!(MerkleTree::from_nodes(vec![1, 2]), None);
+        assert_eq!(MerkleTree::from_nodes(vec![]), None);
+        assert_eq!(MerkleTree::from_nodes(vec![0; (2 << (MAX_DEPTH + 1)) - 1]), None);
+        assert_eq!(MerkleTree::from_nodes(vec![9]).map(|t| t.depth()), Some(0));
+    }
+}
@@ -0,0 +1,560 @@
+// tonic::Status is large, but it is the natural error type for request handlers
+#![allow(clippy::result_large_err)]
+
+use futures::future::join_all;
+use rand::seq::SliceRandom;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("gossip");
+}
+
+use proto::gossip_client::GossipClient;
+use proto::gossip_server::{Gossip, GossipServer};
+use proto::{Ack, PingReqRequest, PingRequest, SyncRequest, SyncResponse};
+pub use proto::{Member, MemberState, Role};
+
+// Most updates piggybacked on one message
+const MAX_PIGGYBACK: usize = 16;
+// Membership lists are exchanged in full every this many protocol periods
+const SYNC_PERIODS: u64 = 10;
+
+#[derive(Clone, Debug)]
+pub struct GossipConfig {
+    // URL other members reach this node at
+    pub addr: String,
+    pub role: Role,
+    // Members to join the cluster through; without any, the node waits to be joined
+    pub seeds: Vec<String>,
+    // One member is probed per period
+    pub protocol_period: Duration,
+    // How long to wait for a probe's ack
+    pub ping_timeout: Duration,
+    // Members asked to probe a member that missed a direct probe
+    pub indirect_probes: usize,
+    // How long a suspect has to refute before it's declared dead
+    pub suspicion_timeout: Duration,
+}
+
+impl GossipConfig {
+    pub fn new(addr: &str, role: Role) -> Self {
+        GossipConfig {
+            addr: addr.to_string(),
+            role,
+            seeds: Vec::new(),
+            protocol_period: Duration::from_secs(1),
+            ping_timeout: Duration::from_millis(300),
+            indirect_probes: 3,
+            suspicion_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Whether `update` carries newer news about a member than `current`, by
+/// SWIM's rules: a higher incarnation always wins; at the same incarnation,
+/// dead beats suspect beats alive. Only the member itself bumps its
+/// incarnation, so only it can overturn a suspicion.
+pub fn supersedes(update: &Member, current: &Member) -> bool {
+    use MemberState::*;
+
+    if update.incarnation != current.incarnation {
+        return update.incarnation > current.incarnation;
+    }
+    matches!(
+        (update.state(), current.state()),
+        (Suspect, Alive) | (Dead, Alive) | (Dead, Suspect)
+    )
+}
+
+struct State {
+    // Every member ever heard of, this one included; the dead are kept so
+    // stale gossip can't bring them back
+    members: BTreeMap<String, Member>,
+    suspected_at: HashMap<String, Instant>,
+    // Updates to piggyback, each with how many more messages should carry it
+    broadcasts: HashMap<String, (Member, usize)>,
+    // Members left to probe this round, in random order
+    probe_order: Vec<String>,
+}
+
+impl State {
+    fn broadcast(&mut self, member: Member) {
+        // Enough retransmissions to reach every member with high probability
+        let rounds = 3 * (usize::BITS - self.members.len().leading_zeros()) as usize;
+        self.broadcasts.insert(member.addr.clone(), (member, rounds));
+    }
+}
+
+/// One member of a SWIM-style gossip cluster (Das et al., 2002).
+///
+/// Every protocol period the node pings one other member, in a shuffled
+/// round-robin. If no ack arrives in time it asks `indirect_probes` others to
+/// ping the member for it, and if none of them can reach it either, marks it
+/// suspect. A suspect that doesn't refute within the suspicion timeout is
+/// declared dead. State changes are piggybacked on pings and acks, and full
+/// membership lists are swapped with a random member now and then and when
+/// joining through a seed.
+pub struct GossipNode {
+    config: GossipConfig,
+    state: Mutex<State>,
+    channels: Mutex<HashMap<String, Channel>>,
+    view: watch::Sender<Vec<Member>>,
+}
+
+impl GossipNode {
+    pub fn new(config: GossipConfig) -> Arc<Self> {
+        let me = Member {
+            addr: config.addr.clone(),
+            incarnation: 0,
+            state: MemberState::Alive as i32,
+            role: config.role as i32,
+        };
+        let (view, _) = watch::channel(vec![me.clone()]);
+        Arc::new(GossipNode {
+            state: Mutex::new(State {
+                members: BTreeMap::from([(me.addr.clone(), me)]),
+                suspected_at: HashMap::new(),
+                broadcasts: HashMap::new(),
+                probe_order: Vec::new(),
+            }),
+            channels: Mutex::new(HashMap::new()),
+            view,
+            config,
+        })
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.config.addr
+    }
+
+    /// The gRPC service other members talk to.
+    pub fn service(self: &Arc<Self>) -> GossipServer<GossipNode> {
+        GossipServer::from_arc(self.clone())
+    }
+
+    /// Joins through the seeds, then runs the protocol until the task is aborted.
+    pub fn start(self: &Arc<Self>) -> JoinHandle<()> {
+        let node = self.clone();
+        tokio::spawn(async move { node.run().await })
+    }
+
+    /// Every known member, this one and dead ones included, sorted by address.
+    pub fn members(&self) -> Vec<Member> {
+        self.state.lock().unwrap().members.values().cloned().collect()
+    }
+
+    /// Receives `members()` again whenever a member joins or changes state.
+    pub fn subscribe(&self) -> watch::Receiver<Vec<Member>> {
+        self.view.subscribe()
+    }
+
+    pub fn state_of(&self, addr: &str) -> Option<MemberState> {
+        self.state.lock().unwrap().members.get(addr).map(|m| m.state())
+    }
+
+    async fn run(&self) {
+        let mut ticks = tokio::time::interval(self.config.protocol_period);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        for period in 0u64.. {
+            ticks.tick().await;
+            self.expire_suspects();
+
+            // Alone, or everyone else is dead: (re)join through the seeds
+            let peers = self.peers(None);
+            if peers.is_empty() {
+                self.join().await;
+                continue;
+            }
+            if period % SYNC_PERIODS == 0 {
+                let peer = peers.choose(&mut rand::thread_rng()).unwrap();
+                if let Err(e) = self.sync_with(peer).await {
+                    println!("Gossip sync with {} failed: {}", peer, e.message());
+                }
+            }
+            if let Some(target) = self.next_target() {
+                self.probe(&target).await;
+            }
+        }
+    }
+
+    async fn join(&self) {
+        for seed in self.config.seeds.iter().filter(|seed| **seed != self.config.addr) {
+            match self.sync_with(seed).await {
+                Ok(()) => {
+                    println!("Joined the cluster through {}", seed);
+                    return;
+                }
+                Err(e) => println!("Failed to join through {}: {}", seed, e.message()),
+            }
+        }
+    }
+
+    async fn sync_with(&self, addr: &str) -> Result<(), Status> {
+        let req = SyncRequest { members: self.members() };
+        let mut client = self.client(addr)?;
+        let resp = call(self.config.protocol_period, client.sync(req)).await?;
+        self.merge(resp.members);
+        Ok(())
+    }
+
+    async fn probe(&self, target: &str) {
+        if self.ping_member(target).await {
+            return;
+        }
+
+        // The problem may lie between this node and the target only
+        let helpers = self.peers(Some(target));
+        let helpers: Vec<&String> = helpers
+            .choose_multiple(&mut rand::thread_rng(), self.config.indirect_probes)
+            .collect();
+        let acks = join_all(helpers.into_iter().map(|helper| async move {
+            let req = PingReqRequest {
+                from: self.config.addr.clone(),
+                target: target.to_string(),
+                updates: self.piggyback(),
+            };
+            let mut client = self.client(helper)?;
+            // The helper needs a full ping timeout of its own
+            call(self.config.ping_timeout * 2, client.ping_req(req)).await
+        }))
+        .await;
+
+        let mut reached = false;
+        for ack in acks.into_iter().flatten() {
+            reached |= ack.ok;
+            self.merge(ack.updates);
+        }
+        if !reached {
+            self.suspect(target);
+        }
+    }
+
+    async fn ping_member(&self, target: &str) -> bool {
+        let req = PingRequest { from: self.config.addr.clone(), updates: self.piggyback() };
+        let result = match self.client(target) {
+            Ok(mut client) => call(self.config.ping_timeout, client.ping(req)).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(ack) => {
+                self.merge(ack.updates);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn suspect(&self, addr: &str) {
+        let mut state = self.state.lock().unwrap();
+        let Some(member) = state.members.get(addr) else { return };
+        if member.state() == MemberState::Alive {
+            let update = Member { state: MemberState::Suspect as i32, ..member.clone() };
+            self.apply(&mut state, update);
+            self.publish(&state);
+        }
+    }
+
+    fn expire_suspects(&self) {
+        let mut state = self.state.lock().unwrap();
+        let expired: Vec<String> = state.suspected_at
+            .iter()
+            .filter(|(_, at)| at.elapsed() >= self.config.suspicion_timeout)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in &expired {
+            let update = Member { state: MemberState::Dead as i32, ..state.members[addr].clone() };
+            self.apply(&mut state, update);
+        }
+        if !expired.is_empty() {
+            self.publish(&state);
+        }
+    }
+
+    fn merge(&self, updates: Vec<Member>) {
+        let mut state = self.state.lock().unwrap();
+        let mut changed = false;
+        for update in updates {
+            changed |= self.apply(&mut state, update);
+        }
+        if changed {
+            self.publish(&state);
+        }
+    }
+
+    // Applies one update and queues it for dissemination if it's news.
+    // Returns whether the membership view changed.
+    fn apply(&self, state: &mut State, update: Member) -> bool {
+        if update.addr == self.config.addr {
+            let me = state.members.get_mut(&update.addr).unwrap();
+            if update.state() != MemberState::Alive && update.incarnation >= me.incarnation {
+                me.incarnation = update.incarnation + 1;
+                println!("Refuting {:?} with incarnation {}", update.state(), me.incarnation);
+                let me = me.clone();
+                state.broadcast(me);
+            }
+            return false;
+        }
+
+        if let Some(current) = state.members.get(&update.addr) {
+            if !supersedes(&update, current) {
+                return false;
+            }
+            if current.state() != update.state() {
+                println!("Member {} is now {:?}", update.addr, update.state());
+            }
+        } else {
+            println!("Member {} joined as {:?} ({:?})", update.addr, update.role(), update.state());
+        }
+
+        match update.state() {
+            MemberState::Suspect => {
+                state.suspected_at.insert(update.addr.clone(), Instant::now());
+            }
+            _ => {
+                state.suspected_at.remove(&update.addr);
+            }
+        }
+        state.members.insert(update.addr.clone(), update.clone());
+        state.broadcast(update);
+        true
+    }
+
+    fn publish(&self, state: &State) {
+        self.view.send_replace(state.members.values().cloned().collect());
+    }
+
+    // The freshest updates, each sent a bounded number of times
+    fn piggyback(&self) -> Vec<Member> {
+        let mut state = self.state.lock().unwrap();
+        let mut pending: Vec<(usize, String)> = state.broadcasts
+            .iter()
+            .map(|(addr, (_, left))| (*left, addr.clone()))
+            .collect();
+        pending.sort_unstable_by(|a, b| b.cmp(a));
+
+        let mut updates = Vec::new();
+        for (_, addr) in pending.into_iter().take(MAX_PIGGYBACK) {
+            let (member, left) = state.broadcasts.get_mut(&addr).unwrap();
+            updates.push(member.clone());
+            *left -= 1;
+            if *left == 0 {
+                state.broadcasts.remove(&addr);
+            }
+        }
+        updates
+    }
+
+    // Members other than this one and `except` that aren't dead
+    fn peers(&self, except: Option<&str>) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.members
+            .values()
+            .filter(|m| m.addr != self.config.addr && Some(m.addr.as_str()) != except)
+            .filter(|m| m.state() != MemberState::Dead)
+            .map(|m| m.addr.clone())
+            .collect()
+    }
+
+    fn next_target(&self) -> Option<String> {
+        loop {
+            let mut state = self.state.lock().unwrap();
+            if state.probe_order.is_empty() {
+                drop(state);
+                let mut peers = self.peers(None);
+                if peers.is_empty() {
+                    return None;
+                }
+                peers.shuffle(&mut rand::thread_rng());
+                state = self.state.lock().unwrap();
+                state.probe_order = peers;
+            }
+            let addr = state.probe_order.pop().unwrap();
+            // Skip members that died since the round was shuffled
+            if state.members.get(&addr).is_some_and(|m| m.state() != MemberState::Dead) {
+                return Some(addr);
+            }
+        }
+    }
+
+    fn client(&self, addr: &str) -> Result<GossipClient<Channel>, Status> {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(channel) = channels.get(addr) {
+            return Ok(GossipClient::new(channel.clone()));
+        }
+        let channel = Endpoint::from_shared(addr.to_string())
+            .map_err(|e| Status::invalid_argument(format!("Invalid member address {}: {}", addr, e)))?
+            .connect_timeout(self.config.ping_timeout)
+            .connect_lazy();
+        channels.insert(addr.to_string(), channel.clone());
+        Ok(GossipClient::new(channel))
+    }
+}
+
+async fn call<T>(wait: Duration, request: impl Future<Output = Result<Response<T>, Status>>) -> Result<T, Status> {
+    match tokio::time::timeout(wait, request).await {
+        Ok(result) => result.map(Response::into_inner),
+        Err(_) => Err(Status::deadline_exceeded("No answer in time")),
+    }
+}
+
+#[tonic::async_trait]
+impl Gossip for GossipNode {
+    async fn ping(&self, request: Request<PingRequest>) -> Result<Response<Ack>, Status> {
+        let req = request.into_inner();
+        self.merge(req.updates);
+
+        let mut updates = self.piggyback();
+        // A member declared dead while cut off learns it here, and refutes
+        if let Some(sender) = self.state.lock().unwrap().members.get(&req.from) {
+            if sender.state() != MemberState::Alive && !updates.contains(sender) {
+                updates.push(sender.clone());
+            }
+        }
+        Ok(Response::new(Ack { ok: true, updates }))
+    }
+
+    async fn ping_req(&self, request: Request<PingReqRequest>) -> Result<Response<Ack>, Status> {
+        let req = request.into_inner();
+        self.merge(req.updates);
+        let ok = self.ping_member(&req.target).await;
+        Ok(Response::new(Ack { ok, updates: self.piggyback() }))
+    }
+
+    async fn sync(&self, request: Request<SyncRequest>) -> Result<Response<SyncResponse>, Status> {
+        self.merge(request.into_inner().members);
+        Ok(Response::new(SyncResponse { members: self.members() }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use tokio::sync::oneshot;
+    use tonic::transport::Server;
+
+    fn member(incarnation: u64, state: MemberState) -> Member {
+        Member { addr: "a".to_string(), incarnation, state: state as i32, role: Role::Storage as i32 }
+    }
+
+    #[test]
+    fn test_supersedes_follows_incarnation_then_state() {
+        use MemberState::*;
+
+        assert!(supersedes(&member(0, Suspect), &member(0, Alive)));
+        assert!(supersedes(&member(0, Dead), &member(0, Suspect)));
+        assert!(!supersedes(&member(0, Alive), &member(0, Suspect)));
+        assert!(!supersedes(&member(0, Suspect), &member(0, Suspect)));
+        // Only a newer incarnation overturns suspicion or death
+        assert!(supersedes(&member(1, Alive), &member(0, Dead)));
+        assert!(!supersedes(&member(0, Dead), &member(1, Alive)));
+    }
+
+    #[test]
+    fn test_refutes_suspicion_of_itself() {
+        let node = GossipNode::new(GossipConfig::new("a", Role::Storage));
+        node.merge(vec![member(3, MemberState::Suspect)]);
+        let me = &node.members()[0];
+        assert_eq!((me.incarnation, me.state()), (4, MemberState::Alive));
+        assert!(node.piggyback().contains(me));
+    }
+
+    struct TestNode {
+        node: Arc<GossipNode>,
+        protocol: JoinHandle<()>,
+        shutdown: oneshot::Sender<()>,
+    }
+
+    impl TestNode {
+        fn stop(self) {
+            self.protocol.abort();
+            let _ = self.shutdown.send(());
+        }
+    }
+
+    fn start(port: u16, seeds: &[String]) -> TestNode {
+        let mut config = GossipConfig::new(&format!("http://[::1]:{}", port), Role::Storage);
+        config.seeds = seeds.to_vec();
+        config.protocol_period = Duration::from_millis(100);
+        config.ping_timeout = Duration::from_millis(50);
+        config.suspicion_timeout = Duration::from_millis(500);
+        let node = GossipNode::new(config);
+
+        let (shutdown, stopped) = oneshot::channel();
+        let service = node.service();
+        tokio::spawn(async move {
+            let addr = format!("[::1]:{}", port).parse().unwrap();
+            Server::builder()
+                .add_service(service)
+                .serve_with_shutdown(addr, async { stopped.await.ok(); })
+                .await
+                .unwrap();
+        });
+        TestNode { protocol: node.start(), node, shutdown }
+    }
+
+    async fn wait_until(what: &str, condition: impl Fn() -> bool) {
+        for _ in 0..200 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("timed out waiting until {}", what);
+    }
+
+    fn states(node: &GossipNode) -> Vec<(String, MemberState)> {
+        node.members().into_iter().map(|m| (m.addr.clone(), m.state())).collect()
+    }
+
+    #[tokio::test]
+    async fn test_nodes_join_detect_failure_and_rejoin() {
+        let ports: Vec<u16> = (0..4)
+            .map(|_| TcpListener::bind("[::1]:0").unwrap().local_addr().unwrap().port())
+            .collect();
+        let addrs: Vec<String> = ports.iter().map(|p| format!("http://[::1]:{}", p)).collect();
+        let seed = vec![addrs[0].clone()];
+
+        let mut nodes: Vec<TestNode> = vec![start(ports[0], &[])];
+        for &port in &ports[1..] {
+            nodes.push(start(port, &seed));
+        }
+
+        // Everyone learns of everyone, though each only contacted the seed
+        let mut all_alive: Vec<(String, MemberState)> = addrs.iter().map(|a| (a.clone(), MemberState::Alive)).collect();
+        all_alive.sort();
+        wait_until("all nodes see each other", || nodes.iter().all(|n| states(&n.node) == all_alive)).await;
+
+        // A stopped node is suspected, then declared dead by the rest
+        let stopped = nodes.pop().unwrap();
+        let mut view = nodes[1].node.subscribe();
+        stopped.stop();
+        wait_until("the stopped node is dead everywhere", || {
+            nodes.iter().all(|n| n.node.state_of(&addrs[3]) == Some(MemberState::Dead))
+        })
+        .await;
+        assert!(view.has_changed().unwrap());
+        assert!(view.borrow_and_update().iter().any(|m| m.addr == addrs[3] && m.state() == MemberState::Dead));
+
+        // Restarted, it refutes its death with a newer incarnation
+        nodes.push(start(ports[3], &seed));
+        wait_until("the restarted node is alive everywhere", || {
+            nodes.iter().all(|n| states(&n.node) == all_alive)
+        })
+        .await;
+        let rejoined = nodes[0].node.members().into_iter().find(|m| m.addr == addrs[3]).unwrap();
+        assert!(rejoined.incarnation > 0);
+
+        for node in nodes {
+            node.stop();
+        }
+    }
+}
@@ -1 +1,4 @@
 // Network module
+pub mod gossip;
+pub mod quorum;
+pub mod ring;
@@ -0,0 +1,105 @@
+use tonic::{Code, Status};
+
+/// Whether a failure means the request never reached the replica, rather
+/// than that the replica refused it.
+pub fn is_unreachable(e: &Status) -> bool {
+    matches!(e.code(), Code::Unavailable | Code::DeadlineExceeded | Code::Cancelled | Code::Unknown)
+}
+
+/// How a write sent to a key's replicas went.
+#[derive(Debug)]
+pub struct WriteTally<T> {
+    /// Answers of the replicas that took the write
+    pub acked: Vec<T>,
+    /// Replicas the write never reached, including those it wasn't sent to
+    pub missed: Vec<String>,
+    /// Each replica that failed, and why
+    pub failures: Vec<(String, Status)>,
+}
+
+impl<T> WriteTally<T> {
+    /// Tallies `results`, one for each of `targets`, the replicas among
+    /// `replicas` the write was sent to.
+    pub fn new(replicas: &[String], targets: &[String], results: Vec<Result<T, Status>>) -> Self {
+        let mut tally = WriteTally {
+            acked: Vec::new(),
+            missed: replicas.iter().filter(|r| !targets.contains(r)).cloned().collect(),
+            failures: Vec::new(),
+        };
+        for (target, result) in targets.iter().zip(results) {
+            match result {
+                Ok(answer) => tally.acked.push(answer),
+                Err(e) => {
+                    if is_unreachable(&e) {
+                        tally.missed.push(target.clone());
+                    }
+                    tally.failures.push((target.clone(), e));
+                }
+            }
+        }
+        tally
+    }
+
+    /// Whether at least `quorum` replicas took the write.
+    pub fn met(&self, quorum: usize) -> bool {
+        self.acked.len() >= quorum
+    }
+
+    pub fn errors(&self) -> Vec<String> {
+        self.failures.iter().map(|(_, e)| e.to_string()).collect()
+    }
+}
+
+/// Where a read answer falls in last-write-wins order: by version, a delete
+/// after a write at the same one. None if the replica knows nothing of the key.
+pub fn recency(found: bool, version: u64) -> Option<(u64, bool)> {
+    (found || version > 0).then_some((version, !found))
+}
+
+/// The index of the newest of `answers`, each a `recency`, and the indices
+/// of those behind it. The first wins a tie. None if no answer knows the key.
+pub fn newest(answers: &[Option<(u64, bool)>]) -> Option<(usize, Vec<usize>)> {
+    let newest = (0..answers.len())
+        .filter(|&i| answers[i].is_some())
+        .fold(None, |newest: Option<usize>, i| match newest {
+            Some(newest) if answers[newest] >= answers[i] => Some(newest),
+            _ => Some(i),
+        })?;
+    let stale = (0..answers.len()).filter(|&i| answers[i] < answers[newest]).collect();
+    Some((newest, stale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_tally() {
+        let replicas: Vec<String> = ["a", "b", "c", "d"].iter().map(|r| r.to_string()).collect();
+        // "d" isn't routable, so the write only went to the first three
+        let results = vec![Ok(1), Err(Status::unavailable("down")), Err(Status::invalid_argument("bad"))];
+        let tally = WriteTally::new(&replicas, &replicas[..3], results);
+
+        assert_eq!(tally.acked, vec![1]);
+        assert!(tally.met(1) && !tally.met(2));
+        // A replica that refused the write has it, as far as hints go
+        assert_eq!(tally.missed, vec!["d".to_string(), "b".to_string()]);
+        let failed: Vec<&str> = tally.failures.iter().map(|(r, _)| r.as_str()).collect();
+        assert_eq!(failed, vec!["b", "c"]);
+        assert_eq!(tally.errors().len(), 2);
+    }
+
+    #[test]
+    fn test_newest_orders_deletes_after_writes() {
+        let write = |version| recency(true, version);
+        let delete = |version| recency(false, version);
+        assert_eq!(recency(false, 0), None);
+
+        assert_eq!(newest(&[write(5), delete(5), write(4)]), Some((1, vec![0, 2])));
+        assert_eq!(newest(&[write(5), delete(4)]), Some((0, vec![1])));
+        // An answer that knows nothing of the key is behind any that does
+        assert_eq!(newest(&[None, write(1)]), Some((1, vec![0])));
+        assert_eq!(newest(&[write(3), write(3)]), Some((0, vec![])));
+        assert_eq!(newest(&[None, None]), None);
+    }
+}
@@ -0,0 +1,541 @@
+use crate::DEFAULT_COLLECTION;
+use std::collections::{BTreeMap, HashMap};
+
+/// Ring points per physical node of weight 1.0, unless configured otherwise.
+pub const DEFAULT_VIRTUAL_NODES: usize = 128;
+
+const PRIME64_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME64_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME64_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME64_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME64_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+/// XXH64, as specified at <https://github.com/Cyan4973/xxHash/blob/dev/doc/xxhash_spec.md>.
+///
+/// Placement must not change between builds, so the ring uses this fixed
+/// algorithm rather than `DefaultHasher`, whose output Rust may change in any
+/// release.
+pub fn xxh64(data: &[u8], seed: u64) -> u64 {
+    let mut rest = data;
+    let mut h = if data.len() >= 32 {
+        let mut lanes = [
+            seed.wrapping_add(PRIME64_1).wrapping_add(PRIME64_2),
+            seed.wrapping_add(PRIME64_2),
+            seed,
+            seed.wrapping_sub(PRIME64_1),
+        ];
+        while rest.len() >= 32 {
+            for (i, lane) in lanes.iter_mut().enumerate() {
+                *lane = xxh64_round(*lane, read_u64(&rest[i * 8..]));
+            }
+            rest = &rest[32..];
+        }
+        let mut h = lanes[0]
+            .rotate_left(1)
+            .wrapping_add(lanes[1].rotate_left(7))
+            .wrapping_add(lanes[2].rotate_left(12))
+            .wrapping_add(lanes[3].rotate_left(18));
+        for lane in lanes {
+            h = (h ^ xxh64_round(0, lane)).wrapping_mul(PRIME64_1).wrapping_add(PRIME64_4);
+        }
+        h
+    } else {
+        seed.wrapping_add(PRIME64_5)
+    };
+
+    h = h.wrapping_add(data.len() as u64);
+    while rest.len() >= 8 {
+        h ^= xxh64_round(0, read_u64(rest));
+        h = h.rotate_left(27).wrapping_mul(PRIME64_1).wrapping_add(PRIME64_4);
+        rest = &rest[8..];
+    }
+    if rest.len() >= 4 {
+        let word = u32::from_le_bytes(rest[..4].try_into().unwrap()) as u64;
+        h ^= word.wrapping_mul(PRIME64_1);
+        h = h.rotate_left(23).wrapping_mul(PRIME64_2).wrapping_add(PRIME64_3);
+        rest = &rest[4..];
+    }
+    for &byte in rest {
+        h ^= (byte as u64).wrapping_mul(PRIME64_5);
+        h = h.rotate_left(11).wrapping_mul(PRIME64_1);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(PRIME64_2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(PRIME64_3);
+    h ^= h >> 32;
+    h
+}
+
+fn xxh64_round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME64_2)).rotate_left(31).wrapping_mul(PRIME64_1)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+/// Ring position of a key: XXH64 (seed 0) of the collection name, a zero
+/// byte, then the id as 4 little-endian bytes. An empty collection name means
+/// `DEFAULT_COLLECTION`. Collection names never contain a zero byte, so
+/// distinct keys never encode alike.
+pub fn key_hash(collection: &str, key: u32) -> u64 {
+    let collection = if collection.is_empty() { DEFAULT_COLLECTION } else { collection };
+    let mut bytes = Vec::with_capacity(collection.len() + 5);
+    bytes.extend_from_slice(collection.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(&key.to_le_bytes());
+    xxh64(&bytes, 0)
+}
+
+/// Ring position of a node's `i`th virtual node: XXH64 (seed 0) of "<addr>#<i>".
+fn point_hash(addr: &str, i: usize) -> u64 {
+    xxh64(format!("{}#{}", addr, i).as_bytes(), 0)
+}
+
+/// Keys whose hash lies in `(start, end]`, wrapping past `u64::MAX` when
+/// `start >= end`. `start == end` covers the whole ring.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HashRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl HashRange {
+    pub fn contains(&self, hash: u64) -> bool {
+        if self.start < self.end {
+            hash > self.start && hash <= self.end
+        } else {
+            hash > self.start || hash <= self.end
+        }
+    }
+}
+
+/// `ranges`, in ring order, with each run of adjacent ranges joined into
+/// one, including a run that wraps from the last range to the first.
+pub fn coalesce(ranges: impl IntoIterator<Item = HashRange>) -> Vec<HashRange> {
+    let mut joined: Vec<HashRange> = Vec::new();
+    for range in ranges {
+        match joined.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => joined.push(range),
+        }
+    }
+    if joined.len() > 1 && joined[joined.len() - 1].end == joined[0].start {
+        let last = joined.pop().unwrap();
+        joined[0].start = last.start;
+    }
+    joined
+}
+
+/// An arc of the ring whose replica set differs between two rings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OwnershipChange {
+    pub range: HashRange,
+    pub old: Vec<String>,
+    pub new: Vec<String>,
+}
+
+/// Every arc whose `n` replicas differ between `old` and `new`. Between two
+/// consecutive points of either ring, both rings map every key to the same
+/// preference list, so comparing one hash per arc is exact.
+pub fn ownership_changes(old: &ConsistentHashRing, new: &ConsistentHashRing, n: usize) -> Vec<OwnershipChange> {
+    let mut boundaries: Vec<u64> = old.points.keys().chain(new.points.keys()).copied().collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut changes = Vec::new();
+    for (i, &end) in boundaries.iter().enumerate() {
+        let start = boundaries[(i + boundaries.len() - 1) % boundaries.len()];
+        let old_owners = old.preference_list_for_hash(end, n);
+        let new_owners = new.preference_list_for_hash(end, n);
+        let mut a = old_owners.clone();
+        let mut b = new_owners.clone();
+        a.sort();
+        b.sort();
+        if a != b {
+            changes.push(OwnershipChange {
+                range: HashRange { start, end },
+                old: old_owners,
+                new: new_owners,
+            });
+        }
+    }
+    changes
+}
+
+/// What a change of rings takes, from `ownership_changes`: the ranges to copy
+/// to each new owner, and those each remaining node gives up.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RebalancePlan {
+    /// (destination, sources) -> ranges. Sources that stay in the cluster
+    /// come first, since nodes being removed may already be gone.
+    pub transfers: BTreeMap<(String, Vec<String>), Vec<HashRange>>,
+    /// Node -> ranges it loses but stays in the cluster
+    pub losses: BTreeMap<String, Vec<HashRange>>,
+}
+
+/// Plans the copies and removals of `changes`, where `target_nodes` are the
+/// nodes of the new ring. A range that had no owner has nothing to copy.
+pub fn rebalance_plan(changes: &[OwnershipChange], target_nodes: &[String]) -> RebalancePlan {
+    let mut plan = RebalancePlan::default();
+    for change in changes {
+        let mut sources = change.old.clone();
+        sources.sort_by_key(|addr| !target_nodes.contains(addr));
+        for dest in change.new.iter().filter(|addr| !change.old.contains(addr)) {
+            if !sources.is_empty() {
+                plan.transfers.entry((dest.clone(), sources.clone())).or_default().push(change.range);
+            }
+        }
+        for node in change.old.iter().filter(|addr| !change.new.contains(addr) && target_nodes.contains(addr)) {
+            plan.losses.entry(node.clone()).or_default().push(change.range);
+        }
+    }
+    plan
+}
+
+/// Which node searches which arcs: each of `assigned` is a node and the
+/// indices of the arcs it was given, and `uncovered` lists arcs none of the
+/// candidates holds.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CoveringPlan {
+    pub assigned: Vec<(String, Vec<usize>)>,
+    pub uncovered: Vec<usize>,
+}
+
+/// Assigns every arc of `arcs` (as from `replica_ranges`) to one of its
+/// replicas among `candidates`, using as few nodes as it can: it repeatedly
+/// picks the candidate holding the most unassigned arcs, the earlier one on a
+/// tie. Greedy set cover isn't always minimal, but it is close. With virtual
+/// nodes every pair of nodes shares some arc, so covering the ring takes
+/// `nodes - n + 1` of them at `n` replicas.
+pub fn covering_plan(arcs: &[(HashRange, Vec<String>)], candidates: &[String]) -> CoveringPlan {
+    let mut open: Vec<usize> = (0..arcs.len()).collect();
+    let mut plan = CoveringPlan::default();
+    let mut remaining: Vec<&String> = candidates.iter().collect();
+    while !open.is_empty() && !remaining.is_empty() {
+        let held = |node: &String| open.iter().filter(|&&i| arcs[i].1.contains(node)).count();
+        let (best, count) = remaining
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (i, held(node)))
+            .fold((0, 0), |best, (i, count)| if count > best.1 { (i, count) } else { best });
+        if count == 0 {
+            break;
+        }
+        let node = remaining.remove(best);
+        let (mine, rest) = open.into_iter().partition(|&i| arcs[i].1.contains(node));
+        open = rest;
+        plan.assigned.push((node.clone(), mine));
+    }
+    plan.uncovered = open;
+    plan
+}
+
+/// Consistent hashing with virtual nodes. Each physical node owns
+/// `virtual_nodes * weight` points (at least one), so nodes of equal weight
+/// get roughly equal shares of the keys, and adding or removing a node only
+/// moves the keys next to its points.
+#[derive(Clone, Debug)]
+pub struct ConsistentHashRing {
+    points: BTreeMap<u64, String>, // Hash -> Node Address
+    weights: BTreeMap<String, f64>,
+    virtual_nodes: usize,
+}
+
+impl Default for ConsistentHashRing {
+    fn default() -> Self {
+        Self::new(DEFAULT_VIRTUAL_NODES)
+    }
+}
+
+impl ConsistentHashRing {
+    pub fn new(virtual_nodes: usize) -> Self {
+        ConsistentHashRing {
+            points: BTreeMap::new(),
+            weights: BTreeMap::new(),
+            virtual_nodes: virtual_nodes.max(1),
+        }
+    }
+
+    pub fn add_node(&mut self, node_addr: &str) {
+        self.add_weighted_node(node_addr, 1.0);
+    }
+
+    /// Adds a node (or re-adds it with a new weight). A weight of 2.0 takes
+    /// about twice the keys of a node of weight 1.0.
+    pub fn add_weighted_node(&mut self, node_addr: &str, weight: f64) {
+        self.remove_node(node_addr);
+        let count = ((self.virtual_nodes as f64 * weight).round() as usize).max(1);
+        for i in 0..count {
+            // On the (vanishingly rare) collision, the point stays with its first owner
+            self.points.entry(point_hash(node_addr, i)).or_insert_with(|| node_addr.to_string());
+        }
+        self.weights.insert(node_addr.to_string(), weight);
+    }
+
+    pub fn remove_node(&mut self, node_addr: &str) {
+        if self.weights.remove(node_addr).is_some() {
+            self.points.retain(|_, addr| addr != node_addr);
+        }
+    }
+
+    // Get the primary node and its successors for replication: the owners of
+    // the first points clockwise from the key, skipping nodes already chosen.
+    // Keys are placed per collection, so each collection spreads over the ring independently.
+    pub fn get_preference_list(&self, collection: &str, key: u32, n: usize) -> Vec<String> {
+        self.preference_list_for_hash(key_hash(collection, key), n)
+    }
+
+    pub fn preference_list_for_hash(&self, hash: u64, n: usize) -> Vec<String> {
+        let mut nodes: Vec<String> = Vec::new();
+        for (_, addr) in self.points.range(hash..).chain(self.points.range(..hash)) {
+            if nodes.len() >= n || nodes.len() == self.weights.len() {
+                break;
+            }
+            if !nodes.contains(addr) {
+                nodes.push(addr.clone());
+            }
+        }
+        nodes
+    }
+
+    /// Every arc between consecutive points, with the `n` replicas of the
+    /// keys on it, in preference order.
+    pub fn replica_ranges(&self, n: usize) -> Vec<(HashRange, Vec<String>)> {
+        let points: Vec<u64> = self.points.keys().copied().collect();
+        points
+            .iter()
+            .enumerate()
+            .map(|(i, &end)| {
+                let start = points[(i + points.len() - 1) % points.len()];
+                (HashRange { start, end }, self.preference_list_for_hash(end, n))
+            })
+            .collect()
+    }
+
+    /// Every physical node, sorted by address.
+    pub fn get_all_nodes(&self) -> Vec<String> {
+        self.weights.keys().cloned().collect()
+    }
+
+    pub fn weights(&self) -> HashMap<String, f64> {
+        self.weights.iter().map(|(addr, w)| (addr.clone(), *w)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xxh64_reference_values() {
+        // From the reference implementation
+        assert_eq!(xxh64(b"", 0), 0xEF46_DB37_51D8_E999);
+        assert_eq!(xxh64(b"a", 0), 0xD24E_C4F1_A98C_6E5B);
+        assert_eq!(xxh64(b"abc", 0), 0x44BC_2CF5_AD77_0999);
+        assert_eq!(xxh64(b"abc", 1), 0xBEA9_CA81_9932_8908);
+        // 79 bytes: two 32-byte stripes, then 8-, 4- and 1-byte tails
+        let long = b"The quick brown fox jumps over the lazy dog, then naps in the warm sun all day.";
+        assert_eq!(xxh64(long, 0), 0x45DD_54B8_2417_7A6A);
+    }
+
+    #[test]
+    fn test_key_hash_is_stable() {
+        // Changing these moves keys between shards on upgrade
+        assert_eq!(key_hash("", 42), key_hash(DEFAULT_COLLECTION, 42));
+        assert_eq!(key_hash("default", 42), 0xAAB9_6E15_3EBF_63D3);
+        assert_ne!(key_hash("docs", 42), key_hash("default", 42));
+    }
+
+    fn key_counts(ring: &ConsistentHashRing, keys: u32) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for key in 0..keys {
+            let primary = ring.get_preference_list("", key, 1).remove(0);
+            *counts.entry(primary).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    // Most loaded node's share relative to a perfectly even split
+    fn skew(counts: &HashMap<String, usize>, keys: u32) -> f64 {
+        let max = *counts.values().max().unwrap() as f64;
+        max / (keys as f64 / counts.len() as f64)
+    }
+
+    #[test]
+    fn test_virtual_nodes_even_out_load() {
+        let keys = 100_000;
+        let nodes = ["http://[::1]:50051", "http://[::1]:50052", "http://[::1]:50053"];
+
+        let mut single = ConsistentHashRing::new(1);
+        let mut virtual_ring = ConsistentHashRing::new(DEFAULT_VIRTUAL_NODES);
+        for node in nodes {
+            single.add_node(node);
+            virtual_ring.add_node(node);
+        }
+        // Placement is fixed, so these are too: 2.125 and 1.102
+        let single_skew = skew(&key_counts(&single, keys), keys);
+        let virtual_skew = skew(&key_counts(&virtual_ring, keys), keys);
+        assert!(single_skew > 2.0, "skew {:.3} with one point per node", single_skew);
+        assert!(virtual_skew < 1.15, "skew {:.3} with virtual nodes", virtual_skew);
+
+        // Weights scale a node's share
+        virtual_ring.add_weighted_node(nodes[2], 2.0);
+        let counts = key_counts(&virtual_ring, keys);
+        let share = counts[nodes[2]] as f64 / keys as f64;
+        assert!((share - 0.5).abs() < 0.06, "weight 2 of 4 took {:.3} of the keys", share);
+    }
+
+    #[test]
+    fn test_ownership_changes_cover_exactly_the_moved_keys() {
+        let mut old = ConsistentHashRing::new(16);
+        for node in ["a", "b", "c"] {
+            old.add_node(node);
+        }
+        let mut new = old.clone();
+        new.add_node("d");
+        new.remove_node("b");
+
+        let changes = ownership_changes(&old, &new, 2);
+        assert!(!changes.is_empty());
+        for key in 0..5000 {
+            let hash = key_hash("", key);
+            let before = old.get_preference_list("", key, 2);
+            let after = new.get_preference_list("", key, 2);
+            let change = changes.iter().find(|c| c.range.contains(hash));
+            match change {
+                Some(c) => {
+                    assert_eq!((&c.old, &c.new), (&before, &after));
+                    assert!(c.new.contains(&"d".to_string()) || c.old.contains(&"b".to_string()));
+                }
+                None => assert_eq!(before, after),
+            }
+        }
+        assert!(ownership_changes(&old, &old, 2).is_empty());
+    }
+
+    #[test]
+    fn test_rebalance_plan_copies_to_new_owners_only() {
+        let nodes = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let range = |end| HashRange { start: end - 1, end };
+        let changes = vec![
+            // "b" leaves, "d" takes its place
+            OwnershipChange { range: range(10), old: nodes(&["a", "b"]), new: nodes(&["a", "d"]) },
+            // "c" stays but hands its range to "d"
+            OwnershipChange { range: range(20), old: nodes(&["c", "a"]), new: nodes(&["d", "a"]) },
+            // Nobody held it before
+            OwnershipChange { range: range(30), old: vec![], new: nodes(&["d"]) },
+        ];
+        let plan = rebalance_plan(&changes, &nodes(&["a", "c", "d"]));
+
+        let transfers: Vec<_> = plan.transfers.into_iter().collect();
+        assert_eq!(transfers, vec![
+            (("d".to_string(), nodes(&["a", "b"])), vec![range(10)]),
+            (("d".to_string(), nodes(&["c", "a"])), vec![range(20)]),
+        ]);
+        let losses: Vec<_> = plan.losses.into_iter().collect();
+        assert_eq!(losses, vec![("c".to_string(), vec![range(20)])], "a leaving node loses nothing it must drop");
+
+        // Sources staying in the cluster are tried first
+        let changes = vec![OwnershipChange { range: range(10), old: nodes(&["b", "a"]), new: nodes(&["a", "d"]) }];
+        let plan = rebalance_plan(&changes, &nodes(&["a", "d"]));
+        assert!(plan.transfers.contains_key(&("d".to_string(), nodes(&["a", "b"]))));
+    }
+
+    #[test]
+    fn test_replica_ranges_partition_the_ring() {
+        let mut ring = ConsistentHashRing::new(16);
+        for node in ["a", "b", "c"] {
+            ring.add_node(node);
+        }
+        let ranges = ring.replica_ranges(2);
+        assert_eq!(ranges.len(), 48);
+        for key in 0..5000 {
+            let hash = key_hash("", key);
+            let holding: Vec<_> = ranges.iter().filter(|(range, _)| range.contains(hash)).collect();
+            assert_eq!(holding.len(), 1);
+            assert_eq!(holding[0].1, ring.get_preference_list("", key, 2));
+        }
+        assert!(ConsistentHashRing::new(16).replica_ranges(2).is_empty());
+    }
+
+    #[test]
+    fn test_covering_plan_assigns_each_arc_once() {
+        let mut ring = ConsistentHashRing::new(16);
+        let nodes: Vec<String> = ["a", "b", "c", "d"].map(String::from).to_vec();
+        for node in &nodes {
+            ring.add_node(node);
+        }
+        // Virtual nodes pair every node with every other, so no fewer than
+        // n - rf + 1 nodes hold every arc
+        for (rf, expected) in [(1, 4), (2, 3), (3, 2), (4, 1)] {
+            let arcs = ring.replica_ranges(rf);
+            let plan = covering_plan(&arcs, &nodes);
+            assert!(plan.uncovered.is_empty());
+            assert_eq!(plan.assigned.len(), expected, "rf {}", rf);
+            let mut seen = vec![0; arcs.len()];
+            for (node, assigned) in &plan.assigned {
+                for &i in assigned {
+                    assert!(arcs[i].1.contains(node));
+                    seen[i] += 1;
+                }
+            }
+            assert!(seen.iter().all(|&n| n == 1));
+        }
+
+        // Without some replicas, the others take over their arcs, until no
+        // replica of an arc is left
+        let arcs = ring.replica_ranges(2);
+        let plan = covering_plan(&arcs, &nodes[1..]);
+        assert!(plan.uncovered.is_empty());
+        assert!(plan.assigned.iter().all(|(node, _)| node != "a"));
+        let plan = covering_plan(&arcs, &nodes[2..]);
+        let lost: Vec<usize> = (0..arcs.len())
+            .filter(|&i| arcs[i].1.iter().all(|node| node == "a" || node == "b"))
+            .collect();
+        assert!(!lost.is_empty());
+        assert_eq!(plan.uncovered, lost);
+        assert_eq!(covering_plan(&arcs, &[]).uncovered.len(), arcs.len());
+    }
+
+    #[test]
+    fn test_hash_range_wraps() {
+        let whole = HashRange { start: 7, end: 7 };
+        assert!(whole.contains(0) && whole.contains(7) && whole.contains(u64::MAX));
+        let wrapping = HashRange { start: u64::MAX - 1, end: 1 };
+        assert!(wrapping.contains(u64::MAX) && wrapping.contains(0) && wrapping.contains(1));
+        assert!(!wrapping.contains(2) && !wrapping.contains(u64::MAX - 1));
+        let plain = HashRange { start: 10, end: 20 };
+        assert!(!plain.contains(10) && plain.contains(11) && plain.contains(20) && !plain.contains(21));
+
+        let r = |start, end| HashRange { start, end };
+        assert_eq!(coalesce([r(1, 2), r(2, 5), r(7, 9), r(9, 1)]), vec![r(7, 5)]);
+        assert_eq!(coalesce([r(1, 2), r(3, 5), r(5, 6)]), vec![r(1, 2), r(3, 6)]);
+        assert_eq!(coalesce([r(9, 1), r(1, 9)]), vec![r(9, 9)]);
+    }
+
+    #[test]
+    fn test_preference_list_and_removal() {
+        let mut ring = ConsistentHashRing::new(16);
+        for port in 1..=4 {
+            ring.add_node(&format!("node{}", port));
+        }
+        let list = ring.get_preference_list("docs", 7, 3);
+        assert_eq!(list.len(), 3);
+        assert_eq!(list.iter().collect::<std::collections::HashSet<_>>().len(), 3);
+        assert_eq!(ring.get_preference_list("docs", 7, 10).len(), 4);
+
+        // Removing a node only moves the keys it owned
+        let before: Vec<String> = (0..1000).map(|k| ring.get_preference_list("", k, 1).remove(0)).collect();
+        ring.remove_node("node2");
+        assert_eq!(ring.get_all_nodes(), vec!["node1", "node3", "node4"]);
+        for (key, owner) in before.iter().enumerate() {
+            let now = ring.get_preference_list("", key as u32, 1).remove(0);
+            if owner != "node2" {
+                assert_eq!(&now, owner);
+            }
+        }
+    }
+}
@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A single metadata value attached to a vector.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum PayloadValue {
+    String(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    StringList(Vec<String>),
+}
+
+/// Key/value metadata stored alongside a vector. Ordered so that
+/// serialized payloads are byte-for-byte deterministic.
+pub type Payload = BTreeMap<String, PayloadValue>;
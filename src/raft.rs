@@ -0,0 +1,1653 @@
+// tonic::Status is large, but it is the natural error type for request handlers
+#![allow(clippy::result_large_err)]
+
+use crate::network::ring::xxh64;
+use crate::wal::{sync_parent, VersionClock};
+use crc32fast::Hasher;
+use futures::future::join_all;
+use futures::stream::{FuturesUnordered, StreamExt};
+use prost::Message;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{oneshot, watch, OwnedMutexGuard};
+use tokio::task::JoinHandle;
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("raft");
+}
+
+use proto::raft_client::RaftClient;
+use proto::raft_server::{Raft, RaftServer};
+pub use proto::{AppendRequest, AppendResponse, LogEntry, VoteRequest, VoteResponse};
+
+/// Metadata on a "not the leader" error naming the leader.
+pub const LEADER_METADATA: &str = "raft-leader";
+
+// Most entries sent in one AppendEntries
+const MAX_BATCH: usize = 64;
+
+// First and longest pause before applying an entry that failed again
+const APPLY_RETRY_PAUSE: Duration = Duration::from_millis(50);
+const MAX_APPLY_RETRY_PAUSE: Duration = Duration::from_secs(5);
+
+const STATE_FILE: &str = "raft.state";
+const LOG_FILE: &str = "raft.log";
+
+#[derive(Clone, Debug)]
+pub struct RaftConfig {
+    // URL the other members reach this one at
+    pub addr: String,
+    // How often the leader sends each follower entries or a heartbeat
+    pub heartbeat_interval: Duration,
+    // A follower that hears from no leader for a random time between this
+    // and twice this stands for election. A leader that hears from no
+    // majority for as long steps down.
+    pub election_timeout: Duration,
+    // Applied entries each member keeps for followers that fall behind. The
+    // log is compacted once twice as many are applied, and never past an
+    // entry some member doesn't hold yet.
+    pub retained_entries: u64,
+    // How long a group goes without writes before its members stop it, if
+    // they ever do
+    pub idle_timeout: Option<Duration>,
+}
+
+impl RaftConfig {
+    pub fn new(addr: &str) -> Self {
+        RaftConfig {
+            addr: addr.to_string(),
+            heartbeat_interval: Duration::from_millis(100),
+            election_timeout: Duration::from_secs(1),
+            retained_entries: 1024,
+            idle_timeout: Some(Duration::from_secs(600)),
+        }
+    }
+}
+
+/// Names the group of a replica set, whatever order its members are listed in.
+pub fn group_id(members: &[String]) -> String {
+    format!("{:016x}", xxh64(normalize(members).join("\n").as_bytes(), 0))
+}
+
+fn normalize(members: &[String]) -> Vec<String> {
+    let mut members = members.to_vec();
+    members.sort();
+    members.dedup();
+    members
+}
+
+/// What a member that isn't the leader answers a proposal with: a
+/// FAILED_PRECONDITION naming the leader, or UNAVAILABLE if it knows of none.
+pub fn not_leader(leader: Option<&str>) -> Status {
+    let Some(leader) = leader else {
+        return Status::unavailable("The shard's Raft group has no leader");
+    };
+    let mut status = Status::failed_precondition(format!("Not the leader of the shard's Raft group; {} is", leader));
+    if let Ok(value) = MetadataValue::try_from(leader) {
+        status.metadata_mut().insert(LEADER_METADATA, value);
+    }
+    status
+}
+
+/// The leader a `not_leader` error points to.
+pub fn leader_hint(status: &Status) -> Option<String> {
+    status.metadata().get(LEADER_METADATA)?.to_str().ok().map(str::to_string)
+}
+
+fn stopped() -> Status {
+    Status::unavailable("The shard's Raft group stopped")
+}
+
+/// How members reach each other.
+#[tonic::async_trait]
+pub trait Transport: Send + Sync + 'static {
+    async fn request_vote(&self, to: &str, req: VoteRequest) -> Result<VoteResponse, Status>;
+    async fn append_entries(&self, to: &str, req: AppendRequest) -> Result<AppendResponse, Status>;
+}
+
+/// What committed entries are applied to.
+#[tonic::async_trait]
+pub trait StateMachine: Send + Sync + 'static {
+    /// Applies a committed entry. Every member applies the same entries in
+    /// the same order, except that entries applied since the member last
+    /// saved how far it got may be applied again after a crash. Returns whether the entry changed anything. An error goes back to the
+    /// proposer; INVALID_ARGUMENT means the entry can never apply, and it
+    /// counts as applied, while any other error is retried, holding back the
+    /// entries after it.
+    async fn apply(&self, entry: &LogEntry) -> Result<bool, Status>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+// What a member must not forget across restarts
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+struct HardState {
+    members: Vec<String>,
+    term: u64,
+    voted_for: Option<String>,
+    // Entries up to here were applied, so were committed too
+    applied: u64,
+    // The last entry dropped from the front of the log
+    compacted: Compacted,
+}
+
+// What is left of a compacted entry: enough to check that a follower's log
+// agrees with it, and to keep versions increasing after it
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+struct Compacted {
+    index: u64,
+    term: u64,
+    version: u64,
+}
+
+impl Compacted {
+    fn of(entry: &LogEntry) -> Self {
+        Compacted { index: entry.index, term: entry.term, version: entry.version }
+    }
+}
+
+/// Where a member keeps its state and log: a directory, or nowhere.
+struct Storage {
+    dir: Option<PathBuf>,
+    log: Option<File>,
+    // The state as last saved
+    saved: HardState,
+    // Index of the first entry in the log file, and where each entry ends
+    first: u64,
+    ends: Vec<u64>,
+}
+
+// What changed in memory since the last flush: the state if it did, then
+// the entries after those the log file still agrees on
+struct Flush {
+    hard: Option<HardState>,
+    rewrite: bool,
+    keep: u64,
+    entries: Vec<LogEntry>,
+    last: u64,
+}
+
+impl Storage {
+    fn memory(hard: &HardState) -> Self {
+        Storage { dir: None, log: None, saved: hard.clone(), first: hard.compacted.index + 1, ends: Vec::new() }
+    }
+
+    /// Opens `dir`, creating it if needed, with the state and the entries
+    /// after the compacted ones already in it. A torn entry at the end of the
+    /// log is dropped along with anything after it; it was never acknowledged.
+    fn open(dir: &Path) -> io::Result<(Self, Option<HardState>, Vec<LogEntry>)> {
+        fs::create_dir_all(dir)?;
+        let state_path = dir.join(STATE_FILE);
+        let hard: Option<HardState> = if state_path.exists() {
+            let reader = BufReader::new(File::open(&state_path)?);
+            Some(bincode::deserialize_from(reader).map_err(io::Error::other)?)
+        } else {
+            None
+        };
+        let log_path = dir.join(LOG_FILE);
+        let (mut entries, ends) = if log_path.exists() { read_log(&log_path)? } else { (Vec::new(), Vec::new()) };
+        let log = OpenOptions::new().create(true).append(true).open(&log_path)?;
+        log.set_len(ends.last().copied().unwrap_or(0))?;
+        // A crash while compacting can leave compacted entries in the file
+        let compacted = hard.as_ref().map_or(0, |h| h.compacted.index);
+        let first = entries.first().map_or(compacted + 1, |e| e.index);
+        entries.retain(|e| e.index > compacted);
+        let storage = Storage {
+            dir: Some(dir.to_path_buf()),
+            log: Some(log),
+            saved: hard.clone().unwrap_or_default(),
+            first,
+            ends,
+        };
+        Ok((storage, hard, entries))
+    }
+
+    // Index of the last entry in the log file
+    fn last(&self) -> u64 {
+        self.first + self.ends.len() as u64 - 1
+    }
+
+    fn write(&mut self, flush: &Flush) -> io::Result<()> {
+        // The state goes first: entries it compacts away are ignored if the
+        // log isn't rewritten yet
+        if let Some(hard) = &flush.hard {
+            self.save_state(hard)?;
+        }
+        if flush.rewrite {
+            self.rewrite(flush.keep, &flush.entries)
+        } else {
+            self.truncate(flush.keep)?;
+            self.append(&flush.entries)
+        }
+    }
+
+    fn save_state(&mut self, hard: &HardState) -> io::Result<()> {
+        if let Some(dir) = &self.dir {
+            let tmp_path = dir.join(format!("{}.tmp", STATE_FILE));
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            bincode::serialize_into(&mut writer, hard).map_err(io::Error::other)?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+            fs::rename(&tmp_path, dir.join(STATE_FILE))?;
+        }
+        self.saved = hard.clone();
+        Ok(())
+    }
+
+    fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        let Some(first) = entries.first() else { return Ok(()) };
+        if self.ends.is_empty() {
+            self.first = first.index;
+        }
+        let mut end = self.ends.last().copied().unwrap_or(0);
+        let mut bytes = Vec::new();
+        for entry in entries {
+            let record = encode(entry);
+            end += record.len() as u64;
+            self.ends.push(end);
+            bytes.extend_from_slice(&record);
+        }
+        let Some(log) = &mut self.log else { return Ok(()) };
+        log.write_all(&bytes)?;
+        log.sync_data()
+    }
+
+    /// Drops the entries after `last`, in place.
+    fn truncate(&mut self, last: u64) -> io::Result<()> {
+        if last >= self.last() {
+            return Ok(());
+        }
+        let keep = last.saturating_sub(self.first - 1) as usize;
+        self.ends.truncate(keep);
+        if keep == 0 {
+            self.first = last + 1;
+        }
+        let Some(log) = &mut self.log else { return Ok(()) };
+        log.set_len(self.ends.last().copied().unwrap_or(0))?;
+        log.sync_data()
+    }
+
+    /// Replaces the whole log with the entries after `compacted`.
+    fn rewrite(&mut self, compacted: u64, entries: &[LogEntry]) -> io::Result<()> {
+        self.first = compacted + 1;
+        self.ends.clear();
+        let Some(dir) = &self.dir else { return self.append(entries) };
+        let path = dir.join(LOG_FILE);
+        let tmp_path = dir.join(format!("{}.tmp", LOG_FILE));
+        let mut tmp = File::create(&tmp_path)?;
+        let mut end = 0;
+        for entry in entries {
+            let record = encode(entry);
+            tmp.write_all(&record)?;
+            end += record.len() as u64;
+            self.ends.push(end);
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        sync_parent(&path)?;
+        self.log = Some(OpenOptions::new().append(true).open(&path)?);
+        Ok(())
+    }
+}
+
+// [CRC32 (4 bytes)] [Length (8 bytes)] [Data], as in the WAL
+fn encode(entry: &LogEntry) -> Vec<u8> {
+    let data = entry.encode_to_vec();
+    let mut hasher = Hasher::new();
+    hasher.update(&data);
+    let mut record = Vec::with_capacity(data.len() + 12);
+    record.extend_from_slice(&hasher.finalize().to_le_bytes());
+    record.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    record.extend_from_slice(&data);
+    record
+}
+
+// The intact entries in a log file, and where each ends
+fn read_log(path: &Path) -> io::Result<(Vec<LogEntry>, Vec<u64>)> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut entries = Vec::new();
+    let mut ends = Vec::new();
+    let mut valid_len = 0;
+    loop {
+        let mut header = [0u8; 12];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let len = u64::from_le_bytes(header[4..].try_into().unwrap());
+        if valid_len + 12 + len > file_len {
+            println!("Ignoring a torn entry at the end of {}", path.display());
+            break;
+        }
+        let mut data = vec![0u8; len as usize];
+        reader.read_exact(&mut data)?;
+        let mut hasher = Hasher::new();
+        hasher.update(&data);
+        if hasher.finalize() != crc {
+            println!("Ignoring a torn entry at the end of {}", path.display());
+            break;
+        }
+        entries.push(LogEntry::decode(&data[..]).map_err(io::Error::other)?);
+        valid_len += 12 + len;
+        ends.push(valid_len);
+    }
+    Ok((entries, ends))
+}
+
+struct State {
+    role: Role,
+    hard: HardState,
+    leader: Option<String>,
+    // The entries after `hard.compacted`
+    log: Vec<LogEntry>,
+    // Entries up to here are on disk as they are in `log`
+    synced: u64,
+    // Entries up to here are held by every member, so compacting them away
+    // leaves no one behind. The leader tracks it, and tells the followers.
+    held_by_all: u64,
+    commit: u64,
+    election_deadline: Instant,
+    // Leader only, per follower: the next entry to send, the last entry
+    // known to be replicated, and when it last answered
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    heard_from: HashMap<String, Instant>,
+    // Leader only: proposals waiting to be applied, by index
+    waiters: BTreeMap<u64, oneshot::Sender<Result<bool, Status>>>,
+    // When a proposal or a candidate last needed this member. An idle
+    // member nobody needs doesn't stand for election.
+    wanted_at: Option<Instant>,
+    stopped: bool,
+}
+
+impl State {
+    fn last_index(&self) -> u64 {
+        self.hard.compacted.index + self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(self.hard.compacted.term, |e| e.term)
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        let compacted = &self.hard.compacted;
+        match index {
+            i if i < compacted.index => None,
+            i if i == compacted.index => Some(compacted.term),
+            _ => self.log.get((index - compacted.index) as usize - 1).map(|e| e.term),
+        }
+    }
+
+    // The entries after `index` up to `last`; those still in the log
+    fn entries(&self, index: u64, last: u64) -> &[LogEntry] {
+        let compacted = self.hard.compacted.index;
+        let end = (last.saturating_sub(compacted) as usize).min(self.log.len());
+        &self.log[(index.saturating_sub(compacted) as usize).min(end)..end]
+    }
+
+    // Drops applied entries from the front of the log, keeping the last `keep`
+    // for followers that fall behind. With `keep` above zero, waits until
+    // twice as many are applied, so the log file is rewritten only that often.
+    fn compact(&mut self, keep: u64) {
+        let compacted = self.hard.compacted.index;
+        let through = self.hard.applied.saturating_sub(keep).min(self.held_by_all);
+        if through <= compacted || through < compacted + keep {
+            return;
+        }
+        let dropped = (through - compacted) as usize;
+        self.hard.compacted = Compacted::of(&self.log[dropped - 1]);
+        self.log.drain(..dropped);
+    }
+}
+
+/// One member of a Raft group (Ongaro and Ousterhout, 2014).
+///
+/// Followers that stop hearing from a leader stand for election; a
+/// candidate with a majority of votes leads its term, and first commits a
+/// no-op so entries of earlier terms commit with it. The leader appends
+/// proposals to its log and replicates them; an entry held by a majority is
+/// committed and applied by every member in log order. Each entry carries a
+/// version the leader stamps, increasing along the log, so last-write-wins
+/// agrees with the log's order. A leader that loses touch with a majority
+/// steps down, failing its pending proposals rather than holding them.
+///
+/// Members write their log and state off the runtime, one flush at a time,
+/// so proposals that arrive during a flush share the next one. Applied
+/// entries are compacted away, keeping `retained_entries` for followers that
+/// fall behind, and any a follower further behind still needs. A group that goes
+/// `idle_timeout` without a write stops, and starts again when needed.
+pub struct RaftNode {
+    config: RaftConfig,
+    group: String,
+    members: Vec<String>,
+    state: Mutex<State>,
+    // Held while flushing, and while changing the log other than by
+    // appending to it. Taken before `state`.
+    storage: Arc<tokio::sync::Mutex<Storage>>,
+    transport: Arc<dyn Transport>,
+    machine: Arc<dyn StateMachine>,
+    clock: VersionClock,
+    // Index of the last entry appended, to wake the replication loops
+    appended: watch::Sender<u64>,
+    // Commit index, to wake the apply loop
+    committed: watch::Sender<u64>,
+}
+
+impl RaftNode {
+    /// A member that keeps nothing across restarts.
+    pub fn new(
+        config: RaftConfig,
+        members: &[String],
+        transport: Arc<dyn Transport>,
+        machine: Arc<dyn StateMachine>,
+    ) -> Arc<Self> {
+        let hard = HardState { members: normalize(members), ..Default::default() };
+        Self::restore(config, Storage::memory(&hard), hard, Vec::new(), transport, machine)
+    }
+
+    /// A member that keeps its state in `dir`, picking up where it left off.
+    pub fn open(
+        config: RaftConfig,
+        dir: &Path,
+        members: &[String],
+        transport: Arc<dyn Transport>,
+        machine: Arc<dyn StateMachine>,
+    ) -> io::Result<Arc<Self>> {
+        let members = normalize(members);
+        let (mut storage, hard, log) = Storage::open(dir)?;
+        let hard = match hard {
+            Some(hard) if hard.members != members => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} holds the group of {:?}, not {:?}", dir.display(), hard.members, members),
+                ));
+            }
+            Some(hard) => hard,
+            None => {
+                let hard = HardState { members, ..Default::default() };
+                storage.save_state(&hard)?;
+                hard
+            }
+        };
+        Ok(Self::restore(config, storage, hard, log, transport, machine))
+    }
+
+    fn restore(
+        config: RaftConfig,
+        storage: Storage,
+        hard: HardState,
+        log: Vec<LogEntry>,
+        transport: Arc<dyn Transport>,
+        machine: Arc<dyn StateMachine>,
+    ) -> Arc<Self> {
+        let mut state = State {
+            role: Role::Follower,
+            leader: None,
+            log,
+            synced: 0,
+            held_by_all: 0,
+            commit: 0,
+            election_deadline: Instant::now() + random_timeout(config.election_timeout),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            heard_from: HashMap::new(),
+            waiters: BTreeMap::new(),
+            wanted_at: None,
+            stopped: false,
+            hard,
+        };
+        state.synced = state.last_index();
+        state.commit = state.hard.applied.min(state.last_index());
+        let clock = VersionClock::default();
+        Arc::new(RaftNode {
+            group: group_id(&state.hard.members),
+            members: state.hard.members.clone(),
+            appended: watch::channel(state.last_index()).0,
+            committed: watch::channel(state.commit).0,
+            state: Mutex::new(state),
+            storage: Arc::new(tokio::sync::Mutex::new(storage)),
+            config,
+            transport,
+            machine,
+            clock,
+        })
+    }
+
+    pub fn group(&self) -> &str {
+        &self.group
+    }
+
+    pub fn members(&self) -> &[String] {
+        &self.members
+    }
+
+    pub fn role(&self) -> Role {
+        self.state.lock().unwrap().role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.state.lock().unwrap().hard.term
+    }
+
+    /// The leader of the current term, if this member knows it.
+    pub fn leader(&self) -> Option<String> {
+        self.state.lock().unwrap().leader.clone()
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.state.lock().unwrap().commit
+    }
+
+    pub fn applied_index(&self) -> u64 {
+        self.state.lock().unwrap().hard.applied
+    }
+
+    /// Index of the last entry compacted away.
+    pub fn compacted_index(&self) -> u64 {
+        self.state.lock().unwrap().hard.compacted.index
+    }
+
+    /// The entries not compacted away yet.
+    pub fn log(&self) -> Vec<LogEntry> {
+        self.state.lock().unwrap().log.clone()
+    }
+
+    /// Whether the group went idle and stopped here.
+    pub fn is_stopped(&self) -> bool {
+        self.state.lock().unwrap().stopped
+    }
+
+    /// Runs `run` in a task of its own.
+    pub fn start(self: &Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(self.clone().run())
+    }
+
+    /// Runs elections, replication and the apply loop until the group goes
+    /// idle and stops.
+    pub async fn run(self: Arc<Self>) {
+        let node = &self;
+        let mut loops: Vec<Pin<Box<dyn Future<Output = ()> + Send + '_>>> = vec![Box::pin(node.run_apply())];
+        for peer in node.peers() {
+            loops.push(Box::pin(node.replicate_to(peer)));
+        }
+        tokio::select! {
+            _ = node.run_elections() => {}
+            _ = join_all(loops) => {}
+        }
+        // Wait out a flush still writing, so the files are free once this returns
+        let _ = node.storage.lock().await;
+    }
+
+    /// Appends `data` to the log if this member is the leader, and waits
+    /// until it is applied here. Returns what applying it returned.
+    pub async fn propose(&self, data: Vec<u8>) -> Result<bool, Status> {
+        let (index, applied) = {
+            let mut state = self.state.lock().unwrap();
+            if state.stopped {
+                return Err(stopped());
+            }
+            state.wanted_at = Some(Instant::now());
+            if state.role != Role::Leader {
+                return Err(not_leader(state.leader.as_deref()));
+            }
+            let index = self.append_local(&mut state, data);
+            let (reply, applied) = oneshot::channel();
+            state.waiters.insert(index, reply);
+            (index, applied)
+        };
+        // Proposals appended while an earlier flush runs share the next one
+        let storage = self.storage.clone().lock_owned().await;
+        if let Err(e) = self.flush(storage).await {
+            self.state.lock().unwrap().waiters.remove(&index);
+            return Err(e);
+        }
+        applied.await.unwrap_or_else(|_| Err(stopped()))
+    }
+
+    pub async fn handle_vote(&self, req: VoteRequest) -> Result<VoteResponse, Status> {
+        let storage = self.storage.clone().lock_owned().await;
+        let resp = {
+            let mut state = self.state.lock().unwrap();
+            state.wanted_at = Some(Instant::now());
+            if req.term > state.hard.term {
+                self.become_follower(&mut state, req.term, None);
+            }
+            // Only a candidate holding every committed entry can win
+            let up_to_date = (req.last_log_term, req.last_log_index) >= (state.last_term(), state.last_index());
+            let granted = req.term == state.hard.term
+                && up_to_date
+                && state.hard.voted_for.as_ref().is_none_or(|v| *v == req.candidate);
+            if granted {
+                state.hard.voted_for = Some(req.candidate);
+                state.election_deadline = self.next_deadline();
+            }
+            VoteResponse { term: state.hard.term, granted }
+        };
+        self.flush(storage).await?;
+        Ok(resp)
+    }
+
+    pub async fn handle_append(&self, req: AppendRequest) -> Result<AppendResponse, Status> {
+        let storage = self.storage.clone().lock_owned().await;
+        let (resp, commit) = self.append_entries(&req);
+        let _storage = self.flush(storage).await?;
+        if let Some(commit) = commit {
+            let mut state = self.state.lock().unwrap();
+            if commit > state.commit {
+                state.commit = commit;
+                self.committed.send_replace(commit);
+            }
+        }
+        Ok(resp)
+    }
+
+    // Applies an AppendEntries to the log in memory. Returns the response,
+    // and on success how far the entries let this member commit, once they
+    // are flushed.
+    fn append_entries(&self, req: &AppendRequest) -> (AppendResponse, Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        if req.term < state.hard.term {
+            let resp = AppendResponse { term: state.hard.term, success: false, last_index: state.last_index() };
+            return (resp, None);
+        }
+        if req.term > state.hard.term || state.role != Role::Follower || state.leader.as_ref() != Some(&req.leader) {
+            self.become_follower(&mut state, req.term, Some(req.leader.clone()));
+        }
+        state.election_deadline = self.next_deadline();
+
+        // Entries compacted away here were applied, so agree with the leader's
+        let compacted = state.hard.compacted;
+        let (mut prev_index, mut prev_term, mut entries) = (req.prev_log_index, req.prev_log_term, &req.entries[..]);
+        if prev_index < compacted.index {
+            entries = &entries[((compacted.index - prev_index) as usize).min(entries.len())..];
+            (prev_index, prev_term) = (compacted.index, compacted.term);
+        }
+
+        if state.term_at(prev_index) != Some(prev_term) {
+            let last_index = state.last_index().min(prev_index.saturating_sub(1));
+            return (AppendResponse { term: state.hard.term, success: false, last_index }, None);
+        }
+
+        // Skip entries already held; from the first that isn't, the leader's log wins
+        let held = entries.iter().take_while(|e| state.term_at(e.index) == Some(e.term)).count();
+        let new = &entries[held..];
+        if let Some(first) = new.first() {
+            if first.index <= state.last_index() {
+                println!(
+                    "[raft {}] Dropping {} entries from {} that conflict with the leader's",
+                    self.group,
+                    state.last_index() - first.index + 1,
+                    first.index
+                );
+                let keep = (first.index - state.hard.compacted.index - 1) as usize;
+                state.log.truncate(keep);
+                state.synced = state.synced.min(first.index - 1);
+            }
+            state.log.extend(new.iter().cloned());
+        }
+
+        let last_new = req.prev_log_index + req.entries.len() as u64;
+        state.held_by_all = state.held_by_all.max(req.compact_through.min(last_new));
+        let resp = AppendResponse { term: state.hard.term, success: true, last_index: state.last_index() };
+        (resp, Some(req.leader_commit.min(last_new)))
+    }
+
+    // Writes what changed in memory since the last flush, off the runtime.
+    // Holding `storage` throughout, nothing but appends change the log meanwhile.
+    async fn flush(&self, storage: OwnedMutexGuard<Storage>) -> Result<OwnedMutexGuard<Storage>, Status> {
+        let flush = {
+            let state = self.state.lock().unwrap();
+            if state.stopped {
+                return Err(stopped());
+            }
+            let compacted = state.hard.compacted.index;
+            let rewrite = storage.first != compacted + 1;
+            let keep = if rewrite { compacted } else { state.synced };
+            Flush {
+                hard: (state.hard != storage.saved).then(|| state.hard.clone()),
+                rewrite,
+                keep,
+                entries: state.entries(keep, u64::MAX).to_vec(),
+                last: state.last_index(),
+            }
+        };
+        let mut storage = storage;
+        if flush.hard.is_some() || flush.rewrite || !flush.entries.is_empty() || storage.last() > flush.keep {
+            let (returned, result) = tokio::task::spawn_blocking(move || {
+                let result = storage.write(&flush);
+                (storage, result.map(|()| flush.last))
+            })
+            .await
+            .map_err(|e| Status::internal(format!("Failed to write the Raft log: {}", e)))?;
+            storage = returned;
+            let last = result.map_err(|e| Status::internal(format!("Failed to write the Raft log: {}", e)))?;
+            let mut state = self.state.lock().unwrap();
+            state.synced = state.synced.max(last);
+            if state.role == Role::Leader {
+                self.advance_commit(&mut state);
+            }
+        }
+        Ok(storage)
+    }
+
+    fn peers(&self) -> Vec<String> {
+        self.members.iter().filter(|m| **m != self.config.addr).cloned().collect()
+    }
+
+    fn majority(&self) -> usize {
+        self.members.len() / 2 + 1
+    }
+
+    fn next_deadline(&self) -> Instant {
+        Instant::now() + random_timeout(self.config.election_timeout)
+    }
+
+    // A term change is saved with the next flush, before anything is sent in it
+    fn become_follower(&self, state: &mut State, term: u64, leader: Option<String>) {
+        if term > state.hard.term {
+            state.hard.term = term;
+            state.hard.voted_for = None;
+        }
+        if state.role == Role::Leader {
+            println!("[raft {}] Stepping down in term {}", self.group, state.hard.term);
+            for (_, reply) in std::mem::take(&mut state.waiters) {
+                let _ = reply.send(Err(Status::unavailable(
+                    "Lost leadership before the write committed; it may still be applied",
+                )));
+            }
+        }
+        state.role = Role::Follower;
+        state.leader = leader;
+    }
+
+    fn become_leader(&self, state: &mut State) {
+        println!("[raft {}] Elected leader in term {}", self.group, state.hard.term);
+        state.role = Role::Leader;
+        state.leader = Some(self.config.addr.clone());
+        let next = state.last_index() + 1;
+        let now = Instant::now();
+        for peer in self.peers() {
+            state.next_index.insert(peer.clone(), next);
+            state.match_index.insert(peer.clone(), 0);
+            state.heard_from.insert(peer, now);
+        }
+        // Entries of earlier terms only commit along with one of this term
+        self.append_local(state, Vec::new());
+    }
+
+    // Appends an entry of the current term to the leader's log in memory;
+    // returns its index. It counts towards a commit once flushed.
+    fn append_local(&self, state: &mut State, data: Vec<u8>) -> u64 {
+        let last_version = state.log.last().map_or(state.hard.compacted.version, |e| e.version);
+        let entry = LogEntry {
+            term: state.hard.term,
+            index: state.last_index() + 1,
+            version: self.clock.next().max(last_version + 1),
+            data,
+        };
+        let index = entry.index;
+        state.log.push(entry);
+        self.appended.send_replace(index);
+        index
+    }
+
+    // Commits up to the highest entry of this term a majority holds
+    fn advance_commit(&self, state: &mut State) {
+        let mut matched: Vec<u64> = self.peers()
+            .iter()
+            .map(|peer| state.match_index.get(peer).copied().unwrap_or(0))
+            .collect();
+        matched.push(state.synced.min(state.last_index()));
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        // Every member holds what the one furthest behind does
+        state.held_by_all = state.held_by_all.max(*matched.last().unwrap());
+        let index = matched[self.majority() - 1];
+        if index > state.commit && state.term_at(index) == Some(state.hard.term) {
+            state.commit = index;
+            self.committed.send_replace(index);
+        }
+    }
+
+    // Returns once the group stops
+    async fn run_elections(&self) {
+        loop {
+            let deadline = self.state.lock().unwrap().election_deadline;
+            tokio::time::sleep_until(deadline.into()).await;
+            let (role, idle) = {
+                let mut state = self.state.lock().unwrap();
+                if Instant::now() < state.election_deadline {
+                    continue;
+                }
+                state.election_deadline = self.next_deadline();
+                (state.role, self.idle(&state))
+            };
+            if idle && self.stop_if_idle().await {
+                return;
+            }
+            match role {
+                Role::Leader => self.check_quorum(),
+                Role::Follower | Role::Candidate => self.campaign().await,
+            }
+        }
+    }
+
+    // Whether no write reached the group for `idle_timeout`, everything in
+    // the log here is applied, and nobody asked this member for a leader
+    fn idle(&self, state: &State) -> bool {
+        let Some(timeout) = self.config.idle_timeout else { return false };
+        let last_write = state.log
+            .iter()
+            .rev()
+            .find(|e| !e.data.is_empty())
+            .map_or(state.hard.compacted.version, |e| e.version);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0);
+        now.saturating_sub(last_write) >= timeout.as_micros() as u64
+            && state.waiters.is_empty()
+            && state.hard.applied == state.last_index()
+            && state.wanted_at.is_none_or(|at| at.elapsed() >= timeout)
+    }
+
+    // Steps down, compacts the whole log, and marks the group stopped, unless
+    // something needed it meanwhile. Every member sees the same last write,
+    // so they all go idle, and none stands for election to wake the others.
+    async fn stop_if_idle(&self) -> bool {
+        let storage = self.storage.clone().lock_owned().await;
+        {
+            let mut state = self.state.lock().unwrap();
+            if !self.idle(&state) {
+                return false;
+            }
+            let term = state.hard.term;
+            self.become_follower(&mut state, term, None);
+            state.compact(0);
+        }
+        let _storage = match self.flush(storage).await {
+            Ok(storage) => storage,
+            Err(e) => {
+                println!("[raft {}] {}", self.group, e.message());
+                return false;
+            }
+        };
+        let mut state = self.state.lock().unwrap();
+        if !self.idle(&state) {
+            return false;
+        }
+        println!("[raft {}] Stopping after {:?} without writes", self.group, self.config.idle_timeout.unwrap());
+        state.stopped = true;
+        true
+    }
+
+    // A leader cut off from a majority can't commit anything, so it steps
+    // down rather than leave proposals waiting for the partition to heal
+    fn check_quorum(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.role != Role::Leader {
+            return;
+        }
+        let heard = 1 + self.peers()
+            .iter()
+            .filter(|peer| state.heard_from.get(*peer).is_some_and(|at| at.elapsed() < self.config.election_timeout))
+            .count();
+        if heard < self.majority() {
+            println!("[raft {}] Lost touch with a majority", self.group);
+            let term = state.hard.term;
+            self.become_follower(&mut state, term, None);
+        }
+    }
+
+    async fn campaign(&self) {
+        let storage = self.storage.clone().lock_owned().await;
+        let req = {
+            let mut state = self.state.lock().unwrap();
+            if state.role == Role::Leader {
+                return;
+            }
+            state.hard.term += 1;
+            state.hard.voted_for = Some(self.config.addr.clone());
+            state.role = Role::Candidate;
+            state.leader = None;
+            if self.majority() == 1 {
+                self.become_leader(&mut state);
+                None
+            } else {
+                println!("[raft {}] Standing for election in term {}", self.group, state.hard.term);
+                Some(VoteRequest {
+                    members: self.members.clone(),
+                    term: state.hard.term,
+                    candidate: self.config.addr.clone(),
+                    last_log_index: state.last_index(),
+                    last_log_term: state.last_term(),
+                })
+            }
+        };
+        // The vote for itself is saved before any other is asked for
+        if let Err(e) = self.flush(storage).await {
+            println!("[raft {}] {}", self.group, e.message());
+            return;
+        }
+        let Some(req) = req else { return };
+
+        let peers = self.peers();
+        let mut votes: FuturesUnordered<_> = peers
+            .iter()
+            .map(|peer| self.transport.request_vote(peer, req.clone()))
+            .collect();
+        let mut granted = 1;
+        while let Some(vote) = votes.next().await {
+            let Ok(vote) = vote else { continue };
+            {
+                let mut state = self.state.lock().unwrap();
+                if vote.term > state.hard.term {
+                    self.become_follower(&mut state, vote.term, None);
+                    return;
+                }
+                if state.role != Role::Candidate || state.hard.term != req.term {
+                    return;
+                }
+                if !vote.granted {
+                    continue;
+                }
+                granted += 1;
+                if granted < self.majority() {
+                    continue;
+                }
+                self.become_leader(&mut state);
+            }
+            // Flushes the no-op, so it counts towards its own commit
+            let storage = self.storage.clone().lock_owned().await;
+            if let Err(e) = self.flush(storage).await {
+                println!("[raft {}] {}", self.group, e.message());
+            }
+            return;
+        }
+    }
+
+    async fn replicate_to(&self, peer: String) {
+        let mut appended = self.appended.subscribe();
+        loop {
+            appended.borrow_and_update();
+            let more = match self.append_request(&peer) {
+                Some(req) => match self.transport.append_entries(&peer, req.clone()).await {
+                    Ok(resp) => self.handle_append_response(&peer, &req, resp),
+                    Err(_) => false,
+                },
+                None => false,
+            };
+            // Heartbeats go out every interval; new entries, and the rest of
+            // a backlog, right away
+            if !more {
+                let _ = tokio::time::timeout(self.config.heartbeat_interval, appended.changed()).await;
+            }
+        }
+    }
+
+    // The next entries for `peer`, if this member is the leader. A follower
+    // that needs entries compacted away, having lost its log, is only sent
+    // heartbeats.
+    fn append_request(&self, peer: &str) -> Option<AppendRequest> {
+        let state = self.state.lock().unwrap();
+        if state.role != Role::Leader {
+            return None;
+        }
+        let prev = (state.next_index[peer] - 1).max(state.hard.compacted.index);
+        Some(AppendRequest {
+            members: self.members.clone(),
+            term: state.hard.term,
+            leader: self.config.addr.clone(),
+            prev_log_index: prev,
+            prev_log_term: state.term_at(prev).unwrap_or(0),
+            entries: state.entries(prev, u64::MAX).iter().take(MAX_BATCH).cloned().collect(),
+            leader_commit: state.commit,
+            compact_through: state.held_by_all,
+        })
+    }
+
+    // Returns whether `peer` has more entries to catch up on
+    fn handle_append_response(&self, peer: &str, req: &AppendRequest, resp: AppendResponse) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if resp.term > state.hard.term {
+            self.become_follower(&mut state, resp.term, None);
+            return false;
+        }
+        if state.role != Role::Leader || state.hard.term != req.term {
+            return false;
+        }
+        state.heard_from.insert(peer.to_string(), Instant::now());
+        if resp.success {
+            let matched = req.prev_log_index + req.entries.len() as u64;
+            let match_index = state.match_index.get_mut(peer).unwrap();
+            *match_index = (*match_index).max(matched);
+            let next_index = state.next_index.get_mut(peer).unwrap();
+            *next_index = (*next_index).max(matched + 1);
+            self.advance_commit(&mut state);
+        } else {
+            // Back up to where the logs may agree
+            let compacted = state.hard.compacted.index;
+            let next_index = state.next_index.get_mut(peer).unwrap();
+            let was = *next_index;
+            *next_index = (*next_index - 1).min(resp.last_index + 1).max(1);
+            if *next_index <= compacted {
+                if was > compacted {
+                    println!(
+                        "[raft {}] {} needs entries compacted away here; it can't catch up without its data",
+                        self.group, peer
+                    );
+                }
+                return false;
+            }
+        }
+        state.next_index[peer] <= state.last_index()
+    }
+
+    // Applies committed entries in order, and saves how far it got once per
+    // batch, compacting the log as it goes. Proposers hear back once that is
+    // saved.
+    async fn run_apply(&self) {
+        let mut committed = self.committed.subscribe();
+        loop {
+            committed.borrow_and_update();
+            let batch: Vec<LogEntry> = {
+                let state = self.state.lock().unwrap();
+                state.entries(state.hard.applied, state.commit).to_vec()
+            };
+            let Some(last) = batch.last().map(|e| e.index) else {
+                if committed.changed().await.is_err() {
+                    return;
+                }
+                continue;
+            };
+            let mut results = Vec::with_capacity(batch.len());
+            for entry in &batch {
+                results.push((entry.index, self.apply(entry).await));
+            }
+            let storage = self.storage.clone().lock_owned().await;
+            {
+                let mut state = self.state.lock().unwrap();
+                state.hard.applied = state.hard.applied.max(last);
+                state.compact(self.config.retained_entries);
+            }
+            if let Err(e) = self.flush(storage).await {
+                println!("[raft {}] {}", self.group, e.message());
+            }
+            let mut state = self.state.lock().unwrap();
+            for (index, result) in results {
+                if let Some(reply) = state.waiters.remove(&index) {
+                    let _ = reply.send(result);
+                }
+            }
+        }
+    }
+
+    // Applies a committed entry, retrying with backoff until it applies or
+    // fails with INVALID_ARGUMENT, which it would on every member. The
+    // proposer hears of the first failure right away.
+    async fn apply(&self, entry: &LogEntry) -> Result<bool, Status> {
+        let mut pause = APPLY_RETRY_PAUSE;
+        loop {
+            let result = if entry.data.is_empty() { Ok(false) } else { self.machine.apply(entry).await };
+            let e = match result {
+                Err(e) if e.code() == Code::InvalidArgument => {
+                    println!("[raft {}] Entry {} can't be applied: {}", self.group, entry.index, e.message());
+                    return Err(e);
+                }
+                Err(e) => e,
+                Ok(changed) => return Ok(changed),
+            };
+            println!("[raft {}] Entry {} failed to apply, retrying: {}", self.group, entry.index, e.message());
+            if let Some(reply) = self.state.lock().unwrap().waiters.remove(&entry.index) {
+                let _ = reply.send(Err(Status::new(e.code(), format!("Committed, but not applied yet: {}", e.message()))));
+            }
+            tokio::time::sleep(pause).await;
+            pause = (pause * 2).min(MAX_APPLY_RETRY_PAUSE);
+        }
+    }
+}
+
+fn random_timeout(base: Duration) -> Duration {
+    base + base.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+/// Reaches members over gRPC, through one lazily connected channel each.
+pub struct GrpcTransport {
+    channels: Mutex<HashMap<String, Channel>>,
+    timeout: Duration,
+}
+
+impl GrpcTransport {
+    pub fn new(timeout: Duration) -> Self {
+        GrpcTransport { channels: Mutex::new(HashMap::new()), timeout }
+    }
+
+    fn client(&self, addr: &str) -> Result<RaftClient<Channel>, Status> {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(channel) = channels.get(addr) {
+            return Ok(RaftClient::new(channel.clone()));
+        }
+        let channel = Endpoint::from_shared(addr.to_string())
+            .map_err(|e| Status::invalid_argument(format!("Invalid member address {}: {}", addr, e)))?
+            .connect_timeout(self.timeout)
+            .timeout(self.timeout)
+            .connect_lazy();
+        channels.insert(addr.to_string(), channel.clone());
+        Ok(RaftClient::new(channel))
+    }
+}
+
+#[tonic::async_trait]
+impl Transport for GrpcTransport {
+    async fn request_vote(&self, to: &str, req: VoteRequest) -> Result<VoteResponse, Status> {
+        self.client(to)?.request_vote(req).await.map(Response::into_inner)
+    }
+
+    async fn append_entries(&self, to: &str, req: AppendRequest) -> Result<AppendResponse, Status> {
+        self.client(to)?.append_entries(req).await.map(Response::into_inner)
+    }
+}
+
+/// The groups a server is a member of, one per replica set it belongs to,
+/// each kept in a directory of its own under `dir`. A group is joined when a
+/// write or another member first names it. One that goes idle stops, and is
+/// dropped, until it is named again; a replica set no longer in use costs
+/// nothing but its state on disk.
+pub struct RaftGroups {
+    config: RaftConfig,
+    dir: PathBuf,
+    transport: Arc<dyn Transport>,
+    machine: Arc<dyn StateMachine>,
+    groups: Mutex<HashMap<String, RunningGroup>>,
+    // For the tasks running groups, to drop them once they stop
+    me: Weak<RaftGroups>,
+}
+
+// A group this server is in, and the task running its protocol
+type RunningGroup = (Arc<RaftNode>, JoinHandle<()>);
+
+impl RaftGroups {
+    /// Opens and starts every group found under `dir`, creating `dir` if
+    /// needed. Those with nothing left to apply stop again once idle.
+    pub fn open(
+        config: RaftConfig,
+        dir: &Path,
+        transport: Arc<dyn Transport>,
+        machine: Arc<dyn StateMachine>,
+    ) -> io::Result<Arc<Self>> {
+        fs::create_dir_all(dir)?;
+        let raft = Arc::new_cyclic(|me| RaftGroups {
+            config,
+            dir: dir.to_path_buf(),
+            transport,
+            machine,
+            groups: Mutex::new(HashMap::new()),
+            me: me.clone(),
+        });
+        let mut groups = raft.groups.lock().unwrap();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !path.join(STATE_FILE).exists() {
+                continue;
+            }
+            let (storage, hard, log) = Storage::open(&path)?;
+            let hard = hard.unwrap();
+            println!("Rejoining Raft group {:?} at term {}, {} entries", hard.members, hard.term, log.len());
+            let node = RaftNode::restore(
+                raft.config.clone(),
+                storage,
+                hard,
+                log,
+                raft.transport.clone(),
+                raft.machine.clone(),
+            );
+            raft.launch(&mut groups, node);
+        }
+        drop(groups);
+        Ok(raft)
+    }
+
+    /// The group of `members`, joined and started if this server isn't in it yet.
+    pub fn group(&self, members: &[String]) -> Result<Arc<RaftNode>, Status> {
+        if !members.contains(&self.config.addr) {
+            return Err(Status::invalid_argument(format!("{} is not one of {:?}", self.config.addr, members)));
+        }
+        let id = group_id(members);
+        let mut groups = self.groups.lock().unwrap();
+        if let Some((node, task)) = groups.get(&id) {
+            if !node.is_stopped() {
+                return Ok(node.clone());
+            }
+            if !task.is_finished() {
+                return Err(Status::unavailable("The shard's Raft group is stopping"));
+            }
+        }
+        let node = RaftNode::open(
+            self.config.clone(),
+            &self.dir.join(&id),
+            members,
+            self.transport.clone(),
+            self.machine.clone(),
+        )
+        .map_err(|e| Status::internal(format!("Failed to create Raft group: {}", e)))?;
+        println!("Joined Raft group {:?}", node.members);
+        self.launch(&mut groups, node.clone());
+        Ok(node)
+    }
+
+    // Starts `node`, dropping it from `groups` once it stops
+    fn launch(&self, groups: &mut HashMap<String, RunningGroup>, node: Arc<RaftNode>) {
+        let me = self.me.clone();
+        let running = node.clone();
+        let task = tokio::spawn(async move {
+            running.clone().run().await;
+            let Some(raft) = me.upgrade() else { return };
+            let mut groups = raft.groups.lock().unwrap();
+            if groups.get(&running.group).is_some_and(|(node, _)| Arc::ptr_eq(node, &running)) {
+                groups.remove(&running.group);
+                println!("Left idle Raft group {:?}", running.members);
+            }
+        });
+        groups.insert(node.group.clone(), (node, task));
+    }
+
+    /// The gRPC service the other members talk to.
+    pub fn service(self: &Arc<Self>) -> RaftServer<RaftGroups> {
+        RaftServer::from_arc(self.clone())
+    }
+}
+
+impl Drop for RaftGroups {
+    fn drop(&mut self) {
+        for (_, task) in self.groups.lock().unwrap().values() {
+            task.abort();
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Raft for RaftGroups {
+    async fn request_vote(&self, request: Request<VoteRequest>) -> Result<Response<VoteResponse>, Status> {
+        let req = request.into_inner();
+        let node = self.group(&req.members)?;
+        node.handle_vote(req).await.map(Response::new)
+    }
+
+    async fn append_entries(&self, request: Request<AppendRequest>) -> Result<Response<AppendResponse>, Status> {
+        let req = request.into_inner();
+        let node = self.group(&req.members)?;
+        node.handle_append(req).await.map(Response::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // Members wired to each other in memory. Messages to or from an isolated
+    // member are lost.
+    #[derive(Default)]
+    struct Network {
+        nodes: Mutex<HashMap<String, Arc<RaftNode>>>,
+        isolated: Mutex<HashSet<String>>,
+    }
+
+    impl Network {
+        fn deliver(&self, from: &str, to: &str) -> Result<Arc<RaftNode>, Status> {
+            let isolated = self.isolated.lock().unwrap();
+            if isolated.contains(from) || isolated.contains(to) {
+                return Err(Status::unavailable("partitioned"));
+            }
+            Ok(self.nodes.lock().unwrap()[to].clone())
+        }
+
+        fn isolate(&self, addr: &str, isolated: bool) {
+            let mut set = self.isolated.lock().unwrap();
+            if isolated {
+                set.insert(addr.to_string());
+            } else {
+                set.remove(addr);
+            }
+        }
+    }
+
+    #[tonic::async_trait]
+    impl Transport for Network {
+        async fn request_vote(&self, to: &str, req: VoteRequest) -> Result<VoteResponse, Status> {
+            self.deliver(&req.candidate, to)?.handle_vote(req).await
+        }
+
+        async fn append_entries(&self, to: &str, req: AppendRequest) -> Result<AppendResponse, Status> {
+            self.deliver(&req.leader, to)?.handle_append(req).await
+        }
+    }
+
+    // Records what it applies. Refuses "bad" for good, and fails the next
+    // `failures` entries as if the disk were full.
+    #[derive(Default)]
+    struct Applied {
+        applied: Mutex<Vec<Vec<u8>>>,
+        failures: Mutex<usize>,
+    }
+
+    #[tonic::async_trait]
+    impl StateMachine for Applied {
+        async fn apply(&self, entry: &LogEntry) -> Result<bool, Status> {
+            if entry.data == b"bad" {
+                return Err(Status::invalid_argument("bad entry"));
+            }
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(Status::internal("disk full"));
+            }
+            self.applied.lock().unwrap().push(entry.data.clone());
+            Ok(true)
+        }
+    }
+
+    impl Applied {
+        fn get(&self) -> Vec<Vec<u8>> {
+            self.applied.lock().unwrap().clone()
+        }
+    }
+
+    fn config(addr: &str) -> RaftConfig {
+        RaftConfig {
+            addr: addr.to_string(),
+            heartbeat_interval: Duration::from_millis(20),
+            election_timeout: Duration::from_millis(150),
+            retained_entries: 1024,
+            idle_timeout: None,
+        }
+    }
+
+    struct Cluster {
+        network: Arc<Network>,
+        nodes: Vec<Arc<RaftNode>>,
+        machines: Vec<Arc<Applied>>,
+        tasks: Vec<JoinHandle<()>>,
+    }
+
+    impl Cluster {
+        fn start(size: usize) -> Self {
+            Self::start_with(size, config)
+        }
+
+        fn start_with(size: usize, config: impl Fn(&str) -> RaftConfig) -> Self {
+            let members: Vec<String> = (0..size).map(|i| format!("n{}", i)).collect();
+            let network = Arc::new(Network::default());
+            let mut cluster = Cluster { network: network.clone(), nodes: vec![], machines: vec![], tasks: vec![] };
+            for addr in &members {
+                let machine = Arc::new(Applied::default());
+                let node = RaftNode::new(config(addr), &members, network.clone(), machine.clone());
+                network.nodes.lock().unwrap().insert(addr.clone(), node.clone());
+                cluster.nodes.push(node);
+                cluster.machines.push(machine);
+            }
+            cluster.tasks = cluster.nodes.iter().map(|node| node.start()).collect();
+            cluster
+        }
+
+        // The only leader among `among`, once there is exactly one
+        async fn leader(&self, among: &[usize]) -> usize {
+            let mut found = None;
+            wait_until("a single leader is elected", || {
+                let leaders: Vec<usize> = among.iter().copied().filter(|&i| self.nodes[i].role() == Role::Leader).collect();
+                found = leaders.first().copied();
+                leaders.len() == 1
+            })
+            .await;
+            found.unwrap()
+        }
+    }
+
+    impl Drop for Cluster {
+        fn drop(&mut self) {
+            for task in &self.tasks {
+                task.abort();
+            }
+        }
+    }
+
+    async fn wait_until(what: &str, mut condition: impl FnMut() -> bool) {
+        for _ in 0..200 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("timed out waiting until {}", what);
+    }
+
+    fn data(values: &[&str]) -> Vec<Vec<u8>> {
+        values.iter().map(|v| v.as_bytes().to_vec()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_elects_a_leader_and_replicates() {
+        let cluster = Cluster::start(3);
+        let leader = cluster.leader(&[0, 1, 2]).await;
+        for value in ["a", "b", "c"] {
+            assert!(cluster.nodes[leader].propose(value.as_bytes().to_vec()).await.unwrap());
+        }
+
+        // Followers point writers at the leader
+        let follower = (leader + 1) % 3;
+        let err = cluster.nodes[follower].propose(b"x".to_vec()).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        assert_eq!(leader_hint(&err), Some(format!("n{}", leader)));
+
+        wait_until("every member applies every entry", || {
+            cluster.machines.iter().all(|m| m.get() == data(&["a", "b", "c"]))
+        })
+        .await;
+        let log = cluster.nodes[leader].log();
+        assert!(log.windows(2).all(|w| w[0].version < w[1].version), "versions increase along the log");
+        for node in &cluster.nodes {
+            assert_eq!(node.log(), log);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partitioned_leader_is_replaced() {
+        let cluster = Cluster::start(3);
+        let old = cluster.leader(&[0, 1, 2]).await;
+        cluster.nodes[old].propose(b"a".to_vec()).await.unwrap();
+        let old_term = cluster.nodes[old].term();
+
+        // Cut off, the old leader can't commit, and gives up once it notices
+        cluster.network.isolate(&format!("n{}", old), true);
+        let err = cluster.nodes[old].propose(b"lost".to_vec()).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+
+        // The majority carries on under a new leader
+        let rest: Vec<usize> = (0..3).filter(|&i| i != old).collect();
+        let new = cluster.leader(&rest).await;
+        assert!(cluster.nodes[new].term() > old_term);
+        cluster.nodes[new].propose(b"b".to_vec()).await.unwrap();
+
+        // Healed, the old leader drops its uncommitted entry and catches up
+        cluster.network.isolate(&format!("n{}", old), false);
+        wait_until("every member applies the committed entries", || {
+            cluster.machines.iter().all(|m| m.get() == data(&["a", "b"]))
+        })
+        .await;
+        wait_until("the logs converge", || {
+            let log = cluster.nodes[new].log();
+            cluster.nodes.iter().all(|n| n.log() == log)
+        })
+        .await;
+        assert!(cluster.nodes.iter().all(|n| n.log().iter().all(|e| e.data != b"lost")));
+    }
+
+    #[tokio::test]
+    async fn test_state_survives_restart() {
+        let dir = std::env::temp_dir().join(format!("raft_restart_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let members = vec!["n0".to_string()];
+        let network = Arc::new(Network::default());
+
+        let machine = Arc::new(Applied::default());
+        let node = RaftNode::open(config("n0"), &dir, &members, network.clone(), machine.clone()).unwrap();
+        let task = node.start();
+        wait_until("the only member leads", || node.role() == Role::Leader).await;
+        node.propose(b"a".to_vec()).await.unwrap();
+        node.propose(b"b".to_vec()).await.unwrap();
+        let (term, log) = (node.term(), node.log());
+        task.abort();
+        drop(node);
+
+        // Applied entries aren't applied again; the log and term carry on
+        let machine = Arc::new(Applied::default());
+        let node = RaftNode::open(config("n0"), &dir, &members, network.clone(), machine.clone()).unwrap();
+        assert_eq!(node.log(), log);
+        assert_eq!(node.applied_index(), log.len() as u64);
+        let task = node.start();
+        wait_until("the only member leads", || node.role() == Role::Leader).await;
+        assert!(node.term() > term);
+        node.propose(b"c".to_vec()).await.unwrap();
+        assert_eq!(machine.get(), data(&["c"]));
+        task.abort();
+
+        let other = vec!["n0".to_string(), "n1".to_string()];
+        let err = RaftNode::open(config("n0"), &dir, &other, network, machine).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Proposes until a leader takes it; asking stands an idle member for election
+    async fn propose_until_led(node: &RaftNode, data: &[u8]) {
+        for _ in 0..200 {
+            if node.propose(data.to_vec()).await.is_ok() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("no leader took {:?}", data);
+    }
+
+    #[tokio::test]
+    async fn test_log_is_compacted_across_restarts() {
+        let dir = std::env::temp_dir().join(format!("raft_compact_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let members = vec!["n0".to_string()];
+        let network = Arc::new(Network::default());
+        let config = |addr: &str| RaftConfig { retained_entries: 4, ..config(addr) };
+
+        let machine = Arc::new(Applied::default());
+        let node = RaftNode::open(config("n0"), &dir, &members, network.clone(), machine.clone()).unwrap();
+        let task = node.start();
+        wait_until("the only member leads", || node.role() == Role::Leader).await;
+        for i in 0..20 {
+            node.propose(vec![i]).await.unwrap();
+        }
+        wait_until("the log is compacted", || node.compacted_index() >= 13).await;
+        let (log, compacted) = (node.log(), node.compacted_index());
+        assert!(log.len() <= 8, "{} entries left", log.len());
+        assert_eq!(compacted + log.len() as u64, 21);
+        task.abort();
+        drop(node);
+
+        // Only the entries after the compacted ones are read back
+        let machine = Arc::new(Applied::default());
+        let node = RaftNode::open(config("n0"), &dir, &members, network, machine.clone()).unwrap();
+        assert_eq!(node.log(), log);
+        assert_eq!(node.compacted_index(), compacted);
+        assert_eq!(node.applied_index(), 21);
+        let task = node.start();
+        wait_until("the only member leads", || node.role() == Role::Leader).await;
+        node.propose(b"x".to_vec()).await.unwrap();
+        assert_eq!(machine.get(), data(&["x"]));
+        let versions: Vec<u64> = node.log().iter().map(|e| e.version).collect();
+        assert!(versions.windows(2).all(|w| w[0] < w[1]), "versions increase past compacted entries");
+        task.abort();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lagging_follower_gets_every_entry() {
+        let cluster = Cluster::start_with(3, |addr| RaftConfig { retained_entries: 4, ..config(addr) });
+        let leader = cluster.leader(&[0, 1, 2]).await;
+        let lagging = (leader + 1) % 3;
+        cluster.network.isolate(&format!("n{}", lagging), true);
+        let values: Vec<String> = (0..20).map(|i| i.to_string()).collect();
+        for value in &values {
+            propose_until_led(&cluster.nodes[leader], value.as_bytes()).await;
+        }
+        // Nobody compacts away what the lagging member still needs
+        let behind = cluster.nodes[lagging].applied_index();
+        assert!(cluster.nodes.iter().all(|n| n.compacted_index() <= behind));
+
+        // Back, it applies everything it missed
+        cluster.network.isolate(&format!("n{}", lagging), false);
+        let everything: Vec<&str> = values.iter().map(String::as_str).collect();
+        wait_until("the lagging member catches up", || cluster.machines[lagging].get() == data(&everything)).await;
+
+        // Then the log is compacted again
+        let leader = cluster.leader(&[0, 1, 2]).await;
+        propose_until_led(&cluster.nodes[leader], b"x").await;
+        wait_until("the log is compacted", || cluster.nodes[leader].compacted_index() >= 13).await;
+    }
+
+    #[tokio::test]
+    async fn test_failed_apply_is_retried() {
+        let cluster = Cluster::start(1);
+        let node = &cluster.nodes[0];
+        wait_until("the only member leads", || node.role() == Role::Leader).await;
+
+        // An entry no member can apply is skipped over
+        let err = node.propose(b"bad".to_vec()).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+        // Any other failure holds the entries after it back until it applies
+        *cluster.machines[0].failures.lock().unwrap() = 3;
+        let err = node.propose(b"a".to_vec()).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Internal);
+        assert!(node.propose(b"b".to_vec()).await.unwrap());
+        assert_eq!(cluster.machines[0].get(), data(&["a", "b"]));
+        assert_eq!(node.applied_index(), node.log().len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_idle_group_stops_until_needed() {
+        let dir = std::env::temp_dir().join(format!("raft_idle_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let members = vec!["n0".to_string()];
+        let config = RaftConfig { idle_timeout: Some(Duration::from_millis(300)), ..config("n0") };
+        let machine = Arc::new(Applied::default());
+        let raft = RaftGroups::open(config, &dir, Arc::new(Network::default()), machine.clone()).unwrap();
+
+        let node = raft.group(&members).unwrap();
+        propose_until_led(&node, b"a").await;
+        wait_until("the idle group is dropped", || raft.groups.lock().unwrap().is_empty()).await;
+        assert!(node.is_stopped());
+        assert_eq!(node.propose(b"b".to_vec()).await.unwrap_err().code(), tonic::Code::Unavailable);
+
+        // Named again, it picks up where it left off, with its log compacted away
+        let node = raft.group(&members).unwrap();
+        assert!(node.log().is_empty());
+        assert_eq!(node.applied_index(), 2);
+        propose_until_led(&node, b"b").await;
+        assert_eq!(machine.get(), data(&["a", "b"]));
+        drop(raft);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
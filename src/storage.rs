@@ -1 +1,375 @@
-pub struct Storage {}
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Appended vectors are buffered until they fill this many bytes, then written
+// to the file and mapped in one go
+const FLUSH_BYTES: usize = 1 << 20;
+
+/// A flat file of fixed-size f32 vectors, addressed by a dense offset
+/// (0, 1, 2, ... in insertion order).
+///
+/// Vectors are appended to an in-memory buffer and written out in batches;
+/// everything written is read back through a memory map, so the OS decides
+/// how much of the file stays resident. Floats are stored in native byte
+/// order.
+///
+/// The file isn't the source of truth: anything written after the last
+/// `checkpoint` is rebuilt from the WAL on recovery. So a slot given up with
+/// `release` is only reused once a snapshot that no longer references it is
+/// durable (see `mark_durable`); until then, recovering from the previous
+/// snapshot may still read it. A reused slot is overwritten in place.
+pub struct VectorStorage {
+    file: Option<File>, // None keeps every vector in `buffered`
+    mmap: Option<Mmap>,
+    // Vectors [0, mapped) are in the file and the map
+    mapped: usize,
+    // Vectors [mapped, len) waiting to be flushed
+    buffered: Vec<f32>,
+    // Fixed by the first push when not known up front
+    dimension: usize,
+    // Slots no durable snapshot references, reused before appending
+    free: Vec<u32>,
+    // Slots released since, oldest first. `released[0]` was the
+    // `released_base`th release ever.
+    released: VecDeque<u32>,
+    released_base: usize,
+    // How many releases ever happened before the last durable snapshot
+    durable_releases: AtomicUsize,
+}
+
+/// What a snapshot records to reopen the storage it was taken from: the
+/// number of vectors already synced to the file, plus the buffered ones.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub dimension: usize,
+    pub flushed: usize,
+    pub buffered: Vec<f32>,
+}
+
+impl Default for VectorStorage {
+    fn default() -> Self {
+        Self::in_memory()
+    }
+}
+
+impl VectorStorage {
+    /// Storage with no backing file.
+    pub fn in_memory() -> Self {
+        VectorStorage {
+            file: None,
+            mmap: None,
+            mapped: 0,
+            buffered: Vec::new(),
+            dimension: 0,
+            free: Vec::new(),
+            released: VecDeque::new(),
+            released_base: 0,
+            durable_releases: AtomicUsize::new(0),
+        }
+    }
+
+    /// Empty file-backed storage. An existing file at `path` is truncated.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        Ok(VectorStorage { file: Some(file), ..Self::in_memory() })
+    }
+
+    /// Reopens the file at `path` as it was at `checkpoint`. Vectors written
+    /// after the checkpoint are discarded. The file is created if missing,
+    /// which only a checkpoint with nothing flushed allows. Call
+    /// `reclaim_unreferenced` to reuse the slots the checkpoint's snapshot
+    /// doesn't reference.
+    pub fn restore(path: &Path, checkpoint: Checkpoint) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let flushed_bytes = (checkpoint.flushed * checkpoint.dimension * 4) as u64;
+        if file.metadata()?.len() < flushed_bytes {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is shorter than its snapshot expects", path.display()),
+            ));
+        }
+        file.set_len(flushed_bytes)?;
+
+        Ok(VectorStorage {
+            mmap: if checkpoint.flushed > 0 { Some(map(&file)?) } else { None },
+            file: Some(file),
+            mapped: checkpoint.flushed,
+            buffered: checkpoint.buffered,
+            dimension: checkpoint.dimension,
+            ..Self::in_memory()
+        })
+    }
+
+    /// In-memory storage holding a checkpoint's buffered vectors. Fails if
+    /// the checkpoint was taken from a file.
+    pub fn restore_in_memory(checkpoint: Checkpoint) -> io::Result<Self> {
+        if checkpoint.flushed > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "snapshot references a vector file",
+            ));
+        }
+        Ok(VectorStorage {
+            buffered: checkpoint.buffered,
+            dimension: checkpoint.dimension,
+            ..Self::in_memory()
+        })
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    /// Number of slots, including free and released ones.
+    pub fn len(&self) -> usize {
+        self.mapped + self.buffered.len() / self.dimension.max(1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Stores a vector in a free slot, or appends it, and returns its
+    /// offset. The first push fixes the dimension; later vectors must match
+    /// it, or fail with `ErrorKind::InvalidInput`. A failed write stores
+    /// nothing.
+    pub fn push(&mut self, vector: &[f32]) -> io::Result<u32> {
+        if self.dimension == 0 {
+            self.dimension = vector.len();
+        }
+        if vector.len() != self.dimension {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("vector has dimension {}, storage has {}", vector.len(), self.dimension),
+            ));
+        }
+
+        let durable = *self.durable_releases.get_mut();
+        while self.released_base < durable {
+            let Some(slot) = self.released.pop_front() else { break };
+            self.free.push(slot);
+            self.released_base += 1;
+        }
+        if let Some(slot) = self.free.pop() {
+            if let Err(e) = self.overwrite(slot, vector) {
+                self.free.push(slot);
+                return Err(e);
+            }
+            return Ok(slot);
+        }
+
+        let offset = self.len() as u32;
+        self.buffered.extend_from_slice(vector);
+        if self.file.is_some() && self.buffered.len() * 4 >= FLUSH_BYTES {
+            // The rest stays buffered, so the next push retries the flush
+            if let Err(e) = self.flush() {
+                self.buffered.truncate(self.buffered.len() - self.dimension);
+                return Err(e);
+            }
+        }
+        Ok(offset)
+    }
+
+    /// The vector at `offset`. Panics if no vector was pushed there.
+    pub fn get(&self, offset: u32) -> &[f32] {
+        let offset = offset as usize;
+        if offset < self.mapped {
+            let mmap = self.mmap.as_ref().unwrap();
+            let start = offset * self.dimension * 4;
+            let bytes = &mmap[start..start + self.dimension * 4];
+            // SAFETY: the map is page aligned and every vector starts at a
+            // multiple of 4 bytes, so the prefix is empty
+            let (prefix, floats, _) = unsafe { bytes.align_to::<f32>() };
+            debug_assert!(prefix.is_empty());
+            floats
+        } else {
+            let start = (offset - self.mapped) * self.dimension;
+            &self.buffered[start..start + self.dimension]
+        }
+    }
+
+    /// Gives up the slot at `offset`, which nothing references any more. It
+    /// is reused once a snapshot taken after this is durable.
+    pub fn release(&mut self, offset: u32) {
+        self.released.push_back(offset);
+    }
+
+    /// Number of releases so far. A snapshot taken now references none of
+    /// the slots released before; pass this to `mark_durable` once it is
+    /// safely on disk.
+    pub fn releases(&self) -> usize {
+        self.released_base + self.released.len()
+    }
+
+    /// Records that a snapshot taken after `releases` releases is durable,
+    /// so their slots can be reused.
+    pub fn mark_durable(&self, releases: usize) {
+        self.durable_releases.fetch_max(releases, Ordering::Relaxed);
+    }
+
+    /// Frees every slot not in `referenced`, e.g. those the snapshot just
+    /// restored from doesn't use.
+    pub fn reclaim_unreferenced(&mut self, referenced: impl IntoIterator<Item = u32>) {
+        let referenced: HashSet<u32> = referenced.into_iter().collect();
+        self.free = (0..self.len() as u32).rev().filter(|slot| !referenced.contains(slot)).collect();
+        self.released.clear();
+    }
+
+    /// Number of slots waiting to be reused.
+    pub fn free_slots(&self) -> usize {
+        self.free.len() + self.released.len()
+    }
+
+    /// Syncs the file and returns what a snapshot needs to `restore` it.
+    pub fn checkpoint(&self) -> io::Result<Checkpoint> {
+        if let Some(file) = &self.file {
+            file.sync_all()?;
+        }
+        Ok(Checkpoint {
+            dimension: self.dimension,
+            flushed: self.mapped,
+            buffered: self.buffered.clone(),
+        })
+    }
+
+    // Replaces the vector in a free slot
+    fn overwrite(&mut self, slot: u32, vector: &[f32]) -> io::Result<()> {
+        let slot = slot as usize;
+        if slot < self.mapped {
+            let bytes: Vec<u8> = vector.iter().flat_map(|x| x.to_ne_bytes()).collect();
+            let file = self.file.as_mut().unwrap();
+            file.seek(SeekFrom::Start((slot * self.dimension * 4) as u64))?;
+            file.write_all(&bytes)?;
+        } else {
+            let start = (slot - self.mapped) * self.dimension;
+            self.buffered[start..start + self.dimension].copy_from_slice(vector);
+        }
+        Ok(())
+    }
+
+    // Writes the buffered vectors after the mapped ones and maps them
+    fn flush(&mut self) -> io::Result<()> {
+        let file = self.file.as_mut().unwrap();
+        let bytes: Vec<u8> = self.buffered.iter().flat_map(|x| x.to_ne_bytes()).collect();
+        // Seek rather than append, so a retry overwrites a partial write
+        file.seek(SeekFrom::Start((self.mapped * self.dimension * 4) as u64))?;
+        file.write_all(&bytes)?;
+        self.mmap = Some(map(file)?);
+
+        self.mapped += self.buffered.len() / self.dimension;
+        self.buffered.clear();
+        Ok(())
+    }
+}
+
+fn map(file: &File) -> io::Result<Mmap> {
+    // SAFETY: the file is private to this storage. Within the mapped range,
+    // only free slots are written, and nothing borrows a free slot's vector.
+    unsafe { Mmap::map(file) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn vector(i: usize) -> Vec<f32> {
+        (0..64).map(|j| (i * 64 + j) as f32).collect()
+    }
+
+    #[test]
+    fn test_push_get_across_flushes() {
+        let path = std::env::temp_dir().join(format!("storage_flush_{}.dat", std::process::id()));
+        let mut storage = VectorStorage::create(&path).unwrap();
+
+        // 64 floats per vector: 5000 vectors spill past one flush
+        for i in 0..5000 {
+            assert_eq!(storage.push(&vector(i)).unwrap(), i as u32);
+        }
+        assert!(storage.mapped > 0 && !storage.buffered.is_empty());
+        assert_eq!(storage.len(), 5000);
+        for i in [0, 1, 4095, 4096, 4999] {
+            assert_eq!(storage.get(i as u32), vector(i).as_slice());
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_push_rejects_other_dimensions() {
+        let mut storage = VectorStorage::in_memory();
+        assert_eq!(storage.push(&vector(0)).unwrap(), 0);
+        let err = storage.push(&[1.0, 2.0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(storage.len(), 1);
+        assert_eq!(storage.push(&vector(1)).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_restore_discards_writes_after_checkpoint() {
+        let path = std::env::temp_dir().join(format!("storage_restore_{}.dat", std::process::id()));
+        let mut storage = VectorStorage::create(&path).unwrap();
+        for i in 0..4100 {
+            storage.push(&vector(i)).unwrap();
+        }
+        let checkpoint = storage.checkpoint().unwrap();
+        for i in 4100..9000 {
+            storage.push(&vector(i)).unwrap();
+        }
+        drop(storage);
+
+        let mut storage = VectorStorage::restore(&path, checkpoint.clone()).unwrap();
+        assert_eq!(storage.len(), 4100);
+        // Slots the snapshot doesn't reference are free straight away
+        storage.reclaim_unreferenced((0..4100).filter(|&slot| slot != 7));
+        assert_eq!(storage.push(&vector(8)).unwrap(), 7);
+        assert_eq!(storage.get(7), vector(8).as_slice());
+        assert_eq!(storage.get(4099), vector(4099).as_slice());
+        assert_eq!(storage.push(&vector(9)).unwrap(), 4100);
+        assert_eq!(storage.get(4100), vector(9).as_slice());
+        drop(storage);
+
+        // A file shorter than the checkpoint is rejected
+        File::create(&path).unwrap();
+        let err = VectorStorage::restore(&path, checkpoint).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_released_slots_are_reused_once_durable() {
+        let path = std::env::temp_dir().join(format!("storage_reuse_{}.dat", std::process::id()));
+        let mut storage = VectorStorage::create(&path).unwrap();
+        for i in 0..5000 {
+            storage.push(&vector(i)).unwrap();
+        }
+        // One slot in the file, one still buffered
+        storage.release(3);
+        storage.release(4999);
+        let releases = storage.releases();
+        assert_eq!(storage.push(&vector(5000)).unwrap(), 5000);
+
+        storage.mark_durable(releases);
+        assert_eq!(storage.push(&vector(7000)).unwrap(), 4999);
+        assert_eq!(storage.push(&vector(7001)).unwrap(), 3);
+        assert_eq!(storage.get(4999), vector(7000).as_slice());
+        assert_eq!(storage.get(3), vector(7001).as_slice());
+        assert_eq!(storage.get(4), vector(4).as_slice());
+        assert_eq!(storage.push(&vector(7002)).unwrap(), 5001);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_in_memory_checkpoint_holds_every_vector() {
+        let mut storage = VectorStorage::in_memory();
+        storage.push(&[1.0, 2.0]).unwrap();
+        storage.push(&[3.0, 4.0]).unwrap();
+        let checkpoint = storage.checkpoint().unwrap();
+        assert_eq!(checkpoint, Checkpoint { dimension: 2, flushed: 0, buffered: vec![1.0, 2.0, 3.0, 4.0] });
+        assert_eq!(storage.get(1), &[3.0, 4.0]);
+    }
+}
@@ -1,102 +1,1158 @@
-use std::fs::{File, OpenOptions};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use bincode::Options;
 use serde::{Deserialize, Serialize};
 use crc32fast::Hasher;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use crate::payload::Payload;
+
+const SEGMENT_EXTENSION: &str = "wal";
+// Record header: CRC32 and data length
+const HEADER_BYTES: u64 = 12;
+// Where `Wal::import` converts a log before moving it into place
+const IMPORT_FILE: &str = "import.tmp";
+// Scanning for the next valid entry after a corrupt one reads this much at a time
+const SCAN_WINDOW: usize = 1 << 20;
+// Under the log's directory, for segments kept with `WalConfig::archive`
+const ARCHIVE_DIR: &str = "archive";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum OpType {
     Insert,
     Delete,
+    // Removes an id and forgets any delete of it, as when a node stops
+    // owning the key: no version is left behind
+    Evict,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WalEntry {
+    // Log sequence number, assigned by `Wal::append`. Starts at 1.
+    pub lsn: u64,
     pub op: OpType,
     pub vector_id: u32,
+    // Orders writes to the same id across replicas: the highest wins
+    pub version: u64,
     pub vector: Vec<f32>,
+    pub payload: Payload,
+}
+
+/// Issues entry versions: microseconds since the Unix epoch, bumped past the
+/// last one issued, so versions from one clock strictly increase. Versions
+/// from different clocks are only as ordered as the clocks are in sync.
+#[derive(Default)]
+pub struct VersionClock {
+    last: AtomicU64,
+}
+
+impl VersionClock {
+    pub fn next(&self) -> u64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0);
+        let previous = self.last
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(now.max(last + 1)))
+            .unwrap();
+        now.max(previous + 1)
+    }
+}
+
+/// When a `Wal` makes appended entries durable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Before a commit returns. Commits waiting at the same time share one
+    /// write and one sync.
+    #[default]
+    Always,
+    /// Every so often, in the background. Each entry is written to the OS
+    /// when appended, so a process crash loses nothing, but an OS crash
+    /// loses up to this long of committed entries.
+    Interval(Duration),
+    /// Whenever the OS flushes its buffers. Each entry is written to the OS
+    /// when appended.
+    Os,
 }
 
+/// How a `Wal` splits the log into segments, and syncs them.
+#[derive(Debug, Clone)]
+pub struct WalConfig {
+    /// A new segment starts when an entry would take the current one past this size
+    pub segment_bytes: u64,
+    /// ... or when an entry is appended to a segment at least this old
+    pub segment_age: Option<Duration>,
+    /// Move segments a snapshot covers to `archive/` rather than deleting them
+    pub archive: bool,
+    pub sync: SyncPolicy,
+    /// Skip over corrupt entries in the middle of the log, losing the
+    /// writes they held, rather than fail to read it
+    pub skip_corrupt: bool,
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        WalConfig {
+            segment_bytes: 64 * 1024 * 1024,
+            segment_age: None,
+            archive: false,
+            sync: SyncPolicy::Always,
+            skip_corrupt: false,
+        }
+    }
+}
+
+struct WalWriter {
+    file: BufWriter<File>,
+    next_lsn: u64,
+    // LSN of the last entry written, which may still be buffered
+    written: u64,
+    // Size and age of the segment being written
+    segment_bytes: u64,
+    segment_started: Instant,
+    // Why the log takes no more entries, once it doesn't: it was closed, or
+    // a write or sync failed. What is on disk after a failure can't be
+    // trusted, so the log takes no more entries until it is reopened.
+    failed: Option<io::Error>,
+}
+
+impl WalWriter {
+    fn check(&self) -> io::Result<()> {
+        match &self.failed {
+            Some(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    fn fail(&mut self, e: io::Error) -> io::Error {
+        self.failed.get_or_insert_with(|| {
+            io::Error::new(e.kind(), format!("The WAL takes no more writes after an earlier failure: {}", e))
+        });
+        e
+    }
+}
+
+/// A log split into segment files in one directory, each named after the
+/// first LSN it may hold (zero-padded, so they sort in order), and holding
+/// entries below the next segment's first LSN.
+///
+/// Appending an entry only buffers it; `commit` waits until it is durable
+/// under the `SyncPolicy`. Callers can append under their own locks, so the
+/// log stays in the order they apply entries, and commit after releasing
+/// them, so writes from many callers share a sync. After a write or sync
+/// fails, the log refuses entries and commits until it is reopened.
 pub struct Wal {
-    file: Arc<Mutex<BufWriter<File>>>,
-    path: String,
+    writer: Arc<Mutex<WalWriter>>,
+    dir: PathBuf,
+    config: WalConfig,
+    // Last LSN durable under the sync policy, for commits and tailing
+    // readers to wait on
+    durable: Arc<watch::Sender<u64>>,
+    // Held by the commit whose sync is in flight. Those queued behind it
+    // find their entries synced, or share the next sync.
+    syncing: tokio::sync::Mutex<()>,
 }
 
 impl Wal {
-    pub fn new(path: &str) -> io::Result<Self> {
+    /// Opens the log in `dir`, creating both if needed. New entries go to
+    /// the last segment.
+    ///
+    /// A crash mid-append can leave part of an entry at the end of the log,
+    /// torn or cut short, with nothing valid after it; it is cut off, with a
+    /// warning. Damage anywhere else is corruption: it fails with
+    /// `ErrorKind::InvalidData`, unless the config says to skip it. So does
+    /// an intact entry that can't be decoded, wherever it is.
+    pub fn open(dir: &Path, config: WalConfig) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let segments = list_segments(dir)?;
+        let segment = segments.last().copied().unwrap_or(1);
+        let path = segment_path(dir, segment);
+
+        // Continue numbering after the last entry already on disk
+        let mut written = segment - 1;
+        if !segments.is_empty() {
+            let mut entries = WalIter::new(dir, vec![segment], config.skip_corrupt);
+            for entry in &mut entries {
+                written = entry?.lsn;
+            }
+            if let Some((_, end)) = entries.torn {
+                let file = OpenOptions::new().write(true).open(&path)?;
+                let dropped = file.metadata()?.len() - end;
+                file.set_len(end)?;
+                file.sync_all()?;
+                println!("Warning: cut {} bytes of an incomplete entry from the end of {}", dropped, path.display());
+            }
+        }
+
         let file = OpenOptions::new()
             .create(true)
             .append(true)
-            .open(path)?;
-        
-        Ok(Wal {
-            file: Arc::new(Mutex::new(BufWriter::new(file))),
-            path: path.to_string(),
-        })
+            .open(&path)?;
+        let segment_bytes = file.metadata()?.len();
+
+        let wal = Wal {
+            writer: Arc::new(Mutex::new(WalWriter {
+                file: BufWriter::new(file),
+                next_lsn: written + 1,
+                written,
+                segment_bytes,
+                segment_started: Instant::now(),
+                failed: None,
+            })),
+            dir: dir.to_path_buf(),
+            config,
+            durable: Arc::new(watch::channel(written).0),
+            syncing: tokio::sync::Mutex::new(()),
+        };
+        if let SyncPolicy::Interval(interval) = wal.config.sync {
+            sync_periodically(Arc::downgrade(&wal.writer), Arc::downgrade(&wal.durable), interval);
+        }
+        Ok(wal)
     }
 
-    pub fn append(&self, entry: &WalEntry) -> io::Result<()> {
-        let mut file = self.file.lock().unwrap();
-        
+    /// Makes a log written as a single file, before logs were segmented, the
+    /// first segment of the log in `dir`. A log written before entries had
+    /// versions is converted, each entry taking version 0 so that any later
+    /// write wins over it; an incomplete entry at its end is dropped.
+    pub fn import(dir: &Path, file: &Path) -> io::Result<()> {
+        let mut reader = BufReader::new(File::open(file)?);
+        let len = reader.get_ref().metadata()?.len();
+        let tmp_path = dir.join(IMPORT_FILE);
+        let mut writer = None;
+        // First and last LSNs converted
+        let mut lsns: Option<(u64, u64)> = None;
+        let mut offset = 0;
+        loop {
+            let data = match read_record(&mut reader, len - offset) {
+                Ok(Some(data)) => data,
+                Ok(None) => break,
+                Err(e) if is_damage(&e) => {
+                    if find_entry(file, offset, lsns.map_or(0, |(_, last)| last))?.is_some() {
+                        let message = format!("Corrupt WAL entry at offset {} of {}: {}", offset, file.display(), e);
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, Corruption(message)));
+                    }
+                    println!("Warning: dropped {} bytes of an incomplete entry from the end of {}", len - offset, file.display());
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            if offset == 0 {
+                fs::create_dir_all(dir)?;
+                // Already in the current format: use the file as it is
+                if let Ok(entry) = decode_entry(&data) {
+                    return fs::rename(file, segment_path(dir, entry.lsn));
+                }
+                writer = Some(BufWriter::new(File::create(&tmp_path)?));
+            }
+            let entry = decode_unversioned_entry(&data).map_err(|e| {
+                let message = format!("WAL entry at offset {} of {} is intact but can't be decoded: {}", offset, file.display(), e);
+                io::Error::new(io::ErrorKind::InvalidData, message)
+            })?;
+            write_record(writer.as_mut().unwrap(), &bincode::serialize(&entry).map_err(io::Error::other)?)?;
+            lsns = Some((lsns.map_or(entry.lsn, |(first, _)| first), entry.lsn));
+            offset += HEADER_BYTES + data.len() as u64;
+        }
+
+        if let (Some(mut writer), Some((first, _))) = (writer, lsns) {
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+            fs::rename(&tmp_path, segment_path(dir, first))?;
+            println!("Converted {} to the current WAL format", file.display());
+        }
+        fs::remove_file(file)
+    }
+
+    /// Appends `entry` to the log, stamping it with the next LSN.
+    /// Returns the assigned LSN. The entry isn't durable until committed.
+    pub fn append(&self, entry: &mut WalEntry) -> io::Result<u64> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.check()?;
+        entry.lsn = writer.next_lsn;
+        self.write(&mut writer, entry).map_err(|e| writer.fail(e))?;
+        Ok(entry.lsn)
+    }
+
+    /// Appends an entry that already has an LSN, such as one replicated from
+    /// another log. Fails with `ErrorKind::InvalidInput` unless the LSN is
+    /// past the last one written; later entries are numbered after it.
+    pub fn append_at(&self, entry: &WalEntry) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.check()?;
+        if entry.lsn < writer.next_lsn {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("LSN {} is not after the last one written, {}", entry.lsn, writer.next_lsn - 1),
+            ));
+        }
+        writer.next_lsn = entry.lsn;
+        self.write(&mut writer, entry).map_err(|e| writer.fail(e))
+    }
+
+    fn write(&self, writer: &mut WalWriter, entry: &WalEntry) -> io::Result<()> {
         // Serialize entry
-        let data = bincode::serialize(entry).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        
-        // Calculate CRC32
-        let mut hasher = Hasher::new();
-        hasher.update(&data);
-        let checksum = hasher.finalize();
-
-        // Write format: [CRC32 (4 bytes)] [Length (8 bytes)] [Data]
-        file.write_all(&checksum.to_le_bytes())?;
-        file.write_all(&(data.len() as u64).to_le_bytes())?;
-        file.write_all(&data)?;
-        
-        // Ensure it hits the disk
-        file.flush()?;
-        file.get_ref().sync_all()?;
-        
+        let data = bincode::serialize(entry).map_err(io::Error::other)?;
+
+        let len = HEADER_BYTES + data.len() as u64;
+        let full = writer.segment_bytes + len > self.config.segment_bytes;
+        let old = self.config.segment_age.is_some_and(|age| writer.segment_started.elapsed() >= age);
+        if writer.segment_bytes > 0 && (full || old) {
+            self.rotate(writer, entry.lsn)?;
+        }
+
+        write_record(&mut writer.file, &data)?;
+
+        writer.segment_bytes += len;
+        writer.next_lsn += 1;
+        writer.written = entry.lsn;
+        match self.config.sync {
+            // Left for the next commit to write
+            SyncPolicy::Always => {}
+            SyncPolicy::Interval(_) => writer.file.flush()?,
+            SyncPolicy::Os => {
+                writer.file.flush()?;
+                mark_durable(&self.durable, entry.lsn);
+            }
+        }
         Ok(())
     }
 
-    pub fn read_all(&self) -> io::Result<Vec<WalEntry>> {
-        let file = File::open(&self.path)?;
-        let mut reader = BufReader::new(file);
-        let mut entries = Vec::new();
+    /// Waits until the entry at `lsn`, and every one before it, is durable
+    /// under the sync policy. With `SyncPolicy::Always`, that is once it is
+    /// on disk; otherwise this returns at once.
+    pub async fn commit(&self, lsn: u64) -> io::Result<()> {
+        if self.config.sync != SyncPolicy::Always {
+            return Ok(());
+        }
+        let _syncing = self.syncing.lock().await;
+        if *self.durable.borrow() >= lsn {
+            return Ok(());
+        }
+        // Sync everything written so far, not just up to `lsn`, without
+        // holding up appends meanwhile
+        let (file, written) = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.check()?;
+            writer.file.flush().map_err(|e| writer.fail(e))?;
+            (writer.file.get_ref().try_clone()?, writer.written)
+        };
+        let synced = tokio::task::spawn_blocking(move || file.sync_data()).await.map_err(io::Error::other)?;
+        if let Err(e) = synced {
+            return Err(self.writer.lock().unwrap().fail(e));
+        }
+        mark_durable(&self.durable, written);
+        Ok(())
+    }
 
+    // Starts a new segment, whose first entry will be `first_lsn`, once
+    // the current one is written out
+    fn rotate(&self, writer: &mut WalWriter, first_lsn: u64) -> io::Result<()> {
+        writer.file.flush()?;
+        if self.config.sync != SyncPolicy::Os {
+            writer.file.get_ref().sync_data()?;
+            mark_durable(&self.durable, writer.written);
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.dir, first_lsn))?;
+        writer.file = BufWriter::new(file);
+        writer.segment_bytes = 0;
+        writer.segment_started = Instant::now();
+        Ok(())
+    }
+
+    /// Refuses every later append with `reason`, e.g. once the log's
+    /// collection is dropped.
+    pub fn close(&self, reason: io::Error) {
+        self.writer.lock().unwrap().failed.get_or_insert(reason);
+    }
+
+    /// LSN of the most recently appended entry, or 0 if the log is empty.
+    pub fn last_lsn(&self) -> u64 {
+        self.writer.lock().unwrap().next_lsn - 1
+    }
+
+    /// Ensures every future entry gets an LSN greater than `lsn`.
+    /// Used when a snapshot is newer than the log (e.g. the log was removed).
+    pub fn skip_to(&self, lsn: u64) {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.next_lsn <= lsn {
+            writer.next_lsn = lsn + 1;
+        }
+    }
+
+    /// Every entry in the log, in order, read one segment at a time.
+    pub fn read_all(&self) -> io::Result<WalIter> {
+        Ok(WalIter::new(&self.dir, list_segments(&self.dir)?, self.config.skip_corrupt))
+    }
+
+    /// Deletes, or archives, every segment holding only entries up to `lsn`,
+    /// such as those a snapshot covers. The segment being written is kept.
+    /// Returns how many segments went.
+    pub fn remove_through(&self, lsn: u64) -> io::Result<usize> {
+        let segments = list_segments(&self.dir)?;
+        let mut removed = 0;
+        for pair in segments.windows(2) {
+            // A segment ends where the next begins
+            if pair[1] > lsn + 1 {
+                break;
+            }
+            let path = segment_path(&self.dir, pair[0]);
+            if self.config.archive {
+                let archive = self.dir.join(ARCHIVE_DIR);
+                fs::create_dir_all(&archive)?;
+                fs::rename(&path, archive.join(path.file_name().unwrap()))?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Reads the entries after `after_lsn`, in order, and then each new one
+    /// as it is appended.
+    pub fn tail(&self, after_lsn: u64) -> io::Result<WalTail> {
+        // Start in the last segment that can hold the first entry wanted
+        let segments = list_segments(&self.dir)?;
+        let segment = segments
+            .iter()
+            .rev()
+            .find(|&&first| first <= after_lsn + 1)
+            .or(segments.first())
+            .copied()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "The log has no segments"))?;
+        Ok(WalTail {
+            dir: self.dir.clone(),
+            segment,
+            reader: BufReader::new(File::open(segment_path(&self.dir, segment))?),
+            offset: 0,
+            next_lsn: after_lsn + 1,
+            pending: VecDeque::new(),
+            durable: self.durable.subscribe(),
+        })
+    }
+}
+
+fn mark_durable(durable: &watch::Sender<u64>, lsn: u64) {
+    durable.send_if_modified(|last| {
+        let newer = lsn > *last;
+        if newer {
+            *last = lsn;
+        }
+        newer
+    });
+}
+
+// For `SyncPolicy::Interval`: syncs the log every `interval` until it is dropped
+fn sync_periodically(writer: Weak<Mutex<WalWriter>>, durable: Weak<watch::Sender<u64>>, interval: Duration) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        let (Some(writer), Some(durable)) = (writer.upgrade(), durable.upgrade()) else {
+            return;
+        };
+        let synced = {
+            let mut writer = writer.lock().unwrap();
+            writer.check()
+                .and_then(|_| writer.file.flush())
+                .and_then(|_| writer.file.get_ref().try_clone())
+                .map(|file| (file, writer.written))
+        };
+        match synced.and_then(|(file, written)| file.sync_data().map(|_| written)) {
+            Ok(written) => mark_durable(&durable, written),
+            Err(e) => {
+                println!("Failed to sync WAL: {}", e);
+                writer.lock().unwrap().fail(e);
+                return;
+            }
+        }
+    });
+}
+
+fn segment_path(dir: &Path, first_lsn: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", first_lsn, SEGMENT_EXTENSION))
+}
+
+// First LSNs of the segments in `dir`, in order
+fn list_segments(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION) {
+            if let Some(first) = path.file_stem().and_then(|stem| stem.to_str()?.parse().ok()) {
+                segments.push(first);
+            }
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// The entries of a `Wal`, from `Wal::read_all`. Stops at the first error.
+///
+/// An entry that fails to read, cut short or failing its CRC, is either a
+/// torn write, if it is in the last segment and nothing valid follows it,
+/// where the log ends; or corruption, which is an `ErrorKind::InvalidData`
+/// error unless `skip_corrupt` is set, in which case reading resumes at the
+/// next valid entry. An entry that reads whole but can't be decoded was
+/// written in a format this build doesn't know: it is always an error.
+pub struct WalIter {
+    dir: PathBuf,
+    segments: std::vec::IntoIter<u64>,
+    skip_corrupt: bool,
+    current: Option<SegmentReader>,
+    // LSN of the last entry read
+    last_lsn: u64,
+    // Segment and offset of a torn write, if the log ends in one
+    torn: Option<(u64, u64)>,
+}
+
+struct SegmentReader {
+    segment: u64,
+    reader: BufReader<File>,
+    len: u64,
+    // End of the last whole entry read
+    offset: u64,
+}
+
+impl WalIter {
+    fn new(dir: &Path, segments: Vec<u64>, skip_corrupt: bool) -> Self {
+        WalIter {
+            dir: dir.to_path_buf(),
+            segments: segments.into_iter(),
+            skip_corrupt,
+            current: None,
+            last_lsn: 0,
+            torn: None,
+        }
+    }
+
+    // Decides what to make of an entry that failed to read at the current
+    // offset, and moves past it if that's allowed
+    fn recover(&mut self, error: io::Error) -> io::Result<()> {
+        let current = self.current.as_mut().unwrap();
+        let path = segment_path(&self.dir, current.segment);
+        let after_lsn = self.last_lsn.max(current.segment - 1);
+        let resume = find_entry(&path, current.offset, after_lsn)?;
+        if resume.is_none() && self.segments.len() == 0 {
+            self.torn = Some((current.segment, current.offset));
+            self.current = None;
+            return Ok(());
+        }
+
+        let message = format!("Corrupt WAL entry at offset {} of {}: {}", current.offset, path.display(), error);
+        if !self.skip_corrupt {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, Corruption(message)));
+        }
+        let skipped = resume.unwrap_or(current.len) - current.offset;
+        println!("Warning: {}; skipping {} bytes", message, skipped);
+        match resume {
+            Some(offset) => {
+                current.reader.seek(SeekFrom::Start(offset))?;
+                current.offset = offset;
+            }
+            None => self.current = None,
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for WalIter {
+    type Item = io::Result<WalEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
         loop {
-            // Read CRC32
-            let mut crc_buf = [0u8; 4];
-            if let Err(e) = reader.read_exact(&mut crc_buf) {
-                if e.kind() == io::ErrorKind::UnexpectedEof {
-                    break;
+            if let Some(current) = &mut self.current {
+                let error = match read_record(&mut current.reader, current.len - current.offset) {
+                    Ok(Some(data)) => match decode_entry(&data) {
+                        Ok(entry) => {
+                            current.offset += HEADER_BYTES + data.len() as u64;
+                            self.last_lsn = entry.lsn;
+                            return Some(Ok(entry));
+                        }
+                        Err(e) => {
+                            let path = segment_path(&self.dir, current.segment);
+                            let message = format!("WAL entry at offset {} of {} is intact but can't be decoded: {}", current.offset, path.display(), e);
+                            Some(io::Error::new(io::ErrorKind::InvalidData, message))
+                        }
+                    },
+                    Ok(None) => {
+                        self.current = None;
+                        continue;
+                    }
+                    Err(e) if is_damage(&e) => self.recover(e).err(),
+                    Err(e) => Some(e),
+                };
+                if let Some(e) = error {
+                    // Stop after an error
+                    self.current = None;
+                    self.segments = Vec::new().into_iter();
+                    return Some(Err(e));
+                }
+                continue;
+            }
+            let segment = self.segments.next()?;
+            let opened = File::open(segment_path(&self.dir, segment))
+                .and_then(|file| Ok((file.metadata()?.len(), file)));
+            match opened {
+                Ok((len, file)) => {
+                    self.current = Some(SegmentReader { segment, reader: BufReader::new(file), len, offset: 0 });
                 }
-                return Err(e);
+                Err(e) => return Some(Err(e)),
             }
-            let expected_crc = u32::from_le_bytes(crc_buf);
+        }
+    }
+}
+
+/// Whether `error` is from a damaged entry in the middle of a log, which
+/// `WalConfig::skip_corrupt` would skip.
+pub fn is_corruption(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<Corruption>())
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct Corruption(String);
 
-            // Read Length
-            let mut len_buf = [0u8; 8];
-            reader.read_exact(&mut len_buf)?;
-            let len = u64::from_le_bytes(len_buf);
+// Whether a failed `read_record` means the bytes themselves are bad: the
+// record is cut short or fails its CRC
+fn is_damage(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData)
+}
 
-            // Read Data
+// Offset of the first valid record in the file at `path` after `from` whose
+// entry comes after `after_lsn`, if any. Only offsets whose header fits in
+// the file, and whose LSN is in order, are checksummed, and the file is read
+// a window at a time.
+fn find_entry(path: &Path, from: u64, after_lsn: u64) -> io::Result<Option<u64>> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut window = Vec::new();
+    let mut window_start = from;
+    // The record header plus the LSN that starts every entry
+    let probe = HEADER_BYTES + 8;
+    for start in from + 1..file_len.saturating_sub(probe - 1) {
+        if start + probe > window_start + window.len() as u64 {
+            window_start = start;
+            window.resize(SCAN_WINDOW.min((file_len - start) as usize), 0);
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut window)?;
+        }
+        let header = &window[(start - window_start) as usize..];
+        let len = u64::from_le_bytes(header[4..12].try_into().unwrap());
+        if len < 8 || len > file_len - start - HEADER_BYTES {
+            continue;
+        }
+        let lsn = u64::from_le_bytes(header[12..20].try_into().unwrap());
+        if lsn <= after_lsn {
+            continue;
+        }
+        let end = start + HEADER_BYTES + len;
+        let data = if end <= window_start + window.len() as u64 {
+            window[(start + HEADER_BYTES - window_start) as usize..(end - window_start) as usize].to_vec()
+        } else {
             let mut data = vec![0u8; len as usize];
-            reader.read_exact(&mut data)?;
+            file.seek(SeekFrom::Start(start + HEADER_BYTES))?;
+            file.read_exact(&mut data)?;
+            data
+        };
+        if crc32fast::hash(&data).to_le_bytes() == header[..4] {
+            return Ok(Some(start));
+        }
+    }
+    Ok(None)
+}
+
+/// A tailing read of a `Wal`, from `Wal::tail`.
+pub struct WalTail {
+    dir: PathBuf,
+    // First LSN of the segment being read
+    segment: u64,
+    reader: BufReader<File>,
+    // End of the last whole entry read
+    offset: u64,
+    next_lsn: u64,
+    pending: VecDeque<WalEntry>,
+    // Entries past the durable LSN are left until they are synced
+    durable: watch::Receiver<u64>,
+}
 
-            // Verify CRC
-            let mut hasher = Hasher::new();
-            hasher.update(&data);
-            if hasher.finalize() != expected_crc {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "CRC mismatch - corrupted WAL entry"));
+impl WalTail {
+    /// The next entry, waiting for one to be appended if need be. Returns
+    /// None once the log is closed and read to the end. Entries must follow
+    /// on without a gap: if the log no longer holds the next one (it starts
+    /// after a snapshot, say), this fails with `ErrorKind::NotFound`.
+    pub async fn next(&mut self) -> Option<io::Result<WalEntry>> {
+        let mut closed = false;
+        loop {
+            if let Some(entry) = self.pending.pop_front() {
+                return Some(Ok(entry));
+            }
+            // Mark what's durable so far as seen before reading it
+            let durable = *self.durable.borrow_and_update();
+            if let Err(e) = self.read_new(durable) {
+                return Some(Err(e));
+            }
+            if self.pending.is_empty() {
+                if closed {
+                    return None;
+                }
+                closed = self.durable.changed().await.is_err();
+            }
+        }
+    }
+
+    // Reads every whole entry written since the last call, moving on to
+    // later segments as they appear
+    fn read_new(&mut self, durable: u64) -> io::Result<()> {
+        loop {
+            if !self.read_segment(durable)? {
+                return Ok(());
             }
+            let next = list_segments(&self.dir)?.into_iter().find(|&first| first > self.segment);
+            let Some(next) = next else {
+                return Ok(());
+            };
+            // Nothing is written to a segment once the next exists, but some
+            // entries may have been since the last read
+            if !self.read_segment(durable)? {
+                return Ok(());
+            }
+            self.reader = BufReader::new(File::open(segment_path(&self.dir, next))?);
+            self.segment = next;
+            self.offset = 0;
+        }
+    }
 
-            // Deserialize
-            let entry: WalEntry = bincode::deserialize(&data)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-            entries.push(entry);
+    // Reads the whole entries written to the current segment since the last
+    // call, up to `durable`. An entry still being written is left for next
+    // time. Returns false if it stopped at an entry not yet durable.
+    fn read_segment(&mut self, durable: u64) -> io::Result<bool> {
+        let file_len = self.reader.get_ref().metadata()?.len();
+        self.reader.seek(SeekFrom::Start(self.offset))?;
+        loop {
+            let (entry, len) = match read_entry(&mut self.reader, file_len - self.offset) {
+                Ok(Some(read)) => read,
+                Ok(None) => return Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(true),
+                Err(e) => return Err(e),
+            };
+            if entry.lsn > durable {
+                return Ok(false);
+            }
+            self.offset += len;
+            if entry.lsn < self.next_lsn {
+                continue;
+            }
+            if entry.lsn > self.next_lsn {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("The log no longer holds LSN {}; it continues at {}", self.next_lsn, entry.lsn),
+                ));
+            }
+            self.next_lsn += 1;
+            self.pending.push_back(entry);
         }
+    }
+}
+
+// Reads one entry and the number of bytes it took, or None at the end of
+// the log, given that `available` bytes are left. Fails as `read_record`
+// does, or with `ErrorKind::InvalidData` if the entry can't be decoded.
+fn read_entry(reader: &mut impl Read, available: u64) -> io::Result<Option<(WalEntry, u64)>> {
+    match read_record(reader, available)? {
+        Some(data) => Ok(Some((decode_entry(&data)?, HEADER_BYTES + data.len() as u64))),
+        None => Ok(None),
+    }
+}
+
+// Reads one record's data, or None at the end of the log, given that
+// `available` bytes are left. Fails with `ErrorKind::UnexpectedEof` if the
+// log ends mid-record, and with `ErrorKind::InvalidData` if the data doesn't
+// match its CRC or is empty.
+fn read_record(reader: &mut impl Read, available: u64) -> io::Result<Option<Vec<u8>>> {
+    if available == 0 {
+        return Ok(None);
+    }
+
+    // Read CRC32
+    let mut crc_buf = [0u8; 4];
+    reader.read_exact(&mut crc_buf)?;
+    let expected_crc = u32::from_le_bytes(crc_buf);
+
+    // Read Length
+    let mut len_buf = [0u8; 8];
+    reader.read_exact(&mut len_buf)?;
+    let len = u64::from_le_bytes(len_buf);
+    // A torn or corrupt length may be anything; don't trust it past the end
+    if len > available.saturating_sub(HEADER_BYTES) {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "WAL entry runs past the end of the log"));
+    }
+
+    // Read Data
+    let mut data = vec![0u8; len as usize];
+    reader.read_exact(&mut data)?;
+
+    // Verify CRC. Zeros, as a crash can leave past the end of a file, have
+    // a matching one, but no entry is empty.
+    let mut hasher = Hasher::new();
+    hasher.update(&data);
+    if hasher.finalize() != expected_crc || data.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "CRC mismatch - corrupted WAL entry"));
+    }
+    Ok(Some(data))
+}
+
+// Write format: [CRC32 (4 bytes)] [Length (8 bytes)] [Data]
+fn write_record(writer: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    let mut hasher = Hasher::new();
+    hasher.update(data);
+    writer.write_all(&hasher.finalize().to_le_bytes())?;
+    writer.write_all(&(data.len() as u64).to_le_bytes())?;
+    writer.write_all(data)
+}
+
+// Entries are encoded as `bincode::serialize` does, and must take up the
+// whole record
+fn decode<T: serde::de::DeserializeOwned>(data: &[u8]) -> io::Result<T> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .deserialize(data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn decode_entry(data: &[u8]) -> io::Result<WalEntry> {
+    decode(data)
+}
+
+// An entry as logged before entries had versions
+#[derive(Deserialize)]
+struct UnversionedEntry {
+    lsn: u64,
+    op: OpType,
+    vector_id: u32,
+    vector: Vec<f32>,
+    payload: Payload,
+}
+
+fn decode_unversioned_entry(data: &[u8]) -> io::Result<WalEntry> {
+    let old: UnversionedEntry = decode(data)?;
+    Ok(WalEntry { lsn: old.lsn, op: old.op, vector_id: old.vector_id, version: 0, vector: old.vector, payload: old.payload })
+}
+
+/// Syncs the directory holding `path`, so a file created in or renamed into
+/// it survives a crash.
+pub fn sync_parent(path: &Path) -> io::Result<()> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(entries)
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wal_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn insert(id: u32) -> WalEntry {
+        WalEntry {
+            lsn: 0,
+            op: OpType::Insert,
+            vector_id: id,
+            version: 1,
+            vector: vec![id as f32; 4],
+            payload: Payload::new(),
+        }
+    }
+
+    fn lsns(wal: &Wal) -> Vec<u64> {
+        wal.read_all().unwrap().map(|entry| entry.unwrap().lsn).collect()
+    }
+
+    #[tokio::test]
+    async fn test_segments_rotate_and_are_removed() {
+        let dir = temp_dir("segments");
+        // Room for two entries per segment
+        let config = WalConfig { segment_bytes: 150, ..Default::default() };
+        let wal = Wal::open(&dir, config.clone()).unwrap();
+        for id in 0..5 {
+            wal.append(&mut insert(id)).unwrap();
+        }
+        wal.commit(5).await.unwrap();
+        assert_eq!(list_segments(&dir).unwrap(), vec![1, 3, 5]);
+        assert_eq!(lsns(&wal), vec![1, 2, 3, 4, 5]);
+
+        // Only segments whose every entry is covered go, never the current one
+        assert_eq!(wal.remove_through(3).unwrap(), 1);
+        assert_eq!(wal.remove_through(5).unwrap(), 1);
+        assert_eq!(list_segments(&dir).unwrap(), vec![5]);
+        drop(wal);
+
+        let wal = Wal::open(&dir, config).unwrap();
+        assert_eq!(wal.last_lsn(), 5);
+        assert_eq!(wal.append(&mut insert(5)).unwrap(), 6);
+        wal.commit(6).await.unwrap();
+        assert_eq!(lsns(&wal), vec![5, 6]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_write_stops_the_log() {
+        let dir = temp_dir("failed");
+        let config = WalConfig { segment_bytes: 150, ..Default::default() };
+        let wal = Wal::open(&dir, config.clone()).unwrap();
+        wal.append(&mut insert(0)).unwrap();
+        wal.append(&mut insert(1)).unwrap();
+        wal.commit(2).await.unwrap();
+
+        // The next entry starts a segment, which can't be created
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(wal.append(&mut insert(2)).is_err());
+        fs::create_dir_all(&dir).unwrap();
+        assert!(wal.append(&mut insert(2)).is_err(), "the log stays failed");
+        drop(wal);
+
+        let wal = Wal::open(&dir, config).unwrap();
+        assert_eq!(wal.append(&mut insert(2)).unwrap(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_covered_segments_can_be_archived() {
+        let dir = temp_dir("archive");
+        let config = WalConfig { segment_age: Some(Duration::ZERO), archive: true, ..Default::default() };
+        let wal = Wal::open(&dir, config).unwrap();
+        for id in 0..3 {
+            wal.append(&mut insert(id)).unwrap();
+        }
+        wal.commit(3).await.unwrap();
+        assert_eq!(list_segments(&dir).unwrap(), vec![1, 2, 3]);
+        assert_eq!(wal.remove_through(3).unwrap(), 2);
+        assert_eq!(list_segments(&dir.join(ARCHIVE_DIR)).unwrap(), vec![1, 2]);
+        assert_eq!(lsns(&wal), vec![3]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_single_file_log_is_imported() {
+        let dir = temp_dir("import");
+        let wal = Wal::open(&dir, WalConfig::default()).unwrap();
+        wal.skip_to(9);
+        wal.append(&mut insert(0)).unwrap();
+        wal.append(&mut insert(1)).unwrap();
+        drop(wal);
+        let file = dir.with_extension("wal");
+        fs::rename(segment_path(&dir, 1), &file).unwrap();
+
+        Wal::import(&dir, &file).unwrap();
+        assert!(!file.exists());
+        assert_eq!(list_segments(&dir).unwrap(), vec![10]);
+        let wal = Wal::open(&dir, WalConfig::default()).unwrap();
+        assert_eq!(lsns(&wal), vec![10, 11]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unversioned_log_is_converted() {
+        #[derive(Serialize)]
+        struct Unversioned {
+            lsn: u64,
+            op: OpType,
+            vector_id: u32,
+            vector: Vec<f32>,
+            payload: Payload,
+        }
+        let dir = temp_dir("unversioned");
+        let file = dir.with_extension("wal");
+        let mut bytes = Vec::new();
+        for lsn in 4..7 {
+            let entry = Unversioned { lsn, op: OpType::Insert, vector_id: lsn as u32, vector: vec![1.0; 2], payload: Payload::new() };
+            write_record(&mut bytes, &bincode::serialize(&entry).unwrap()).unwrap();
+        }
+        // Cut short mid-header
+        bytes.extend([1, 2, 3]);
+        fs::write(&file, &bytes).unwrap();
+
+        Wal::import(&dir, &file).unwrap();
+        assert!(!file.exists());
+        assert_eq!(list_segments(&dir).unwrap(), vec![4]);
+        let wal = Wal::open(&dir, WalConfig::default()).unwrap();
+        let entries: Vec<(u64, u32, u64)> = wal
+            .read_all()
+            .unwrap()
+            .map(|entry| entry.map(|e| (e.lsn, e.vector_id, e.version)).unwrap())
+            .collect();
+        assert_eq!(entries, vec![(4, 4, 0), (5, 5, 0), (6, 6, 0)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tail_follows_rotation() {
+        let dir = temp_dir("tail");
+        let config = WalConfig { segment_bytes: 150, ..Default::default() };
+        let wal = Arc::new(Wal::open(&dir, config).unwrap());
+        wal.append(&mut insert(0)).unwrap();
+        wal.commit(1).await.unwrap();
+        let mut tail = wal.tail(0).unwrap();
+
+        let writer = wal.clone();
+        tokio::spawn(async move {
+            for id in 1..6 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                let lsn = writer.append(&mut insert(id)).unwrap();
+                writer.commit(lsn).await.unwrap();
+            }
+        });
+        for lsn in 1..=6 {
+            assert_eq!(tail.next().await.unwrap().unwrap().lsn, lsn);
+        }
+
+        // Reading from before the first segment left finds a gap
+        wal.remove_through(4).unwrap();
+        let err = wal.tail(0).unwrap().next().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(wal.tail(4).unwrap().next().await.unwrap().unwrap().lsn, 5);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tail_sees_only_durable_entries() {
+        let dir = temp_dir("policies");
+        let policies = [SyncPolicy::Always, SyncPolicy::Interval(Duration::from_millis(50)), SyncPolicy::Os];
+        for (i, sync) in policies.into_iter().enumerate() {
+            let wal = Wal::open(&dir.join(i.to_string()), WalConfig { sync, ..Default::default() }).unwrap();
+            let mut tail = wal.tail(0).unwrap();
+
+            // Commits waiting at once share syncs
+            let wal = Arc::new(wal);
+            let writes: Vec<_> = (0..20)
+                .map(|id| {
+                    let wal = wal.clone();
+                    tokio::spawn(async move {
+                        let lsn = wal.append(&mut insert(id)).unwrap();
+                        wal.commit(lsn).await.unwrap();
+                    })
+                })
+                .collect();
+            for write in writes {
+                write.await.unwrap();
+            }
+            // Unless syncs wait for the interval, a commit makes its entry durable
+            if !matches!(sync, SyncPolicy::Interval(_)) {
+                assert_eq!(*wal.durable.borrow(), 20);
+            }
+            for lsn in 1..=20 {
+                let next = tokio::time::timeout(Duration::from_secs(1), tail.next()).await;
+                assert_eq!(next.unwrap().unwrap().unwrap().lsn, lsn, "{:?}", sync);
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Writes `count` entries to a one-segment log in `dir`. Returns its bytes,
+    // and the offset where each entry ends.
+    async fn write_log(dir: &Path, count: u32) -> (Vec<u8>, Vec<usize>) {
+        let wal = Wal::open(dir, WalConfig::default()).unwrap();
+        let mut ends = Vec::new();
+        for id in 0..count {
+            let lsn = wal.append(&mut insert(id)).unwrap();
+            wal.commit(lsn).await.unwrap();
+            ends.push(fs::metadata(segment_path(dir, 1)).unwrap().len() as usize);
+        }
+        (fs::read(segment_path(dir, 1)).unwrap(), ends)
+    }
+
+    // Replaces the log in `dir` with one segment holding `bytes`
+    fn write_segment(dir: &Path, bytes: &[u8]) {
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        fs::write(segment_path(dir, 1), bytes).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recovers_from_truncation_at_every_offset() {
+        let dir = temp_dir("truncate");
+        let (bytes, ends) = write_log(&dir.join("source"), 4).await;
+        let log = dir.join("log");
+        for cut in 0..=bytes.len() {
+            write_segment(&log, &bytes[..cut]);
+            let whole = ends.iter().filter(|&&end| end <= cut).count() as u64;
+
+            let wal = Wal::open(&log, WalConfig::default()).unwrap();
+            assert_eq!(wal.last_lsn(), whole, "cut at {}", cut);
+            assert_eq!(lsns(&wal), (1..=whole).collect::<Vec<_>>(), "cut at {}", cut);
+
+            // The partial entry is gone, so a new one reads back after the last whole one
+            let lsn = wal.append(&mut insert(9)).unwrap();
+            wal.commit(lsn).await.unwrap();
+            assert_eq!(lsns(&wal), (1..=whole + 1).collect::<Vec<_>>(), "cut at {}", cut);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_corruption_mid_log_fails_unless_skipped() {
+        let dir = temp_dir("corrupt");
+        let (bytes, ends) = write_log(&dir.join("source"), 5).await;
+        let log = dir.join("log");
+
+        // A flipped byte in the third entry, with whole entries after it
+        let mut corrupt = bytes.clone();
+        corrupt[ends[1] + 20] ^= 0xff;
+        write_segment(&log, &corrupt);
+        let err = Wal::open(&log, WalConfig::default()).err().unwrap();
+        assert!(is_corruption(&err));
+        let config = WalConfig { skip_corrupt: true, ..Default::default() };
+        let wal = Wal::open(&log, config).unwrap();
+        assert_eq!(wal.last_lsn(), 5);
+        assert_eq!(lsns(&wal), vec![1, 2, 4, 5]);
+        drop(wal);
+
+        // The same damage to the last entry, or a run of zeros after it, is a torn write
+        let mut torn = bytes.clone();
+        torn[ends[3] + 20] ^= 0xff;
+        write_segment(&log, &torn);
+        assert_eq!(lsns(&Wal::open(&log, WalConfig::default()).unwrap()), vec![1, 2, 3, 4]);
+        let mut zeros = bytes.clone();
+        zeros.extend([0; 100]);
+        write_segment(&log, &zeros);
+        assert_eq!(lsns(&Wal::open(&log, WalConfig::default()).unwrap()), vec![1, 2, 3, 4, 5]);
+        assert_eq!(fs::metadata(segment_path(&log, 1)).unwrap().len(), bytes.len() as u64);
+
+        // Any damage to a segment before the last is corruption
+        write_segment(&log, &bytes[..ends[2] - 1]);
+        fs::write(segment_path(&log, 4), []).unwrap();
+        let wal = Wal::open(&log, WalConfig::default()).unwrap();
+        let err = wal.read_all().unwrap().find_map(Result::err).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let config = WalConfig { skip_corrupt: true, ..Default::default() };
+        assert_eq!(lsns(&Wal::open(&log, config).unwrap()), vec![1, 2]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_undecodable_entry_is_never_cut() {
+        let dir = temp_dir("undecodable");
+        let (mut bytes, _) = write_log(&dir.join("source"), 3).await;
+        let log = dir.join("log");
+
+        // A whole record, CRC and all, holding something other than an entry
+        let data = [7u8; 5];
+        bytes.extend(crc32fast::hash(&data).to_le_bytes());
+        bytes.extend((data.len() as u64).to_le_bytes());
+        bytes.extend(data);
+        write_segment(&log, &bytes);
+        for skip_corrupt in [false, true] {
+            let err = Wal::open(&log, WalConfig { skip_corrupt, ..Default::default() }).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(!is_corruption(&err));
+        }
+        assert_eq!(fs::metadata(segment_path(&log, 1)).unwrap().len(), bytes.len() as u64);
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,139 @@
+mod common;
+
+use common::vector_db::{
+    CreateCollectionRequest, DescribeCollectionRequest, DropCollectionRequest, ListCollectionsRequest, Metric,
+    PutRequest, SearchRequest,
+};
+use common::{connect, free_port, spawn_server, temp_dir, ServerGuard};
+
+fn put(collection: &str, id: u32, vector: Vec<f32>) -> PutRequest {
+    PutRequest { id, vector, collection: collection.to_string(), ..Default::default() }
+}
+
+fn search(collection: &str, vector: Vec<f32>, k: u32) -> SearchRequest {
+    SearchRequest { vector, k, collection: collection.to_string(), ..Default::default() }
+}
+
+#[tokio::test]
+async fn test_collections_are_isolated_and_persistent() {
+    let data_dir = temp_dir("collections");
+    let port = free_port();
+
+    let mut server = ServerGuard(spawn_server(port, &data_dir, &[]));
+    let mut client = connect(port).await;
+
+    client
+        .create_collection(CreateCollectionRequest {
+            name: "docs".into(),
+            metric: Metric::Cosine as i32,
+            dimension: 2,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    client
+        .create_collection(CreateCollectionRequest { name: "images".into(), m: 8, ..Default::default() })
+        .await
+        .unwrap();
+
+    let err = client
+        .create_collection(CreateCollectionRequest { name: "docs".into(), ..Default::default() })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::AlreadyExists);
+
+    // Same id, different collections, different vectors
+    client.put(put("docs", 1, vec![10.0, 0.0])).await.unwrap();
+    client.put(put("docs", 2, vec![0.7, 0.7])).await.unwrap();
+    client.put(put("images", 1, vec![0.0, 5.0])).await.unwrap();
+
+    // Cosine ranks the aligned long vector first; euclidean would not
+    let resp = client.search(search("docs", vec![1.0, 0.0], 2)).await.unwrap().into_inner();
+    assert_eq!(resp.results[0].id, 1);
+    let resp = client.search(search("images", vec![1.0, 0.0], 5)).await.unwrap().into_inner();
+    assert_eq!(resp.results.len(), 1);
+    let resp = client.search(search("", vec![1.0, 0.0], 5)).await.unwrap().into_inner();
+    assert!(resp.results.is_empty(), "default collection must not see other collections' data");
+
+    let err = client.search(search("missing", vec![1.0, 0.0], 1)).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound);
+
+    client.drop_collection(DropCollectionRequest { name: "images".into() }).await.unwrap();
+
+    server.0.kill().unwrap();
+    server.0.wait().unwrap();
+    let _server = ServerGuard(spawn_server(port, &data_dir, &[]));
+    let mut client = connect(port).await;
+
+    let resp = client.list_collections(ListCollectionsRequest {}).await.unwrap().into_inner();
+    let names: Vec<&str> = resp.collections.iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(names, vec!["default", "docs"]);
+
+    let info = client
+        .describe_collection(DescribeCollectionRequest { name: "docs".into() })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(info.metric, Metric::Cosine as i32);
+    assert_eq!(info.dimension, 2);
+    assert_eq!(info.vector_count, 2);
+
+    let err = client
+        .describe_collection(DescribeCollectionRequest { name: "images".into() })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound);
+
+    drop(_server);
+    let _ = std::fs::remove_dir_all(&data_dir);
+}
+
+#[tokio::test]
+async fn test_invalid_vectors_are_rejected() {
+    let data_dir = temp_dir("validation");
+    let port = free_port();
+
+    let _server = ServerGuard(spawn_server(port, &data_dir, &[]));
+    let mut client = connect(port).await;
+
+    client
+        .create_collection(CreateCollectionRequest { name: "docs".into(), dimension: 3, ..Default::default() })
+        .await
+        .unwrap();
+
+    // Declared dimension applies from the first put
+    let err = client.put(put("docs", 1, vec![1.0, 0.0])).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    client.put(put("docs", 1, vec![1.0, 0.0, 0.0])).await.unwrap();
+
+    // Undeclared dimension is fixed by the first put
+    client.put(put("", 1, vec![1.0, 0.0])).await.unwrap();
+    for vector in [vec![], vec![1.0, 0.0, 0.0], vec![f32::NAN, 0.0], vec![f32::INFINITY, 0.0]] {
+        let err = client.put(put("", 2, vector)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+    let err = client.search(search("", vec![1.0], 1)).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    let err = client.search(search("docs", vec![1.0, f32::NEG_INFINITY, 0.0], 1)).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    let err = client.search(SearchRequest { ef: Some(0), ..search("docs", vec![1.0, 0.0, 0.0], 0) }).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    let err = client
+        .create_collection(CreateCollectionRequest { name: "empty".into(), ef_search: Some(0), ..Default::default() })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+    // The shard keeps serving after the bad requests
+    let resp = client.search(search("", vec![1.0, 0.0], 5)).await.unwrap().into_inner();
+    assert_eq!(resp.results.len(), 1);
+    let info = client
+        .describe_collection(DescribeCollectionRequest { name: "".into() })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(info.dimension, 2);
+
+    drop(_server);
+    let _ = std::fs::remove_dir_all(&data_dir);
+}
@@ -0,0 +1,125 @@
+#![allow(dead_code)]
+
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use tonic::transport::Channel;
+
+#[allow(clippy::enum_variant_names)]
+pub mod vector_db {
+    tonic::include_proto!("vector_db");
+}
+
+use vector_db::router_admin_client::RouterAdminClient;
+use vector_db::vector_db_client::VectorDbClient;
+use vector_db::{GetMembershipRequest, GetRebalanceStatusRequest, RebalanceStatus};
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("[::1]:0").unwrap().local_addr().unwrap().port()
+}
+
+/// A fresh, empty scratch directory unique to this test process and `name`.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("vdb_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn spawn_server(port: u16, data_dir: &Path, extra_args: &[&str]) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_server"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg("--data-dir")
+        .arg(data_dir)
+        .args(extra_args)
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start server")
+}
+
+/// Starts a router; unless `extra_args` say otherwise, it keeps hints in a
+/// scratch directory of its own.
+pub fn spawn_router(port: u16, extra_args: &[&str]) -> Child {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_router"));
+    if !extra_args.contains(&"--hints-dir") {
+        let hints = std::env::temp_dir().join(format!("vdb_hints_{}", port));
+        let _ = std::fs::remove_dir_all(&hints);
+        command.arg("--hints-dir").arg(hints);
+    }
+    command
+        .arg("--listen")
+        .arg(format!("[::1]:{}", port))
+        .args(extra_args)
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start router")
+}
+
+pub fn url(port: u16) -> String {
+    format!("http://[::1]:{}", port)
+}
+
+pub async fn connect(port: u16) -> VectorDbClient<Channel> {
+    for _ in 0..100 {
+        if let Ok(client) = VectorDbClient::connect(url(port)).await {
+            return client;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("server on port {} did not come up", port);
+}
+
+pub async fn connect_admin(port: u16) -> RouterAdminClient<Channel> {
+    for _ in 0..100 {
+        if let Ok(client) = RouterAdminClient::connect(url(port)).await {
+            return client;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("router on port {} did not come up", port);
+}
+
+pub fn sorted(mut backends: Vec<String>) -> Vec<String> {
+    backends.sort();
+    backends
+}
+
+/// Waits for a rebalance to finish, successfully or not, and returns its status.
+pub async fn wait_for_rebalance(admin: &mut RouterAdminClient<Channel>) -> RebalanceStatus {
+    use vector_db::rebalance_status::State;
+
+    for _ in 0..300 {
+        let status = admin.get_rebalance_status(GetRebalanceStatusRequest {}).await.unwrap().into_inner();
+        if status.state() != State::Running {
+            return status;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("rebalance did not finish");
+}
+
+/// Waits until the router serves exactly `backends`.
+pub async fn wait_for_membership(admin: &mut RouterAdminClient<Channel>, backends: &[String]) {
+    let expected = sorted(backends.to_vec());
+    let mut serving = vec![];
+    for _ in 0..300 {
+        serving = sorted(admin.get_membership(GetMembershipRequest {}).await.unwrap().into_inner().backends);
+        if serving == expected {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("membership is {:?}, expected {:?}", serving, expected);
+}
+
+/// Kills the child process when dropped, so a failing test never leaks servers.
+pub struct ServerGuard(pub Child);
+
+impl Drop for ServerGuard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
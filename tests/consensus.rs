@@ -0,0 +1,73 @@
+mod common;
+
+use common::vector_db::{DeleteRequest, GetRequest, PutRequest};
+use common::{connect, free_port, spawn_router, spawn_server, temp_dir, url, ServerGuard};
+use std::time::Duration;
+
+const RAFT_TIMING: [&str; 4] = ["--raft-heartbeat-ms", "50", "--raft-election-timeout-ms", "300"];
+
+// Waits until the server on `port` holds `id` at some version, and returns it
+async fn version_on(port: u16, id: u32) -> u64 {
+    let mut node = connect(port).await;
+    for _ in 0..100 {
+        let resp = node.get(GetRequest { id, ..Default::default() }).await.unwrap().into_inner();
+        if resp.found {
+            return resp.version;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("{} never reached the server on port {}", id, port);
+}
+
+#[tokio::test]
+async fn test_strong_writes_commit_through_raft() {
+    let dir = temp_dir("consensus");
+    let ports: Vec<u16> = (0..3).map(|_| free_port()).collect();
+    let mut servers: Vec<ServerGuard> = ports
+        .iter()
+        .map(|&port| ServerGuard(spawn_server(port, &dir.join(port.to_string()), &RAFT_TIMING)))
+        .collect();
+    for &port in &ports {
+        connect(port).await;
+    }
+
+    let router_port = free_port();
+    let backends: Vec<String> = ports.iter().flat_map(|&p| ["--backend".to_string(), url(p)]).collect();
+    let mut args: Vec<&str> = backends.iter().map(String::as_str).collect();
+    args.extend(["--replication-factor", "3", "--consistency", "strong", "--request-timeout-ms", "2000"]);
+    let _router = ServerGuard(spawn_router(router_port, &args));
+    let mut client = connect(router_port).await;
+
+    for id in 0..10 {
+        client.put(PutRequest { id, vector: vec![id as f32, 0.0], ..Default::default() }).await.unwrap();
+    }
+    let resp = client.delete(DeleteRequest { id: 0, ..Default::default() }).await.unwrap().into_inner();
+    assert!(resp.found);
+
+    // Every replica applies the same writes at the same versions
+    for id in 1..10 {
+        let version = version_on(ports[0], id).await;
+        assert_eq!(version_on(ports[1], id).await, version);
+        assert_eq!(version_on(ports[2], id).await, version);
+    }
+    let err = client.put(PutRequest { id: 20, vector: vec![1.0], ..Default::default() }).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument, "{}", err.message());
+
+    // With one replica gone, the other two still form a majority
+    drop(servers.remove(0));
+    for id in 10..15 {
+        client.put(PutRequest { id, vector: vec![id as f32, 0.0], ..Default::default() }).await.unwrap();
+    }
+    for id in 10..15 {
+        assert_eq!(version_on(ports[1], id).await, version_on(ports[2], id).await);
+    }
+
+    // Without a majority nothing commits
+    drop(servers.remove(0));
+    let err = client.put(PutRequest { id: 15, vector: vec![15.0, 0.0], ..Default::default() }).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::Unavailable, "{}", err.message());
+
+    drop(_router);
+    drop(servers);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,62 @@
+mod common;
+
+use common::vector_db::{DeleteRequest, PutRequest, SearchRequest, SnapshotRequest};
+use common::{connect, free_port, spawn_server, temp_dir, ServerGuard};
+
+fn vector_for(id: u32) -> Vec<f32> {
+    vec![id as f32, (id * 2) as f32, (id % 7) as f32]
+}
+
+fn put(id: u32) -> PutRequest {
+    PutRequest { id, vector: vector_for(id), ..Default::default() }
+}
+
+fn search(id: u32, k: u32) -> SearchRequest {
+    SearchRequest { vector: vector_for(id), k, ..Default::default() }
+}
+
+#[tokio::test]
+async fn test_puts_after_snapshot_survive_kill() {
+    let data_dir = temp_dir("crash");
+    let port = free_port();
+
+    let mut server = ServerGuard(spawn_server(port, &data_dir, &[]));
+    let mut client = connect(port).await;
+
+    for id in 0..20 {
+        client.put(put(id)).await.unwrap();
+    }
+    client.snapshot(SnapshotRequest {}).await.unwrap();
+    for id in 20..40 {
+        client.put(put(id)).await.unwrap();
+    }
+    let resp = client.delete(DeleteRequest { id: 5, ..Default::default() }).await.unwrap().into_inner();
+    assert!(resp.found);
+
+    // SIGKILL: no chance to flush or snapshot on the way down
+    server.0.kill().unwrap();
+    server.0.wait().unwrap();
+
+    let mut server = ServerGuard(spawn_server(port, &data_dir, &[]));
+    let mut client = connect(port).await;
+
+    for id in (0..40).filter(|&id| id != 5) {
+        let resp = client.search(search(id, 1)).await.unwrap().into_inner();
+        assert_eq!(resp.results.len(), 1);
+        assert_eq!(resp.results[0].id, id, "vector {} lost after restart", id);
+    }
+    let resp = client.search(search(5, 40)).await.unwrap().into_inner();
+    assert!(resp.results.iter().all(|r| r.id != 5), "deleted vector came back after restart");
+
+    // A second restart must not replay the post-snapshot entries twice or drop any
+    server.0.kill().unwrap();
+    server.0.wait().unwrap();
+    let _server = ServerGuard(spawn_server(port, &data_dir, &[]));
+    let mut client = connect(port).await;
+    client.put(put(40)).await.unwrap();
+    let resp = client.search(search(40, 41)).await.unwrap().into_inner();
+    assert_eq!(resp.results.len(), 40);
+
+    drop(_server);
+    let _ = std::fs::remove_dir_all(&data_dir);
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use common::vector_db::{GetMembershipRequest, GetRequest, PutRequest, ReloadMembershipRequest};
+use common::{connect, connect_admin, free_port, spawn_router, spawn_server, temp_dir, url, wait_for_membership, ServerGuard};
+
+const GOSSIP_ARGS: [&str; 4] = ["--gossip-period-ms", "100", "--suspicion-timeout-ms", "1000"];
+
+#[tokio::test]
+async fn test_router_follows_gossiped_membership() {
+    let dir = temp_dir("gossip");
+    let ports: Vec<u16> = (0..3).map(|_| free_port()).collect();
+    let seed = url(ports[0]);
+    let start = |port: u16, seeds: &[&str]| {
+        let mut args = GOSSIP_ARGS.to_vec();
+        for seed in seeds {
+            args.extend(["--seed", seed]);
+        }
+        ServerGuard(spawn_server(port, &dir.join(port.to_string()), &args))
+    };
+    let mut servers = vec![start(ports[0], &[])];
+    for &port in &ports[1..] {
+        servers.push(start(port, &[&seed]));
+    }
+
+    // The router only knows the seed; the rest of the cluster comes from gossip
+    let router_port = free_port();
+    let mut router_args = GOSSIP_ARGS.to_vec();
+    router_args.extend(["--seed", &seed]);
+    let _router = ServerGuard(spawn_router(router_port, &router_args));
+    let mut client = connect(router_port).await;
+    let mut admin = connect_admin(router_port).await;
+
+    let all: Vec<String> = ports.iter().map(|&p| url(p)).collect();
+    wait_for_membership(&mut admin, &all).await;
+    for id in 0..50 {
+        client.put(PutRequest { id, vector: vec![id as f32, 0.0], ..Default::default() }).await.unwrap();
+    }
+
+    // Manual membership changes would fight gossip
+    let err = admin.reload_membership(ReloadMembershipRequest::default()).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+
+    // A backend that stops is declared dead and leaves the ring, and its
+    // ranges are re-replicated from the survivors
+    drop(servers.pop());
+    wait_for_membership(&mut admin, &all[..2]).await;
+    let membership = admin.get_membership(GetMembershipRequest {}).await.unwrap().into_inner();
+    assert_eq!(membership.member_states[&all[2]], "dead");
+    assert_eq!(membership.member_states[&all[0]], "alive");
+    assert_eq!(membership.member_states.len(), 4, "the router is a member too");
+    for id in 0..50 {
+        let resp = client.get(GetRequest { id, ..Default::default() }).await.unwrap().into_inner();
+        assert!(resp.found, "lost {}", id);
+    }
+
+    // Restarted, it refutes its death and is rebalanced back in
+    servers.push(start(ports[2], &[&seed]));
+    wait_for_membership(&mut admin, &all).await;
+    for id in 0..50 {
+        let resp = client.get(GetRequest { id, ..Default::default() }).await.unwrap().into_inner();
+        assert!(resp.found, "lost {}", id);
+    }
+
+    drop(_router);
+    drop(servers);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use common::vector_db::router_admin_client::RouterAdminClient;
+use common::vector_db::{DeleteRequest, GetMembershipRequest, GetRequest, PutRequest};
+use common::{connect, connect_admin, free_port, spawn_router, spawn_server, temp_dir, url, ServerGuard};
+use std::collections::HashMap;
+use std::time::Duration;
+use tonic::transport::Channel;
+
+async fn pending_hints(admin: &mut RouterAdminClient<Channel>) -> HashMap<String, u64> {
+    admin.get_membership(GetMembershipRequest {}).await.unwrap().into_inner().pending_hints
+}
+
+#[tokio::test]
+async fn test_missed_writes_are_replayed() {
+    let dir = temp_dir("handoff");
+    let ports = [free_port(), free_port()];
+    let start = |port: u16| ServerGuard(spawn_server(port, &dir.join(port.to_string()), &[]));
+    let mut servers = vec![start(ports[0]), start(ports[1])];
+    connect(ports[1]).await;
+
+    let hints_dir = dir.join("hints");
+    let router_port = free_port();
+    let router_args = [
+        "--backend",
+        &url(ports[0]),
+        "--backend",
+        &url(ports[1]),
+        "--replication-factor",
+        "2",
+        "--hint-replay-interval-ms",
+        "200",
+        "--hints-dir",
+        hints_dir.to_str().unwrap(),
+    ];
+    let mut router = ServerGuard(spawn_router(router_port, &router_args));
+    let mut client = connect(router_port).await;
+    for id in 0..10 {
+        client.put(PutRequest { id, vector: vec![id as f32, 0.0], ..Default::default() }).await.unwrap();
+    }
+
+    // With a write quorum of 1, writes keep succeeding while a replica is down
+    drop(servers.pop());
+    for id in 10..30 {
+        client.put(PutRequest { id, vector: vec![id as f32, 1.0], ..Default::default() }).await.unwrap();
+    }
+    client.delete(DeleteRequest { id: 0, ..Default::default() }).await.unwrap();
+
+    let missed = url(ports[1]);
+    let mut admin = connect_admin(router_port).await;
+    assert_eq!(pending_hints(&mut admin).await, HashMap::from([(missed.clone(), 21)]));
+
+    // Hints are durable
+    drop(router);
+    router = ServerGuard(spawn_router(router_port, &router_args));
+    let mut admin = connect_admin(router_port).await;
+    assert_eq!(pending_hints(&mut admin).await[&missed], 21);
+
+    // Once the replica is back, it catches up
+    servers.push(start(ports[1]));
+    let mut replica = connect(ports[1]).await;
+    for _ in 0..100 {
+        if pending_hints(&mut admin).await.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(pending_hints(&mut admin).await.is_empty(), "hints were not replayed");
+    for id in 10..30 {
+        let resp = replica.get(GetRequest { id, ..Default::default() }).await.unwrap().into_inner();
+        assert!(resp.found, "{} was not replayed", id);
+        assert_eq!(resp.vector, vec![id as f32, 1.0]);
+    }
+    let resp = replica.get(GetRequest { id: 0, ..Default::default() }).await.unwrap().into_inner();
+    assert!(!resp.found, "the delete was not replayed");
+
+    drop(router);
+    drop(servers);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,128 @@
+mod common;
+
+use common::vector_db::value::Kind;
+use common::vector_db::{GetRequest, PutRequest, SearchRequest, SnapshotRequest, StringList, Value};
+use common::{connect, free_port, spawn_server, temp_dir, ServerGuard};
+use std::collections::HashMap;
+
+fn value(kind: Kind) -> Value {
+    Value { kind: Some(kind) }
+}
+
+#[tokio::test]
+async fn test_payload_roundtrip_and_persistence() {
+    let data_dir = temp_dir("payload");
+    let port = free_port();
+
+    let payload: HashMap<String, Value> = HashMap::from([
+        ("title".to_string(), value(Kind::StringValue("hello".into()))),
+        ("year".to_string(), value(Kind::IntValue(2021))),
+        ("score".to_string(), value(Kind::FloatValue(0.5))),
+        ("public".to_string(), value(Kind::BoolValue(true))),
+        ("tags".to_string(), value(Kind::StringListValue(StringList { values: vec!["a".into(), "b".into()] }))),
+    ]);
+
+    let mut server = ServerGuard(spawn_server(port, &data_dir, &[]));
+    let mut client = connect(port).await;
+
+    client
+        .put(PutRequest { id: 1, vector: vec![1.0, 0.0], payload: payload.clone(), ..Default::default() })
+        .await
+        .unwrap();
+    client.snapshot(SnapshotRequest {}).await.unwrap();
+    client
+        .put(PutRequest { id: 2, vector: vec![0.0, 1.0], payload: payload.clone(), ..Default::default() })
+        .await
+        .unwrap();
+
+    // Payloads are only returned on request
+    let resp = client
+        .search(SearchRequest { vector: vec![1.0, 0.0], k: 1, ..Default::default() })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(resp.results[0].payload.is_empty());
+
+    // Restart: id 1 comes from the snapshot, id 2 from WAL replay
+    server.0.kill().unwrap();
+    server.0.wait().unwrap();
+    let _server = ServerGuard(spawn_server(port, &data_dir, &[]));
+    let mut client = connect(port).await;
+
+    let resp = client
+        .search(SearchRequest { vector: vec![1.0, 0.0], k: 2, with_payload: true, ..Default::default() })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(resp.results.len(), 2);
+    for result in &resp.results {
+        assert_eq!(result.payload, payload);
+    }
+
+    let resp = client.get(GetRequest { id: 2, ..Default::default() }).await.unwrap().into_inner();
+    assert!(resp.found);
+    assert_eq!(resp.vector, vec![0.0, 1.0]);
+    assert_eq!(resp.payload, payload);
+
+    let resp = client.get(GetRequest { id: 3, ..Default::default() }).await.unwrap().into_inner();
+    assert!(!resp.found);
+
+    // A value with no kind set is rejected
+    let bad = HashMap::from([("x".to_string(), Value { kind: None })]);
+    let err = client
+        .put(PutRequest { id: 4, vector: vec![1.0, 1.0], payload: bad, ..Default::default() })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+    drop(_server);
+    let _ = std::fs::remove_dir_all(&data_dir);
+}
+
+#[tokio::test]
+async fn test_filtered_search() {
+    use common::vector_db::{filter, Filter, FilterList, Match, Range};
+
+    let data_dir = temp_dir("filter");
+    let port = free_port();
+    let _server = ServerGuard(spawn_server(port, &data_dir, &[]));
+    let mut client = connect(port).await;
+
+    for id in 0..50u32 {
+        let payload = HashMap::from([
+            ("lang".to_string(), value(Kind::StringValue(if id % 2 == 0 { "en" } else { "de" }.into()))),
+            ("year".to_string(), value(Kind::IntValue(2000 + id as i64))),
+        ]);
+        client
+            .put(PutRequest { id, vector: vec![id as f32, 0.0], payload, ..Default::default() })
+            .await
+            .unwrap();
+    }
+
+    // lang == "en" AND year >= 2030
+    let en = Filter {
+        kind: Some(filter::Kind::Eq(Match { key: "lang".into(), value: Some(value(Kind::StringValue("en".into()))) })),
+    };
+    let recent = Filter {
+        kind: Some(filter::Kind::Range(Range { key: "year".into(), gte: Some(2030.0), ..Default::default() })),
+    };
+    let both = Filter { kind: Some(filter::Kind::And(FilterList { filters: vec![en, recent] })) };
+
+    let resp = client
+        .search(SearchRequest { vector: vec![0.0, 0.0], k: 3, filter: Some(both), ..Default::default() })
+        .await
+        .unwrap()
+        .into_inner();
+    let ids: Vec<u32> = resp.results.iter().map(|r| r.id).collect();
+    assert_eq!(ids, vec![30, 32, 34]);
+
+    // A filter with no condition is rejected
+    let err = client
+        .search(SearchRequest { vector: vec![0.0, 0.0], k: 3, filter: Some(Filter { kind: None }), ..Default::default() })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+    drop(_server);
+    let _ = std::fs::remove_dir_all(&data_dir);
+}
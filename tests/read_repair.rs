@@ -0,0 +1,90 @@
+mod common;
+
+use common::vector_db::vector_db_client::VectorDbClient;
+use common::vector_db::{DeleteRequest, GetRequest, GetResponse, PutRequest};
+use common::{connect, free_port, spawn_router, spawn_server, temp_dir, url, ServerGuard};
+use std::time::Duration;
+use tonic::transport::Channel;
+
+fn put(id: u32, x: f32, version: u64) -> PutRequest {
+    PutRequest { id, vector: vec![x, 0.0], version, ..Default::default() }
+}
+
+async fn get(client: &mut VectorDbClient<Channel>, id: u32) -> GetResponse {
+    client.get(GetRequest { id, ..Default::default() }).await.unwrap().into_inner()
+}
+
+// Polls until `client` holds `expected` for `id`, as read repair runs in the background
+async fn wait_for(client: &mut VectorDbClient<Channel>, id: u32, expected: &GetResponse) {
+    for _ in 0..50 {
+        if get(client, id).await == *expected {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(get(client, id).await, *expected, "{} was not repaired", id);
+}
+
+#[tokio::test]
+async fn test_quorum_reads_return_and_repair_the_newest_version() {
+    let dir = temp_dir("read_repair");
+    let ports = [free_port(), free_port()];
+    let _servers: Vec<ServerGuard> = ports
+        .iter()
+        .map(|&port| ServerGuard(spawn_server(port, &dir.join(port.to_string()), &[])))
+        .collect();
+    let mut a = connect(ports[0]).await;
+    let mut b = connect(ports[1]).await;
+
+    let router_port = free_port();
+    let _router = ServerGuard(spawn_router(
+        router_port,
+        &["--backend", &url(ports[0]), "--backend", &url(ports[1]), "--replication-factor", "2", "--read-quorum", "2"],
+    ));
+    let mut client = connect(router_port).await;
+    for id in 0..3 {
+        client.put(put(id, id as f32, 0)).await.unwrap();
+    }
+    let written = get(&mut client, 0).await;
+    assert!(written.found && written.version > 0, "the router versions writes");
+    assert_eq!(get(&mut a, 0).await, written, "every replica gets the same version");
+
+    // Last write wins, whatever order writes arrive in
+    b.put(put(0, -1.0, written.version - 1)).await.unwrap();
+    assert_eq!(get(&mut b, 0).await, written);
+    b.put(put(0, 9.0, written.version + 1)).await.unwrap();
+    client.delete(DeleteRequest { id: 0, version: written.version, ..Default::default() }).await.unwrap();
+    assert!(!get(&mut a, 0).await.found);
+    assert!(get(&mut b, 0).await.found, "an older delete leaves a newer write");
+
+    // A read returns the newest version among the replicas, and brings the
+    // others up to date
+    let newest = get(&mut client, 0).await;
+    assert_eq!((newest.vector.as_slice(), newest.version), ([9.0, 0.0].as_slice(), written.version + 1));
+    wait_for(&mut a, 0, &newest).await;
+
+    let version = get(&mut client, 1).await.version;
+    a.put(put(1, 11.0, version + 5)).await.unwrap();
+    let newest = get(&mut client, 1).await;
+    assert_eq!(newest.vector, vec![11.0, 0.0]);
+    wait_for(&mut b, 1, &newest).await;
+
+    // A replica that never got a key gets it
+    a.put(put(3, 3.0, 0)).await.unwrap();
+    let found = get(&mut client, 3).await;
+    assert!(found.found);
+    wait_for(&mut b, 3, &found).await;
+
+    // A newer delete on one replica wins, and reaches the others
+    let version = get(&mut client, 2).await.version;
+    b.delete(DeleteRequest { id: 2, version: version + 1, ..Default::default() }).await.unwrap();
+    let deleted = get(&mut client, 2).await;
+    assert_eq!((deleted.found, deleted.version), (false, version + 1));
+    wait_for(&mut a, 2, &deleted).await;
+    a.put(put(2, 2.0, version)).await.unwrap();
+    assert_eq!(get(&mut a, 2).await, deleted, "an older write loses to the delete");
+
+    drop(_router);
+    drop(_servers);
+    let _ = std::fs::remove_dir_all(&dir);
+}
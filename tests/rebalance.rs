@@ -0,0 +1,153 @@
+mod common;
+
+use common::vector_db::rebalance_status::State;
+use common::vector_db::{
+    CreateCollectionRequest, DeleteRequest, GetRequest, ListCollectionsRequest, PutRequest, ReloadMembershipRequest,
+    RepairRequest,
+};
+use common::{connect, connect_admin, free_port, spawn_router, spawn_server, temp_dir, url, wait_for_rebalance, ServerGuard};
+use tonic::transport::Channel;
+
+type Client = common::vector_db::vector_db_client::VectorDbClient<Channel>;
+
+// Vectors held by each node, summed over collections
+async fn stored(nodes: &mut [Client]) -> u64 {
+    let mut total = 0;
+    for node in nodes {
+        let resp = node.list_collections(ListCollectionsRequest {}).await.unwrap().into_inner();
+        total += resp.collections.iter().map(|c| c.vector_count).sum::<u64>();
+    }
+    total
+}
+
+#[tokio::test]
+async fn test_rebalance_on_join_and_leave() {
+    let dir = temp_dir("rebalance");
+    let ports: Vec<u16> = (0..3).map(|_| free_port()).collect();
+    let mut servers: Vec<ServerGuard> = ports
+        .iter()
+        .map(|&port| ServerGuard(spawn_server(port, &dir.join(port.to_string()), &[])))
+        .collect();
+    let mut nodes = Vec::new();
+    for &port in &ports {
+        nodes.push(connect(port).await);
+    }
+
+    let router_port = free_port();
+    let _router = ServerGuard(spawn_router(
+        router_port,
+        &["--backend", &url(ports[0]), "--backend", &url(ports[1]), "--replication-factor", "2"],
+    ));
+    let mut client = connect(router_port).await;
+    let mut admin = connect_admin(router_port).await;
+
+    client
+        .create_collection(CreateCollectionRequest { name: "docs".to_string(), ..Default::default() })
+        .await
+        .unwrap();
+    let ids = 0..200;
+    for id in ids.clone() {
+        for collection in ["", "docs"] {
+            let req = PutRequest {
+                id,
+                vector: vec![id as f32, 1.0],
+                collection: collection.to_string(),
+                ..Default::default()
+            };
+            client.put(req).await.unwrap();
+        }
+    }
+    assert_eq!(stored(&mut nodes).await, 800);
+
+    // A third node joins: it receives its ranges, including the collection
+    // it didn't have, and the others drop what they handed over
+    let all: Vec<String> = ports.iter().map(|&p| url(p)).collect();
+    admin.reload_membership(ReloadMembershipRequest { backends: all }).await.unwrap();
+    let status = wait_for_rebalance(&mut admin).await;
+    assert_eq!(status.state(), State::Done, "{}", status.error);
+    assert!(status.changed_ranges > 0);
+    assert_eq!(status.transfers_done, status.transfers_total);
+    assert!(status.vectors_copied > 0 && status.vectors_removed > 0);
+    assert_eq!(stored(&mut nodes).await, 800);
+    let docs = nodes[2].list_collections(ListCollectionsRequest {}).await.unwrap().into_inner();
+    assert!(docs.collections.iter().any(|c| c.name == "docs" && c.vector_count > 0));
+
+    // A write after the join reaches the new owners
+    client.put(PutRequest { id: 500, vector: vec![5.0, 5.0], ..Default::default() }).await.unwrap();
+
+    // The first node leaves; its ranges are re-replicated from the survivors
+    let remaining = vec![url(ports[1]), url(ports[2])];
+    admin.reload_membership(ReloadMembershipRequest { backends: remaining }).await.unwrap();
+    let status = wait_for_rebalance(&mut admin).await;
+    assert_eq!(status.state(), State::Done, "{}", status.error);
+    drop(servers.remove(0));
+
+    for id in ids.chain([500]) {
+        for collection in ["", "docs"] {
+            if id == 500 && collection == "docs" {
+                continue;
+            }
+            let req = GetRequest { id, collection: collection.to_string() };
+            let resp = client.get(req).await.unwrap().into_inner();
+            assert!(resp.found, "lost {} in '{}'", id, collection);
+        }
+    }
+
+    drop(_router);
+    drop(servers);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_rebalance_carries_deletes() {
+    let dir = temp_dir("rebalance_deletes");
+    let ports: Vec<u16> = (0..4).map(|_| free_port()).collect();
+    let _servers: Vec<ServerGuard> = ports
+        .iter()
+        .map(|&port| ServerGuard(spawn_server(port, &dir.join(port.to_string()), &[])))
+        .collect();
+    let mut joined = connect(ports[2]).await;
+
+    let router_port = free_port();
+    let _router = ServerGuard(spawn_router(
+        router_port,
+        &["--backend", &url(ports[0]), "--backend", &url(ports[1]), "--replication-factor", "2"],
+    ));
+    let mut client = connect(router_port).await;
+    let mut admin = connect_admin(router_port).await;
+
+    let mut puts = Vec::new();
+    for id in 0..20 {
+        let req = PutRequest { id, vector: vec![id as f32, 1.0], ..Default::default() };
+        client.put(req.clone()).await.unwrap();
+        let version = client.get(GetRequest { id, ..Default::default() }).await.unwrap().into_inner().version;
+        puts.push(PutRequest { version, ..req });
+    }
+    for id in 0..10 {
+        client.delete(DeleteRequest { id, ..Default::default() }).await.unwrap();
+    }
+
+    // Every key moves to two new nodes, deletes included
+    let replaced = vec![url(ports[2]), url(ports[3])];
+    admin.reload_membership(ReloadMembershipRequest { backends: replaced }).await.unwrap();
+    let status = wait_for_rebalance(&mut admin).await;
+    assert_eq!(status.state(), State::Done, "{}", status.error);
+
+    // A write from before the deletes turns up late on one new owner, as a
+    // replayed hint might. It loses to the delete there, so repair has
+    // nothing to spread.
+    for req in &puts[..10] {
+        joined.put(req.clone()).await.unwrap();
+    }
+    let report = admin.repair(RepairRequest { dry_run: false }).await.unwrap().into_inner();
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.divergent_keys, 0);
+    for id in 0..20 {
+        let resp = client.get(GetRequest { id, ..Default::default() }).await.unwrap().into_inner();
+        assert_eq!(resp.found, id >= 10, "id {}", id);
+    }
+
+    drop(_router);
+    drop(_servers);
+    let _ = std::fs::remove_dir_all(&dir);
+}
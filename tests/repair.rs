@@ -0,0 +1,74 @@
+mod common;
+
+use common::vector_db::{
+    CreateCollectionRequest, DeleteRequest, GetRequest, ListCollectionsRequest, PutRequest, RepairRequest,
+};
+use common::{connect, connect_admin, free_port, spawn_router, spawn_server, temp_dir, url, ServerGuard};
+
+fn put(id: u32, x: f32, collection: &str) -> PutRequest {
+    PutRequest { id, vector: vec![x, 1.0], collection: collection.to_string(), ..Default::default() }
+}
+
+#[tokio::test]
+async fn test_repair_finds_and_fixes_divergent_replicas() {
+    let dir = temp_dir("repair");
+    let ports = [free_port(), free_port()];
+    let _servers: Vec<ServerGuard> = ports
+        .iter()
+        .map(|&port| ServerGuard(spawn_server(port, &dir.join(port.to_string()), &[])))
+        .collect();
+    let mut a = connect(ports[0]).await;
+    let mut b = connect(ports[1]).await;
+
+    let router_port = free_port();
+    let _router = ServerGuard(spawn_router(
+        router_port,
+        &["--backend", &url(ports[0]), "--backend", &url(ports[1]), "--replication-factor", "2"],
+    ));
+    let mut client = connect(router_port).await;
+    let mut admin = connect_admin(router_port).await;
+    for id in 0..50 {
+        client.put(put(id, id as f32, "")).await.unwrap();
+    }
+    let report = admin.repair(RepairRequest { dry_run: false }).await.unwrap().into_inner();
+    assert_eq!(report.divergent_keys, 0);
+    assert!(report.ranges_checked > 0);
+
+    // Make the replicas disagree behind the router's back: two keys missing
+    // from b, one changed on b, one deleted only on b, one only on a, and a
+    // collection only on b
+    b.delete(DeleteRequest { id: 3, evict: true, ..Default::default() }).await.unwrap();
+    b.delete(DeleteRequest { id: 4, evict: true, ..Default::default() }).await.unwrap();
+    b.delete(DeleteRequest { id: 5, ..Default::default() }).await.unwrap();
+    b.put(put(7, -7.0, "")).await.unwrap();
+    a.put(put(100, 100.0, "")).await.unwrap();
+    b.create_collection(CreateCollectionRequest { name: "docs".to_string(), ..Default::default() })
+        .await
+        .unwrap();
+    b.put(put(1, 1.0, "docs")).await.unwrap();
+
+    let report = admin.repair(RepairRequest { dry_run: true }).await.unwrap().into_inner();
+    assert_eq!((report.divergent_keys, report.keys_repaired), (6, 0), "{:?}", report);
+    let resp = b.get(GetRequest { id: 3, ..Default::default() }).await.unwrap().into_inner();
+    assert!(!resp.found, "a dry run changed nothing");
+    let collections = a.list_collections(ListCollectionsRequest {}).await.unwrap().into_inner().collections;
+    assert!(collections.iter().all(|c| c.name != "docs"), "a dry run created nothing");
+
+    let report = admin.repair(RepairRequest { dry_run: false }).await.unwrap().into_inner();
+    assert_eq!((report.divergent_keys, report.keys_repaired), (6, 6), "{:?}", report);
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    let report = admin.repair(RepairRequest { dry_run: true }).await.unwrap().into_inner();
+    assert_eq!(report.divergent_keys, 0);
+
+    for (id, collection) in (0..50).chain([100]).map(|id| (id, "")).chain([(1, "docs")]) {
+        let req = GetRequest { id, collection: collection.to_string() };
+        let on_a = a.get(req.clone()).await.unwrap().into_inner();
+        let on_b = b.get(req).await.unwrap().into_inner();
+        assert_eq!(on_a.found, id != 5 || collection == "docs", "the newer delete wins on every replica");
+        assert_eq!(on_a, on_b, "replicas disagree on {}", id);
+    }
+
+    drop(_router);
+    drop(_servers);
+    let _ = std::fs::remove_dir_all(&dir);
+}
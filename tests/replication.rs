@@ -0,0 +1,125 @@
+mod common;
+
+use common::vector_db::vector_db_client::VectorDbClient;
+use common::vector_db::{
+    CreateCollectionRequest, DeleteRequest, DescribeCollectionRequest, GetRequest, PutRequest, SearchRequest,
+    SnapshotRequest,
+};
+use common::{connect, free_port, spawn_server, temp_dir, url, ServerGuard};
+use std::time::Duration;
+use tonic::transport::Channel;
+
+// Waits until `node` holds `count` vectors in `collection`
+async fn wait_for_count(node: &mut VectorDbClient<Channel>, collection: &str, count: u64) {
+    let mut seen = 0;
+    for _ in 0..100 {
+        let req = DescribeCollectionRequest { name: collection.to_string() };
+        if let Ok(info) = node.describe_collection(req).await {
+            seen = info.into_inner().vector_count;
+            if seen == count {
+                return;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("{} holds {} vectors, expected {}", collection, seen, count);
+}
+
+fn put(id: u32, collection: &str) -> PutRequest {
+    PutRequest { id, vector: vec![id as f32, 1.0], collection: collection.to_string(), ..Default::default() }
+}
+
+#[tokio::test]
+async fn test_follower_replicates_and_resumes() {
+    let dir = temp_dir("replication");
+    let primary_port = free_port();
+    let follower_port = free_port();
+    let _primary = ServerGuard(spawn_server(primary_port, &dir.join("primary"), &[]));
+    let mut primary = connect(primary_port).await;
+    for id in 0..10 {
+        primary.put(put(id, "")).await.unwrap();
+    }
+
+    let primary_url = url(primary_port);
+    let follower_dir = dir.join("follower");
+    let follower_args = ["--follow", primary_url.as_str()];
+    let follower_guard = ServerGuard(spawn_server(follower_port, &follower_dir, &follower_args));
+    let mut follower = connect(follower_port).await;
+    wait_for_count(&mut follower, "default", 10).await;
+
+    // New writes and collections stream through as they happen
+    primary.delete(DeleteRequest { id: 0, ..Default::default() }).await.unwrap();
+    primary.create_collection(CreateCollectionRequest { name: "docs".to_string(), ..Default::default() }).await.unwrap();
+    for id in 0..5 {
+        primary.put(put(id, "docs")).await.unwrap();
+    }
+    wait_for_count(&mut follower, "default", 9).await;
+    wait_for_count(&mut follower, "docs", 5).await;
+    let get = GetRequest { id: 3, ..Default::default() };
+    let expected = primary.get(get.clone()).await.unwrap().into_inner();
+    assert_eq!(follower.get(get).await.unwrap().into_inner(), expected);
+
+    let search = SearchRequest { vector: vec![4.0, 1.0], k: 1, ..Default::default() };
+    let results = follower.search(search).await.unwrap().into_inner().results;
+    assert_eq!(results[0].id, 4);
+    let err = follower.put(put(100, "")).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::FailedPrecondition, "{}", err.message());
+
+    // A restarted follower picks up what it missed, without applying anything twice
+    drop(follower_guard);
+    for id in 10..20 {
+        primary.put(put(id, "")).await.unwrap();
+    }
+    primary.put(put(5, "")).await.unwrap();
+    let _follower = ServerGuard(spawn_server(follower_port, &follower_dir, &follower_args));
+    let mut follower = connect(follower_port).await;
+    wait_for_count(&mut follower, "default", 19).await;
+    let get = GetRequest { id: 5, ..Default::default() };
+    let expected = primary.get(get.clone()).await.unwrap().into_inner();
+    assert_eq!(follower.get(get).await.unwrap().into_inner(), expected);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_follower_copies_what_the_primary_log_no_longer_holds() {
+    let dir = temp_dir("replication_copy");
+    let primary_port = free_port();
+    let follower_port = free_port();
+    let _primary = ServerGuard(spawn_server(primary_port, &dir.join("primary"), &["--wal-segment-bytes", "256"]));
+    let mut primary = connect(primary_port).await;
+    for id in 0..10 {
+        primary.put(put(id, "")).await.unwrap();
+    }
+
+    let primary_url = url(primary_port);
+    let follower_dir = dir.join("follower");
+    let follower_args = ["--follow", primary_url.as_str()];
+    let follower_guard = ServerGuard(spawn_server(follower_port, &follower_dir, &follower_args));
+    let mut follower = connect(follower_port).await;
+    wait_for_count(&mut follower, "default", 10).await;
+
+    // While the follower is down, the segments it would resume from go
+    drop(follower_guard);
+    for id in 10..30 {
+        primary.put(put(id, "")).await.unwrap();
+    }
+    primary.delete(DeleteRequest { id: 0, ..Default::default() }).await.unwrap();
+    primary.put(PutRequest { vector: vec![5.0, 2.0], ..put(5, "") }).await.unwrap();
+    primary.snapshot(SnapshotRequest {}).await.unwrap();
+
+    // It copies the collection instead, then streams on from there
+    let _follower = ServerGuard(spawn_server(follower_port, &follower_dir, &follower_args));
+    let mut follower = connect(follower_port).await;
+    wait_for_count(&mut follower, "default", 29).await;
+    for id in [0, 5, 29] {
+        let get = GetRequest { id, ..Default::default() };
+        let expected = primary.get(get.clone()).await;
+        let got = follower.get(get).await;
+        assert_eq!(got.as_ref().map(|r| r.get_ref()).ok(), expected.as_ref().map(|r| r.get_ref()).ok(), "id {}", id);
+    }
+    primary.put(put(30, "")).await.unwrap();
+    wait_for_count(&mut follower, "default", 30).await;
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
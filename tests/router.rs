@@ -0,0 +1,103 @@
+mod common;
+
+use common::vector_db::{GetMembershipRequest, GetRequest, PutRequest, ReloadMembershipRequest, SearchRequest};
+use common::{
+    connect, connect_admin, free_port, sorted, spawn_router, spawn_server, temp_dir, url, wait_for_membership,
+    wait_for_rebalance, ServerGuard,
+};
+
+#[tokio::test]
+async fn test_router_config_and_membership_reload() {
+    let dir = temp_dir("router");
+    let ports: Vec<u16> = (0..3).map(|_| free_port()).collect();
+    let _servers: Vec<ServerGuard> = ports
+        .iter()
+        .map(|&port| ServerGuard(spawn_server(port, &dir.join(port.to_string()), &[])))
+        .collect();
+    for &port in &ports {
+        connect(port).await;
+    }
+
+    let config_path = dir.join("router.toml");
+    let write_config = |backends: &[u16]| {
+        let backends: Vec<String> = backends.iter().map(|&p| format!("\"{}\"", url(p))).collect();
+        let text = format!(
+            "backends = [{}]\nreplication_factor = 2\nwrite_quorum = 2\n",
+            backends.join(", ")
+        );
+        std::fs::write(&config_path, text).unwrap();
+    };
+    write_config(&ports[..2]);
+
+    // A quorum larger than the replication factor is rejected at startup
+    let status = spawn_router(free_port(), &["--config", config_path.to_str().unwrap(), "--write-quorum", "3"])
+        .wait()
+        .unwrap();
+    assert!(!status.success());
+
+    let router_port = free_port();
+    let _router = ServerGuard(spawn_router(
+        router_port,
+        &["--config", config_path.to_str().unwrap(), "--request-timeout-ms", "2000"],
+    ));
+    let mut client = connect(router_port).await;
+    let mut admin = connect_admin(router_port).await;
+
+    // Both backends must acknowledge every write
+    for id in 0..20 {
+        client
+            .put(PutRequest { id, vector: vec![id as f32, 0.0], ..Default::default() })
+            .await
+            .unwrap();
+    }
+    let resp = client.search(SearchRequest { vector: vec![3.0, 0.0], k: 3, ..Default::default() }).await.unwrap();
+    assert_eq!(resp.into_inner().results[0].id, 3);
+    let resp = client.get(GetRequest { id: 7, ..Default::default() }).await.unwrap().into_inner();
+    assert_eq!(resp.vector, vec![7.0, 0.0]);
+
+    let membership = admin.get_membership(GetMembershipRequest {}).await.unwrap().into_inner();
+    assert_eq!(sorted(membership.backends), sorted(vec![url(ports[0]), url(ports[1])]));
+
+    // Explicit membership through the admin RPC. Data moves first, so the
+    // new node is pending until the rebalance finishes.
+    let all: Vec<String> = ports.iter().map(|&p| url(p)).collect();
+    let membership = admin
+        .reload_membership(ReloadMembershipRequest { backends: all.clone() })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(sorted(membership.pending_backends), sorted(all.clone()));
+    wait_for_membership(&mut admin, &all).await;
+    // Stale copies are removed after the switch; a reload waits for that
+    wait_for_rebalance(&mut admin).await;
+    let err = admin
+        .reload_membership(ReloadMembershipRequest { backends: vec!["not a url".to_string()] })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+    // SIGHUP re-reads the backends from the config file
+    write_config(&[ports[0], ports[2]]);
+    let status = std::process::Command::new("kill")
+        .args(["-HUP", &_router.0.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    wait_for_membership(&mut admin, &[url(ports[0]), url(ports[2])]).await;
+    wait_for_rebalance(&mut admin).await;
+
+    // So does an empty reload request
+    write_config(&ports[1..]);
+    admin.reload_membership(ReloadMembershipRequest::default()).await.unwrap();
+    wait_for_membership(&mut admin, &[url(ports[1]), url(ports[2])]).await;
+
+    // Every vector survived the moves
+    for id in 0..20 {
+        let resp = client.get(GetRequest { id, ..Default::default() }).await.unwrap().into_inner();
+        assert!(resp.found, "lost {}", id);
+    }
+
+    drop(_router);
+    drop(_servers);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,129 @@
+mod common;
+
+use common::vector_db::shard_status::State;
+use common::vector_db::vector_db_client::VectorDbClient;
+use common::vector_db::{HashRange, PutRequest, SearchRequest};
+use common::{connect, free_port, spawn_router, spawn_server, temp_dir, url, ServerGuard};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use tonic::transport::Channel;
+
+fn search(k: u32, ranges: Vec<HashRange>) -> SearchRequest {
+    SearchRequest { vector: vec![0.0, 0.0], k, ranges, ..Default::default() }
+}
+
+struct Cluster {
+    dir: PathBuf,
+    ports: Vec<u16>,
+    servers: Vec<ServerGuard>,
+    router: ServerGuard,
+    client: VectorDbClient<Channel>,
+}
+
+// Three servers behind a router at replication factor 2, holding ids 0..60
+async fn start_cluster(name: &str) -> Cluster {
+    let dir = temp_dir(name);
+    let ports: Vec<u16> = (0..3).map(|_| free_port()).collect();
+    let servers: Vec<ServerGuard> = ports
+        .iter()
+        .map(|&port| ServerGuard(spawn_server(port, &dir.join(port.to_string()), &[])))
+        .collect();
+    for &port in &ports {
+        connect(port).await;
+    }
+
+    let router_port = free_port();
+    let backends: Vec<String> = ports.iter().flat_map(|&p| ["--backend".to_string(), url(p)]).collect();
+    let mut args: Vec<&str> = backends.iter().map(String::as_str).collect();
+    args.extend(["--replication-factor", "2", "--request-timeout-ms", "2000"]);
+    let router = ServerGuard(spawn_router(router_port, &args));
+    let mut client = connect(router_port).await;
+    for id in 0..60 {
+        client
+            .put(PutRequest { id, vector: vec![id as f32, 1.0], ..Default::default() })
+            .await
+            .unwrap();
+    }
+    Cluster { dir, ports, servers, router, client }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        self.servers.clear();
+        let _ = self.router.0.kill();
+        let _ = self.router.0.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[tokio::test]
+async fn test_searches_cover_every_key_once() {
+    let mut cluster = start_cluster("scatter_search").await;
+    let client = &mut cluster.client;
+
+    // A node searches only the ranges it is asked for
+    let mut node = connect(cluster.ports[0]).await;
+    let held = node.search(search(100, vec![])).await.unwrap().into_inner().results;
+    let low = node.search(search(100, vec![HashRange { start: 0, end: 1 << 63 }])).await.unwrap();
+    let high = node.search(search(100, vec![HashRange { start: 1 << 63, end: 0 }])).await.unwrap();
+    let low: HashSet<u32> = low.into_inner().results.iter().map(|r| r.id).collect();
+    let high: HashSet<u32> = high.into_inner().results.iter().map(|r| r.id).collect();
+    assert!(low.is_disjoint(&high));
+    assert_eq!(low.len() + high.len(), held.len());
+
+    // Through the router, every key comes back exactly once, in order
+    let resp = client.search(search(100, vec![])).await.unwrap().into_inner();
+    let ids: Vec<u32> = resp.results.iter().map(|r| r.id).collect();
+    assert_eq!(ids, (0..60).collect::<Vec<_>>());
+    assert!(!resp.partial);
+    assert_eq!(resp.shards.len(), 2, "two of three nodes cover the ring at replication factor 2");
+    assert!(resp.shards.iter().all(|s| s.state() == State::Ok));
+    let results = client.search(search(5, vec![])).await.unwrap().into_inner().results;
+    assert_eq!(results.iter().map(|r| r.id).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+
+    // With a node down, its arcs are searched on their other replicas
+    drop(cluster.servers.remove(1));
+    let resp = client.search(search(100, vec![])).await.unwrap().into_inner();
+    let ids: Vec<u32> = resp.results.iter().map(|r| r.id).collect();
+    assert_eq!(ids, (0..60).collect::<Vec<_>>());
+    assert!(!resp.partial);
+}
+
+#[tokio::test]
+async fn test_partial_results_are_reported() {
+    let mut cluster = start_cluster("partial_search").await;
+    let client = &mut cluster.client;
+
+    // A stopped node keeps its connections open but never answers, so it
+    // times out; the search still completes through the other replicas
+    let stopped = cluster.servers[1].0.id().to_string();
+    let status = std::process::Command::new("kill").args(["-STOP", &stopped]).status().unwrap();
+    assert!(status.success());
+    let mut timed_out = false;
+    for _ in 0..10 {
+        let req = SearchRequest { shard_timeout_ms: Some(300), ..search(100, vec![]) };
+        let resp = client.search(req).await.unwrap().into_inner();
+        assert_eq!(resp.results.len(), 60);
+        assert!(!resp.partial && resp.missing.is_empty());
+        if let Some(shard) = resp.shards.iter().find(|s| s.node == url(cluster.ports[1])) {
+            assert_eq!(shard.state(), State::Timeout, "{:?}", shard);
+            assert!(shard.arcs > 0);
+            timed_out = true;
+        }
+    }
+    assert!(timed_out, "the stopped node was never asked");
+
+    // With a second node gone, the keys only those two held are missing
+    drop(cluster.servers.remove(2));
+    let req = SearchRequest { shard_timeout_ms: Some(300), ..search(100, vec![]) };
+    let resp = client.search(req.clone()).await.unwrap().into_inner();
+    assert!(resp.partial);
+    assert!(!resp.missing.is_empty());
+    assert!(!resp.results.is_empty() && resp.results.len() < 60, "{} results", resp.results.len());
+    assert!(resp.shards.iter().any(|s| s.state() == State::Error));
+
+    let err = client.search(SearchRequest { fail_on_partial: true, ..req }).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::Unavailable);
+
+    let _ = std::process::Command::new("kill").args(["-CONT", &stopped]).status();
+}